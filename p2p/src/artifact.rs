@@ -0,0 +1,650 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, error::Error, fs::{self, File}, io::{self, Read, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use async_trait::async_trait;
+use futures::{stream::FuturesUnordered, AsyncRead, AsyncWrite, StreamExt};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec, PeerId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{files::{self, read_frames, write_frames}, handle::NodeHandle};
+
+/// Floodsub topic of the `Announcement`s
+pub const ARTIFACT_TOPIC: &str = "artifacts";
+pub const ARTIFACT_CHUNK_SIZE: u64 = 1024 * 1024;
+// Fits the manifest of an artifact of about 60GiB
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+// Chunk requests in flight during a fetch, spread over the providers
+const MAX_IN_FLIGHT: usize = 8;
+// Rounds over every known provider without getting a chunk before a fetch fails
+const MAX_STALLED_ROUNDS: u32 = 5;
+/// Artifacts per announcement, floodsub drops packets over 2KiB
+pub const ANNOUNCEMENT_BATCH: usize = 16;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CHUNKS_DIR: &str = "chunks";
+// Markers in the directory of an artifact
+const COMPLETE_FILE: &str = "complete";
+const PINNED_FILE: &str = "pinned";
+
+#[derive(Debug, Clone)]
+pub struct ArtifactProtocol;
+
+impl ProtocolName for ArtifactProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/artifact/1.0.0"
+    }
+}
+
+/// Lists the chunks of an artifact, its id is the hash of the manifest so a
+/// manifest from any peer can be checked against the id asked for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    // Name of the file it was added from, not part of the id
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub chunk_size: u64,
+    pub chunks: Vec<String>,
+}
+
+impl Manifest {
+    fn digest(size: u64, sha256: &str, chunk_size: u64, chunks: &[String]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}\n{}\n{}\n", size, sha256, chunk_size));
+        for chunk in chunks {
+            hasher.update(chunk);
+            hasher.update(b"\n");
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn verify(&self) -> bool {
+        let count = if self.chunk_size == 0 { 0 } else { (self.size + self.chunk_size - 1) / self.chunk_size };
+        self.chunk_size > 0
+            && self.chunk_size <= ARTIFACT_CHUNK_SIZE
+            && self.chunks.len() as u64 == count
+            && self.id == Manifest::digest(self.size, &self.sha256, self.chunk_size, &self.chunks)
+    }
+}
+
+pub fn valid_id(id: &str) -> bool {
+    id.len() == 64 && id.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ArtifactOp {
+    Manifest { id: String },
+    Chunk { id: String, index: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ArtifactResult {
+    Manifest { manifest: Manifest },
+    // Followed by the bytes of the chunk
+    Chunk,
+    // The peer does not have it (yet)
+    Missing,
+    Error { message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ArtifactResponse {
+    pub result: ArtifactResult,
+    pub data: Vec<u8>,
+}
+
+impl ArtifactResponse {
+    fn result(result: ArtifactResult) -> ArtifactResponse {
+        ArtifactResponse { result, data: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArtifactCodec;
+
+#[async_trait]
+impl RequestResponseCodec for ArtifactCodec {
+    type Protocol = ArtifactProtocol;
+    type Request = ArtifactOp;
+    type Response = ArtifactResponse;
+
+    async fn read_request<T>(&mut self, _: &ArtifactProtocol, io: &mut T) -> io::Result<ArtifactOp>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (op, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(op)
+    }
+
+    async fn read_response<T>(&mut self, _: &ArtifactProtocol, io: &mut T) -> io::Result<ArtifactResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (result, data) = read_frames(io, MAX_FRAME).await?;
+        Ok(ArtifactResponse { result, data })
+    }
+
+    async fn write_request<T>(&mut self, _: &ArtifactProtocol, io: &mut T, op: ArtifactOp) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &op, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &ArtifactProtocol, io: &mut T, res: ArtifactResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res.result, &res.data).await
+    }
+}
+
+/// Artifacts a node has, published on `ARTIFACT_TOPIC` when they change and
+/// when a peer subscribes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub artifacts: Vec<Availability>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Availability {
+    pub id: String,
+    // Partial copies serve the chunks they have
+    pub complete: bool,
+}
+
+/// A peer that may have the chunks of an artifact
+#[derive(Debug, Clone)]
+pub struct Provider {
+    pub peer: PeerId,
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactInfo {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub chunks: u64,
+    // Chunks in the store
+    pub have: u64,
+    pub complete: bool,
+    // Kept by `gc`
+    pub pinned: bool,
+    #[serde(default)]
+    pub fetching: bool,
+    // Why the last fetch failed
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GcResult {
+    pub removed: Vec<String>,
+    pub freed: u64,
+}
+
+/// Artifacts kept in a directory of the node, laid out as
+/// `<id>/manifest.json` and `<id>/chunks/<index>`
+#[derive(Debug, Clone)]
+pub struct ArtifactStore {
+    root: PathBuf,
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.flatten().map(|entry| match entry.metadata() {
+        Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    }).sum()
+}
+
+impl ArtifactStore {
+    pub fn new(root: &str) -> ArtifactStore {
+        ArtifactStore { root: PathBuf::from(root) }
+    }
+
+    // Ids come from peers, they must not name anything outside the root
+    fn dir(&self, id: &str) -> Result<PathBuf, String> {
+        match valid_id(id) {
+            true => Ok(self.root.join(id)),
+            false => Err(format!("Invalid artifact id {}", id)),
+        }
+    }
+
+    fn chunk_path(&self, id: &str, index: u64) -> Result<PathBuf, String> {
+        Ok(self.dir(id)?.join(CHUNKS_DIR).join(index.to_string()))
+    }
+
+    /// Split a file into the store, the artifact is pinned
+    pub fn add(&self, path: &Path, name: &str) -> Result<Manifest, Box<dyn Error>> {
+        let mut file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        // Chunks are written before the id is known
+        let tmp = self.root.join(format!(".add-{}-{}", std::process::id(), nanos));
+        fs::create_dir_all(tmp.join(CHUNKS_DIR))?;
+        let r = (|| -> Result<Manifest, Box<dyn Error>> {
+            let mut hasher = Sha256::new();
+            let mut chunks = Vec::new();
+            let mut size = 0;
+            loop {
+                let mut data = Vec::with_capacity(ARTIFACT_CHUNK_SIZE as usize);
+                (&mut file).take(ARTIFACT_CHUNK_SIZE).read_to_end(&mut data)?;
+                if data.is_empty() {
+                    break;
+                }
+                hasher.update(&data);
+                fs::write(tmp.join(CHUNKS_DIR).join(chunks.len().to_string()), &data)?;
+                chunks.push(files::sha256_hex(&data));
+                size += data.len() as u64;
+            }
+            let sha256 = hex::encode(hasher.finalize());
+            let id = Manifest::digest(size, &sha256, ARTIFACT_CHUNK_SIZE, &chunks);
+            Ok(Manifest { id, name: name.to_string(), size, sha256, chunk_size: ARTIFACT_CHUNK_SIZE, chunks })
+        })();
+        let manifest = match r {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&tmp);
+                return Err(e);
+            },
+        };
+        let dir = self.dir(&manifest.id)?;
+        if self.is_complete(&manifest.id) {
+            fs::remove_dir_all(&tmp)?;
+        } else {
+            fs::write(tmp.join(MANIFEST_FILE), serde_json::to_vec(&manifest)?)?;
+            fs::write(tmp.join(COMPLETE_FILE), b"")?;
+            // Replaces a partial copy left by a fetch
+            let _ = fs::remove_dir_all(&dir);
+            fs::rename(&tmp, &dir)?;
+        }
+        self.pin(&manifest.id)?;
+        Ok(manifest)
+    }
+
+    pub fn manifest(&self, id: &str) -> Option<Manifest> {
+        let data = fs::read(self.dir(id).ok()?.join(MANIFEST_FILE)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save_manifest(&self, manifest: &Manifest) -> Result<(), String> {
+        if !manifest.verify() {
+            return Err(format!("Manifest of {} does not match its id", manifest.id));
+        }
+        let dir = self.dir(&manifest.id)?;
+        let io_err = |e: io::Error| format!("{}: {}", dir.display(), e);
+        fs::create_dir_all(dir.join(CHUNKS_DIR)).map_err(io_err)?;
+        let data = serde_json::to_vec(manifest).map_err(|e| e.to_string())?;
+        fs::write(dir.join(MANIFEST_FILE), data).map_err(io_err)
+    }
+
+    pub fn has_chunk(&self, id: &str, index: u64) -> bool {
+        self.chunk_path(id, index).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    /// Store a chunk fetched from a peer once it matches the manifest
+    pub fn write_chunk(&self, manifest: &Manifest, index: u64, data: &[u8]) -> Result<(), String> {
+        match manifest.chunks.get(index as usize) {
+            Some(sha256) if *sha256 == files::sha256_hex(data) => {},
+            Some(_) => return Err(format!("Chunk {} does not match its hash", index)),
+            None => return Err(format!("No chunk {} in {}", index, manifest.id)),
+        }
+        let path = self.chunk_path(&manifest.id, index)?;
+        let tmp = files::partial_path(&path);
+        let io_err = |e: io::Error| format!("{}: {}", path.display(), e);
+        fs::write(&tmp, data).map_err(io_err)?;
+        fs::rename(&tmp, &path).map_err(io_err)
+    }
+
+    pub fn missing(&self, manifest: &Manifest) -> Vec<u64> {
+        (0..manifest.chunks.len() as u64).filter(|i| !self.has_chunk(&manifest.id, *i)).collect()
+    }
+
+    pub fn is_complete(&self, id: &str) -> bool {
+        self.dir(id).map(|d| d.join(COMPLETE_FILE).is_file()).unwrap_or(false)
+    }
+
+    fn mark_complete(&self, id: &str) -> Result<(), String> {
+        let dir = self.dir(id)?;
+        fs::write(dir.join(COMPLETE_FILE), b"").map_err(|e| format!("{}: {}", dir.display(), e))
+    }
+
+    pub fn pin(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        Ok(fs::write(self.dir(id)?.join(PINNED_FILE), b"")?)
+    }
+
+    /// Write a complete artifact to a file, checking the hash of the whole
+    pub fn export(&self, id: &str, out: &Path) -> Result<(), Box<dyn Error>> {
+        let manifest = match (self.manifest(id), self.is_complete(id)) {
+            (Some(manifest), true) => manifest,
+            _ => return Err(format!("Artifact {} is not in the store, fetch it first", id).into()),
+        };
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = files::partial_path(out);
+        let mut file = File::create(&partial)?;
+        let mut hasher = Sha256::new();
+        for index in 0..manifest.chunks.len() as u64 {
+            let data = fs::read(self.chunk_path(id, index)?)?;
+            hasher.update(&data);
+            file.write_all(&data)?;
+        }
+        file.sync_all()?;
+        if hex::encode(hasher.finalize()) != manifest.sha256 {
+            let _ = fs::remove_file(&partial);
+            return Err(format!("Artifact {} does not match its hash, the store is corrupt", id).into());
+        }
+        fs::rename(&partial, out)?;
+        Ok(())
+    }
+
+    pub fn info(&self, id: &str) -> Option<ArtifactInfo> {
+        let manifest = self.manifest(id)?;
+        let dir = self.dir(id).ok()?;
+        let chunks = manifest.chunks.len() as u64;
+        let complete = self.is_complete(id);
+        Some(ArtifactInfo {
+            have: if complete { chunks } else { chunks - self.missing(&manifest).len() as u64 },
+            id: manifest.id,
+            name: manifest.name,
+            size: manifest.size,
+            chunks,
+            complete,
+            pinned: dir.join(PINNED_FILE).is_file(),
+            fetching: false,
+            error: None,
+        })
+    }
+
+    fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = match fs::read_dir(&self.root) {
+            Ok(entries) => entries.flatten()
+                .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+                .filter(|name| valid_id(name))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        ids.sort();
+        ids
+    }
+
+    pub fn list(&self) -> Vec<ArtifactInfo> {
+        self.ids().iter().filter_map(|id| self.info(id)).collect()
+    }
+
+    /// What the node can serve, for announcements
+    pub fn available(&self) -> Vec<Availability> {
+        self.ids().into_iter().map(|id| Availability { complete: self.is_complete(&id), id }).collect()
+    }
+
+    /// Remove the artifacts that are not pinned, except those in `keep`
+    pub fn gc(&self, keep: &HashSet<String>) -> Result<GcResult, Box<dyn Error>> {
+        let mut result = GcResult { removed: Vec::new(), freed: 0 };
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(result),
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            let removable = match valid_id(&name) {
+                true => !keep.contains(&name) && !path.join(PINNED_FILE).is_file(),
+                // Left by an interrupted add
+                false => name.starts_with(".add-"),
+            };
+            if removable {
+                let size = dir_size(&path);
+                fs::remove_dir_all(&path)?;
+                result.freed += size;
+                if valid_id(&name) {
+                    result.removed.push(name);
+                }
+            }
+        }
+        result.removed.sort();
+        Ok(result)
+    }
+
+    /// Answer a request of a peer
+    pub fn serve(&self, op: ArtifactOp) -> ArtifactResponse {
+        match op {
+            ArtifactOp::Manifest { id } => match self.manifest(&id) {
+                Some(manifest) => ArtifactResponse::result(ArtifactResult::Manifest { manifest }),
+                None => ArtifactResponse::result(ArtifactResult::Missing),
+            },
+            ArtifactOp::Chunk { id, index } => match self.chunk_path(&id, index).map(fs::read) {
+                Ok(Ok(data)) => ArtifactResponse { result: ArtifactResult::Chunk, data },
+                Ok(Err(_)) => ArtifactResponse::result(ArtifactResult::Missing),
+                Err(message) => ArtifactResponse::result(ArtifactResult::Error { message }),
+            },
+        }
+    }
+}
+
+/// The store and the fetches of the running node
+#[derive(Debug)]
+pub struct Artifacts {
+    store: ArtifactStore,
+    // Running fetches map to None, failed ones to their error
+    fetches: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Artifacts {
+    pub fn new(root: &str) -> Artifacts {
+        Artifacts { store: ArtifactStore::new(root), fetches: Mutex::new(HashMap::new()) }
+    }
+
+    fn with_fetch(&self, mut info: ArtifactInfo) -> ArtifactInfo {
+        if let Some(state) = self.fetches.lock().unwrap().get(&info.id) {
+            info.fetching = state.is_none();
+            info.error = state.clone();
+        }
+        info
+    }
+
+    /// An artifact being fetched is listed before its manifest arrived
+    pub fn info(&self, id: &str) -> Option<ArtifactInfo> {
+        match self.store.info(id) {
+            Some(info) => Some(self.with_fetch(info)),
+            None if self.fetches.lock().unwrap().contains_key(id) => Some(self.with_fetch(ArtifactInfo {
+                id: id.to_string(),
+                name: String::new(),
+                size: 0,
+                chunks: 0,
+                have: 0,
+                complete: false,
+                pinned: false,
+                fetching: false,
+                error: None,
+            })),
+            None => None,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ArtifactInfo> {
+        self.store.list().into_iter().map(|info| self.with_fetch(info)).collect()
+    }
+
+    /// Add a file of the node and tell the peers
+    pub async fn add(&self, handle: &NodeHandle, path: &str, name: &str) -> Result<ArtifactInfo, Box<dyn Error>> {
+        if !Path::new(path).is_absolute() {
+            return Err(format!("Path {} is not absolute", path).into());
+        }
+        let store = self.store.clone();
+        let (path, name) = (PathBuf::from(path), name.to_string());
+        let manifest = tokio::task::spawn_blocking(move || store.add(&path, &name).map_err(|e| e.to_string())).await??;
+        info!("Added artifact {} ({} bytes)", manifest.id, manifest.size);
+        handle.announce_artifacts(vec![Availability { id: manifest.id.clone(), complete: true }]).await?;
+        self.info(&manifest.id).ok_or_else(|| "Artifact vanished after it was added".into())
+    }
+
+    /// Fetch an artifact from the peers in the background, unless it is in
+    /// the store or already being fetched
+    pub fn fetch(self: &Arc<Self>, handle: &NodeHandle, id: &str, pin: bool) -> Result<ArtifactInfo, Box<dyn Error>> {
+        if !valid_id(id) {
+            return Err(format!("Invalid artifact id {}", id).into());
+        }
+        if self.store.is_complete(id) {
+            if pin {
+                self.store.pin(id)?;
+            }
+        } else if self.fetches.lock().unwrap().insert(id.to_string(), None) != Some(None) {
+            let (artifacts, handle, id) = (self.clone(), handle.clone(), id.to_string());
+            tokio::spawn(async move {
+                let r = fetch(&artifacts.store, &handle, &id).await;
+                let r = r.and_then(|_| match pin {
+                    true => artifacts.store.pin(&id).map_err(|e| e.to_string()),
+                    false => Ok(()),
+                });
+                let mut fetches = artifacts.fetches.lock().unwrap();
+                match r {
+                    Ok(_) => {
+                        info!("Fetched artifact {}", id);
+                        fetches.remove(&id);
+                    },
+                    Err(e) => {
+                        warn!("Failed to fetch artifact {}: {}", id, e);
+                        fetches.insert(id, Some(e));
+                    },
+                }
+            });
+        }
+        self.info(id).ok_or_else(|| format!("No artifact {}", id).into())
+    }
+
+    pub async fn export(&self, id: &str, out: &str) -> Result<ArtifactInfo, Box<dyn Error>> {
+        if !Path::new(out).is_absolute() {
+            return Err(format!("Path {} is not absolute", out).into());
+        }
+        let store = self.store.clone();
+        let (id, out) = (id.to_string(), PathBuf::from(out));
+        let info = tokio::task::spawn_blocking(move || {
+            store.export(&id, &out).map_err(|e| e.to_string())?;
+            store.info(&id).ok_or_else(|| format!("No artifact {}", id))
+        }).await??;
+        Ok(info)
+    }
+
+    /// Remove the artifacts that are neither pinned nor being fetched
+    pub fn gc(&self) -> Result<GcResult, Box<dyn Error>> {
+        let keep: HashSet<String> = self.fetches.lock().unwrap().iter()
+            .filter(|(_, state)| state.is_none())
+            .map(|(id, _)| id.clone())
+            .collect();
+        let result = self.store.gc(&keep)?;
+        let mut fetches = self.fetches.lock().unwrap();
+        for id in result.removed.iter() {
+            fetches.remove(id);
+        }
+        Ok(result)
+    }
+}
+
+async fn fetch_manifest(store: &ArtifactStore, handle: &NodeHandle, id: &str) -> Result<Manifest, String> {
+    for round in 1..=MAX_STALLED_ROUNDS {
+        let mut providers = handle.artifact_providers(id).await.map_err(|e| e.to_string())?;
+        providers.sort_by_key(|p| !p.complete);
+        for provider in providers {
+            match handle.artifact_request(provider.peer, ArtifactOp::Manifest { id: id.to_string() }).await {
+                Ok(ArtifactResponse { result: ArtifactResult::Manifest { manifest }, .. }) if manifest.id == id && manifest.verify() => {
+                    store.save_manifest(&manifest)?;
+                    return Ok(manifest);
+                },
+                Ok(res) => debug!("No manifest of {} from {}: {:?}", id, provider.peer, res.result),
+                Err(e) => debug!("No manifest of {} from {}: {}", id, provider.peer, e),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(round as u64)).await;
+    }
+    Err(format!("No peer has artifact {}", id))
+}
+
+// Chunks are asked from the least busy provider that may have them, partial
+// copies included, so every node fetching an artifact also serves it
+async fn fetch(store: &ArtifactStore, handle: &NodeHandle, id: &str) -> Result<(), String> {
+    let manifest = match store.manifest(id) {
+        Some(manifest) => manifest,
+        None => fetch_manifest(store, handle, id).await?,
+    };
+    let announce = |complete| handle.announce_artifacts(vec![Availability { id: id.to_string(), complete }]);
+    announce(false).await.map_err(|e| e.to_string())?;
+    let mut missing: VecDeque<u64> = store.missing(&manifest).into();
+    let mut providers = handle.artifact_providers(id).await.map_err(|e| e.to_string())?;
+    // Peers that did not have a chunk, and peers that could not be reached
+    let mut lacking: HashSet<(PeerId, u64)> = HashSet::new();
+    let mut down: HashSet<PeerId> = HashSet::new();
+    let mut in_flight: HashMap<PeerId, usize> = HashMap::new();
+    let mut requests = FuturesUnordered::new();
+    let mut stalled = 0;
+    while !missing.is_empty() || !requests.is_empty() {
+        let mut deferred = Vec::new();
+        while requests.len() < MAX_IN_FLIGHT {
+            let index = match missing.pop_front() {
+                Some(index) => index,
+                None => break,
+            };
+            let provider = providers.iter()
+                .filter(|p| !down.contains(&p.peer) && !lacking.contains(&(p.peer, index)))
+                .min_by_key(|p| (in_flight.get(&p.peer).copied().unwrap_or(0), !p.complete))
+                .map(|p| p.peer);
+            match provider {
+                Some(peer) => {
+                    *in_flight.entry(peer).or_default() += 1;
+                    let (handle, op) = (handle.clone(), ArtifactOp::Chunk { id: id.to_string(), index });
+                    requests.push(async move { (peer, index, handle.artifact_request(peer, op).await.map_err(|e| e.to_string())) });
+                },
+                None => deferred.push(index),
+            }
+        }
+        missing.extend(deferred);
+        let (peer, index, res) = match requests.next().await {
+            Some(r) => r,
+            None => {
+                // Every provider was tried, wait for new ones to announce
+                stalled += 1;
+                if stalled > MAX_STALLED_ROUNDS {
+                    return Err(format!("No peer has {} of the {} chunks of {}", missing.len(), manifest.chunks.len(), id));
+                }
+                tokio::time::sleep(Duration::from_secs(stalled as u64)).await;
+                providers = handle.artifact_providers(id).await.map_err(|e| e.to_string())?;
+                lacking.clear();
+                down.clear();
+                continue;
+            },
+        };
+        if let Some(n) = in_flight.get_mut(&peer) {
+            *n -= 1;
+        }
+        match res {
+            Ok(ArtifactResponse { result: ArtifactResult::Chunk, data }) => match store.write_chunk(&manifest, index, &data) {
+                Ok(_) => stalled = 0,
+                Err(e) => {
+                    warn!("Bad chunk {} of {} from {}: {}", index, id, peer, e);
+                    lacking.insert((peer, index));
+                    missing.push_back(index);
+                },
+            },
+            Ok(res) => {
+                debug!("{} has no chunk {} of {}: {:?}", peer, index, id, res.result);
+                lacking.insert((peer, index));
+                missing.push_back(index);
+            },
+            Err(e) => {
+                debug!("Chunk {} of {} from {} failed: {}", index, id, peer, e);
+                down.insert(peer);
+                missing.push_back(index);
+            },
+        }
+    }
+    store.mark_complete(id)?;
+    announce(true).await.map_err(|e| e.to_string())
+}
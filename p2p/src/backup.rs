@@ -0,0 +1,189 @@
+use std::{error::Error, io::{BufRead, Write}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{node::NodeStateKey, schema::{self, Tree, SCHEMA_VERSION}, utils::now_secs};
+
+pub const BACKUP_FORMAT: &str = "hanode-backup";
+pub const BACKUP_VERSION: u32 = 1;
+
+// Name of the sled default tree in backups
+const DEFAULT_TREE: &str = "default";
+
+/// First line of a backup, the following lines are `BackupRecord`s
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupHeader {
+    pub format: String,
+    pub version: u32,
+    pub schema_version: u32,
+    pub created_at: u64,
+}
+
+/// One key/value pair, hex encoded so backups are plain JSON lines
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupRecord {
+    pub tree: String,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupSummary {
+    pub schema_version: u32,
+    pub trees: usize,
+    pub records: usize,
+}
+
+fn tree_name(name: &[u8]) -> String {
+    if name == b"__sled__default" {
+        DEFAULT_TREE.to_string()
+    } else {
+        String::from_utf8_lossy(name).to_string()
+    }
+}
+
+fn open_tree(db: &sled::Db, name: &str) -> sled::Result<sled::Tree> {
+    if name == DEFAULT_TREE {
+        Ok((**db).clone())
+    } else {
+        db.open_tree(name)
+    }
+}
+
+/// Write every tree of the database as JSON lines
+pub fn export<W: Write>(db: &sled::Db, w: &mut W) -> Result<BackupSummary, Box<dyn Error>> {
+    let header = BackupHeader {
+        format: BACKUP_FORMAT.to_string(),
+        version: BACKUP_VERSION,
+        schema_version: schema::stored_version(db)?.unwrap_or(0),
+        created_at: now_secs(),
+    };
+    writeln!(w, "{}", serde_json::to_string(&header)?)?;
+    let mut summary = BackupSummary { schema_version: header.schema_version, trees: 0, records: 0 };
+    for name in db.tree_names() {
+        let name = tree_name(&name);
+        let tree = open_tree(db, &name)?;
+        if !tree.is_empty() {
+            summary.trees += 1;
+        }
+        for item in tree.iter() {
+            let (key, value) = item?;
+            let record = BackupRecord {
+                tree: name.clone(),
+                key: hex::encode(key),
+                value: hex::encode(value),
+            };
+            writeln!(w, "{}", serde_json::to_string(&record)?)?;
+            summary.records += 1;
+        }
+    }
+    w.flush()?;
+    Ok(summary)
+}
+
+type Record = (String, Vec<u8>, Vec<u8>);
+
+// Parse and validate a whole backup before anything touches the database
+fn read<R: BufRead>(r: R) -> Result<(BackupHeader, Vec<Record>), Box<dyn Error>> {
+    let mut lines = r.lines();
+    let header: BackupHeader = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).map_err(|e| format!("Invalid backup header: {}", e))?,
+        None => return Err("Empty backup".into()),
+    };
+    if header.format != BACKUP_FORMAT || header.version != BACKUP_VERSION {
+        return Err(format!("Unsupported backup format: {} version {}", header.format, header.version).into());
+    }
+    if header.schema_version > SCHEMA_VERSION {
+        return Err(format!(
+            "Backup schema version {} is newer than the supported version {}",
+            header.schema_version, SCHEMA_VERSION
+        ).into());
+    }
+    let mut records = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: BackupRecord = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid backup record on line {}: {}", i + 2, e))?;
+        records.push((record.tree, hex::decode(record.key)?, hex::decode(record.value)?));
+    }
+    Ok((header, records))
+}
+
+fn summarize(header: &BackupHeader, records: &[Record]) -> BackupSummary {
+    let mut trees: Vec<&String> = records.iter().map(|(tree, _, _)| tree).collect();
+    trees.sort();
+    trees.dedup();
+    BackupSummary { schema_version: header.schema_version, trees: trees.len(), records: records.len() }
+}
+
+/// Validate a backup without restoring it
+pub fn inspect<R: BufRead>(r: R) -> Result<BackupSummary, Box<dyn Error>> {
+    let (header, records) = read(r)?;
+    Ok(summarize(&header, &records))
+}
+
+/// Replace the content of the database with the backup, then migrate the
+/// database if the backup was written by an older schema.
+pub fn import<R: BufRead>(db: &sled::Db, r: R) -> Result<BackupSummary, Box<dyn Error>> {
+    let (header, records) = read(r)?;
+    // Empty every tree so no stale state survives the restore
+    for name in db.tree_names() {
+        open_tree(db, &tree_name(&name))?.clear()?;
+    }
+    for (tree, key, value) in records.iter() {
+        open_tree(db, tree)?.insert(key, value.as_slice())?;
+    }
+    db.flush()?;
+    // Backups of unversioned databases are migrated like the database would be
+    if header.schema_version > 0 {
+        schema::set_version(db, header.schema_version)?;
+    }
+    schema::migrate(db)?;
+    Ok(summarize(&header, &records))
+}
+
+/// Record of a tree decoded for humans, secrets are redacted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpRecord {
+    pub key: String,
+    pub value: serde_json::Value,
+}
+
+fn is_secret(tree: &str, key: &[u8]) -> bool {
+    tree == Tree::Secrets.name() || (tree == Tree::Node.name() && key == NodeStateKey::NodeLocalKey.to_string().as_bytes())
+}
+
+fn printable(bytes: &[u8]) -> Option<String> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.chars().any(char::is_control) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+pub fn dump(db: &sled::Db, tree: &str) -> Result<Vec<DumpRecord>, Box<dyn Error>> {
+    if tree != DEFAULT_TREE && !db.tree_names().iter().any(|n| n.as_ref() == tree.as_bytes()) {
+        return Err(format!("Unknown tree: {}", tree).into());
+    }
+    let mut records = Vec::new();
+    for item in open_tree(db, tree)?.iter() {
+        let (key, value) = item?;
+        let value = if is_secret(tree, &key) {
+            serde_json::Value::String("<redacted>".to_string())
+        } else if let Ok(v) = serde_json::from_slice::<serde_json::Value>(&value) {
+            v
+        } else if let Some(s) = printable(&value) {
+            serde_json::Value::String(s)
+        } else {
+            serde_json::Value::String(format!("0x{}", hex::encode(&value)))
+        };
+        let key = match printable(&key) {
+            Some(k) => k,
+            None => format!("0x{}", hex::encode(&key)),
+        };
+        records.push(DumpRecord { key, value });
+    }
+    Ok(records)
+}
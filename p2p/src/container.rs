@@ -0,0 +1,545 @@
+use std::{collections::{BTreeMap, HashMap}, fs::{self, OpenOptions}, io, path::{Path, PathBuf}, process::Stdio, sync::Mutex, time::Duration};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::{files::{read_frames, write_frames}, service::{self, Limits, MAX_LOG_LINES}};
+
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+// Between SIGTERM and SIGKILL
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// Directory under the containers directory the root filesystems are
+/// unpacked in, a container runs from no other
+pub const ROOTFS_DIR: &str = "rootfs";
+// User and group nobody, that containers run as unless given
+const NOBODY: u32 = 65534;
+
+#[derive(Debug, Clone)]
+pub struct ContainerProtocol;
+
+impl ProtocolName for ContainerProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/container/1.0.0"
+    }
+}
+
+fn default_runtime() -> String {
+    "runc".to_string()
+}
+
+fn nobody() -> u32 {
+    NOBODY
+}
+
+/// The `containers` of `config.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerConfig {
+    // `runc`, `youki` or the path of either
+    #[serde(default = "default_runtime")]
+    pub runtime: String,
+}
+
+impl Default for ContainerConfig {
+    fn default() -> Self {
+        ContainerConfig { runtime: default_runtime() }
+    }
+}
+
+/// A container, run from a root filesystem already on the node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContainerSpec {
+    pub name: String,
+    // Absolute path of the root filesystem on the node
+    pub rootfs: String,
+    // Program and its arguments inside the container
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // Working directory inside the container, `/` by default
+    #[serde(default)]
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub limits: Limits,
+    // User and group the command runs as, nobody by default
+    #[serde(default = "nobody")]
+    pub uid: u32,
+    #[serde(default = "nobody")]
+    pub gid: u32,
+    // The network of the node, instead of a namespace with a loopback only
+    #[serde(default)]
+    pub host_network: bool,
+}
+
+impl ContainerSpec {
+    pub fn check(&self) -> Result<(), String> {
+        if !service::valid_name(&self.name) {
+            return Err(format!("Invalid container name {}, use at most 63 letters, digits and ._-", self.name));
+        }
+        if !Path::new(&self.rootfs).is_absolute() {
+            return Err(format!("The rootfs of container {} must be an absolute path", self.name));
+        }
+        if self.command.is_empty() || self.command[0].is_empty() {
+            return Err(format!("Container {} has no command", self.name));
+        }
+        if matches!(self.limits.cpu, Some(cpu) if cpu <= 0.0) {
+            return Err(format!("The cpu limit of container {} must be positive", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Status of the OCI runtime spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerState {
+    Creating,
+    Created,
+    Running,
+    Paused,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerInfo {
+    pub name: String,
+    pub state: ContainerState,
+    // None once stopped
+    pub pid: Option<u32>,
+    pub rootfs: String,
+    // RFC 3339, as the runtime reports it
+    pub created: String,
+}
+
+/// Drives the containers of a node
+#[async_trait]
+pub trait ContainerRuntime: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Create and start a container, failing when the name is taken
+    async fn run(&self, spec: &ContainerSpec) -> Result<ContainerInfo, String>;
+
+    async fn list(&self) -> Result<Vec<ContainerInfo>, String>;
+
+    async fn state(&self, name: &str) -> Result<Option<ContainerInfo>, String>;
+
+    /// Send `signal`, as in `TERM` or `KILL`, to the container process
+    async fn kill(&self, name: &str, signal: &str) -> Result<(), String>;
+
+    /// Delete a stopped container, or a running one with `force`
+    async fn remove(&self, name: &str, force: bool) -> Result<(), String>;
+
+    /// Last lines of the output
+    async fn logs(&self, name: &str, lines: usize) -> Result<Vec<String>, String>;
+
+    /// SIGTERM, then SIGKILL once `timeout` went by
+    async fn stop(&self, name: &str, timeout: Duration) -> Result<ContainerInfo, String> {
+        let info = self.state(name).await?.ok_or_else(|| format!("No container {}", name))?;
+        if info.state == ContainerState::Stopped {
+            return Ok(info);
+        }
+        self.kill(name, "TERM").await?;
+        let step = Duration::from_millis(100);
+        let mut waited = Duration::ZERO;
+        while waited < timeout {
+            match self.state(name).await? {
+                Some(info) if info.state == ContainerState::Stopped => {
+                    info!("Stopped container {}", name);
+                    return Ok(info);
+                },
+                Some(_) => {},
+                None => return Err(format!("Container {} was removed", name)),
+            }
+            tokio::time::sleep(step).await;
+            waited += step;
+        }
+        warn!("Container {} did not stop within {}s, killing it", name, timeout.as_secs());
+        self.kill(name, "KILL").await?;
+        for _ in 0..50 {
+            match self.state(name).await? {
+                Some(info) if info.state == ContainerState::Stopped => return Ok(info),
+                _ => tokio::time::sleep(step).await,
+            }
+        }
+        Err(format!("Container {} did not stop", name))
+    }
+}
+
+/// Operations on the containers of a node, served to its peers too
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ContainerRequest {
+    Run { spec: ContainerSpec },
+    List,
+    Stop { name: String },
+    Remove { name: String, force: bool },
+    Logs { name: String, lines: usize },
+}
+
+impl ContainerRequest {
+    /// Whether the request changes what runs on the node, which only its
+    /// operators may ask of it
+    pub fn changes(&self) -> bool {
+        matches!(self, ContainerRequest::Run { .. } | ContainerRequest::Stop { .. } | ContainerRequest::Remove { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ContainerResponse {
+    Containers { containers: Vec<ContainerInfo> },
+    Container { container: ContainerInfo },
+    Removed { name: String },
+    Logs { lines: Vec<String> },
+    Error { message: String },
+}
+
+pub async fn serve(runtime: &dyn ContainerRuntime, req: ContainerRequest) -> ContainerResponse {
+    let result = match req {
+        ContainerRequest::Run { spec } => runtime.run(&spec).await.map(|container| ContainerResponse::Container { container }),
+        ContainerRequest::List => runtime.list().await.map(|containers| ContainerResponse::Containers { containers }),
+        ContainerRequest::Stop { name } => runtime.stop(&name, STOP_TIMEOUT).await.map(|container| ContainerResponse::Container { container }),
+        ContainerRequest::Remove { name, force } => runtime.remove(&name, force).await.map(|_| ContainerResponse::Removed { name }),
+        ContainerRequest::Logs { name, lines } => runtime.logs(&name, lines.min(MAX_LOG_LINES)).await.map(|lines| ContainerResponse::Logs { lines }),
+    };
+    result.unwrap_or_else(|message| ContainerResponse::Error { message })
+}
+
+#[derive(Debug, Clone)]
+pub struct ContainerCodec;
+
+#[async_trait]
+impl RequestResponseCodec for ContainerCodec {
+    type Protocol = ContainerProtocol;
+    type Request = ContainerRequest;
+    type Response = ContainerResponse;
+
+    async fn read_request<T>(&mut self, _: &ContainerProtocol, io: &mut T) -> io::Result<ContainerRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (req, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(req)
+    }
+
+    async fn read_response<T>(&mut self, _: &ContainerProtocol, io: &mut T) -> io::Result<ContainerResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (res, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(res)
+    }
+
+    async fn write_request<T>(&mut self, _: &ContainerProtocol, io: &mut T, req: ContainerRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &req, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &ContainerProtocol, io: &mut T, res: ContainerResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res, &[]).await
+    }
+}
+
+/// OCI runtime config of a container, in a network namespace of its own
+/// unless it shares the network of the node
+pub fn oci_config(spec: &ContainerSpec) -> serde_json::Value {
+    let mut env = vec![DEFAULT_PATH.to_string()];
+    env.extend(spec.env.iter().map(|(k, v)| format!("{}={}", k, v)));
+    let mut resources = json!({});
+    if let Some(memory) = spec.limits.memory {
+        resources["memory"] = json!({ "limit": memory });
+    }
+    if let Some(cores) = spec.limits.cpu {
+        resources["cpu"] = json!({ "quota": (cores * 100000.0) as u64, "period": 100000 });
+    }
+    if let Some(pids) = spec.limits.pids {
+        resources["pids"] = json!({ "limit": pids });
+    }
+    let mut namespaces = vec![json!({ "type": "pid" }), json!({ "type": "ipc" }), json!({ "type": "uts" }), json!({ "type": "mount" })];
+    if !spec.host_network {
+        namespaces.push(json!({ "type": "network" }));
+    }
+    json!({
+        "ociVersion": "1.0.2",
+        "process": {
+            "terminal": false,
+            "user": { "uid": spec.uid, "gid": spec.gid },
+            "args": spec.command,
+            "env": env,
+            "cwd": spec.dir.clone().unwrap_or_else(|| "/".to_string()),
+            "noNewPrivileges": true,
+        },
+        "root": { "path": spec.rootfs, "readonly": false },
+        "hostname": spec.name,
+        "mounts": [
+            { "destination": "/proc", "type": "proc", "source": "proc" },
+            { "destination": "/dev", "type": "tmpfs", "source": "tmpfs", "options": ["nosuid", "strictatime", "mode=755", "size=65536k"] },
+            { "destination": "/dev/pts", "type": "devpts", "source": "devpts", "options": ["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620"] },
+            { "destination": "/dev/shm", "type": "tmpfs", "source": "shm", "options": ["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"] },
+            { "destination": "/sys", "type": "sysfs", "source": "sysfs", "options": ["nosuid", "noexec", "nodev", "ro"] },
+            { "destination": "/etc/resolv.conf", "type": "bind", "source": "/etc/resolv.conf", "options": ["rbind", "ro"] },
+        ],
+        "linux": {
+            "namespaces": namespaces,
+            "resources": resources,
+            "maskedPaths": ["/proc/kcore", "/proc/keys", "/proc/timer_list", "/sys/firmware"],
+            "readonlyPaths": ["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger"],
+        },
+    })
+}
+
+// `state` and `list --format json` of runc and youki
+#[derive(Debug, Deserialize)]
+struct RuntimeState {
+    id: String,
+    #[serde(default)]
+    pid: u32,
+    status: ContainerState,
+    bundle: String,
+    #[serde(default)]
+    created: String,
+}
+
+/// runc or youki, driven through their command line
+#[derive(Debug, Clone)]
+pub struct CliRuntime {
+    binary: String,
+    // Bundles and output, `<dir>/<name>/`
+    dir: PathBuf,
+    // State of the runtime, apart from the containers it runs for others
+    root: PathBuf,
+}
+
+impl CliRuntime {
+    pub fn new(binary: &str, dir: &str) -> CliRuntime {
+        let dir = PathBuf::from(dir);
+        CliRuntime { binary: binary.to_string(), root: dir.join(".state"), dir }
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.binary);
+        cmd.arg("--root").arg(&self.root).stdin(Stdio::null());
+        cmd
+    }
+
+    // Stdout of a runtime command, its stderr as the error
+    async fn output(&self, args: &[&str]) -> Result<Vec<u8>, String> {
+        let output = self.command().args(args).output().await.map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => format!("Container runtime {} is not installed", self.binary),
+            _ => format!("Failed to run {}: {}", self.binary, e),
+        })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("{} {} failed: {}", self.binary, args[0], stderr.trim()));
+        }
+        Ok(output.stdout)
+    }
+
+    fn info(&self, state: RuntimeState) -> ContainerInfo {
+        let rootfs = fs::read(Path::new(&state.bundle).join("config.json")).ok()
+            .and_then(|config| serde_json::from_slice::<serde_json::Value>(&config).ok())
+            .and_then(|config| config["root"]["path"].as_str().map(|s| s.to_string()))
+            .unwrap_or_default();
+        let pid = match state.status {
+            ContainerState::Stopped => None,
+            _ => Some(state.pid).filter(|pid| *pid > 0),
+        };
+        ContainerInfo { name: state.id, state: state.status, pid, rootfs, created: state.created }
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for CliRuntime {
+    fn name(&self) -> &str {
+        &self.binary
+    }
+
+    async fn run(&self, spec: &ContainerSpec) -> Result<ContainerInfo, String> {
+        spec.check()?;
+        if spec.name == ROOTFS_DIR {
+            return Err(format!("The container name {} is reserved", ROOTFS_DIR));
+        }
+        let rootfs = fs::canonicalize(&spec.rootfs).ok().filter(|p| p.is_dir())
+            .ok_or_else(|| format!("No rootfs directory {} on this node", spec.rootfs))?;
+        // Symbolic links resolved, a rootfs elsewhere would expose the host
+        let allowed = fs::create_dir_all(self.dir.join(ROOTFS_DIR)).and_then(|_| fs::canonicalize(self.dir.join(ROOTFS_DIR)))
+            .map_err(|e| format!("Failed to create {}: {}", self.dir.join(ROOTFS_DIR).display(), e))?;
+        if rootfs == allowed || !rootfs.starts_with(&allowed) {
+            return Err(format!("The rootfs of container {} must be under {}", spec.name, allowed.display()));
+        }
+        let spec = &ContainerSpec { rootfs: rootfs.to_string_lossy().to_string(), ..spec.clone() };
+        if self.state(&spec.name).await?.is_some() {
+            return Err(format!("Container {} exists, remove it first", spec.name));
+        }
+        let bundle = self.dir.join(&spec.name);
+        let write_bundle = || -> io::Result<fs::File> {
+            fs::create_dir_all(&bundle)?;
+            fs::create_dir_all(&self.root)?;
+            fs::write(bundle.join("config.json"), serde_json::to_vec_pretty(&oci_config(spec))?)?;
+            OpenOptions::new().create(true).append(true).open(bundle.join(service::LOG_FILE))
+        };
+        let log = write_bundle().map_err(|e| format!("Failed to write the bundle of {}: {}", spec.name, e))?;
+        let stderr = log.try_clone().map_err(|e| e.to_string())?;
+        // Detached, the container keeps the output of the runtime, which is the log
+        let status = self.command()
+            .arg("run").arg("--detach").arg("--bundle").arg(&bundle).arg(&spec.name)
+            .stdout(log).stderr(stderr)
+            .status().await
+            .map_err(|e| format!("Failed to run {}: {}", self.binary, e))?;
+        if !status.success() {
+            let output = service::tail(&bundle, 5).unwrap_or_default();
+            return Err(format!("{} run failed with {}: {}", self.binary, status, output.join("\n")));
+        }
+        info!("Started container {} with {}", spec.name, self.binary);
+        self.state(&spec.name).await?.ok_or_else(|| format!("Container {} exited at once", spec.name))
+    }
+
+    async fn list(&self) -> Result<Vec<ContainerInfo>, String> {
+        if !self.root.exists() {
+            return Ok(Vec::new());
+        }
+        let stdout = self.output(&["list", "--format", "json"]).await?;
+        // `null` without containers
+        let states: Option<Vec<RuntimeState>> = serde_json::from_slice(&stdout)
+            .map_err(|e| format!("Unexpected output of {} list: {}", self.binary, e))?;
+        let mut list: Vec<ContainerInfo> = states.unwrap_or_default().into_iter().map(|s| self.info(s)).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn state(&self, name: &str) -> Result<Option<ContainerInfo>, String> {
+        Ok(self.list().await?.into_iter().find(|c| c.name == name))
+    }
+
+    async fn kill(&self, name: &str, signal: &str) -> Result<(), String> {
+        self.output(&["kill", name, signal]).await.map(|_| ())
+    }
+
+    async fn remove(&self, name: &str, force: bool) -> Result<(), String> {
+        if self.state(name).await?.is_none() {
+            return Err(format!("No container {}", name));
+        }
+        match force {
+            true => self.output(&["delete", "--force", name]).await?,
+            false => self.output(&["delete", name]).await?,
+        };
+        if let Err(e) = fs::remove_dir_all(self.dir.join(name)) {
+            warn!("Failed to remove the bundle of container {}: {}", name, e);
+        }
+        info!("Removed container {}", name);
+        Ok(())
+    }
+
+    async fn logs(&self, name: &str, lines: usize) -> Result<Vec<String>, String> {
+        let bundle = self.dir.join(name);
+        if !service::valid_name(name) || !bundle.exists() {
+            return Err(format!("No container {}", name));
+        }
+        service::tail(&bundle, lines).map_err(|e| format!("Failed to read the output of {}: {}", name, e))
+    }
+}
+
+struct FakeContainer {
+    info: ContainerInfo,
+    logs: Vec<String>,
+}
+
+/// Keeps containers in memory without running anything, for tests
+#[derive(Default)]
+pub struct FakeRuntime {
+    containers: Mutex<HashMap<String, FakeContainer>>,
+}
+
+impl FakeRuntime {
+    pub fn new() -> FakeRuntime {
+        FakeRuntime::default()
+    }
+
+    /// As if the process of the container wrote `line`
+    pub fn write(&self, name: &str, line: &str) {
+        if let Some(c) = self.containers.lock().unwrap().get_mut(name) {
+            c.logs.push(line.to_string());
+        }
+    }
+
+    /// As if the process of the container exited
+    pub fn exit(&self, name: &str) {
+        if let Some(c) = self.containers.lock().unwrap().get_mut(name) {
+            c.info.state = ContainerState::Stopped;
+            c.info.pid = None;
+        }
+    }
+}
+
+#[async_trait]
+impl ContainerRuntime for FakeRuntime {
+    fn name(&self) -> &str {
+        "fake"
+    }
+
+    async fn run(&self, spec: &ContainerSpec) -> Result<ContainerInfo, String> {
+        spec.check()?;
+        let mut containers = self.containers.lock().unwrap();
+        if containers.contains_key(&spec.name) {
+            return Err(format!("Container {} exists, remove it first", spec.name));
+        }
+        let info = ContainerInfo {
+            name: spec.name.clone(),
+            state: ContainerState::Running,
+            pid: Some(1000 + containers.len() as u32),
+            rootfs: spec.rootfs.clone(),
+            created: "1970-01-01T00:00:00Z".to_string(),
+        };
+        containers.insert(spec.name.clone(), FakeContainer { info: info.clone(), logs: Vec::new() });
+        Ok(info)
+    }
+
+    async fn list(&self) -> Result<Vec<ContainerInfo>, String> {
+        let mut list: Vec<ContainerInfo> = self.containers.lock().unwrap().values().map(|c| c.info.clone()).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    async fn state(&self, name: &str) -> Result<Option<ContainerInfo>, String> {
+        Ok(self.containers.lock().unwrap().get(name).map(|c| c.info.clone()))
+    }
+
+    async fn kill(&self, name: &str, _signal: &str) -> Result<(), String> {
+        let running = self.state(name).await?.map(|c| c.state == ContainerState::Running);
+        match running {
+            Some(true) => {
+                self.exit(name);
+                Ok(())
+            },
+            Some(false) => Err(format!("Container {} is not running", name)),
+            None => Err(format!("No container {}", name)),
+        }
+    }
+
+    async fn remove(&self, name: &str, force: bool) -> Result<(), String> {
+        let mut containers = self.containers.lock().unwrap();
+        match containers.get(name) {
+            None => Err(format!("No container {}", name)),
+            Some(c) if c.info.state != ContainerState::Stopped && !force => Err(format!("Container {} is running, stop it first", name)),
+            Some(_) => {
+                containers.remove(name);
+                Ok(())
+            },
+        }
+    }
+
+    async fn logs(&self, name: &str, lines: usize) -> Result<Vec<String>, String> {
+        let containers = self.containers.lock().unwrap();
+        let c = containers.get(name).ok_or_else(|| format!("No container {}", name))?;
+        Ok(c.logs[c.logs.len().saturating_sub(lines)..].to_vec())
+    }
+}
@@ -0,0 +1,442 @@
+use std::{collections::{BTreeMap, HashSet}, process::Stdio, str::FromStr, sync::{Arc, Mutex}, time::Duration};
+use futures::future::join;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncReadExt, process::Command, sync::mpsc::Sender};
+use tracing::{info, warn};
+
+use crate::{kv::Record, labels::Selector, operators::Operators, service, utils::now_secs};
+
+/// Cluster keys of the jobs, `cron/<name>/job` and the runs of each node
+/// under `cron/<name>/runs/<peer id>/<slot>`
+pub const CRON_PREFIX: &str = "cron/";
+/// Runs kept per job and node, the oldest is overwritten
+pub const HISTORY_SIZE: usize = 20;
+/// Bytes of output kept per run, the end of it
+pub const MAX_OUTPUT: usize = 4096;
+/// Seconds a run may take unless given
+pub const DEFAULT_TIMEOUT: u64 = 3600;
+/// Lease of the lock electing the node running a job targeted at one node,
+/// longer than the time nodes take to try for it
+pub const ELECTION_TTL: Duration = Duration::from_secs(60);
+// Time the output is read for once the command exited
+const PIPE_GRACE: Duration = Duration::from_secs(1);
+
+pub fn job_key(name: &str) -> String {
+    format!("{}{}/job", CRON_PREFIX, name)
+}
+
+pub fn runs_prefix(name: &str, node: &str) -> String {
+    format!("{}{}/runs/{}/", CRON_PREFIX, name, node)
+}
+
+pub fn lock_name(name: &str) -> String {
+    format!("cron/{}", name)
+}
+
+// Allowed values of a field, as a bit per value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field(u64);
+
+impl Field {
+    fn parse(s: &str, min: u32, max: u32, names: &[&str]) -> Result<Field, String> {
+        let value = |v: &str| -> Result<u32, String> {
+            let n = match names.iter().position(|n| n.eq_ignore_ascii_case(v)) {
+                Some(i) => i as u32 + min,
+                None => v.parse().map_err(|_| format!("Invalid value {}", v))?,
+            };
+            match n >= min && n <= max {
+                true => Ok(n),
+                false => Err(format!("{} is out of {}-{}", n, min, max)),
+            }
+        };
+        let mut bits = 0u64;
+        for part in s.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => (range, step),
+                    _ => return Err(format!("Invalid step in {}", part)),
+                },
+                None => (part, 1),
+            };
+            let (first, last) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((first, last)) => (value(first)?, value(last)?),
+                    // A step runs to the end, as in 5/15
+                    None if step > 1 => (value(range)?, max),
+                    None => (value(range)?, value(range)?),
+                },
+            };
+            if first > last {
+                return Err(format!("Invalid range {}", range));
+            }
+            for n in (first..=last).step_by(step as usize) {
+                bits |= 1 << n;
+            }
+        }
+        Ok(Field(bits))
+    }
+
+    fn contains(&self, n: u32) -> bool {
+        self.0 & (1 << n) != 0
+    }
+}
+
+/// When a job runs, a cron expression of five fields: minute, hour, day of
+/// the month, month and day of the week, in UTC. Also @hourly, @daily,
+/// @weekly, @monthly and @yearly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+    // Both days restricted, either one matching is enough as in cron
+    either_day: bool,
+}
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Schedule, String> {
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            s => s,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid schedule {}, use minute hour day month weekday as in */5 * * * *", s));
+        }
+        let invalid = |e: String| format!("Invalid schedule {}: {}", s, e);
+        let mut weekdays = Field::parse(fields[4], 0, 7, &WEEKDAYS).map_err(invalid)?;
+        // 7 is Sunday too
+        if weekdays.contains(7) {
+            weekdays.0 |= 1;
+        }
+        Ok(Schedule {
+            minutes: Field::parse(fields[0], 0, 59, &[]).map_err(invalid)?,
+            hours: Field::parse(fields[1], 0, 23, &[]).map_err(invalid)?,
+            days: Field::parse(fields[2], 1, 31, &[]).map_err(invalid)?,
+            months: Field::parse(fields[3], 1, 12, &MONTHS).map_err(invalid)?,
+            weekdays,
+            either_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+}
+
+// Month and day of a number of days since 1970-01-01, in the calendar of
+// years starting in March where leap days come last
+fn month_day(days: i64) -> (u32, u32) {
+    let doe = (days + 719468).rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month as u32, day as u32)
+}
+
+impl Schedule {
+    fn matches_day(&self, days: i64) -> bool {
+        let (month, day) = month_day(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7) as u32;
+        let (by_day, by_weekday) = (self.days.contains(day), self.weekdays.contains(weekday));
+        self.months.contains(month) && match self.either_day {
+            true => by_day || by_weekday,
+            false => by_day && by_weekday,
+        }
+    }
+
+    /// True when the job runs in the minute of this Unix time
+    pub fn matches(&self, time: u64) -> bool {
+        let minute = time / 60;
+        self.matches_day((minute / 1440) as i64)
+            && self.hours.contains((minute / 60 % 24) as u32)
+            && self.minutes.contains((minute % 60) as u32)
+    }
+
+    /// Unix time of the first run after `time`, None when there is none in
+    /// the next years as on February 30
+    pub fn next_after(&self, time: u64) -> Option<u64> {
+        let mut minute = time / 60 + 1;
+        let last = minute + 5 * 366 * 1440;
+        while minute < last {
+            if !self.matches_day((minute / 1440) as i64) {
+                minute = (minute / 1440 + 1) * 1440;
+                continue;
+            }
+            if self.matches(minute * 60) {
+                return Some(minute * 60);
+            }
+            minute += 1;
+        }
+        None
+    }
+}
+
+/// Nodes a job runs on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Target {
+    // The node of this peer id
+    Node { node: String },
+    // Every node matching the selector
+    All,
+    // One of the nodes matching the selector, elected with a lock
+    One,
+}
+
+/// A command run on a schedule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub name: String,
+    pub schedule: String,
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    pub target: Target,
+    // Label selector of the nodes for all and one, every node when empty
+    #[serde(default)]
+    pub selector: String,
+    // Seconds before a run is killed
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+impl Job {
+    pub fn check(&self) -> Result<(), String> {
+        if !service::valid_name(&self.name) {
+            return Err(format!("Invalid job name {}, use at most 63 letters, digits and ._-", self.name));
+        }
+        self.schedule.parse::<Schedule>()?;
+        if self.command.is_empty() {
+            return Err(format!("Job {} has no command", self.name));
+        }
+        self.selector.parse::<Selector>()?;
+        if self.timeout == 0 {
+            return Err("The timeout of a job must be at least a second".to_string());
+        }
+        match &self.target {
+            Target::Node { node } if node.is_empty() => Err(format!("Job {} targets no node", self.name)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A run of a job on a node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub job: String,
+    pub node: String,
+    // Unix times in seconds of the minute it was scheduled for, its start and end
+    pub scheduled: u64,
+    pub started: u64,
+    pub finished: u64,
+    pub exit_code: Option<i32>,
+    // Not started, timed out or killed by a signal
+    pub error: Option<String>,
+    // End of the standard output then the standard error
+    pub output: String,
+}
+
+impl Run {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Runs of a job with their keys
+pub type Runs = Vec<(String, Run)>;
+
+/// The records a node acts on, as any member can write to the cluster kv: the
+/// jobs written by one of its `operators`, and all the rest
+pub fn authorized(records: Vec<Record>, operators: &Operators) -> Vec<Record> {
+    records.into_iter().filter(|record| {
+        if !record.key.ends_with("/job") || record.value.is_none() || operators.allows(&record.version.node) {
+            return true;
+        }
+        warn!("Ignoring job {} written by {}, not an operator", record.key, record.version.node);
+        false
+    }).collect()
+}
+
+/// Jobs and their runs, from the records under `CRON_PREFIX`. A job is named
+/// by its key, a run counts only when written by the node it is about.
+pub fn parse_records(records: &[Record]) -> (BTreeMap<String, Job>, BTreeMap<String, Runs>) {
+    let mut jobs = BTreeMap::new();
+    let mut runs: BTreeMap<String, Runs> = BTreeMap::new();
+    for record in records {
+        let (value, path) = match (&record.value, record.key.strip_prefix(CRON_PREFIX)) {
+            (Some(value), Some(path)) => (value, path),
+            _ => continue,
+        };
+        match path.split('/').collect::<Vec<&str>>()[..] {
+            [name, "job"] => match serde_json::from_str::<Job>(value) {
+                Ok(job) => {
+                    jobs.insert(name.to_string(), Job { name: name.to_string(), ..job });
+                },
+                Err(e) => warn!("Ignoring job {}: {}", record.key, e),
+            },
+            [name, "runs", node, _] => match serde_json::from_str::<Run>(value) {
+                Ok(run) if node == record.version.node && run.node == node && run.job == name => runs.entry(name.to_string()).or_default().push((record.key.clone(), run)),
+                Ok(_) => warn!("Ignoring run {} written by {}", record.key, record.version.node),
+                Err(e) => warn!("Ignoring run {}: {}", record.key, e),
+            },
+            _ => {},
+        }
+    }
+    (jobs, runs)
+}
+
+/// Key a node writes its next run of a job to: a free slot, or the one of
+/// its oldest run
+pub fn run_key(name: &str, node: &str, runs: &[(String, Run)]) -> String {
+    let prefix = runs_prefix(name, node);
+    let mine: Vec<&(String, Run)> = runs.iter().filter(|(key, _)| key.starts_with(&prefix)).collect();
+    let slot = match (0..HISTORY_SIZE).find(|slot| !mine.iter().any(|(key, _)| key[prefix.len()..] == slot.to_string())) {
+        Some(slot) => slot.to_string(),
+        None => match mine.iter().min_by_key(|(_, run)| run.started) {
+            Some((key, _)) => key[prefix.len()..].to_string(),
+            None => "0".to_string(),
+        },
+    };
+    format!("{}{}", prefix, slot)
+}
+
+/// A job with when it runs next and its last run on any node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job: Job,
+    pub next: Option<u64>,
+    pub last: Option<Run>,
+}
+
+impl JobStatus {
+    pub fn new(job: Job, runs: &[(String, Run)]) -> JobStatus {
+        let next = job.schedule.parse::<Schedule>().ok().and_then(|s| s.next_after(now_secs()));
+        let last = runs.iter().map(|(_, run)| run).max_by_key(|run| run.started).cloned();
+        JobStatus { job, next, last }
+    }
+}
+
+// Keeps the end of what a pipe gives
+async fn read_tail<R: tokio::io::AsyncRead + Unpin>(reader: Option<R>, tail: Arc<Mutex<Vec<u8>>>) {
+    let mut reader = match reader {
+        Some(reader) => reader,
+        None => return,
+    };
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = reader.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let mut tail = tail.lock().unwrap();
+        tail.extend_from_slice(&buf[..n]);
+        if tail.len() > MAX_OUTPUT {
+            let extra = tail.len() - MAX_OUTPUT;
+            tail.drain(..extra);
+        }
+    }
+}
+
+/// Runs the command of a job to its end or its timeout
+pub async fn run(job: &Job, node: &str, scheduled: u64) -> Run {
+    let started = now_secs();
+    let mut run = Run {
+        job: job.name.clone(),
+        node: node.to_string(),
+        scheduled,
+        started,
+        finished: started,
+        exit_code: None,
+        error: None,
+        output: String::new(),
+    };
+    let child = Command::new(&job.command[0])
+        .args(&job.command[1..])
+        .envs(&job.env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            run.error = Some(format!("Failed to run {}: {}", job.command[0], e));
+            return run;
+        },
+    };
+    let (stdout, stderr) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+    let mut outputs = tokio::spawn(join(read_tail(child.stdout.take(), stdout.clone()), read_tail(child.stderr.take(), stderr.clone())));
+    match tokio::time::timeout(Duration::from_secs(job.timeout), child.wait()).await {
+        Ok(Ok(status)) => {
+            run.exit_code = status.code();
+            if status.code().is_none() {
+                run.error = Some("Killed by a signal".to_string());
+            }
+        },
+        Ok(Err(e)) => run.error = Some(e.to_string()),
+        Err(_) => {
+            let _ = child.kill().await;
+            run.error = Some(format!("Timed out after {}s", job.timeout));
+        },
+    }
+    // Processes the command left behind may keep the pipes open
+    if tokio::time::timeout(PIPE_GRACE, &mut outputs).await.is_err() {
+        outputs.abort();
+    }
+    let mut output = stdout.lock().unwrap().clone();
+    output.extend_from_slice(&stderr.lock().unwrap());
+    let from = output.len().saturating_sub(MAX_OUTPUT);
+    run.output = String::from_utf8_lossy(&output[from..]).to_string();
+    run.finished = now_secs();
+    run
+}
+
+/// Starts the runs of a node, one at a time per job
+#[derive(Clone)]
+pub struct Runner {
+    node: String,
+    running: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Runner {
+    pub fn new(node: &str) -> Runner {
+        Runner { node: node.to_string(), running: Arc::new(Mutex::new(HashSet::new())) }
+    }
+
+    /// Runs the job in a task, the run is sent to `done`. A run still going
+    /// on skips this one.
+    pub fn spawn(&self, job: Job, scheduled: u64, done: Sender<Run>) {
+        if !self.running.lock().unwrap().insert(job.name.clone()) {
+            warn!("Skipping job {}, the previous run is still going on", job.name);
+            return;
+        }
+        let runner = self.clone();
+        tokio::spawn(async move {
+            info!("Running job {}", job.name);
+            let run = run(&job, &runner.node, scheduled).await;
+            match (&run.error, run.exit_code) {
+                (Some(e), _) => warn!("Job {} failed: {}", job.name, e),
+                (None, Some(code)) if code != 0 => warn!("Job {} exited with {}", job.name, code),
+                _ => info!("Job {} done", job.name),
+            }
+            runner.running.lock().unwrap().remove(&job.name);
+            let _ = done.send(run).await;
+        });
+    }
+}
@@ -0,0 +1,508 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, error::Error, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{container::{ContainerRuntime, ContainerSpec, ContainerState}, health::{self, Check}, kv::Record, labels::{self, Labels, Selector}, operators::Operators, rollout::{Rollout, RolloutState}, schema::Tree, service::{ServiceSpec, ServiceState, Supervisor}, utils::now_secs};
+
+/// Cluster keys of the deployments, `deploy/<name>/spec` and a
+/// `deploy/<name>/status/<peer id>` written by each node running it
+pub const DEPLOY_PREFIX: &str = "deploy/";
+/// Between the passes of a node over the deployments
+pub const RECONCILE_INTERVAL: Duration = Duration::from_secs(10);
+/// A status is written again this often in seconds when nothing changed,
+/// an older one is from a node that stopped reporting
+pub const REPORT_INTERVAL: u64 = 60;
+// Between SIGTERM and SIGKILL when a container is replaced
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+// For a repaired workload to run again before the repair counts as failed
+const REPAIR_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn spec_key(name: &str) -> String {
+    format!("{}{}/spec", DEPLOY_PREFIX, name)
+}
+
+pub fn status_key(name: &str, node: &str) -> String {
+    format!("{}{}/status/{}", DEPLOY_PREFIX, name, node)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Workload {
+    Service(ServiceSpec),
+    Container(ContainerSpec),
+}
+
+impl Workload {
+    pub fn name(&self) -> &str {
+        match self {
+            Workload::Service(spec) => &spec.name,
+            Workload::Container(spec) => &spec.name,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Workload::Service(_) => "service",
+            Workload::Container(_) => "container",
+        }
+    }
+}
+
+/// A version of a workload, what a node applies
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub version: String,
+    pub workload: Workload,
+}
+
+impl Revision {
+    pub fn name(&self) -> &str {
+        self.workload.name()
+    }
+}
+
+/// Desired state of a workload, named after it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deployment {
+    pub version: String,
+    // Label selector of the nodes, every node when empty
+    #[serde(default)]
+    pub selector: String,
+    // Number of matching nodes to run on, all of them when None
+    #[serde(default)]
+    pub replicas: Option<usize>,
+    pub workload: Workload,
+    // Must pass for a node to be ready
+    #[serde(default)]
+    pub health: Option<Check>,
+    // Revision before the last change of version, for `rollout undo`
+    #[serde(default)]
+    pub previous: Option<Revision>,
+    #[serde(default)]
+    pub rollout: Option<Rollout>,
+}
+
+impl Deployment {
+    pub fn name(&self) -> &str {
+        self.workload.name()
+    }
+
+    pub fn revision(&self) -> Revision {
+        Revision { version: self.version.clone(), workload: self.workload.clone() }
+    }
+
+    /// Revision the node runs, the previous one until a rollout moves the
+    /// node to the version of the spec
+    pub fn revision_for(&self, node: &str) -> Revision {
+        match (&self.rollout, &self.previous) {
+            (Some(rollout), Some(previous)) if rollout.state != RolloutState::Complete && !rollout.nodes.contains(node) => previous.clone(),
+            _ => self.revision(),
+        }
+    }
+
+    /// The spec replacing `current`, on every node at once. The version it
+    /// replaces is kept for `rollout undo`.
+    pub fn replacing(self, current: Option<&Deployment>) -> Deployment {
+        let previous = match current {
+            Some(current) if current.version != self.version => Some(current.revision()),
+            Some(current) => current.previous.clone(),
+            None => None,
+        };
+        Deployment { previous, rollout: None, ..self }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        match &self.workload {
+            Workload::Service(spec) => spec.check()?,
+            Workload::Container(spec) => spec.check()?,
+        }
+        if !labels::valid_label_value(&self.version) {
+            return Err(format!("Invalid version {}, use at most 63 letters, digits and ._-", self.version));
+        }
+        self.selector.parse::<Selector>()?;
+        if self.replicas == Some(0) {
+            return Err(format!("Deployment {} needs at least one replica", self.name()));
+        }
+        if let Some(check) = &self.health {
+            check.check()?;
+        }
+        Ok(())
+    }
+}
+
+/// Nodes a deployment may be placed on as this node sees them, with the
+/// labels they announce. Tags are left out, they are set on one node only.
+#[derive(Debug, Clone)]
+pub struct Candidates {
+    pub local: String,
+    pub nodes: BTreeMap<String, Labels>,
+}
+
+// Rendezvous hashing, each node ranks the candidates the same way and a
+// replica only moves when the node it ran on goes away
+fn score(name: &str, node: &str) -> Vec<u8> {
+    Sha256::digest(format!("{}/{}", name, node).as_bytes()).to_vec()
+}
+
+impl Candidates {
+    /// Nodes the deployment runs on
+    pub fn placed(&self, deployment: &Deployment) -> Vec<String> {
+        let selector: Selector = deployment.selector.parse().unwrap_or_default();
+        let mut nodes: Vec<&String> = self.nodes.iter()
+            .filter(|(_, labels)| selector.matches(labels))
+            .map(|(node, _)| node)
+            .collect();
+        if let Some(replicas) = deployment.replicas {
+            nodes.sort_by_cached_key(|node| std::cmp::Reverse(score(deployment.name(), node)));
+            nodes.truncate(replicas);
+        }
+        nodes.into_iter().cloned().collect()
+    }
+
+    pub fn places(&self, deployment: &Deployment) -> bool {
+        self.placed(deployment).contains(&self.local)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployState {
+    // Applied, not running yet
+    Progressing,
+    Ready,
+    Failed,
+}
+
+/// What a node reports of a deployment it runs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeployStatus {
+    pub deployment: String,
+    pub node: String,
+    // Version running on the node, None until the first one is applied
+    pub version: Option<String>,
+    pub state: DeployState,
+    pub message: Option<String>,
+    // Last difference from the spec the node found and corrected
+    pub drift: Option<String>,
+    pub drifted: Option<u64>,
+    // Unix time in seconds of the report
+    pub updated: u64,
+}
+
+/// True when the status changed, or the last report is getting old
+pub fn needs_report(current: Option<&DeployStatus>, status: &DeployStatus) -> bool {
+    match current {
+        Some(current) => {
+            let unchanged = DeployStatus { updated: status.updated, ..current.clone() } == *status;
+            !unchanged || status.updated >= current.updated + REPORT_INTERVAL
+        },
+        None => true,
+    }
+}
+
+/// The records a node acts on, as any member can write to the cluster kv: the
+/// specs written by one of its `operators`, and all the rest
+pub fn authorized(records: Vec<Record>, operators: &Operators) -> Vec<Record> {
+    records.into_iter().filter(|record| {
+        if !record.key.ends_with("/spec") || record.value.is_none() || operators.allows(&record.version.node) {
+            return true;
+        }
+        warn!("Ignoring deployment {} written by {}, not an operator", record.key, record.version.node);
+        false
+    }).collect()
+}
+
+/// Deployments and the statuses reported for them, from the records under
+/// `DEPLOY_PREFIX`. A status counts only when written by the node it is about.
+pub fn parse_records(records: &[Record]) -> (BTreeMap<String, Deployment>, BTreeMap<String, Vec<DeployStatus>>) {
+    let mut deployments = BTreeMap::new();
+    let mut statuses: BTreeMap<String, Vec<DeployStatus>> = BTreeMap::new();
+    for record in records {
+        let (value, path) = match (&record.value, record.key.strip_prefix(DEPLOY_PREFIX)) {
+            (Some(value), Some(path)) => (value, path),
+            _ => continue,
+        };
+        match path.split('/').collect::<Vec<&str>>()[..] {
+            [_, "spec"] => match serde_json::from_str::<Deployment>(value) {
+                Ok(d) => {
+                    deployments.insert(d.name().to_string(), d);
+                },
+                Err(e) => warn!("Ignoring deployment {}: {}", record.key, e),
+            },
+            [name, "status", node] => match serde_json::from_str::<DeployStatus>(value) {
+                Ok(s) if node == record.version.node && s.node == node => statuses.entry(name.to_string()).or_default().push(s),
+                Ok(_) => warn!("Ignoring deployment status {} written by {}", record.key, record.version.node),
+                Err(e) => warn!("Ignoring deployment status {}: {}", record.key, e),
+            },
+            _ => {},
+        }
+    }
+    (deployments, statuses)
+}
+
+/// A deployment and how far the nodes got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeploymentStatus {
+    pub name: String,
+    pub kind: String,
+    pub version: String,
+    pub selector: String,
+    pub replicas: Option<usize>,
+    pub previous: Option<String>,
+    pub rollout: Option<Rollout>,
+    // Nodes that reported, ready with the version of the spec, and the others
+    pub nodes: usize,
+    pub ready: usize,
+    pub not_ready: usize,
+    pub statuses: Vec<DeployStatus>,
+}
+
+impl DeploymentStatus {
+    pub fn new(deployment: &Deployment, mut statuses: Vec<DeployStatus>) -> DeploymentStatus {
+        statuses.sort_by(|a, b| a.node.cmp(&b.node));
+        let ready = statuses.iter()
+            .filter(|s| s.state == DeployState::Ready && s.version.as_deref() == Some(deployment.version.as_str()))
+            .count();
+        DeploymentStatus {
+            name: deployment.name().to_string(),
+            kind: deployment.workload.kind().to_string(),
+            version: deployment.version.clone(),
+            selector: deployment.selector.clone(),
+            replicas: deployment.replicas,
+            previous: deployment.previous.as_ref().map(|p| p.version.clone()),
+            rollout: deployment.rollout.clone(),
+            nodes: statuses.len(),
+            ready,
+            not_ready: statuses.len() - ready,
+            statuses,
+        }
+    }
+}
+
+/// Converges the workloads of this node to the deployments placed on it
+#[derive(Clone)]
+pub struct Reconciler {
+    node: String,
+    supervisor: Supervisor,
+    runtime: Arc<dyn ContainerRuntime>,
+    applied: sled::Tree,
+    drifts: Arc<Mutex<HashMap<String, (String, u64)>>>,
+}
+
+impl Reconciler {
+    pub fn new(node: &str, supervisor: Supervisor, runtime: Arc<dyn ContainerRuntime>, db: &sled::Db) -> sled::Result<Reconciler> {
+        Ok(Reconciler {
+            node: node.to_string(),
+            supervisor,
+            runtime,
+            applied: Tree::Deployed.open(db)?,
+            drifts: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Revisions this node applied
+    pub fn applied(&self) -> Result<Vec<Revision>, Box<dyn Error>> {
+        let mut applied = Vec::new();
+        for item in self.applied.iter() {
+            let (_, v) = item?;
+            applied.push(serde_json::from_slice(&v)?);
+        }
+        Ok(applied)
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Revision>, String> {
+        match self.applied.get(name.as_bytes()).map_err(|e| e.to_string())? {
+            Some(v) => serde_json::from_slice(&v).map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    /// One pass: apply what changed, repair what drifted and tear down what
+    /// is no longer placed here. Returns the status of each deployment, None
+    /// once torn down.
+    pub async fn reconcile(&self, deployments: &[Deployment], candidates: &Candidates) -> Vec<(String, Option<DeployStatus>)> {
+        let mut reports = Vec::new();
+        let mut placed = HashSet::new();
+        for deployment in deployments.iter().filter(|d| candidates.places(d)) {
+            placed.insert(deployment.name().to_string());
+            reports.push((deployment.name().to_string(), Some(self.converge(deployment).await)));
+        }
+        let applied = match self.applied() {
+            Ok(applied) => applied,
+            Err(e) => {
+                warn!("Failed to read the applied deployments: {}", e);
+                return reports;
+            },
+        };
+        for revision in applied.iter().filter(|r| !placed.contains(r.name())) {
+            self.teardown(&revision.workload).await;
+            if let Err(e) = self.applied.remove(revision.name().as_bytes()) {
+                warn!("Failed to forget deployment {}: {}", revision.name(), e);
+                continue;
+            }
+            self.drifts.lock().unwrap().remove(revision.name());
+            info!("Removed deployment {}, no longer placed on this node", revision.name());
+            reports.push((revision.name().to_string(), None));
+        }
+        reports
+    }
+
+    async fn converge(&self, deployment: &Deployment) -> DeployStatus {
+        let name = deployment.name();
+        let revision = deployment.revision_for(&self.node);
+        let result = match self.get(name) {
+            Ok(Some(applied)) if applied == revision => self.repair(&revision.workload).await,
+            Ok(applied) => self.apply(&revision, applied.as_ref()).await,
+            Err(e) => Err(e),
+        };
+        let result = match (result, &deployment.health) {
+            (Ok((DeployState::Ready, _)), Some(check)) => match check.probe(health::CHECK_TIMEOUT).await {
+                Ok(_) => Ok((DeployState::Ready, None)),
+                Err(e) => Ok((DeployState::Progressing, Some(format!("health check failed: {}", e)))),
+            },
+            (result, _) => result,
+        };
+        let (state, message) = result.unwrap_or_else(|e| (DeployState::Failed, Some(e)));
+        let version = self.get(name).ok().flatten().map(|r| r.version);
+        let drift = self.drifts.lock().unwrap().get(name).cloned();
+        DeployStatus {
+            deployment: name.to_string(),
+            node: self.node.clone(),
+            version,
+            state,
+            message,
+            drift: drift.as_ref().map(|d| d.0.clone()),
+            drifted: drift.map(|d| d.1),
+            updated: now_secs(),
+        }
+    }
+
+    async fn apply(&self, revision: &Revision, applied: Option<&Revision>) -> Result<(DeployState, Option<String>), String> {
+        let name = revision.name();
+        match applied {
+            Some(applied) => self.teardown(&applied.workload).await,
+            // Not taking over what was started otherwise
+            None => match &revision.workload {
+                Workload::Service(_) if self.supervisor.status(name).is_ok() => {
+                    return Err(format!("Service {} is declared in the config of the node", name));
+                },
+                Workload::Container(_) if self.runtime.state(name).await?.is_some() => {
+                    return Err(format!("Container {} exists and was not started by a deployment", name));
+                },
+                _ => {},
+            },
+        }
+        self.start(&revision.workload).await?;
+        let value = serde_json::to_vec(revision).map_err(|e| e.to_string())?;
+        self.applied.insert(name.as_bytes(), value).map_err(|e| e.to_string())?;
+        match applied {
+            Some(applied) => info!("Updated deployment {} from version {} to {}", name, applied.version, revision.version),
+            None => info!("Applied deployment {} version {}", name, revision.version),
+        }
+        self.observe(&revision.workload).await
+    }
+
+    async fn start(&self, workload: &Workload) -> Result<(), String> {
+        match workload {
+            Workload::Service(spec) => {
+                self.supervisor.declare(spec.clone())?;
+                self.supervisor.start(&spec.name).map(|_| ())
+            },
+            Workload::Container(spec) => self.runtime.run(spec).await.map(|_| ()),
+        }
+    }
+
+    async fn teardown(&self, workload: &Workload) {
+        let name = workload.name();
+        let result = match workload {
+            Workload::Service(_) => match self.supervisor.status(name) {
+                Ok(_) => self.supervisor.remove(name).await,
+                Err(_) => Ok(()),
+            },
+            Workload::Container(_) => match self.runtime.state(name).await {
+                Ok(Some(_)) => {
+                    let _ = self.runtime.stop(name, STOP_TIMEOUT).await;
+                    self.runtime.remove(name, true).await
+                },
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            warn!("Failed to remove {} {}: {}", workload.kind(), name, e);
+        }
+    }
+
+    // The applied workload as it runs, restarted when it was stopped
+    async fn repair(&self, workload: &Workload) -> Result<(DeployState, Option<String>), String> {
+        let name = workload.name();
+        let drift = match workload {
+            Workload::Service(spec) => match self.supervisor.status(name).map(|s| s.state) {
+                // Gone with the restart of the node, not a drift
+                Err(_) => {
+                    self.start(workload).await?;
+                    None
+                },
+                Ok(ServiceState::Stopped) | Ok(ServiceState::Exited) => {
+                    self.supervisor.start(&spec.name)?;
+                    Some(format!("service {} was not running, started it", name))
+                },
+                Ok(_) => None,
+            },
+            Workload::Container(_) => match self.runtime.state(name).await? {
+                None => {
+                    self.start(workload).await?;
+                    Some(format!("container {} was removed, ran it again", name))
+                },
+                Some(info) if info.state == ContainerState::Stopped => {
+                    self.runtime.remove(name, true).await?;
+                    self.start(workload).await?;
+                    Some(format!("container {} exited, ran it again", name))
+                },
+                Some(_) => None,
+            },
+        };
+        let drift = match drift {
+            Some(drift) => drift,
+            None => return self.observe(workload).await,
+        };
+        // Recorded once the workload runs again, otherwise the repair failed
+        let deadline = Instant::now() + REPAIR_TIMEOUT;
+        let observed = loop {
+            let observed = self.observe(workload).await?;
+            if observed.0 != DeployState::Progressing || Instant::now() >= deadline {
+                break observed;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        };
+        if observed.0 == DeployState::Ready {
+            warn!("Deployment {} drifted: {}", name, drift);
+            self.drifts.lock().unwrap().insert(name.to_string(), (drift, now_secs()));
+            return Ok(observed);
+        }
+        let message = format!("{} but it does not run{}", drift, observed.1.map(|e| format!(": {}", e)).unwrap_or_default());
+        warn!("Deployment {} drifted, failed to repair it: {}", name, message);
+        Ok((DeployState::Failed, Some(message)))
+    }
+
+    async fn observe(&self, workload: &Workload) -> Result<(DeployState, Option<String>), String> {
+        let name = workload.name();
+        match workload {
+            Workload::Service(_) => {
+                let status = self.supervisor.status(name)?;
+                Ok(match status.state {
+                    ServiceState::Running => (DeployState::Ready, None),
+                    ServiceState::Failed => (DeployState::Failed, status.error.or_else(|| status.exit_code.map(|c| format!("exited with {}", c)))),
+                    _ => (DeployState::Progressing, status.error),
+                })
+            },
+            Workload::Container(_) => match self.runtime.state(name).await? {
+                Some(info) if info.state == ContainerState::Running => Ok((DeployState::Ready, None)),
+                Some(info) if info.state == ContainerState::Paused => Ok((DeployState::Progressing, Some("paused".to_string()))),
+                Some(_) => Ok((DeployState::Progressing, None)),
+                None => Err(format!("No container {}", name)),
+            },
+        }
+    }
+}
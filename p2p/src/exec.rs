@@ -0,0 +1,139 @@
+use std::{collections::BTreeMap, io};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+
+use crate::{cron::{self, Job, Run, Target}, files::{read_frames, write_frames}, utils::now_secs};
+
+const MAX_FRAME: usize = 1024 * 1024;
+/// Seconds a command may take unless given
+pub const DEFAULT_TIMEOUT: u64 = 30;
+/// Most seconds a command may take, below the minute the node waits for the
+/// response of a peer
+pub const MAX_TIMEOUT: u64 = 50;
+
+#[derive(Debug, Clone)]
+pub struct ExecProtocol;
+
+impl ProtocolName for ExecProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/exec/1.0.0"
+    }
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+/// A command run once on a node, `hanode exec`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecRequest {
+    // Program and its arguments, not run through a shell
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+impl ExecRequest {
+    pub fn check(&self) -> Result<(), String> {
+        if self.command.is_empty() || self.command[0].is_empty() {
+            return Err("No command to run".to_string());
+        }
+        if self.timeout == 0 || self.timeout > MAX_TIMEOUT {
+            return Err(format!("The timeout must be between 1 and {}s", MAX_TIMEOUT));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ExecResponse {
+    Done { run: Run },
+    Error { message: String },
+}
+
+/// What a node answered, `hanode exec` shows one per node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecResult {
+    pub node: String,
+    pub exit_code: Option<i32>,
+    // Refused, unreachable, not started, timed out or killed by a signal
+    pub error: Option<String>,
+    // End of the standard output then the standard error
+    pub output: String,
+}
+
+impl ExecResult {
+    pub fn new(node: &str, response: Result<ExecResponse, String>) -> ExecResult {
+        let (exit_code, error, output) = match response {
+            Ok(ExecResponse::Done { run }) => (run.exit_code, run.error, run.output),
+            Ok(ExecResponse::Error { message }) | Err(message) => (None, Some(message), String::new()),
+        };
+        ExecResult { node: node.to_string(), exit_code, error, output }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Runs the command as a job would, the output kept the same way
+pub async fn serve(node: &str, req: ExecRequest) -> ExecResponse {
+    if let Err(message) = req.check() {
+        return ExecResponse::Error { message };
+    }
+    let job = Job {
+        name: "exec".to_string(),
+        schedule: String::new(),
+        command: req.command,
+        env: req.env,
+        target: Target::Node { node: node.to_string() },
+        selector: String::new(),
+        timeout: req.timeout,
+    };
+    ExecResponse::Done { run: cron::run(&job, node, now_secs()).await }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecCodec;
+
+#[async_trait]
+impl RequestResponseCodec for ExecCodec {
+    type Protocol = ExecProtocol;
+    type Request = ExecRequest;
+    type Response = ExecResponse;
+
+    async fn read_request<T>(&mut self, _: &ExecProtocol, io: &mut T) -> io::Result<ExecRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (req, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(req)
+    }
+
+    async fn read_response<T>(&mut self, _: &ExecProtocol, io: &mut T) -> io::Result<ExecResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (res, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(res)
+    }
+
+    async fn write_request<T>(&mut self, _: &ExecProtocol, io: &mut T, req: ExecRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &req, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &ExecProtocol, io: &mut T, res: ExecResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res, &[]).await
+    }
+}
@@ -0,0 +1,280 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::{Component, Path, PathBuf}};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::{core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName}, request_response::RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Bytes sent in one request or response
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+// Largest header or data frame accepted from a peer
+const MAX_FRAME: usize = 2 * CHUNK_SIZE as usize;
+// Suffix of a file being received
+const PARTIAL_SUFFIX: &str = ".part";
+/// Largest file a peer may write unless configured, 4GiB
+pub const DEFAULT_MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct FileProtocol;
+
+impl ProtocolName for FileProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/file/1.0.0"
+    }
+}
+
+/// Operations on the files directory of a peer, paths are relative to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum FileOp {
+    // Size and hash of a file, and how much of it was received so far
+    Stat { path: String },
+    Read { path: String, offset: u64, len: u64 },
+    // Append the data to the partial file, which is started over at offset 0
+    // and moved in place once `size` bytes matching `file_sha256` arrived
+    Write { path: String, offset: u64, size: u64, sha256: String, file_sha256: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum FileResult {
+    Stat { size: Option<u64>, sha256: Option<String>, partial: u64 },
+    Chunk { size: u64, sha256: String },
+    // Length of the partial file, where the next write has to start
+    Written { partial: u64 },
+    Error { message: String },
+}
+
+fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+/// The `files` of `config.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileConfig {
+    // Largest file in bytes a peer may write
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+}
+
+impl Default for FileConfig {
+    fn default() -> Self {
+        FileConfig { max_size: default_max_size() }
+    }
+}
+
+/// A json header followed by the raw bytes of a chunk
+#[derive(Debug, Clone)]
+pub struct FileRequest {
+    pub op: FileOp,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileResponse {
+    pub result: FileResult,
+    pub data: Vec<u8>,
+}
+
+impl FileResponse {
+    fn error(message: String) -> FileResponse {
+        FileResponse { result: FileResult::Error { message }, data: Vec::new() }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Hash of a whole file, read in chunks
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Where a file is written until it is complete
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(PARTIAL_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// A json header and the data, each at most `max` bytes
+pub(crate) async fn read_frames<T: AsyncRead + Unpin + Send, H: for<'de> Deserialize<'de>>(io: &mut T, max: usize) -> io::Result<(H, Vec<u8>)> {
+    let header = read_length_prefixed(io, max).await?;
+    let header = serde_json::from_slice(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let data = read_length_prefixed(io, max).await?;
+    Ok((header, data))
+}
+
+pub(crate) async fn write_frames<T: AsyncWrite + Unpin + Send, H: Serialize>(io: &mut T, header: &H, data: &[u8]) -> io::Result<()> {
+    write_length_prefixed(io, serde_json::to_vec(header)?).await?;
+    write_length_prefixed(io, data).await?;
+    io.close().await
+}
+
+#[derive(Debug, Clone)]
+pub struct FileCodec;
+
+#[async_trait]
+impl RequestResponseCodec for FileCodec {
+    type Protocol = FileProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(&mut self, _: &FileProtocol, io: &mut T) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (op, data) = read_frames(io, MAX_FRAME).await?;
+        Ok(FileRequest { op, data })
+    }
+
+    async fn read_response<T>(&mut self, _: &FileProtocol, io: &mut T) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (result, data) = read_frames(io, MAX_FRAME).await?;
+        Ok(FileResponse { result, data })
+    }
+
+    async fn write_request<T>(&mut self, _: &FileProtocol, io: &mut T, req: FileRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &req.op, &req.data).await
+    }
+
+    async fn write_response<T>(&mut self, _: &FileProtocol, io: &mut T, res: FileResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res.result, &res.data).await
+    }
+}
+
+/// The files directory of the node, which peers read from and write to
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    root: PathBuf,
+    // Largest file a peer may write
+    max_size: u64,
+}
+
+impl FileStore {
+    pub fn new(root: &str, max_size: u64) -> FileStore {
+        FileStore { root: PathBuf::from(root), max_size }
+    }
+
+    // Paths of peers stay inside the root, symbolic links included
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let relative = Path::new(path);
+        let inside = relative.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !inside || path.is_empty() || path.ends_with(PARTIAL_SUFFIX) {
+            return Err(format!("Invalid path {}", path));
+        }
+        let root = self.root.canonicalize().map_err(|e| format!("{}: {}", self.root.display(), e))?;
+        let resolved = self.root.join(relative);
+        for p in [&resolved, &partial_path(&resolved)] {
+            if !matches!(real_path(p), Some(real) if real.starts_with(&root)) {
+                return Err(format!("Invalid path {}", path));
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Answer a request of a peer, only operators may write
+    pub fn serve(&self, req: FileRequest, operator: bool) -> FileResponse {
+        let r = match req.op {
+            FileOp::Stat { path } => self.resolve(&path).and_then(|p| stat(&p).map_err(|e| e.to_string())),
+            FileOp::Read { path, offset, len } => self.resolve(&path).and_then(|p| read_chunk(&p, offset, len).map_err(|e| format!("{}: {}", path, e))),
+            FileOp::Write { .. } if !operator => Err("Not an operator of this node".to_string()),
+            FileOp::Write { size, .. } if size > self.max_size => {
+                Err(format!("File of {} bytes is larger than the {} bytes allowed", size, self.max_size))
+            },
+            FileOp::Write { path, offset, size, sha256, file_sha256 } => self.resolve(&path).and_then(|p| {
+                write_chunk(&p, offset, size, &sha256, &file_sha256, &req.data)
+            }),
+        };
+        match r {
+            Ok(res) => res,
+            Err(message) => FileResponse::error(message),
+        }
+    }
+}
+
+// Where a path leads once its symbolic links are followed, through the
+// closest part of it that exists, None for a dangling link
+fn real_path(path: &Path) -> Option<PathBuf> {
+    let mut existing = path;
+    while fs::symlink_metadata(existing).is_err() {
+        existing = existing.parent()?;
+    }
+    let real = existing.canonicalize().ok()?;
+    Some(real.join(path.strip_prefix(existing).ok()?))
+}
+
+fn len_or_zero(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+fn stat(path: &Path) -> io::Result<FileResponse> {
+    let (size, sha256) = if path.is_file() {
+        (Some(fs::metadata(path)?.len()), Some(file_sha256(path)?))
+    } else {
+        (None, None)
+    };
+    let partial = len_or_zero(&partial_path(path));
+    Ok(FileResponse { result: FileResult::Stat { size, sha256, partial }, data: Vec::new() })
+}
+
+fn read_chunk(path: &Path, offset: u64, len: u64) -> io::Result<FileResponse> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(len.min(CHUNK_SIZE)).read_to_end(&mut data)?;
+    Ok(FileResponse { result: FileResult::Chunk { size, sha256: sha256_hex(&data) }, data })
+}
+
+fn write_chunk(path: &Path, offset: u64, size: u64, sha256: &str, expected: &str, data: &[u8]) -> Result<FileResponse, String> {
+    if sha256_hex(data) != sha256 {
+        return Err("Chunk does not match its hash".to_string());
+    }
+    if !matches!(offset.checked_add(data.len() as u64), Some(end) if end <= size) {
+        return Err(format!("Chunk goes past the {} bytes of the file", size));
+    }
+    let partial = partial_path(path);
+    let current = len_or_zero(&partial);
+    // Out of order, tell the sender where to resume
+    if offset != 0 && offset != current {
+        return Ok(FileResponse { result: FileResult::Written { partial: current }, data: Vec::new() });
+    }
+    let io_err = |e: io::Error| format!("{}: {}", path.display(), e);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(io_err)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&partial).map_err(io_err)?;
+    if offset == 0 {
+        file.set_len(0).map_err(io_err)?;
+    }
+    file.write_all(data).map_err(io_err)?;
+    file.sync_data().map_err(io_err)?;
+    let written = offset + data.len() as u64;
+    if written >= size {
+        if file_sha256(&partial).map_err(io_err)? != expected {
+            let _ = fs::remove_file(&partial);
+            return Err("File does not match its hash, the partial copy was removed".to_string());
+        }
+        fs::rename(&partial, path).map_err(io_err)?;
+    }
+    Ok(FileResponse { result: FileResult::Written { partial: written }, data: Vec::new() })
+}
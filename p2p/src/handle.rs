@@ -0,0 +1,362 @@
+use std::{error::Error, fmt, str::FromStr, sync::Arc, time::Duration};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::{self, error::TrySendError}, oneshot};
+
+use crate::{container::{ContainerRequest, ContainerResponse}, exec::{ExecRequest, ExecResponse}, artifact::{ArtifactOp, ArtifactResponse, Availability, Provider}, files::{FileRequest, FileResponse}, health::{Alert, CheckResult}, kv::{KvList, Record}, labels::Labels, lock::{self, Lock, LockRequest, LockResponse}, message::Message, raft::{RaftCommand, RaftResult, RaftStatus}, service::{ServiceRequest, ServiceResponse}, metrics::QueueMetrics, node::{Receiver, Sender}, peer::Peer, swim::Member, transfer::Transfers};
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+pub const QUEUE_POLICIES: [&str; 3] = ["wait", "reject", "drop"];
+
+// Control commands are few, they wait for room instead of failing
+const CONTROL_CAPACITY: usize = 64;
+
+/// Requests served by the swarm task, answered on the oneshot sender
+#[derive(Debug)]
+pub enum Command {
+    // Handled as if it came from the message queue, ahead of the messages
+    // waiting there
+    Message(Message),
+    // Stop the node, sent on the control queue so a full message queue
+    // does not hold it up
+    Stop,
+    ListPeers(oneshot::Sender<Vec<Peer>>),
+    // Set then remove tags of a known peer
+    TagPeer(PeerId, Labels, Vec<String>, oneshot::Sender<Result<Peer, String>>),
+    Dial(Multiaddr, oneshot::Sender<Result<(), String>>),
+    // Replies false when the peer was not connected
+    Disconnect(PeerId, oneshot::Sender<bool>),
+    Info(oneshot::Sender<NodeInfo>),
+    // Request of the file protocol, dialing the peer when needed
+    File(PeerId, FileRequest, oneshot::Sender<Result<FileResponse, String>>),
+    Artifact(PeerId, ArtifactOp, oneshot::Sender<Result<ArtifactResponse, String>>),
+    // Peers that announced an artifact, then the other connected peers
+    ArtifactProviders(String, oneshot::Sender<Vec<Provider>>),
+    AnnounceArtifacts(Vec<Availability>),
+    // Stamped by the node and gossiped to the peers, None deletes the key
+    KvWrite(String, Option<String>, oneshot::Sender<Result<Record, String>>),
+    KvGet(String, oneshot::Sender<Result<Option<Record>, String>>),
+    // Records under a prefix, or the changes after a cursor
+    KvList(String, Option<u64>, oneshot::Sender<Result<KvList, String>>),
+    // None when the node is not a raft voter
+    RaftStatus(oneshot::Sender<Option<RaftStatus>>),
+    // Linearizable, answered by the leader once a majority agreed
+    RaftWrite(RaftCommand, oneshot::Sender<RaftResult>),
+    RaftRead(String, oneshot::Sender<RaftResult>),
+    // Sent to the raft leader, or served by it when this node leads
+    Lock(LockRequest, oneshot::Sender<LockResponse>),
+    // Renew a lock this node holds until it is released
+    KeepLock(String, Duration),
+    // Members of the cluster as the failure detector sees them
+    Members(oneshot::Sender<Vec<Member>>),
+    // Served by the supervisor of this node, or of the peer when given
+    Service(Option<PeerId>, ServiceRequest, oneshot::Sender<Result<ServiceResponse, String>>),
+    // Served by the container runtime of this node, or of the peer when given
+    Container(Option<PeerId>, ContainerRequest, oneshot::Sender<Result<ContainerResponse, String>>),
+    // Run by this node, or by the peer when given
+    Exec(Option<PeerId>, ExecRequest, oneshot::Sender<Result<ExecResponse, String>>),
+    // Results of the checks of this node, then of the peers that reported lately
+    HealthChecks(oneshot::Sender<Vec<CheckResult>>),
+    // Alerts of this node firing
+    Alerts(oneshot::Sender<Vec<Alert>>),
+}
+
+/// Live state of the swarm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub peer_id: String,
+    pub listen_addrs: Vec<String>,
+    pub connected_peers: Vec<String>,
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+/// What publishing does when the message queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    // Wait for room
+    Wait,
+    // Fail with `QueueFull`, HTTP callers get a 429
+    Reject,
+    // Discard the new message
+    Drop,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(QueuePolicy::Wait),
+            "reject" => Ok(QueuePolicy::Reject),
+            "drop" => Ok(QueuePolicy::Drop),
+            _ => Err(format!("Unknown queue policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueOptions {
+    pub capacity: usize,
+    pub policy: QueuePolicy,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions { capacity: DEFAULT_QUEUE_CAPACITY, policy: QueuePolicy::Reject }
+    }
+}
+
+/// Returned by `NodeHandle::publish` when the queue is full and the policy
+/// rejects
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The message queue of the node is full, retry later")
+    }
+}
+
+impl Error for QueueFull {}
+
+/// Receiving ends given to `Node::new`
+pub struct NodeQueues {
+    pub messages: Receiver<Message>,
+    pub control: Receiver<Command>,
+}
+
+/// Cheap to clone handle on a running node
+#[derive(Debug, Clone)]
+pub struct NodeHandle {
+    messages: Sender<Message>,
+    control: Sender<Command>,
+    opts: QueueOptions,
+    metrics: Arc<QueueMetrics>,
+    transfers: Arc<Transfers>,
+}
+
+fn not_running<T>(_: T) -> Box<dyn Error> {
+    "The node is not running".into()
+}
+
+fn lock_error(res: LockResponse) -> String {
+    match res {
+        LockResponse::Held { lock } => format!("Lock {} is held by {}", lock.name, lock.holder),
+        LockResponse::Error { message } => message,
+        LockResponse::NotLeader { .. } => "No raft leader elected yet, retry later".to_string(),
+        res => format!("Unexpected lock response {:?}", res),
+    }
+}
+
+impl NodeHandle {
+    /// The handle and the receivers given to `Node::new`
+    pub fn channel(opts: QueueOptions) -> (NodeHandle, NodeQueues) {
+        let (messages, message_receiver) = mpsc::channel(opts.capacity);
+        let (control, control_receiver) = mpsc::channel(CONTROL_CAPACITY);
+        let handle = NodeHandle {
+            messages,
+            control,
+            opts,
+            metrics: Arc::new(QueueMetrics::default()),
+            transfers: Arc::new(Transfers::default()),
+        };
+        (handle, NodeQueues { messages: message_receiver, control: control_receiver })
+    }
+
+    async fn send(&self, command: Command) -> Result<(), Box<dyn Error>> {
+        self.control.send(command).await.map_err(not_running)
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T, Box<dyn Error>> {
+        let (reply, response) = oneshot::channel();
+        self.send(command(reply)).await?;
+        response.await.map_err(|_| "The node stopped before replying".into())
+    }
+
+    /// Queue a message following the policy, false when it was dropped
+    pub async fn publish(&self, msg: Message) -> Result<bool, Box<dyn Error>> {
+        match self.opts.policy {
+            QueuePolicy::Wait => self.messages.send(msg).await.map_err(not_running)?,
+            QueuePolicy::Reject | QueuePolicy::Drop => match self.messages.try_send(msg) {
+                Ok(_) => {},
+                Err(TrySendError::Full(_)) if self.opts.policy == QueuePolicy::Drop => {
+                    self.metrics.dropped();
+                    return Ok(false);
+                },
+                Err(TrySendError::Full(_)) => {
+                    self.metrics.rejected();
+                    return Err(QueueFull.into());
+                },
+                Err(TrySendError::Closed(_)) => return Err(not_running(())),
+            },
+        }
+        self.metrics.queued(self.depth());
+        Ok(true)
+    }
+
+    /// Queue a message, waiting for room whatever the policy
+    pub async fn publish_wait(&self, msg: Message) -> Result<(), Box<dyn Error>> {
+        self.messages.send(msg).await.map_err(not_running)?;
+        self.metrics.queued(self.depth());
+        Ok(())
+    }
+
+    /// Messages waiting for the swarm task
+    pub fn depth(&self) -> usize {
+        self.opts.capacity - self.messages.capacity()
+    }
+
+    /// Prometheus text exposition of the queue metrics
+    pub fn metrics(&self) -> String {
+        self.metrics.render(self.depth(), self.opts.capacity)
+    }
+
+    pub async fn stop(&self) -> Result<(), Box<dyn Error>> {
+        self.send(Command::Stop).await
+    }
+
+    /// Known peers, with their status taken from the open connections
+    pub async fn peers(&self) -> Result<Vec<Peer>, Box<dyn Error>> {
+        self.request(Command::ListPeers).await
+    }
+
+    /// The peer with its new tags
+    pub async fn tag_peer(&self, peer_id: PeerId, set: Labels, remove: Vec<String>) -> Result<Peer, Box<dyn Error>> {
+        self.request(|reply| Command::TagPeer(peer_id, set, remove, reply)).await?.map_err(|e| e.into())
+    }
+
+    pub async fn dial(&self, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
+        self.request(|reply| Command::Dial(addr, reply)).await?.map_err(|e| e.into())
+    }
+
+    pub async fn disconnect(&self, peer_id: PeerId) -> Result<bool, Box<dyn Error>> {
+        self.request(|reply| Command::Disconnect(peer_id, reply)).await
+    }
+
+    pub async fn info(&self) -> Result<NodeInfo, Box<dyn Error>> {
+        self.request(Command::Info).await
+    }
+
+    pub async fn file_request(&self, peer: PeerId, req: FileRequest) -> Result<FileResponse, Box<dyn Error>> {
+        self.request(|reply| Command::File(peer, req, reply)).await?.map_err(|e| e.into())
+    }
+
+    pub async fn artifact_request(&self, peer: PeerId, op: ArtifactOp) -> Result<ArtifactResponse, Box<dyn Error>> {
+        self.request(|reply| Command::Artifact(peer, op, reply)).await?.map_err(|e| e.into())
+    }
+
+    pub async fn artifact_providers(&self, id: &str) -> Result<Vec<Provider>, Box<dyn Error>> {
+        let id = id.to_string();
+        self.request(|reply| Command::ArtifactProviders(id, reply)).await
+    }
+
+    pub async fn announce_artifacts(&self, artifacts: Vec<Availability>) -> Result<(), Box<dyn Error>> {
+        self.send(Command::AnnounceArtifacts(artifacts)).await
+    }
+
+    pub async fn kv_put(&self, key: &str, value: &str) -> Result<Record, Box<dyn Error>> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.request(|reply| Command::KvWrite(key, Some(value), reply)).await?.map_err(|e| e.into())
+    }
+
+    /// Leaves a tombstone so the delete reaches the peers
+    pub async fn kv_delete(&self, key: &str) -> Result<Record, Box<dyn Error>> {
+        let key = key.to_string();
+        self.request(|reply| Command::KvWrite(key, None, reply)).await?.map_err(|e| e.into())
+    }
+
+    /// The record of a key, None when it was never written or is deleted
+    pub async fn kv_get(&self, key: &str) -> Result<Option<Record>, Box<dyn Error>> {
+        let key = key.to_string();
+        let record = self.request(|reply| Command::KvGet(key, reply)).await??;
+        Ok(record.filter(|r| r.value.is_some()))
+    }
+
+    pub async fn kv_list(&self, prefix: &str, since: Option<u64>) -> Result<KvList, Box<dyn Error>> {
+        let prefix = prefix.to_string();
+        self.request(|reply| Command::KvList(prefix, since, reply)).await?.map_err(|e| e.into())
+    }
+
+    pub async fn raft_status(&self) -> Result<Option<RaftStatus>, Box<dyn Error>> {
+        self.request(Command::RaftStatus).await
+    }
+
+    /// Fails on the followers, which name the leader in the error
+    pub async fn raft_write(&self, command: RaftCommand) -> Result<(), Box<dyn Error>> {
+        self.request(|reply| Command::RaftWrite(command, reply)).await??;
+        Ok(())
+    }
+
+    pub async fn raft_read(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let key = key.to_string();
+        self.request(|reply| Command::RaftRead(key, reply)).await?.map_err(|e| e.into())
+    }
+
+    pub async fn members(&self) -> Result<Vec<Member>, Box<dyn Error>> {
+        self.request(Command::Members).await
+    }
+
+    /// Acquire a lock for this node, or renew it when the node holds it.
+    /// With `keep` the node renews it until `lock_release`.
+    pub async fn lock_acquire(&self, name: &str, ttl: Duration, keep: bool) -> Result<Lock, Box<dyn Error>> {
+        lock::check_ttl(ttl)?;
+        let req = LockRequest::Acquire { name: name.to_string(), ttl: ttl.as_millis() as u64 };
+        match self.request(|reply| Command::Lock(req, reply)).await? {
+            LockResponse::Granted { lock } => {
+                if keep {
+                    self.send(Command::KeepLock(lock.name.clone(), ttl)).await?;
+                }
+                Ok(lock)
+            },
+            res => Err(lock_error(res).into()),
+        }
+    }
+
+    /// Release a lock of this node, only with that token when given
+    pub async fn lock_release(&self, name: &str, token: Option<u64>) -> Result<(), Box<dyn Error>> {
+        let req = LockRequest::Release { name: name.to_string(), token };
+        match self.request(|reply| Command::Lock(req, reply)).await? {
+            LockResponse::Released => Ok(()),
+            res => Err(lock_error(res).into()),
+        }
+    }
+
+    /// Locks held in the cluster, as a voter applied them
+    pub async fn lock_list(&self) -> Result<Vec<Lock>, Box<dyn Error>> {
+        match self.request(|reply| Command::Lock(LockRequest::List, reply)).await? {
+            LockResponse::Locks { locks } => Ok(locks),
+            res => Err(lock_error(res).into()),
+        }
+    }
+
+    /// Operate the services of this node, or of a peer
+    pub async fn service(&self, peer: Option<PeerId>, req: ServiceRequest) -> Result<ServiceResponse, Box<dyn Error>> {
+        self.request(|reply| Command::Service(peer, req, reply)).await?.map_err(|e| e.into())
+    }
+
+    /// Operate the containers of this node, or of a peer
+    pub async fn container(&self, peer: Option<PeerId>, req: ContainerRequest) -> Result<ContainerResponse, Box<dyn Error>> {
+        self.request(|reply| Command::Container(peer, req, reply)).await?.map_err(|e| e.into())
+    }
+
+    /// Run a command on this node, or on a peer
+    pub async fn exec(&self, peer: Option<PeerId>, req: ExecRequest) -> Result<ExecResponse, Box<dyn Error>> {
+        self.request(|reply| Command::Exec(peer, req, reply)).await?.map_err(|e| e.into())
+    }
+
+    /// Last results of the health checks of this node and of its peers
+    pub async fn health_checks(&self) -> Result<Vec<CheckResult>, Box<dyn Error>> {
+        self.request(Command::HealthChecks).await
+    }
+
+    /// Alerts of this node firing
+    pub async fn alerts(&self) -> Result<Vec<Alert>, Box<dyn Error>> {
+        self.request(Command::Alerts).await
+    }
+
+    /// Transfers started with `transfer::start`
+    pub fn transfers(&self) -> &Transfers {
+        &self.transfers
+    }
+}
@@ -0,0 +1,433 @@
+use std::{collections::{BTreeMap, HashSet}, path::Path, process::Stdio, sync::Arc, time::Duration};
+use reqwest::{header::CONTENT_TYPE, redirect::Policy, Client, Url};
+use serde::{Deserialize, Serialize};
+use sysinfo::{DiskExt, System, SystemExt};
+use tokio::{net::TcpStream, process::Command, sync::mpsc};
+use tracing::{info, warn};
+
+use crate::service;
+
+/// Time a check may take unless configured otherwise
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Seconds between two runs of a check of the config unless given
+pub const DEFAULT_INTERVAL: u64 = 30;
+/// Floodsub topic the nodes publish the results of their checks on
+pub const HEALTH_TOPIC: &str = "health";
+
+/// A probe of something running on the node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Check {
+    // Passes once a connection is accepted
+    Tcp { address: String },
+    // http or https, passes on a 2xx or 3xx status, redirects not followed
+    Http { url: String },
+    // Passes on a zero exit code
+    Command { command: Vec<String> },
+    // Passes while the filesystem holding the path is at most `max_used` percent full
+    Disk { path: String, max_used: f64 },
+    // Passes while at most `max_used` percent of the memory is used
+    Memory { max_used: f64 },
+}
+
+impl Check {
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Check::Tcp { address } if !matches!(address.rsplit_once(':'), Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok()) => {
+                Err(format!("Invalid address {}, use host:port", address))
+            },
+            Check::Http { url } => check_url(url),
+            Check::Command { command } if command.is_empty() => Err("Empty health check command".to_string()),
+            Check::Disk { path, .. } if !Path::new(path).is_absolute() => Err(format!("Invalid path {}, use an absolute path", path)),
+            Check::Disk { max_used, .. } | Check::Memory { max_used } if !(0.0..=100.0).contains(max_used) => {
+                Err(format!("Invalid threshold {}, use a percentage between 0 and 100", max_used))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the check, the error says why it did not pass
+    pub async fn probe(&self, timeout: Duration) -> Result<(), String> {
+        self.observe(timeout).await.map(|_| ())
+    }
+
+    /// Runs the check, with what it measured when it passed
+    pub async fn observe(&self, timeout: Duration) -> Outcome {
+        self.observe_with(timeout, Arc::new(SystemReadings)).await
+    }
+
+    /// Runs the check, the disk and memory ones over the readings given
+    pub async fn observe_with(&self, timeout: Duration, readings: Arc<dyn Readings>) -> Outcome {
+        match tokio::time::timeout(timeout, self.run(readings)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("timed out after {}s", timeout.as_secs_f32())),
+        }
+    }
+
+    async fn run(&self, readings: Arc<dyn Readings>) -> Outcome {
+        match self {
+            Check::Tcp { address } => TcpStream::connect(address).await
+                .map(|_| None)
+                .map_err(|e| format!("connecting to {}: {}", address, e)),
+            Check::Http { url } => match http_request(url, None).await? {
+                status if (200..400).contains(&status) => Ok(None),
+                status => Err(format!("{} returned {}", url, status)),
+            },
+            Check::Disk { path, max_used } => {
+                let path = path.clone();
+                let (mount, used) = tokio::task::spawn_blocking(move || readings.disk_usage(&path)).await.map_err(|e| e.to_string())??;
+                threshold(&format!("{} is {:.1}% full", mount, used), used, *max_used)
+            },
+            Check::Memory { max_used } => {
+                let used = tokio::task::spawn_blocking(move || readings.memory_usage()).await.map_err(|e| e.to_string())??;
+                threshold(&format!("memory is {:.1}% used", used), used, *max_used)
+            },
+            Check::Command { command } => {
+                let output = Command::new(&command[0])
+                    .args(&command[1..])
+                    .stdin(Stdio::null())
+                    .kill_on_drop(true)
+                    .output().await
+                    .map_err(|e| format!("running {}: {}", command[0], e))?;
+                if output.status.success() {
+                    return Ok(None);
+                }
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                match (output.status.code(), stderr.lines().last()) {
+                    (Some(code), Some(line)) => Err(format!("exited with {}: {}", code, line)),
+                    (Some(code), None) => Err(format!("exited with {}", code)),
+                    (None, _) => Err("killed by a signal".to_string()),
+                }
+            },
+        }
+    }
+}
+
+// An http:// or https:// url with a host
+fn check_url(url: &str) -> Result<(), String> {
+    match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.host_str().is_some() => Ok(()),
+        Ok(_) => Err(format!("Invalid url {}, use http:// or https:// with a host", url)),
+        Err(e) => Err(format!("Invalid url {}: {}", url, e)),
+    }
+}
+
+// Status code of a GET, or of a POST of the JSON body when given
+async fn http_request(url: &str, body: Option<Vec<u8>>) -> Result<u16, String> {
+    let client = Client::builder()
+        .redirect(Policy::none())
+        .user_agent("hanode")
+        .build()
+        .map_err(|e| e.to_string())?;
+    let request = match body {
+        Some(body) => client.post(url).header(CONTENT_TYPE, "application/json").body(body),
+        None => client.get(url),
+    };
+    request.send().await.map(|res| res.status().as_u16()).map_err(|e| e.to_string())
+}
+
+/// Where the disk and memory checks read how full the node is
+pub trait Readings: Send + Sync {
+    /// Mount point of the filesystem holding the path, and the percentage of it used
+    fn disk_usage(&self, path: &str) -> Result<(String, f64), String>;
+    /// Percentage of the memory used
+    fn memory_usage(&self) -> Result<f64, String>;
+}
+
+/// Readings of the node itself
+pub struct SystemReadings;
+
+impl Readings for SystemReadings {
+    fn disk_usage(&self, path: &str) -> Result<(String, f64), String> {
+        let mut system = System::new();
+        system.refresh_disks_list();
+        let path = Path::new(path);
+        // The innermost mount point, / holds every path
+        let disk = system.disks().iter()
+            .filter(|d| path.starts_with(d.mount_point()))
+            .max_by_key(|d| d.mount_point().as_os_str().len())
+            .ok_or_else(|| format!("no filesystem found holding {}", path.display()))?;
+        let total = disk.total_space();
+        if total == 0 {
+            return Err(format!("{} reports no space", disk.mount_point().display()));
+        }
+        let used = total.saturating_sub(disk.available_space());
+        Ok((disk.mount_point().display().to_string(), used as f64 * 100.0 / total as f64))
+    }
+
+    fn memory_usage(&self) -> Result<f64, String> {
+        let mut system = System::new();
+        system.refresh_memory();
+        match system.total_memory() {
+            0 => Err("the memory size is unknown".to_string()),
+            total => Ok(system.used_memory() as f64 * 100.0 / total as f64),
+        }
+    }
+}
+
+fn threshold(measured: &str, used: f64, max_used: f64) -> Outcome {
+    match used > max_used {
+        true => Err(format!("{}, above {}%", measured, max_used)),
+        false => Ok(Some(measured.to_string())),
+    }
+}
+
+/// What a check measured when it passed, or why it did not
+pub type Outcome = Result<Option<String>, String>;
+
+/// A check the node runs on its own, from the `health` section of the config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub name: String,
+    #[serde(flatten)]
+    pub check: Check,
+    // Seconds between two runs
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // Seconds a run may take
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+fn default_timeout() -> u64 {
+    CHECK_TIMEOUT.as_secs()
+}
+
+/// How an alert is sent
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notify {
+    // The alert POSTed as JSON, over http or https
+    Webhook { url: String },
+    // A warning in the log of the node, and an info line once resolved
+    Log,
+}
+
+/// Fires when a check of the node fails for long enough
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    // Checks the rule watches, every check of the node when empty
+    #[serde(default)]
+    pub checks: Vec<String>,
+    // Seconds a check has to fail before the alert fires
+    #[serde(default)]
+    pub after: u64,
+    pub notify: Notify,
+}
+
+impl AlertRule {
+    fn watches(&self, check: &str) -> bool {
+        self.checks.is_empty() || self.checks.iter().any(|c| c == check)
+    }
+}
+
+/// `health` section of `<datadir>/config.json`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    pub checks: Vec<HealthCheck>,
+    pub alerts: Vec<AlertRule>,
+}
+
+impl HealthConfig {
+    pub fn check(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for check in &self.checks {
+            if !service::valid_name(&check.name) {
+                return Err(format!("Invalid health check name {}", check.name));
+            }
+            if !names.insert(check.name.as_str()) {
+                return Err(format!("Health check {} is declared twice", check.name));
+            }
+            if check.interval == 0 || check.timeout == 0 {
+                return Err(format!("The interval and timeout of health check {} must be at least a second", check.name));
+            }
+            check.check.check().map_err(|e| format!("Health check {}: {}", check.name, e))?;
+        }
+        let mut rules = HashSet::new();
+        for rule in &self.alerts {
+            if !service::valid_name(&rule.name) || !rules.insert(rule.name.as_str()) {
+                return Err(format!("Invalid or duplicate alert rule name {}", rule.name));
+            }
+            if let Some(unknown) = rule.checks.iter().find(|c| !names.contains(c.as_str())) {
+                return Err(format!("Alert rule {} watches unknown health check {}", rule.name, unknown));
+            }
+            if let Notify::Webhook { url } = &rule.notify {
+                check_url(url).map_err(|e| format!("Alert rule {}: {}", rule.name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passing,
+    Failing,
+}
+
+/// Last run of a check on a node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub node: String,
+    pub check: String,
+    pub status: CheckStatus,
+    // What was measured, or why the check failed
+    pub message: Option<String>,
+    // Seconds since the epoch of the run, and of the first run with this status
+    pub checked: u64,
+    pub since: u64,
+}
+
+impl CheckResult {
+    /// The outcome of a run at `now`, following the previous result of the check
+    pub fn new(node: &str, check: &str, outcome: Outcome, previous: Option<&CheckResult>, now: u64) -> CheckResult {
+        let (status, message) = match outcome {
+            Ok(message) => (CheckStatus::Passing, message),
+            Err(message) => (CheckStatus::Failing, Some(message)),
+        };
+        let since = match previous {
+            Some(p) if p.status == status => p.since,
+            _ => now,
+        };
+        CheckResult { node: node.to_string(), check: check.to_string(), status, message, checked: now, since }
+    }
+}
+
+/// Results of the checks of a node, published to its peers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub node: String,
+    pub results: Vec<CheckResult>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Firing,
+    Resolved,
+}
+
+/// Body of a webhook, and what `hanode health alerts` lists
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alert {
+    pub rule: String,
+    pub node: String,
+    pub check: String,
+    pub state: AlertState,
+    // Why the check failed
+    pub message: Option<String>,
+    // Seconds since the epoch
+    pub fired: u64,
+    pub resolved: Option<u64>,
+}
+
+/// Alerts of the rules over the results of the checks of a node. An alert
+/// is sent once when it fires and once when it resolves, not on every run:
+/// until it is delivered, the next run sends it again.
+pub struct Alerter {
+    rules: Vec<AlertRule>,
+    // By rule then check, the alerts delivered as firing
+    firing: BTreeMap<(String, String), Alert>,
+    // Alerts being sent, no other is sent for the rule and check meanwhile
+    sending: HashSet<(String, String)>,
+}
+
+impl Alerter {
+    pub fn new(rules: Vec<AlertRule>) -> Alerter {
+        Alerter { rules, firing: BTreeMap::new(), sending: HashSet::new() }
+    }
+
+    /// Alerts to send following a result, with where to send them. Each is
+    /// to be passed to `delivered` once sent or not.
+    pub fn evaluate(&mut self, result: &CheckResult) -> Vec<(Notify, Alert)> {
+        let mut sent = Vec::new();
+        for rule in self.rules.iter().filter(|r| r.watches(&result.check)) {
+            let key = (rule.name.clone(), result.check.clone());
+            if self.sending.contains(&key) {
+                continue;
+            }
+            let alert = match (result.status, self.firing.get(&key)) {
+                (CheckStatus::Failing, None) if result.checked >= result.since + rule.after => Alert {
+                    rule: rule.name.clone(),
+                    node: result.node.clone(),
+                    check: result.check.clone(),
+                    state: AlertState::Firing,
+                    message: result.message.clone(),
+                    fired: result.checked,
+                    resolved: None,
+                },
+                (CheckStatus::Passing, Some(alert)) => Alert { state: AlertState::Resolved, resolved: Some(result.checked), ..alert.clone() },
+                _ => continue,
+            };
+            self.sending.insert(key);
+            sent.push((rule.notify.clone(), alert));
+        }
+        sent
+    }
+
+    /// Records an alert as firing or resolved once it reached its receiver,
+    /// otherwise it is sent again
+    pub fn delivered(&mut self, alert: &Alert, ok: bool) {
+        let key = (alert.rule.clone(), alert.check.clone());
+        self.sending.remove(&key);
+        match (ok, alert.state) {
+            (true, AlertState::Firing) => {
+                self.firing.insert(key, alert.clone());
+            },
+            (true, AlertState::Resolved) => {
+                self.firing.remove(&key);
+            },
+            (false, _) => {},
+        }
+    }
+
+    pub fn firing(&self) -> Vec<Alert> {
+        self.firing.values().cloned().collect()
+    }
+}
+
+/// Sends an alert, the error says why it could not be delivered
+pub async fn notify(notify: &Notify, alert: &Alert) -> Result<(), String> {
+    match notify {
+        Notify::Log => {
+            let message = alert.message.as_deref().unwrap_or("-");
+            match alert.state {
+                AlertState::Firing => warn!("Alert {} firing on {}: check {} failing: {}", alert.rule, alert.node, alert.check, message),
+                AlertState::Resolved => info!("Alert {} resolved on {}: check {} passing", alert.rule, alert.node, alert.check),
+            }
+            Ok(())
+        },
+        Notify::Webhook { url } => {
+            let body = serde_json::to_vec(alert).map_err(|e| e.to_string())?;
+            match tokio::time::timeout(CHECK_TIMEOUT, http_request(url, Some(body))).await {
+                Ok(Ok(status)) if (200..300).contains(&status) => Ok(()),
+                Ok(Ok(status)) => Err(format!("{} returned {}", url, status)),
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(format!("{} did not answer in {}s", url, CHECK_TIMEOUT.as_secs())),
+            }
+        },
+    }
+}
+
+/// Runs the check on its interval, sending its name and the outcome of each
+/// run until the receiver is dropped
+pub fn schedule(check: HealthCheck, outcomes: mpsc::Sender<(String, Outcome)>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(check.interval));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let outcome = check.check.observe(Duration::from_secs(check.timeout)).await;
+            if outcomes.send((check.name.clone(), outcome)).await.is_err() {
+                break;
+            }
+        }
+    });
+}
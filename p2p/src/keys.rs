@@ -0,0 +1,185 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use libp2p::{identity::{self, Keypair, PublicKey}, PeerId};
+use tracing::debug;
+use serde::{Serialize, Deserialize};
+
+use crate::{node::NodeStateKey, secrets::SecretStore, schema::Tree, utils::now_secs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    Ed25519,
+    Secp256k1,
+}
+
+pub const KEY_TYPES: [&str; 2] = ["ed25519", "secp256k1"];
+
+/// Seconds a rotation is announced to the peers that subscribe, long enough
+/// for the ones offline when it happened to come back.
+pub const ROTATION_ANNOUNCE_SECS: u64 = 7 * 24 * 3600;
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyType::Ed25519 => write!(f, "ed25519"),
+            KeyType::Secp256k1 => write!(f, "secp256k1"),
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            _ => Err(format!("Unknown key type: {}", s)),
+        }
+    }
+}
+
+fn node_tree(db: &sled::Db) -> sled::Result<sled::Tree> {
+    Tree::Node.open(db)
+}
+
+pub fn generate(key_type: KeyType) -> Keypair {
+    match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+    }
+}
+
+pub fn key_type(key: &Keypair) -> KeyType {
+    match key {
+        Keypair::Ed25519(_) => KeyType::Ed25519,
+        _ => KeyType::Secp256k1,
+    }
+}
+
+/// Raw secret key bytes, 32 bytes for both key types
+pub fn secret_bytes(key: &Keypair) -> Vec<u8> {
+    match key {
+        Keypair::Ed25519(k) => k.encode()[..32].to_vec(),
+        Keypair::Secp256k1(k) => k.secret().to_bytes().to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn from_secret_bytes(key_type: KeyType, bytes: &[u8]) -> Result<Keypair, Box<dyn Error>> {
+    let mut bytes = bytes.to_vec();
+    Ok(match key_type {
+        KeyType::Ed25519 => Keypair::Ed25519(identity::ed25519::SecretKey::from_bytes(&mut bytes)?.into()),
+        KeyType::Secp256k1 => Keypair::Secp256k1(identity::secp256k1::SecretKey::from_bytes(&mut bytes)?.into()),
+    })
+}
+
+/// Load the node key from the database. Databases written before key types
+/// were supported only contain the raw secp256k1 secret.
+pub fn load_local_key(secrets: &SecretStore) -> Result<Option<Keypair>, Box<dyn Error>> {
+    let secret = match secrets.get(&NodeStateKey::NodeLocalKey.to_string())? {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+    let key_type = match node_tree(secrets.db())?.get(NodeStateKey::NodeLocalKeyType.to_string())? {
+        Some(t) => String::from_utf8(t.to_vec())?.parse::<KeyType>()?,
+        None => KeyType::Secp256k1,
+    };
+    Ok(Some(from_secret_bytes(key_type, &secret)?))
+}
+
+pub fn store_local_key(secrets: &SecretStore, key: &Keypair) -> Result<(), Box<dyn Error>> {
+    secrets.insert(&NodeStateKey::NodeLocalKey.to_string(), &secret_bytes(key))?;
+    node_tree(secrets.db())?.insert(NodeStateKey::NodeLocalKeyType.to_string(), key_type(key).to_string().as_bytes())?;
+    secrets.flush()?;
+    Ok(())
+}
+
+/// Load the node key, generating and storing one of `key_type` if missing
+pub fn load_or_generate(secrets: &SecretStore, key_type: KeyType) -> Result<Keypair, Box<dyn Error>> {
+    if let Some(key) = load_local_key(secrets)? {
+        debug!("Found local key in database");
+        return Ok(key);
+    }
+    let key = generate(key_type);
+    store_local_key(secrets, &key)?;
+    Ok(key)
+}
+
+/// Announcement that a node replaced its key, signed by the old key so peers
+/// can trust the mapping from the old peer id to the new one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub old_peer_id: String,
+    pub new_peer_id: String,
+    // Hex encoded protobuf of the old public key
+    pub old_public_key: String,
+    pub timestamp: u64,
+    pub signature: String,
+}
+
+impl KeyRotation {
+    fn payload(old_peer_id: &str, new_peer_id: &str, timestamp: u64) -> Vec<u8> {
+        format!("hanode-key-rotation:{}:{}:{}", old_peer_id, new_peer_id, timestamp).into_bytes()
+    }
+
+    pub fn new(old_key: &Keypair, new_peer_id: &PeerId) -> Result<KeyRotation, Box<dyn Error>> {
+        let old_peer_id = PeerId::from(old_key.public()).to_base58();
+        let new_peer_id = new_peer_id.to_base58();
+        let timestamp = now_secs();
+        let signature = old_key.sign(&Self::payload(&old_peer_id, &new_peer_id, timestamp))?;
+        Ok(KeyRotation {
+            old_peer_id,
+            new_peer_id,
+            old_public_key: hex::encode(old_key.public().to_protobuf_encoding()),
+            timestamp,
+            signature: hex::encode(signature),
+        })
+    }
+
+    /// Check that the announcement was signed by the key behind `old_peer_id`
+    pub fn verify(&self) -> bool {
+        let public = match hex::decode(&self.old_public_key).ok().and_then(|b| PublicKey::from_protobuf_encoding(&b).ok()) {
+            Some(public) => public,
+            None => return false,
+        };
+        if PeerId::from(public.clone()).to_base58() != self.old_peer_id {
+            return false;
+        }
+        if self.new_peer_id.parse::<PeerId>().is_err() {
+            return false;
+        }
+        match hex::decode(&self.signature) {
+            Ok(sig) => public.verify(&Self::payload(&self.old_peer_id, &self.new_peer_id, self.timestamp), &sig),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Replace the node key with a new one of `key_type`, keeping a signed
+/// rotation record that the node announces to its peers on the next start.
+pub fn rotate(secrets: &SecretStore, key_type: KeyType) -> Result<KeyRotation, Box<dyn Error>> {
+    let old_key = match load_local_key(secrets)? {
+        Some(key) => key,
+        None => return Err("There is no node key to rotate".into()),
+    };
+    let new_key = generate(key_type);
+    let rotation = KeyRotation::new(&old_key, &PeerId::from(new_key.public()))?;
+    node_tree(secrets.db())?.insert(NodeStateKey::NodeKeyRotation.to_string(), serde_json::to_vec(&rotation)?)?;
+    store_local_key(secrets, &new_key)?;
+    Ok(rotation)
+}
+
+pub fn pending_rotation(db: &sled::Db) -> Option<KeyRotation> {
+    match Tree::Node.open(db).and_then(|t| t.get(NodeStateKey::NodeKeyRotation.to_string())) {
+        Ok(Some(v)) => serde_json::from_slice(&v).ok(),
+        _ => None,
+    }
+}
+
+/// Forget the rotation record once it has been announced long enough.
+pub fn clear_rotation(db: &sled::Db) -> Result<(), Box<dyn Error>> {
+    node_tree(db)?.remove(NodeStateKey::NodeKeyRotation.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::{error::Error, fs, path::Path};
+
+use chacha20poly1305::{aead::{Aead, NewAead, Payload}, ChaCha20Poly1305, Key, Nonce};
+use libp2p::{identity::Keypair, PeerId};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+
+use crate::keys::{self, KeyType};
+
+pub const KEYSTORE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeystoreCrypto {
+    pub kdf: String,
+    pub kdf_params: KdfParams,
+    pub salt: String,
+    pub cipher: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Password encrypted node key, the file written by `hanode key export`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u32,
+    pub key_type: KeyType,
+    pub peer_id: String,
+    pub crypto: KeystoreCrypto,
+}
+
+fn derive_key(password: &str, salt: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn Error>> {
+    let scrypt_params = scrypt::Params::new(params.log_n, params.r, params.p)
+        .map_err(|e| format!("Invalid scrypt parameters: {}", e))?;
+    let mut out = [0u8; 32];
+    scrypt::scrypt(password.as_bytes(), salt, &scrypt_params, &mut out)
+        .map_err(|e| format!("Failed to derive key: {}", e))?;
+    Ok(out)
+}
+
+impl Keystore {
+    pub fn encrypt(key: &Keypair, password: &str) -> Result<Keystore, Box<dyn Error>> {
+        let params = KdfParams { log_n: 15, r: 8, p: 1 };
+        let mut salt = [0u8; 32];
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let peer_id = PeerId::from(key.public()).to_base58();
+        let derived = derive_key(password, &salt, &params)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&derived));
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload {
+            msg: &keys::secret_bytes(key),
+            aad: peer_id.as_bytes(),
+        }).map_err(|_| "Failed to encrypt the key")?;
+        Ok(Keystore {
+            version: KEYSTORE_VERSION,
+            key_type: keys::key_type(key),
+            peer_id,
+            crypto: KeystoreCrypto {
+                kdf: "scrypt".to_string(),
+                kdf_params: params,
+                salt: hex::encode(salt),
+                cipher: "chacha20poly1305".to_string(),
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            },
+        })
+    }
+
+    pub fn decrypt(&self, password: &str) -> Result<Keypair, Box<dyn Error>> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(format!("Unsupported keystore version: {}", self.version).into());
+        }
+        if self.crypto.kdf != "scrypt" || self.crypto.cipher != "chacha20poly1305" {
+            return Err(format!("Unsupported keystore crypto: {}/{}", self.crypto.kdf, self.crypto.cipher).into());
+        }
+        let derived = derive_key(password, &hex::decode(&self.crypto.salt)?, &self.crypto.kdf_params)?;
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&derived));
+        let nonce = hex::decode(&self.crypto.nonce)?;
+        if nonce.len() != 12 {
+            return Err("Invalid keystore nonce".into());
+        }
+        let secret = cipher.decrypt(Nonce::from_slice(&nonce), Payload {
+            msg: &hex::decode(&self.crypto.ciphertext)?,
+            aad: self.peer_id.as_bytes(),
+        }).map_err(|_| "Wrong password or corrupted keystore")?;
+        let key = keys::from_secret_bytes(self.key_type, &secret)?;
+        if PeerId::from(key.public()).to_base58() != self.peer_id {
+            return Err("Keystore peer id does not match the decrypted key".into());
+        }
+        Ok(key)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Keystore, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
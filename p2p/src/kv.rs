@@ -0,0 +1,387 @@
+use std::{collections::HashMap, error::Error, io, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, identity::{Keypair, PublicKey}, request_response::RequestResponseCodec, PeerId};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{files::{read_frames, write_frames}, schema::Tree, utils::now_millis};
+
+/// Floodsub topic of the `KvGossip`s
+pub const KV_TOPIC: &str = "kv";
+pub const MAX_KEY_SIZE: usize = 256;
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+// Fits the versions of every key of a store of about 20k keys
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+// Records per pull response, the puller asks again for the rest
+const MAX_PULL_SIZE: usize = 2 * 1024 * 1024;
+// Floodsub drops packets over 2KiB, larger records are pulled by the peers
+const MAX_GOSSIP_SIZE: usize = 1536;
+// How far ahead of the local clock a record may be, a later one would drag
+// the clock of every node along
+pub const MAX_CLOCK_SKEW: u64 = 60 * 1000;
+
+#[derive(Debug, Clone)]
+pub struct KvProtocol;
+
+impl ProtocolName for KvProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/kv/1.0.0"
+    }
+}
+
+/// Last writer wins: a record replaces another when its version is greater,
+/// the node id breaks ties between writes of the same millisecond
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Version {
+    // Unix time in milliseconds, never behind a version the node has seen
+    pub time: u64,
+    pub node: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub key: String,
+    // None once deleted, the tombstone keeps older writes from bringing it back
+    pub value: Option<String>,
+    pub version: Version,
+    // Order in which this node applied the change, not replicated
+    #[serde(default)]
+    pub seq: u64,
+    // Hex encoded protobuf of the public key of `version.node`, and its
+    // signature of the key, value and version
+    #[serde(default)]
+    pub public_key: String,
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Record {
+    fn payload(&self) -> Vec<u8> {
+        serde_json::to_vec(&(&self.key, &self.value, &self.version)).unwrap_or_default()
+    }
+
+    /// Sign as the writer, `key` must be the one of `version.node`
+    pub fn sign(&mut self, key: &Keypair) -> Result<(), Box<dyn Error>> {
+        self.public_key = hex::encode(key.public().to_protobuf_encoding());
+        self.signature = hex::encode(key.sign(&self.payload())?);
+        Ok(())
+    }
+
+    /// Check that the node named in the version wrote the record
+    pub fn verify(&self) -> Result<(), String> {
+        let public = hex::decode(&self.public_key).ok()
+            .and_then(|b| PublicKey::from_protobuf_encoding(&b).ok())
+            .ok_or_else(|| format!("Record {} is not signed", self.key))?;
+        if PeerId::from(public.clone()).to_base58() != self.version.node {
+            return Err(format!("Record {} is signed by another node than {}", self.key, self.version.node));
+        }
+        match hex::decode(&self.signature) {
+            Ok(sig) if public.verify(&self.payload(), &sig) => Ok(()),
+            _ => Err(format!("Record {} has an invalid signature", self.key)),
+        }
+    }
+}
+
+/// Keys are paths such as `config/app/replicas`, kept to characters that
+/// need no escaping in the control API
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_SIZE
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || "._-/:".contains(c))
+}
+
+pub fn valid_prefix(prefix: &str) -> bool {
+    prefix.is_empty() || valid_key(prefix)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum KvRequest {
+    // Records newer than `known`, limited to `keys` when given
+    Pull { keys: Option<Vec<String>>, known: HashMap<String, Version> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum KvResponse {
+    // `more` when the response was cut short, pull again for the rest
+    Records { records: Vec<Record>, more: bool },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct KvCodec;
+
+#[async_trait]
+impl RequestResponseCodec for KvCodec {
+    type Protocol = KvProtocol;
+    type Request = KvRequest;
+    type Response = KvResponse;
+
+    async fn read_request<T>(&mut self, _: &KvProtocol, io: &mut T) -> io::Result<KvRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (req, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(req)
+    }
+
+    async fn read_response<T>(&mut self, _: &KvProtocol, io: &mut T) -> io::Result<KvResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (res, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(res)
+    }
+
+    async fn write_request<T>(&mut self, _: &KvProtocol, io: &mut T, req: KvRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &req, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &KvProtocol, io: &mut T, res: KvResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res, &[]).await
+    }
+}
+
+/// Published on `KV_TOPIC` after a local write
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvGossip {
+    #[serde(default)]
+    pub records: Vec<Record>,
+    // Keys whose record was too large to gossip, pulled from the source
+    #[serde(default)]
+    pub changed: Vec<String>,
+}
+
+impl KvGossip {
+    pub fn encode(record: &Record) -> Vec<u8> {
+        let gossip = KvGossip { records: vec![record.clone()], changed: Vec::new() };
+        match serde_json::to_vec(&gossip) {
+            Ok(data) if data.len() <= MAX_GOSSIP_SIZE => data,
+            _ => serde_json::to_vec(&KvGossip { records: Vec::new(), changed: vec![record.key.clone()] }).unwrap_or_default(),
+        }
+    }
+}
+
+/// Records under a prefix, or the changes after a cursor, with the cursor to
+/// pass on the next call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KvList {
+    pub records: Vec<Record>,
+    pub seq: u64,
+}
+
+/// The cluster namespace, a map of last-writer-wins registers kept in the
+/// `kv` tree. Every node holds the whole map, local writes are signed and
+/// gossiped and peers pull what they missed when they connect.
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    db: sled::Db,
+    tree: sled::Tree,
+    // Signs local writes, its peer id is stamped on them
+    key: Keypair,
+    node: String,
+    // Greatest time seen in a version
+    clock: Arc<AtomicU64>,
+    // Held while a record is compared and replaced, so changes get their
+    // `seq` in the order they land
+    writes: Arc<Mutex<()>>,
+}
+
+impl KvStore {
+    pub fn open(db: &sled::Db, key: &Keypair) -> Result<KvStore, Box<dyn Error>> {
+        let store = KvStore {
+            db: db.clone(),
+            tree: Tree::Kv.open(db)?,
+            key: key.clone(),
+            node: PeerId::from(key.public()).to_base58(),
+            clock: Arc::new(AtomicU64::new(0)),
+            writes: Arc::new(Mutex::new(())),
+        };
+        store.sign_unsigned()?;
+        Ok(store)
+    }
+
+    // Records written before they were signed: the ones of this node get
+    // signed, the others are dropped and pulled again from their writer
+    fn sign_unsigned(&self) -> Result<(), Box<dyn Error>> {
+        let (mut signed, mut dropped) = (0, 0);
+        for item in self.tree.iter() {
+            let (k, v) = item?;
+            let mut record: Record = serde_json::from_slice(&v)?;
+            if !record.signature.is_empty() {
+                continue;
+            }
+            if record.version.node == self.node {
+                record.sign(&self.key)?;
+                self.tree.insert(k, serde_json::to_vec(&record)?)?;
+                signed += 1;
+            } else {
+                self.tree.remove(k)?;
+                dropped += 1;
+            }
+        }
+        if signed + dropped > 0 {
+            info!("Signed {} kv records of this node, dropped {} unsigned ones of peers", signed, dropped);
+        }
+        Ok(())
+    }
+
+    fn read(&self, key: &[u8]) -> Result<Option<Record>, Box<dyn Error>> {
+        match self.tree.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&self, mut record: Record) -> Result<Record, Box<dyn Error>> {
+        record.seq = self.db.generate_id()?;
+        self.tree.insert(record.key.as_bytes(), serde_json::to_vec(&record)?)?;
+        self.clock.fetch_max(record.version.time, Ordering::Relaxed);
+        Ok(record)
+    }
+
+    /// Write a key on this node, None deletes it. The version is greater than
+    /// the one of the current record whatever the clock says.
+    pub fn write(&self, key: &str, value: Option<String>) -> Result<Record, Box<dyn Error>> {
+        if !valid_key(key) {
+            return Err(format!("Invalid key {}, use at most {} letters, digits and ._-/:", key, MAX_KEY_SIZE).into());
+        }
+        if value.as_ref().map(|v| v.len() > MAX_VALUE_SIZE).unwrap_or(false) {
+            return Err(format!("The value of {} is larger than {} bytes", key, MAX_VALUE_SIZE).into());
+        }
+        let _guard = self.writes.lock().unwrap();
+        let current = self.read(key.as_bytes())?.map(|r| r.version.time).unwrap_or(0);
+        let time = now_millis().max(self.clock.load(Ordering::Relaxed) + 1).max(current + 1);
+        let mut record = Record {
+            key: key.to_string(),
+            value,
+            version: Version { time, node: self.node.clone() },
+            seq: 0,
+            public_key: String::new(),
+            signature: String::new(),
+        };
+        record.sign(&self.key)?;
+        self.insert(record)
+    }
+
+    /// Apply a record from a peer, true when it replaced the local one. The
+    /// record must be signed by the node of its version and not be from the
+    /// future.
+    pub fn merge(&self, record: Record) -> Result<bool, Box<dyn Error>> {
+        if !valid_key(&record.key) || record.value.as_ref().map(|v| v.len() > MAX_VALUE_SIZE).unwrap_or(false) {
+            return Err(format!("Invalid record {}", record.key).into());
+        }
+        record.verify()?;
+        if record.version.time > now_millis() + MAX_CLOCK_SKEW {
+            return Err(format!("Record {} is {}ms ahead of the local clock", record.key, record.version.time - now_millis()).into());
+        }
+        let _guard = self.writes.lock().unwrap();
+        match self.read(record.key.as_bytes())? {
+            Some(current) if current.version >= record.version => Ok(false),
+            _ => self.insert(record).map(|_| true),
+        }
+    }
+
+    /// The record of a key, tombstones included
+    pub fn get(&self, key: &str) -> Result<Option<Record>, Box<dyn Error>> {
+        self.read(key.as_bytes())
+    }
+
+    /// Live records under a prefix by key, or with `since` every change after
+    /// that cursor by `seq`, deletes included
+    pub fn list(&self, prefix: &str, since: Option<u64>) -> Result<KvList, Box<dyn Error>> {
+        let mut records = Vec::new();
+        for item in self.tree.scan_prefix(prefix) {
+            let (_, v) = item?;
+            let record: Record = serde_json::from_slice(&v)?;
+            let shown = match since {
+                Some(since) => record.seq > since,
+                None => record.value.is_some(),
+            };
+            if shown {
+                records.push(record);
+            }
+        }
+        let seq = match since {
+            Some(since) => {
+                records.sort_by_key(|r| r.seq);
+                records.last().map(|r| r.seq).unwrap_or(since)
+            },
+            // Any later change gets a greater id
+            None => {
+                let _guard = self.writes.lock().unwrap();
+                self.db.generate_id()?
+            },
+        };
+        Ok(KvList { records, seq })
+    }
+
+    /// Versions of the local records, sent to a peer to pull what it has newer
+    pub fn versions(&self, keys: Option<&[String]>) -> Result<HashMap<String, Version>, Box<dyn Error>> {
+        let mut versions = HashMap::new();
+        match keys {
+            Some(keys) => for key in keys {
+                if let Some(record) = self.read(key.as_bytes())? {
+                    versions.insert(record.key, record.version);
+                }
+            },
+            None => for item in self.tree.iter() {
+                let (_, v) = item?;
+                let record: Record = serde_json::from_slice(&v)?;
+                versions.insert(record.key, record.version);
+            },
+        }
+        Ok(versions)
+    }
+
+    fn pull(&self, keys: Option<Vec<String>>, known: HashMap<String, Version>) -> Result<KvResponse, Box<dyn Error>> {
+        let newer = |record: &Record| known.get(&record.key).map(|v| record.version > *v).unwrap_or(true);
+        let mut records = Vec::new();
+        let mut size = 0;
+        let mut add = |record: Record| {
+            if size >= MAX_PULL_SIZE {
+                return false;
+            }
+            size += record.key.len() + record.value.as_ref().map(|v| v.len()).unwrap_or(0) + record.version.node.len()
+                + record.public_key.len() + record.signature.len() + 64;
+            records.push(record);
+            true
+        };
+        let mut more = false;
+        match keys {
+            Some(keys) => for key in keys {
+                if let Some(record) = self.read(key.as_bytes())?.filter(|r| newer(r)) {
+                    if !add(record) {
+                        more = true;
+                        break;
+                    }
+                }
+            },
+            None => for item in self.tree.iter() {
+                let (_, v) = item?;
+                let record: Record = serde_json::from_slice(&v)?;
+                if newer(&record) && !add(record) {
+                    more = true;
+                    break;
+                }
+            },
+        }
+        Ok(KvResponse::Records { records, more })
+    }
+
+    /// Answer a request of a peer
+    pub fn serve(&self, req: KvRequest) -> KvResponse {
+        let r = match req {
+            KvRequest::Pull { keys, known } => self.pull(keys, known),
+        };
+        r.unwrap_or_else(|e| KvResponse::Error { message: e.to_string() })
+    }
+}
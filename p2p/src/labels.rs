@@ -0,0 +1,201 @@
+use std::{collections::BTreeMap, error::Error, fmt, str::FromStr};
+use serde::{Deserialize, Serialize};
+
+use crate::schema::Tree;
+
+/// Floodsub topic of the `LabelAnnouncement`s
+pub const LABELS_TOPIC: &str = "labels";
+const MAX_LABEL_SIZE: usize = 63;
+
+pub type Labels = BTreeMap<String, String>;
+
+/// Keys as in `env` or `example.com/team`
+pub fn valid_label_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_LABEL_SIZE
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || "._-/".contains(c))
+}
+
+pub fn valid_label_value(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_LABEL_SIZE
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+pub fn check_labels(labels: &Labels) -> Result<(), String> {
+    for (key, value) in labels {
+        if !valid_label_key(key) || !valid_label_value(value) {
+            return Err(format!("Invalid label {}={}, use at most {} letters, digits and ._-", key, value, MAX_LABEL_SIZE));
+        }
+    }
+    Ok(())
+}
+
+/// Labels from `env=prod,role=db`
+pub fn parse_labels(s: &str) -> Result<Labels, String> {
+    let mut labels = Labels::new();
+    for pair in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some((key, value)) => {
+                labels.insert(key.trim().to_string(), value.trim().to_string());
+            },
+            None => return Err(format!("Invalid label {}, expected key=value", pair)),
+        }
+    }
+    check_labels(&labels)?;
+    Ok(labels)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl Requirement {
+    fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            // Also true without the label, as in Kubernetes
+            Requirement::NotEquals(key, value) => labels.get(key) != Some(value),
+            Requirement::Exists(key) => labels.contains_key(key),
+            Requirement::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Requirement::Equals(key, value) => write!(f, "{}={}", key, value),
+            Requirement::NotEquals(key, value) => write!(f, "{}!={}", key, value),
+            Requirement::Exists(key) => write!(f, "{}", key),
+            Requirement::NotExists(key) => write!(f, "!{}", key),
+        }
+    }
+}
+
+/// Label selector such as `env=prod,role!=db,gpu,!canary`: every
+/// requirement must hold, the empty selector matches every peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector {
+    pub requirements: Vec<Requirement>,
+}
+
+impl Selector {
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.requirements.iter().all(|r| r.matches(labels))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+
+    /// Peers matching both selectors
+    pub fn and(mut self, other: Selector) -> Selector {
+        self.requirements.extend(other.requirements);
+        self
+    }
+}
+
+impl FromStr for Selector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut requirements = Vec::new();
+        for term in s.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let requirement = if let Some((key, value)) = term.split_once("!=") {
+                Requirement::NotEquals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some((key, value)) = term.split_once('=') {
+                Requirement::Equals(key.trim().to_string(), value.trim().to_string())
+            } else if let Some(key) = term.strip_prefix('!') {
+                Requirement::NotExists(key.trim().to_string())
+            } else {
+                Requirement::Exists(term.to_string())
+            };
+            let valid = match &requirement {
+                Requirement::Equals(key, value) | Requirement::NotEquals(key, value) => valid_label_key(key) && valid_label_value(value),
+                Requirement::Exists(key) | Requirement::NotExists(key) => valid_label_key(key),
+            };
+            if !valid {
+                return Err(format!("Invalid selector term {}", term));
+            }
+            requirements.push(requirement);
+        }
+        Ok(Selector { requirements })
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms: Vec<String> = self.requirements.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+/// Published on `LABELS_TOPIC`, the labels a node declares in its config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelAnnouncement {
+    pub labels: Labels,
+}
+
+/// A named selector, to target the same peers again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub selector: String,
+}
+
+/// Groups of this node, kept in the `groups` tree
+#[derive(Debug, Clone)]
+pub struct Groups {
+    tree: sled::Tree,
+}
+
+impl Groups {
+    pub fn open(db: &sled::Db) -> sled::Result<Groups> {
+        Ok(Groups { tree: Tree::Groups.open(db)? })
+    }
+
+    pub fn set(&self, name: &str, selector: &Selector) -> Result<Group, Box<dyn Error>> {
+        if !valid_label_value(name) {
+            return Err(format!("Invalid group name {}", name).into());
+        }
+        let group = Group { name: name.to_string(), selector: selector.to_string() };
+        self.tree.insert(name.as_bytes(), serde_json::to_vec(&group)?)?;
+        Ok(group)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Group>, Box<dyn Error>> {
+        match self.tree.get(name.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// True when the group existed
+    pub fn remove(&self, name: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.tree.remove(name.as_bytes())?.is_some())
+    }
+
+    pub fn list(&self) -> Result<Vec<Group>, Box<dyn Error>> {
+        let mut groups = Vec::new();
+        for item in self.tree.iter() {
+            let (_, v) = item?;
+            groups.push(serde_json::from_slice(&v)?);
+        }
+        Ok(groups)
+    }
+
+    /// The selector of `-l` and the one of the group `-g`, both when given
+    pub fn resolve(&self, selector: Option<&str>, group: Option<&str>) -> Result<Selector, Box<dyn Error>> {
+        let mut resolved: Selector = selector.unwrap_or("").parse()?;
+        if let Some(name) = group {
+            let group = self.get(name)?.ok_or_else(|| format!("No group {}", name))?;
+            resolved = resolved.and(group.selector.parse()?);
+        }
+        Ok(resolved)
+    }
+}
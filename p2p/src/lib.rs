@@ -1,5 +1,28 @@
 pub mod node;
 pub mod message;
+pub mod handle;
+pub mod files;
+pub mod transfer;
+pub mod artifact;
+pub mod kv;
+pub mod raft;
+pub mod lock;
+pub mod swim;
+pub mod labels;
+pub mod operators;
+pub mod service;
+pub mod container;
+pub mod exec;
+pub mod deploy;
+pub mod rollout;
+pub mod health;
+pub mod cron;
+pub mod metrics;
 pub mod lifecycle;
 pub mod peer;
-pub mod state;
+pub mod keys;
+pub mod keystore;
+pub mod secrets;
+pub mod schema;
+pub mod backup;
+pub mod utils;
@@ -1,53 +1,34 @@
-use std::{collections::{HashSet}, process, sync::{Arc, RwLock}};
-
 use libp2p::{Multiaddr, PeerId};
-use log::debug;
-
-use crate::{peer::{Peer, PeerStatus}, state::NodeState};
+use tracing::debug;
 
 
 pub trait NodeLifecycleHooks {
     // on_peer_connection
     fn on_peer_connection(&mut self, peer_id: PeerId, addr: Multiaddr);
+    // A peer replaced its key and is now known by `new_id`
+    fn on_peer_key_rotated(&mut self, old_id: PeerId, new_id: PeerId);
     // Trigger this function when the node is destroyed.
     fn on_stopped(&self);
 }
 
+/// Default hooks, live state is queried through `handle::NodeHandle`
 #[derive(Debug, Clone)]
-pub struct NodeLifecycle {
-    state: Arc<RwLock<NodeState>>
-}
+pub struct NodeLifecycle {}
 
 impl NodeLifecycle {
-    pub fn new(state: Arc<RwLock<NodeState>>) -> Box<dyn NodeLifecycleHooks + Send + Sync> {
-        Box::new(NodeLifecycle {
-            state: state.clone(),
-        })
+    pub fn new() -> Box<dyn NodeLifecycleHooks + Send + Sync> {
+        Box::new(NodeLifecycle {})
     }
 }
 
 impl NodeLifecycleHooks for NodeLifecycle {
     fn on_stopped(&self) {
         debug!("NodeLifecycleHooks on_stopped()");
-        // Shutdown when the node is stopped
-        process::exit(0);
+    }
+    fn on_peer_key_rotated(&mut self, old_id: PeerId, new_id: PeerId) {
+        debug!("NodeLifecycleHooks on_peer_key_rotated({:?}, {:?})", old_id, new_id);
     }
     fn on_peer_connection(&mut self, peer_id: PeerId, addr: Multiaddr) {
-        debug!("NodeLifecycleHooks on_peer_connection({:?})", peer_id);
-        let peer_id_ = peer_id.to_base58();
-        if !(*self.state.read().unwrap()).peers.contains_key(&peer_id_) {
-            (*self.state.write().unwrap()).peers.insert(peer_id_.clone(), Peer {
-                id: peer_id_.clone(),
-                hostname: "".to_string(),
-                host_mac: "".to_string(),
-                addrs: HashSet::new(),
-                status: PeerStatus::Connected,
-            });
-        }
-        let mut p = (*self.state.read().unwrap()).peers.get(&peer_id_).unwrap().clone();
-        let mut addrs = p.addrs.clone();
-        addrs.insert(addr.clone());
-        p.addrs = addrs;
-        (*self.state.write().unwrap()).peers.insert(peer_id_, p);
+        debug!("NodeLifecycleHooks on_peer_connection({:?}, {:?})", peer_id, addr);
     }
 }
@@ -0,0 +1,106 @@
+use std::{io, time::Duration};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+
+use crate::files::{read_frames, write_frames};
+
+const MAX_FRAME: usize = 1024 * 1024;
+pub const MIN_TTL: Duration = Duration::from_secs(1);
+pub const MAX_TTL: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Clone)]
+pub struct LockProtocol;
+
+impl ProtocolName for LockProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/lock/1.0.0"
+    }
+}
+
+/// A lease on a name, held by a node until it expires or is released
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lock {
+    pub name: String,
+    // Peer id of the node holding it
+    pub holder: String,
+    // Raft index of the acquisition, greater for every new holder. Renewals
+    // keep it.
+    pub token: u64,
+    // Unix time in milliseconds, by the clock of the raft leaders
+    pub expires: u64,
+}
+
+/// Lock operations, served by the raft leader. The holder is the node that
+/// sent the request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum LockRequest {
+    // Also renews a lock the node holds
+    Acquire { name: String, ttl: u64 },
+    // Any token of the node when None
+    Release { name: String, token: Option<u64> },
+    // Locks that have not expired, answered by any voter
+    List,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum LockResponse {
+    Granted { lock: Lock },
+    // The lock of the node holding it
+    Held { lock: Lock },
+    Released,
+    Locks { locks: Vec<Lock> },
+    // Send the request to the leader instead
+    NotLeader { leader: Option<String> },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct LockCodec;
+
+#[async_trait]
+impl RequestResponseCodec for LockCodec {
+    type Protocol = LockProtocol;
+    type Request = LockRequest;
+    type Response = LockResponse;
+
+    async fn read_request<T>(&mut self, _: &LockProtocol, io: &mut T) -> io::Result<LockRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (req, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(req)
+    }
+
+    async fn read_response<T>(&mut self, _: &LockProtocol, io: &mut T) -> io::Result<LockResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (res, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(res)
+    }
+
+    async fn write_request<T>(&mut self, _: &LockProtocol, io: &mut T, req: LockRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &req, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &LockProtocol, io: &mut T, res: LockResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res, &[]).await
+    }
+}
+
+pub fn check_ttl(ttl: Duration) -> Result<(), String> {
+    if ttl < MIN_TTL || ttl > MAX_TTL {
+        return Err(format!("The ttl must be between {}s and {}s", MIN_TTL.as_secs(), MAX_TTL.as_secs()));
+    }
+    Ok(())
+}
@@ -1,5 +1,8 @@
 use std::fmt::{Display, self};
 
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+
 #[derive(Debug,Clone)]
 pub enum MessageType {
     Text,
@@ -15,24 +18,77 @@ impl Display for MessageType {
     }
 }
 
+/// Random 16 bytes id, hex encoded like the trace ids of OpenTelemetry
+pub fn new_trace_id() -> String {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    hex::encode(id)
+}
+
 #[derive(Debug,Clone)]
 pub struct Message {
     pub type_: MessageType,
-    pub message: String
+    pub message: String,
+    // Follows the message across nodes in logs and traces
+    pub trace_id: String,
+    // Peer ids the message is meant for, every peer when empty
+    pub targets: Vec<String>,
 }
 
 impl Message {
     pub fn from(s: String) -> Message {
         Message {
             type_: MessageType::Text,
-            message: s
+            message: s,
+            trace_id: new_trace_id(),
+            targets: Vec::new(),
         }
     }
 
     pub fn stop_message() -> Message {
         Message {
             type_: MessageType::Stop,
-            message: String::new()
+            message: String::new(),
+            trace_id: new_trace_id(),
+            targets: Vec::new(),
+        }
+    }
+}
+
+/// Wire format of the messages published to peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub trace_id: String,
+    pub message: String,
+    // Floodsub reaches every peer, the others drop the message
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+}
+
+impl Envelope {
+    pub fn is_for(&self, peer_id: &str) -> bool {
+        self.targets.is_empty() || self.targets.iter().any(|t| t == peer_id)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+
+    /// Nodes of older versions publish the bare text, which gets a new trace id
+    pub fn decode(data: &[u8]) -> Envelope {
+        match serde_json::from_slice::<Envelope>(data) {
+            Ok(envelope) => envelope,
+            Err(_) => Envelope {
+                trace_id: new_trace_id(),
+                message: String::from_utf8_lossy(data).to_string(),
+                targets: Vec::new(),
+            },
         }
     }
 }
+
+impl From<&Message> for Envelope {
+    fn from(msg: &Message) -> Self {
+        Envelope { trace_id: msg.trace_id.clone(), message: msg.message.clone(), targets: msg.targets.clone() }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{fmt::Write, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+
+/// Counters of the message queue of a node
+#[derive(Debug, Default)]
+pub struct QueueMetrics {
+    high_water: AtomicUsize,
+    queued: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl QueueMetrics {
+    /// A message was queued, leaving `depth` messages in the queue
+    pub fn queued(&self, depth: usize) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.high_water.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Prometheus text exposition of the counters and the current depth
+    pub fn render(&self, depth: usize, capacity: usize) -> String {
+        let mut out = String::new();
+        let metrics: [(&str, &str, &str, u64); 6] = [
+            ("hanode_message_queue_depth", "gauge", "Messages waiting for the swarm task", depth as u64),
+            ("hanode_message_queue_capacity", "gauge", "Size of the message queue", capacity as u64),
+            ("hanode_message_queue_high_water", "gauge", "Deepest the message queue has been", self.high_water.load(Ordering::Relaxed) as u64),
+            ("hanode_messages_queued_total", "counter", "Messages queued for publishing", self.queued.load(Ordering::Relaxed)),
+            ("hanode_messages_dropped_total", "counter", "Messages dropped because the queue was full", self.dropped.load(Ordering::Relaxed)),
+            ("hanode_messages_rejected_total", "counter", "Messages refused because the queue was full", self.rejected.load(Ordering::Relaxed)),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
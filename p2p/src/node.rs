@@ -1,25 +1,23 @@
 use async_trait::async_trait;
-use async_std::{task};
-use log::{warn, info, error, debug};
-use futures::{
-    prelude::{stream::StreamExt},
-    select,
-};
+use tracing::{warn, info, error, debug, info_span};
+use futures::prelude::stream::StreamExt;
 use libp2p::{
     core,
     floodsub::{self, Floodsub, FloodsubEvent},
-    mdns::{Mdns, MdnsConfig, MdnsEvent},
+    mdns::{MdnsConfig, MdnsEvent, TokioMdns},
     ping::{Ping, PingConfig, self},
-    swarm::SwarmEvent,
-    identity,
+    request_response::{ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent, RequestResponseMessage, ResponseChannel},
+    swarm::{SwarmBuilder, SwarmEvent},
     NetworkBehaviour, Swarm, PeerId, Multiaddr,
 };
-use std::{error::Error, time::Duration, collections::HashSet};
-use crate::{message::{Message, MessageType}, lifecycle::NodeLifecycleHooks, peer::{Peer, PeerStatus}};
-use futures::channel::mpsc;
+use std::{error::Error, fmt::Debug, time::{Duration, Instant}, collections::{BTreeMap, HashMap, HashSet}, iter, sync::Arc};
+use crate::{container::{self, ContainerCodec, ContainerProtocol, ContainerRequest, ContainerResponse, ContainerRuntime, CliRuntime}, deploy::{self, Candidates, DeployStatus, Deployment, Reconciler}, exec::{self, ExecCodec, ExecProtocol, ExecRequest, ExecResponse}, rollout::{self, RolloutState}, cron::{self, Job, Run, Runner, Schedule, Target}, health::{self, Alert, Alerter, CheckResult, HealthConfig, HealthReport, Outcome, HEALTH_TOPIC}, artifact::{Announcement, ArtifactCodec, ArtifactOp, ArtifactProtocol, ArtifactResponse, ArtifactStore, Availability, Provider, ANNOUNCEMENT_BATCH, ARTIFACT_TOPIC}, files::{FileCodec, FileProtocol, FileRequest, FileResponse, FileStore}, handle::{Command, NodeInfo, NodeQueues}, kv::{self, KvCodec, KvGossip, KvProtocol, KvRequest, KvResponse, KvStore, Record, KV_TOPIC}, labels::{self, LabelAnnouncement, Labels, Selector, LABELS_TOPIC}, operators::Operators, lock::{self, Lock, LockCodec, LockProtocol, LockRequest, LockResponse}, service::{ServiceCodec, ServiceProtocol, ServiceRequest, ServiceResponse, ServiceSpec, Supervisor}, swim::{MemberState, Membership, SwimAck, SwimCodec, SwimConfig, SwimMessage, SwimProtocol}, message::{Envelope, Message, MessageType}, lifecycle::NodeLifecycleHooks, peer::{Peer, PeerStatus}, raft::{Raft, RaftAck, RaftCodec, RaftCommand, RaftConfig, RaftMessage, RaftProtocol, RaftResult, Role}, keys::{self, KeyType, KeyRotation}, secrets::SecretStore, schema::Tree, utils::{now_millis, now_secs}};
+use rand::seq::IteratorRandom;
+use tokio::sync::{mpsc, oneshot};
 
-pub type Sender<T> = mpsc::UnboundedSender<T>;
-pub type Receiver<T> = mpsc::UnboundedReceiver<T>;
+// Bounded, see `handle::QueueOptions`
+pub type Sender<T> = mpsc::Sender<T>;
+pub type Receiver<T> = mpsc::Receiver<T>;
 
 /// Node
 /// Test node: libp2p-lookup direct --address /ip4/127.0.0.1/tcp/32000
@@ -27,18 +25,156 @@ pub struct Node {
     pub key: core::identity::Keypair,
     pub peer_id: PeerId,
     db: sled::Db,
+    peers: sled::Tree,
     port: Option<u16>,
     bootnode: Option<String>,
     swarm: Swarm<MyBehaviour>,
     floodsub_topic: floodsub::Topic,
-    message_receiver:Box<Receiver<Message>>,
+    rotation_topic: floodsub::Topic,
+    artifact_topic: floodsub::Topic,
+    messages: Receiver<Message>,
+    control: Receiver<Command>,
     hooks: Box<dyn NodeLifecycleHooks + Send + Sync>,
+    file_store: FileStore,
+    file_exchanges: Exchanges<FileResponse>,
+    artifact_store: ArtifactStore,
+    artifact_exchanges: Exchanges<ArtifactResponse>,
+    // Peers that announced an artifact, and whether their copy is complete
+    artifact_providers: HashMap<String, HashMap<PeerId, bool>>,
+    kv_topic: floodsub::Topic,
+    kv_store: KvStore,
+    kv_exchanges: Exchanges<KvResponse>,
+    // Pulls to send again, the peer had more records than fit a response
+    kv_pulls: (Sender<(PeerId, Option<Vec<String>>)>, Receiver<(PeerId, Option<Vec<String>>)>),
+    // Only on the voters listed in `NodeBehaviourOptions::raft_voters`
+    raft: Option<Raft>,
+    raft_replies: HashMap<u64, oneshot::Sender<RaftResult>>,
+    raft_voters: Vec<PeerId>,
+    lock_exchanges: Exchanges<LockResponse>,
+    // Requests to send again to the leader a voter pointed to
+    lock_redirects: (Sender<(PeerId, LockRequest, oneshot::Sender<LockResponse>)>, Receiver<(PeerId, LockRequest, oneshot::Sender<LockResponse>)>),
+    // Locks this node renews until released, with their ttl and next renewal
+    kept_locks: HashMap<String, (Duration, Instant)>,
+    membership: Membership,
+    labels_topic: floodsub::Topic,
+    labels: Labels,
+    operators: Operators,
+    supervisor: Supervisor,
+    service_exchanges: Exchanges<ServiceResponse>,
+    containers: Arc<dyn ContainerRuntime>,
+    container_exchanges: Exchanges<ContainerResponse>,
+    exec_exchanges: Exchanges<ExecResponse>,
+    reconciler: Reconciler,
+    // Statuses of the last pass of the reconciler, one pass at a time
+    deploy_reports: (Sender<Vec<(String, Option<DeployStatus>)>>, Receiver<Vec<(String, Option<DeployStatus>)>>),
+    reconciling: bool,
+    cron_runner: Runner,
+    cron_runs: (Sender<Run>, Receiver<Run>),
+    // Start of the last minute the jobs were checked in
+    cron_minute: u64,
+    health_topic: floodsub::Topic,
+    health: HealthConfig,
+    health_outcomes: (Sender<(String, Outcome)>, Receiver<(String, Outcome)>),
+    // Alerts sent, and whether they were delivered
+    alert_deliveries: (Sender<(Alert, bool)>, Receiver<(Alert, bool)>),
+    // Last result of each check of this node
+    health_results: BTreeMap<String, CheckResult>,
+    // Last report of each peer, with when it was received
+    health_reports: HashMap<String, (Instant, HealthReport)>,
+    alerter: Alerter,
+}
+
+// Requests sent to peers over a request-response protocol, and the answers to
+// their requests which are prepared off the swarm task
+struct Exchanges<Res> {
+    replies: HashMap<RequestId, oneshot::Sender<Result<Res, String>>>,
+    responses: (Sender<(ResponseChannel<Res>, Res)>, Receiver<(ResponseChannel<Res>, Res)>),
+}
+
+impl<Res: Send + 'static> Exchanges<Res> {
+    fn new() -> Exchanges<Res> {
+        Exchanges { replies: HashMap::new(), responses: mpsc::channel(16) }
+    }
+
+    fn handle<Req: Debug + Send + 'static>(&mut self, protocol: &str, event: RequestResponseEvent<Req, Res>, serve: impl FnOnce(Req) -> Res + Send + 'static) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                debug!("{} request from {}: {:?}", protocol, peer, request);
+                // Hashing and disk access would hold up the swarm
+                let responses = self.responses.0.clone();
+                tokio::task::spawn_blocking(move || {
+                    let _ = responses.blocking_send((channel, serve(request)));
+                });
+            },
+            RequestResponseEvent::Message { message: RequestResponseMessage::Response { request_id, response }, .. } => {
+                if let Some(reply) = self.replies.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                debug!("{} request to {} failed: {:?}", protocol, peer, error);
+                if let Some(reply) = self.replies.remove(&request_id) {
+                    let _ = reply.send(Err(format!("Request to {} failed: {:?}", peer, error)));
+                }
+            },
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("{} request from {} failed: {:?}", protocol, peer, error);
+            },
+            RequestResponseEvent::ResponseSent { .. } => {},
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct NodeBehaviourOptions {
     pub port: Option<u16>,
-    pub bootnode: Option<String>
+    pub bootnode: Option<String>,
+    // Type of the key generated when the database has none
+    pub key_type: KeyType,
+    // Directory peers read from and write to with the file protocol
+    pub files_dir: String,
+    // Largest file a peer may write there
+    pub max_file_size: u64,
+    // Store of the artifacts served to peers
+    pub artifacts_dir: String,
+    // Peer ids of the raft voters, this node included, empty to disable raft
+    pub raft_voters: Vec<String>,
+    // Announced to the peers, matched by their selectors
+    pub labels: Labels,
+    // Processes the node runs, with their output under `services_dir`
+    pub services: Vec<ServiceSpec>,
+    pub services_dir: String,
+    // runc or youki, with the bundles and output under `containers_dir`
+    pub container_runtime: String,
+    pub containers_dir: String,
+    // Checks the node runs and the alert rules over their results
+    pub health: HealthConfig,
+    // Peers besides this node whose deployments, jobs, containers, commands,
+    // services and files are accepted
+    pub operators: Vec<String>,
+}
+
+// Name of the event in spans
+fn event_kind<E>(event: &SwarmEvent<OutEvent, E>) -> &'static str {
+    match event {
+        SwarmEvent::Behaviour(OutEvent::Floodsub(_)) => "floodsub",
+        SwarmEvent::Behaviour(OutEvent::Mdns(_)) => "mdns",
+        SwarmEvent::Behaviour(OutEvent::Ping(_)) => "ping",
+        SwarmEvent::Behaviour(OutEvent::Files(_)) => "files",
+        SwarmEvent::Behaviour(OutEvent::Artifacts(_)) => "artifacts",
+        SwarmEvent::Behaviour(OutEvent::Kv(_)) => "kv",
+        SwarmEvent::Behaviour(OutEvent::Raft(_)) => "raft",
+        SwarmEvent::Behaviour(OutEvent::Locks(_)) => "locks",
+        SwarmEvent::Behaviour(OutEvent::Swim(_)) => "swim",
+        SwarmEvent::Behaviour(OutEvent::Services(_)) => "services",
+        SwarmEvent::Behaviour(OutEvent::Containers(_)) => "containers",
+        SwarmEvent::Behaviour(OutEvent::Exec(_)) => "exec",
+        SwarmEvent::ConnectionEstablished { .. } => "connection_established",
+        SwarmEvent::ConnectionClosed { .. } => "connection_closed",
+        SwarmEvent::NewListenAddr { .. } => "new_listen_addr",
+        SwarmEvent::Dialing(_) => "dialing",
+        _ => "other",
+    }
 }
 
 // NodeBehaviour
@@ -54,8 +190,17 @@ pub trait NodeBehaviour {
 #[behaviour(out_event = "OutEvent")]
 struct MyBehaviour {
     floodsub: Floodsub,
-    mdns: Mdns,
+    mdns: TokioMdns,
     ping: ping::Behaviour,
+    files: RequestResponse<FileCodec>,
+    artifacts: RequestResponse<ArtifactCodec>,
+    kv: RequestResponse<KvCodec>,
+    raft: RequestResponse<RaftCodec>,
+    locks: RequestResponse<LockCodec>,
+    swim: RequestResponse<SwimCodec>,
+    services: RequestResponse<ServiceCodec>,
+    containers: RequestResponse<ContainerCodec>,
+    exec: RequestResponse<ExecCodec>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -64,6 +209,15 @@ enum OutEvent {
     Floodsub(FloodsubEvent),
     Mdns(MdnsEvent),
     Ping(ping::Event),
+    Files(RequestResponseEvent<FileRequest, FileResponse>),
+    Artifacts(RequestResponseEvent<ArtifactOp, ArtifactResponse>),
+    Kv(RequestResponseEvent<KvRequest, KvResponse>),
+    Raft(RequestResponseEvent<RaftMessage, RaftAck>),
+    Locks(RequestResponseEvent<LockRequest, LockResponse>),
+    Swim(RequestResponseEvent<SwimMessage, SwimAck>),
+    Services(RequestResponseEvent<ServiceRequest, ServiceResponse>),
+    Containers(RequestResponseEvent<ContainerRequest, ContainerResponse>),
+    Exec(RequestResponseEvent<ExecRequest, ExecResponse>),
 }
 
 impl From<MdnsEvent> for OutEvent {
@@ -84,28 +238,100 @@ impl From<ping::Event> for OutEvent {
     }
 }
 
+impl From<RequestResponseEvent<FileRequest, FileResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<FileRequest, FileResponse>) -> Self {
+        Self::Files(v)
+    }
+}
+
+impl From<RequestResponseEvent<ArtifactOp, ArtifactResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<ArtifactOp, ArtifactResponse>) -> Self {
+        Self::Artifacts(v)
+    }
+}
+
+impl From<RequestResponseEvent<KvRequest, KvResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<KvRequest, KvResponse>) -> Self {
+        Self::Kv(v)
+    }
+}
+
+impl From<RequestResponseEvent<RaftMessage, RaftAck>> for OutEvent {
+    fn from(v: RequestResponseEvent<RaftMessage, RaftAck>) -> Self {
+        Self::Raft(v)
+    }
+}
+
+impl From<RequestResponseEvent<LockRequest, LockResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<LockRequest, LockResponse>) -> Self {
+        Self::Locks(v)
+    }
+}
+
+impl From<RequestResponseEvent<SwimMessage, SwimAck>> for OutEvent {
+    fn from(v: RequestResponseEvent<SwimMessage, SwimAck>) -> Self {
+        Self::Swim(v)
+    }
+}
+
+impl From<RequestResponseEvent<ServiceRequest, ServiceResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<ServiceRequest, ServiceResponse>) -> Self {
+        Self::Services(v)
+    }
+}
+
+impl From<RequestResponseEvent<ContainerRequest, ContainerResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<ContainerRequest, ContainerResponse>) -> Self {
+        Self::Containers(v)
+    }
+}
+
+impl From<RequestResponseEvent<ExecRequest, ExecResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<ExecRequest, ExecResponse>) -> Self {
+        Self::Exec(v)
+    }
+}
+
 
 #[derive(strum_macros::Display)]
 pub enum NodeStateKey {
     NodeLocalKey,
+    NodeLocalKeyType,
+    NodeKeyRotation,
     NodePeersKey,
 }
 
+const KEY_ROTATION_TOPIC: &str = "key-rotation";
+// Pull from a random peer this often, in case gossip was lost
+const KV_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+// Heartbeats every 2 ticks, elections after 10 to 20 without one
+const RAFT_TICK: Duration = Duration::from_millis(100);
+// Kept locks are renewed once a third of their ttl went by
+const LOCK_RENEW_INTERVAL: Duration = Duration::from_secs(1);
+// A member is probed every 5 ticks and declared dead 5s after it is suspected
+const SWIM_TICK: Duration = Duration::from_millis(200);
+// Jobs are checked on the first tick of each minute
+const CRON_TICK: Duration = Duration::from_secs(1);
+
 fn peer_db_key(id: &PeerId) -> String {
-    format!("{}${}", NodeStateKey::NodePeersKey, id.to_base58())
+    id.to_base58()
 }
 
 impl Node {
     fn get_peer(&self, id: &PeerId) -> Option<Peer> {
-        match self.db.get(peer_db_key(id)) {
-            Ok(v) => match v {
-                Some(v) => match String::from_utf8(v.to_vec()) {
-                    Ok(v) => Some(Peer::from(v)),
-                    Err(_) => None,
-                },
-                None => None
+        match self.peers.get(peer_db_key(id)) {
+            Ok(Some(v)) => match Peer::parse(&v) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    error!("Failed to parse peer {}: {}", id, e);
+                    None
+                }
             },
-            Err(_) => None
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to read peer {}: {:?}", id, e);
+                None
+            }
         }
     }
 
@@ -121,16 +347,29 @@ impl Node {
                 host_mac: "".to_string(),
                 addrs: HashSet::new(),
                 status: PeerStatus::Connected,
+                last_seen: None,
+                labels: Labels::new(),
+                tags: Labels::new(),
             }
         };
         peer.status = PeerStatus::Connected;
+        self.swarm.behaviour_mut().files.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().artifacts.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().kv.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().raft.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().locks.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().swim.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().services.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().containers.add_address(&id, addr.clone());
+        self.swarm.behaviour_mut().exec.add_address(&id, addr.clone());
         peer.addrs.insert(addr);
+        peer.touch();
         // add to floodsub
         self.swarm
             .behaviour_mut()
             .floodsub
             .add_node_to_partial_view(id);
-        match self.db.insert(peer_db_key(&id), peer.to_string().as_bytes()) {
+        match self.peers.insert(peer_db_key(&id), peer.to_string().as_bytes()) {
             Ok(_) => {},
             Err(e) => error!("Failed to insert peer: {:?}", e)
         };
@@ -141,7 +380,7 @@ impl Node {
             Some(p) => {
                 let mut p = p.clone();
                 p.status = PeerStatus::Disconnected;
-                match self.db.insert(peer_db_key(&id), p.to_string().as_bytes()) {
+                match self.peers.insert(peer_db_key(&id), p.to_string().as_bytes()) {
                     Ok(_) => {},
                     Err(e) => error!("Failed to set peer's status to disconnected: {:?}", e)
                 }
@@ -150,21 +389,1030 @@ impl Node {
         };
     }
 
+    // Stored peers with the status of their connections
+    fn live_peers(&self) -> Vec<Peer> {
+        let mut peers = self.list_peers();
+        for peer in peers.iter_mut() {
+            let connected = peer.id.parse::<PeerId>().map(|id| self.swarm.is_connected(&id)).unwrap_or(false);
+            peer.status = if connected { PeerStatus::Connected } else { PeerStatus::Disconnected };
+        }
+        peers
+    }
+
+    fn info(&self) -> NodeInfo {
+        NodeInfo {
+            peer_id: self.peer_id.to_base58(),
+            listen_addrs: self.swarm.listeners().map(|a| a.to_string()).collect(),
+            connected_peers: self.swarm.connected_peers().map(|id| id.to_base58()).collect(),
+            labels: self.labels.clone(),
+        }
+    }
+
+    fn update_peer(&mut self, id: &PeerId, update: impl FnOnce(&mut Peer)) -> Result<Peer, String> {
+        let mut peer = self.get_peer(id).ok_or_else(|| format!("Unknown peer {}", id))?;
+        update(&mut peer);
+        self.peers.insert(peer_db_key(id), peer.to_string().as_bytes()).map_err(|e| e.to_string())?;
+        Ok(peer)
+    }
+
+    fn tag_peer(&mut self, id: &PeerId, set: Labels, remove: Vec<String>) -> Result<Peer, String> {
+        labels::check_labels(&set)?;
+        self.update_peer(id, |peer| {
+            peer.tags.extend(set);
+            for key in remove {
+                peer.tags.remove(&key);
+            }
+        })
+    }
+
+    fn publish_labels(&mut self) {
+        if self.labels.is_empty() {
+            return;
+        }
+        match serde_json::to_vec(&LabelAnnouncement { labels: self.labels.clone() }) {
+            Ok(data) => self.swarm.behaviour_mut().floodsub.publish(self.labels_topic.clone(), data),
+            Err(e) => error!("Failed to serialize labels: {:?}", e),
+        }
+    }
+
+    fn publish_health(&mut self) {
+        if self.health_results.is_empty() {
+            return;
+        }
+        let report = HealthReport { node: self.peer_id.to_base58(), results: self.health_results.values().cloned().collect() };
+        match serde_json::to_vec(&report) {
+            Ok(data) => self.swarm.behaviour_mut().floodsub.publish(self.health_topic.clone(), data),
+            Err(e) => error!("Failed to serialize health results: {:?}", e),
+        }
+    }
+
+    fn health_reported(&mut self, peer: PeerId, report: HealthReport) {
+        if report.node != peer.to_base58() || report.results.iter().any(|r| r.node != report.node) {
+            warn!("Ignoring health results of {} about other nodes", peer);
+            return;
+        }
+        self.health_reports.insert(report.node.clone(), (Instant::now(), report));
+    }
+
+    // A run of a check of this node, sending the alerts it fires or resolves
+    // and the results to the peers when the status changed
+    fn health_checked(&mut self, check: String, outcome: Outcome) {
+        let local = self.peer_id.to_base58();
+        let previous = self.health_results.get(&check);
+        let result = CheckResult::new(&local, &check, outcome, previous, now_secs());
+        let changed = previous.map(|p| p.status) != Some(result.status);
+        if changed {
+            info!("Health check {} is {:?}: {}", check, result.status, result.message.as_deref().unwrap_or("-"));
+        }
+        for (notify, alert) in self.alerter.evaluate(&result) {
+            let deliveries = self.alert_deliveries.0.clone();
+            tokio::spawn(async move {
+                let result = health::notify(&notify, &alert).await;
+                if let Err(e) = &result {
+                    warn!("Failed to send alert {} of check {}, trying again on the next run: {}", alert.rule, alert.check, e);
+                }
+                let _ = deliveries.send((alert, result.is_ok())).await;
+            });
+        }
+        self.health_results.insert(check, result);
+        if changed {
+            self.publish_health();
+        }
+    }
+
+    // Results of this node then of the peers heard from lately
+    fn health_results(&self) -> Vec<CheckResult> {
+        let mut results: Vec<CheckResult> = self.health_results.values().cloned().collect();
+        let mut reports: Vec<&HealthReport> = self.health_reports.values()
+            .filter(|(received, _)| received.elapsed() < 3 * KV_SYNC_INTERVAL)
+            .map(|(_, report)| report)
+            .collect();
+        reports.sort_by(|a, b| a.node.cmp(&b.node));
+        results.extend(reports.into_iter().flat_map(|r| r.results.iter().cloned()));
+        results
+    }
+
+    fn labels_announced(&mut self, peer: PeerId, announcement: LabelAnnouncement) {
+        if let Err(e) = labels::check_labels(&announcement.labels) {
+            warn!("Ignoring labels of {}: {}", peer, e);
+            return;
+        }
+        debug!("{} has labels {:?}", peer, announcement.labels);
+        if let Err(e) = self.update_peer(&peer, |p| p.labels = announcement.labels) {
+            debug!("Labels of {} not saved: {}", peer, e);
+        }
+    }
+
+    // A pass of the reconciler over the deployments of the cluster kv, placed
+    // among the members alive
+    fn reconcile(&mut self) {
+        let records = match self.kv_store.list(deploy::DEPLOY_PREFIX, None) {
+            Ok(list) => list.records,
+            Err(e) => {
+                error!("Failed to read the deployments: {}", e);
+                return;
+            },
+        };
+        let (deployments, statuses) = deploy::parse_records(&deploy::authorized(records, &self.operators));
+        let local = self.peer_id.to_base58();
+        let mut valid = Vec::new();
+        for d in deployments.into_values() {
+            if let Err(e) = d.check() {
+                warn!("Ignoring deployment {}: {}", d.name(), e);
+                continue;
+            }
+            match d.rollout.as_ref().map(|r| r.driver == local) {
+                Some(true) => {
+                    let reported = statuses.get(d.name()).cloned().unwrap_or_default();
+                    valid.push(self.drive_rollout(d, &reported));
+                },
+                _ => valid.push(d),
+            }
+        }
+        let deployments = valid;
+        let mut nodes = BTreeMap::new();
+        for member in self.membership.members().into_iter().filter(|m| m.state == MemberState::Alive) {
+            let labels = match member.id == local {
+                true => self.labels.clone(),
+                false => member.id.parse::<PeerId>().ok().and_then(|id| self.get_peer(&id)).map(|p| p.labels).unwrap_or_default(),
+            };
+            nodes.insert(member.id, labels);
+        }
+        let candidates = Candidates { local, nodes };
+        let (reconciler, reports) = (self.reconciler.clone(), self.deploy_reports.0.clone());
+        self.reconciling = true;
+        tokio::spawn(async move {
+            let _ = reports.send(reconciler.reconcile(&deployments, &candidates).await).await;
+        });
+    }
+
+    // Rollouts started or resumed on this node move on from here, the spec is
+    // written back when the rollout changed
+    fn drive_rollout(&mut self, mut deployment: Deployment, statuses: &[DeployStatus]) -> Deployment {
+        let rollout = match rollout::step(&deployment, statuses, now_secs()) {
+            Some(rollout) => rollout,
+            None => return deployment,
+        };
+        let name = deployment.name().to_string();
+        match rollout.state {
+            RolloutState::Paused => warn!("Paused the rollout of {}: {}", name, rollout.message.as_deref().unwrap_or_default()),
+            RolloutState::Complete => info!("Rollout of {} complete, {}", name, rollout.message.as_deref().unwrap_or_default()),
+            RolloutState::Progressing => info!("Rollout of {} to version {}: wave {}, {} nodes moved", name, deployment.version, rollout.waves + 1, rollout.nodes.len()),
+        }
+        deployment.rollout = Some(rollout);
+        match serde_json::to_string(&deployment) {
+            Ok(value) => if let Err(e) = self.kv_write(&deploy::spec_key(&name), Some(value)) {
+                warn!("Failed to save the rollout of {}: {}", name, e);
+            },
+            Err(e) => error!("Failed to serialize deployment {}: {}", name, e),
+        }
+        deployment
+    }
+
+    // Statuses written to the cluster kv when they changed
+    fn deploy_reported(&mut self, reports: Vec<(String, Option<DeployStatus>)>) {
+        self.reconciling = false;
+        let node = self.peer_id.to_base58();
+        for (name, status) in reports {
+            let key = deploy::status_key(&name, &node);
+            let current: Option<DeployStatus> = self.kv_store.get(&key).ok().flatten()
+                .and_then(|r| r.value)
+                .and_then(|v| serde_json::from_str(&v).ok());
+            let value = match status {
+                Some(status) if deploy::needs_report(current.as_ref(), &status) => match serde_json::to_string(&status) {
+                    Ok(value) => Some(value),
+                    Err(e) => {
+                        error!("Failed to serialize the status of {}: {}", name, e);
+                        continue;
+                    },
+                },
+                None if current.is_some() => None,
+                _ => continue,
+            };
+            if let Err(e) = self.kv_write(&key, value) {
+                warn!("Failed to report the status of deployment {}: {}", name, e);
+            }
+        }
+    }
+
+    // Jobs of the cluster kv due in the minute, on the nodes they target
+    fn run_cron(&mut self) {
+        let minute = now_secs() / 60 * 60;
+        if minute == self.cron_minute {
+            return;
+        }
+        self.cron_minute = minute;
+        let records = match self.kv_store.list(cron::CRON_PREFIX, None) {
+            Ok(list) => list.records,
+            Err(e) => {
+                error!("Failed to read the jobs: {}", e);
+                return;
+            },
+        };
+        let (jobs, _) = cron::parse_records(&cron::authorized(records, &self.operators));
+        let local = self.peer_id.to_base58();
+        for job in jobs.into_values() {
+            let due = match job.check().and_then(|_| job.schedule.parse::<Schedule>()) {
+                Ok(schedule) => schedule.matches(minute),
+                Err(e) => {
+                    warn!("Ignoring job {}: {}", job.name, e);
+                    false
+                },
+            };
+            let targeted = match &job.target {
+                Target::Node { node } => *node == local,
+                Target::All | Target::One => job.selector.parse::<Selector>().map(|s| s.matches(&self.labels)).unwrap_or(false),
+            };
+            if !due || !targeted {
+                continue;
+            }
+            match job.target == Target::One {
+                true => self.cron_elect(job, minute),
+                false => self.cron_runner.spawn(job, minute, self.cron_runs.0.clone()),
+            }
+        }
+    }
+
+    // The node granted the lock of the job runs it, the others skip the
+    // minute. A grant coming late is not used, the lock may have expired
+    // and been granted to another node since.
+    fn cron_elect(&mut self, job: Job, scheduled: u64) {
+        let (reply, response) = oneshot::channel();
+        let ttl = cron::ELECTION_TTL.as_millis() as u64;
+        self.lock_request(LockRequest::Acquire { name: cron::lock_name(&job.name), ttl }, reply);
+        let (runner, done) = (self.cron_runner.clone(), self.cron_runs.0.clone());
+        tokio::spawn(async move {
+            match response.await {
+                Ok(LockResponse::Granted { .. }) if now_millis() < scheduled * 1000 + ttl / 2 => runner.spawn(job, scheduled, done),
+                Ok(LockResponse::Granted { .. }) => warn!("Skipping job {}, the lock was granted too late", job.name),
+                Ok(LockResponse::Held { lock }) => debug!("Job {} runs on {}", job.name, lock.holder),
+                Ok(LockResponse::Error { message }) => warn!("Failed to elect the node running job {}: {}", job.name, message),
+                _ => {},
+            }
+        });
+    }
+
+    // The run written over the oldest one of this node
+    fn cron_finished(&mut self, run: Run) {
+        let prefix = format!("{}{}/runs/", cron::CRON_PREFIX, run.job);
+        let runs = match self.kv_store.list(&prefix, None) {
+            Ok(list) => cron::parse_records(&list.records).1.remove(&run.job).unwrap_or_default(),
+            Err(e) => {
+                error!("Failed to read the runs of job {}: {}", run.job, e);
+                return;
+            },
+        };
+        let key = cron::run_key(&run.job, &run.node, &runs);
+        let value = match serde_json::to_string(&run) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to serialize a run of job {}: {}", run.job, e);
+                return;
+            },
+        };
+        if let Err(e) = self.kv_write(&key, Some(value)) {
+            warn!("Failed to save the run of job {}: {}", run.job, e);
+        }
+    }
+
     fn list_peers(&self) -> Vec<Peer> {
-        let mut r = self.db.scan_prefix(NodeStateKey::NodePeersKey.to_string());
         let mut peers: Vec<Peer> = Vec::new();
-        while let Some(cur) = r.next() {
+        for cur in self.peers.iter() {
             match cur {
-                Ok((_, value)) => {
-                    let v = Peer::from(String::from_utf8(value.to_vec()).unwrap());
-                    peers.push(v);
+                Ok((key, value)) => match Peer::parse(&value) {
+                    Ok(p) => peers.push(p),
+                    Err(e) => error!("Failed to parse peer {}: {}", String::from_utf8_lossy(&key), e),
                 },
-                Err(_) => {},
+                Err(e) => error!("Failed to read peers: {:?}", e),
             }
         }
         peers
     }
 
+    // A peer announced that it replaced its key, move its record to the new id
+    fn peer_key_rotated(&mut self, rotation: KeyRotation) {
+        if !rotation.verify() {
+            warn!("Ignoring key rotation with an invalid signature from {}", rotation.old_peer_id);
+            return;
+        }
+        let (old_id, new_id) = match (rotation.old_peer_id.parse::<PeerId>(), rotation.new_peer_id.parse::<PeerId>()) {
+            (Ok(old_id), Ok(new_id)) => (old_id, new_id),
+            _ => return,
+        };
+        if let Some(mut peer) = self.get_peer(&old_id) {
+            peer.id = new_id.to_base58();
+            if let Some(existing) = self.get_peer(&new_id) {
+                peer.addrs.extend(existing.addrs);
+                peer.status = existing.status;
+                peer.last_seen = existing.last_seen.max(peer.last_seen);
+            }
+            let r = self.peers.remove(peer_db_key(&old_id))
+                .and_then(|_| self.peers.insert(peer_db_key(&new_id), peer.to_string().as_bytes()));
+            if let Err(e) = r {
+                error!("Failed to apply key rotation: {:?}", e);
+                return;
+            }
+        }
+        self.swarm
+            .behaviour_mut()
+            .floodsub
+            .remove_node_from_partial_view(&old_id);
+        self.hooks.on_peer_key_rotated(old_id, new_id);
+        info!("Peer {} rotated its key, new peer id: {}", old_id, new_id);
+    }
+
+    // Re-announce our own key rotation, peers may have been offline when it
+    // happened. Without acknowledgements it is dropped after a while instead.
+    fn announce_key_rotation(&mut self) {
+        if let Some(rotation) = keys::pending_rotation(&self.db) {
+            if rotation.new_peer_id != self.peer_id.to_base58() {
+                return;
+            }
+            if now_secs() >= rotation.timestamp.saturating_add(keys::ROTATION_ANNOUNCE_SECS) {
+                info!("Key rotation from {} announced long enough, clearing it", rotation.old_peer_id);
+                if let Err(e) = keys::clear_rotation(&self.db) {
+                    error!("Failed to clear key rotation: {:?}", e);
+                }
+                return;
+            }
+            match serde_json::to_vec(&rotation) {
+                Ok(data) => self.swarm.behaviour_mut().floodsub.publish(self.rotation_topic.clone(), data),
+                Err(e) => error!("Failed to serialize key rotation: {:?}", e),
+            }
+        }
+    }
+
+    fn publish_artifacts(&mut self, artifacts: Vec<Availability>) {
+        for batch in artifacts.chunks(ANNOUNCEMENT_BATCH) {
+            match serde_json::to_vec(&Announcement { artifacts: batch.to_vec() }) {
+                Ok(data) => self.swarm.behaviour_mut().floodsub.publish(self.artifact_topic.clone(), data),
+                Err(e) => error!("Failed to serialize artifact announcement: {:?}", e),
+            }
+        }
+    }
+
+    fn artifact_announced(&mut self, peer: PeerId, announcement: Announcement) {
+        for artifact in announcement.artifacts {
+            debug!("{} has artifact {} (complete: {})", peer, artifact.id, artifact.complete);
+            self.artifact_providers.entry(artifact.id).or_default().insert(peer, artifact.complete);
+        }
+    }
+
+    // Announced providers first, any connected peer may have it too
+    fn providers(&self, id: &str) -> Vec<Provider> {
+        let announced = self.artifact_providers.get(id).cloned().unwrap_or_default();
+        let mut providers: Vec<Provider> = announced.iter()
+            .map(|(peer, complete)| Provider { peer: *peer, complete: *complete })
+            .collect();
+        for peer in self.swarm.connected_peers() {
+            if !announced.contains_key(peer) {
+                providers.push(Provider { peer: *peer, complete: false });
+            }
+        }
+        providers
+    }
+
+    fn kv_write(&mut self, key: &str, value: Option<String>) -> Result<Record, String> {
+        let record = self.kv_store.write(key, value).map_err(|e| e.to_string())?;
+        self.swarm.behaviour_mut().floodsub.publish(self.kv_topic.clone(), KvGossip::encode(&record));
+        Ok(record)
+    }
+
+    // Anti-entropy: ask a peer for the records it has newer than ours, all of
+    // them unless `keys` is given
+    fn kv_pull(&mut self, peer: PeerId, keys: Option<Vec<String>>) {
+        let known = match self.kv_store.versions(keys.as_deref()) {
+            Ok(known) => known,
+            Err(e) => {
+                error!("Failed to read the kv store: {}", e);
+                return;
+            },
+        };
+        let request_id = self.swarm.behaviour_mut().kv.send_request(&peer, KvRequest::Pull { keys: keys.clone(), known });
+        let (reply, response) = oneshot::channel();
+        self.kv_exchanges.replies.insert(request_id, reply);
+        let (store, again) = (self.kv_store.clone(), self.kv_pulls.0.clone());
+        tokio::spawn(async move {
+            match response.await {
+                Ok(Ok(KvResponse::Records { records, more })) => {
+                    let mut merged = 0;
+                    for record in records {
+                        match store.merge(record) {
+                            Ok(true) => merged += 1,
+                            Ok(false) => {},
+                            Err(e) => warn!("Invalid kv record from {}: {}", peer, e),
+                        }
+                    }
+                    debug!("Merged {} kv records from {}", merged, peer);
+                    if more {
+                        let _ = again.send((peer, keys)).await;
+                    }
+                },
+                Ok(Ok(KvResponse::Error { message })) => warn!("Kv pull from {} failed: {}", peer, message),
+                Ok(Err(e)) => debug!("Kv pull from {} failed: {}", peer, e),
+                Err(_) => {},
+            }
+        });
+    }
+
+    fn kv_gossiped(&mut self, peer: PeerId, gossip: KvGossip) {
+        for record in gossip.records {
+            if let Err(e) = self.kv_store.merge(record) {
+                warn!("Invalid kv record from {}: {}", peer, e);
+            }
+        }
+        if !gossip.changed.is_empty() {
+            self.kv_pull(peer, Some(gossip.changed));
+        }
+    }
+
+    // Send the messages of the raft voter and answer the requests it is done with
+    fn raft_flush(&mut self) {
+        let (outbox, results) = match self.raft.as_mut() {
+            Some(raft) => (raft.take_outbox(), raft.take_results()),
+            None => return,
+        };
+        for (to, msg) in outbox {
+            match to.parse::<PeerId>() {
+                Ok(peer) => {
+                    self.swarm.behaviour_mut().raft.send_request(&peer, msg);
+                },
+                Err(_) => warn!("Invalid raft voter {}", to),
+            }
+        }
+        for (ticket, result) in results {
+            if let Some(reply) = self.raft_replies.remove(&ticket) {
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    fn raft_request(&mut self, reply: oneshot::Sender<RaftResult>, request: impl FnOnce(&mut Raft) -> Result<u64, String>) {
+        let ticket = match self.raft.as_mut() {
+            Some(raft) => request(raft),
+            None => Err("Raft is not enabled on this node, start it with --raft-voters".to_string()),
+        };
+        match ticket {
+            Ok(ticket) => {
+                self.raft_replies.insert(ticket, reply);
+            },
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            },
+        }
+        self.raft_flush();
+    }
+
+    // Messages are one way, the request is acknowledged at once and the
+    // answer of the voter is a request of its own
+    fn raft_event(&mut self, event: RequestResponseEvent<RaftMessage, RaftAck>) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                let _ = self.swarm.behaviour_mut().raft.send_response(channel, RaftAck);
+                if let Some(raft) = self.raft.as_mut() {
+                    if let Err(e) = raft.step(&peer.to_base58(), request) {
+                        error!("Raft failed to handle a message from {}: {}", peer, e);
+                    }
+                }
+                self.raft_flush();
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                debug!("Raft message to {} failed: {:?}", peer, error);
+            },
+            _ => {},
+        }
+    }
+
+    fn raft_tick(&mut self) {
+        if let Some(raft) = self.raft.as_mut() {
+            if let Err(e) = raft.tick() {
+                error!("Raft tick failed: {}", e);
+            }
+        }
+        self.raft_flush();
+    }
+
+    // Lock requests of `holder` are served by the leader, the other voters
+    // name it and only list the locks
+    fn lock_serve(&mut self, holder: PeerId, req: LockRequest, reply: oneshot::Sender<LockResponse>) {
+        let raft = match self.raft.as_ref() {
+            Some(raft) => raft,
+            None => {
+                let _ = reply.send(LockResponse::Error { message: "This node is not a raft voter".to_string() });
+                return;
+            },
+        };
+        let command = match req {
+            LockRequest::List => {
+                let now = now_millis();
+                let response = match raft.locks() {
+                    Ok(locks) => LockResponse::Locks { locks: locks.into_iter().filter(|l| l.expires > now).collect() },
+                    Err(e) => LockResponse::Error { message: e.to_string() },
+                };
+                let _ = reply.send(response);
+                return;
+            },
+            _ if raft.role() != Role::Leader => {
+                let _ = reply.send(LockResponse::NotLeader { leader: raft.leader().map(|l| l.to_string()) });
+                return;
+            },
+            LockRequest::Acquire { name, ttl } => {
+                let checked = match kv::valid_key(&name) {
+                    true => lock::check_ttl(Duration::from_millis(ttl)),
+                    false => Err(format!("Invalid lock name {}", name)),
+                };
+                if let Err(message) = checked {
+                    let _ = reply.send(LockResponse::Error { message });
+                    return;
+                }
+                RaftCommand::Acquire { name, holder: holder.to_base58(), ttl, now: now_millis() }
+            },
+            LockRequest::Release { name, token } => RaftCommand::Release { name, holder: holder.to_base58(), token },
+        };
+        let (result, response) = oneshot::channel();
+        self.raft_request(result, |raft| raft.propose(command));
+        let holder = holder.to_base58();
+        tokio::spawn(async move {
+            let response = match response.await {
+                Ok(Ok(Some(lock))) => match serde_json::from_str::<Lock>(&lock) {
+                    Ok(lock) if lock.holder != holder => LockResponse::Held { lock },
+                    Ok(lock) => LockResponse::Granted { lock },
+                    Err(e) => LockResponse::Error { message: e.to_string() },
+                },
+                Ok(Ok(None)) => LockResponse::Released,
+                Ok(Err(message)) => LockResponse::Error { message },
+                Err(_) => return,
+            };
+            let _ = reply.send(response);
+        });
+    }
+
+    // Lock request of this node, sent to the leader when it is known and
+    // otherwise to a connected voter
+    fn lock_request(&mut self, req: LockRequest, reply: oneshot::Sender<LockResponse>) {
+        if self.raft.is_some() && matches!(req, LockRequest::List) {
+            return self.lock_serve(self.peer_id, req, reply);
+        }
+        let leader = self.raft.as_ref().and_then(|raft| raft.leader()).and_then(|l| l.parse::<PeerId>().ok());
+        match leader {
+            Some(leader) if leader == self.peer_id => self.lock_serve(self.peer_id, req, reply),
+            Some(leader) => self.lock_send(leader, req, reply, true),
+            None => {
+                let voter = self.raft_voters.iter().filter(|v| self.swarm.is_connected(v)).choose(&mut rand::thread_rng()).copied();
+                match voter {
+                    Some(voter) => self.lock_send(voter, req, reply, true),
+                    None if self.raft_voters.is_empty() => {
+                        let _ = reply.send(LockResponse::Error { message: "Locks need a raft group, start the nodes with --raft-voters".to_string() });
+                    },
+                    None => {
+                        let _ = reply.send(LockResponse::Error { message: "No raft voter is connected".to_string() });
+                    },
+                }
+            },
+        }
+    }
+
+    // A voter that is not the leader names it, the request is sent there once
+    fn lock_send(&mut self, peer: PeerId, req: LockRequest, reply: oneshot::Sender<LockResponse>, redirect: bool) {
+        let request_id = self.swarm.behaviour_mut().locks.send_request(&peer, req.clone());
+        let (result, response) = oneshot::channel();
+        self.lock_exchanges.replies.insert(request_id, result);
+        let again = self.lock_redirects.0.clone();
+        tokio::spawn(async move {
+            let response = match response.await {
+                Ok(Ok(LockResponse::NotLeader { leader: Some(leader) })) if redirect => match leader.parse::<PeerId>() {
+                    Ok(leader) => {
+                        let _ = again.send((leader, req, reply)).await;
+                        return;
+                    },
+                    Err(_) => LockResponse::Error { message: format!("Invalid raft leader {}", leader) },
+                },
+                Ok(Ok(LockResponse::NotLeader { .. })) => LockResponse::Error { message: "No raft leader elected yet, retry later".to_string() },
+                Ok(Ok(response)) => response,
+                Ok(Err(message)) => LockResponse::Error { message },
+                Err(_) => return,
+            };
+            let _ = reply.send(response);
+        });
+    }
+
+    // The answer to a peer waits for the log, it is sent from a task
+    fn lock_event(&mut self, event: RequestResponseEvent<LockRequest, LockResponse>) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                debug!("Lock request from {}: {:?}", peer, request);
+                let (reply, response) = oneshot::channel();
+                self.lock_serve(peer, request, reply);
+                let responses = self.lock_exchanges.responses.0.clone();
+                tokio::spawn(async move {
+                    if let Ok(response) = response.await {
+                        let _ = responses.send((channel, response)).await;
+                    }
+                });
+            },
+            event => self.lock_exchanges.handle("Lock", event, |_| LockResponse::Error { message: "Unexpected lock request".to_string() }),
+        }
+    }
+
+    fn renew_locks(&mut self) {
+        let now = Instant::now();
+        let due: Vec<(String, Duration)> = self.kept_locks.iter()
+            .filter(|(_, (_, at))| *at <= now)
+            .map(|(name, (ttl, _))| (name.clone(), *ttl))
+            .collect();
+        for (name, ttl) in due {
+            self.kept_locks.insert(name.clone(), (ttl, now + ttl / 3));
+            let (reply, response) = oneshot::channel();
+            self.lock_request(LockRequest::Acquire { name: name.clone(), ttl: ttl.as_millis() as u64 }, reply);
+            tokio::spawn(async move {
+                match response.await {
+                    Ok(LockResponse::Granted { lock }) => debug!("Renewed lock {} until {}", name, lock.expires),
+                    Ok(LockResponse::Held { lock }) => warn!("Failed to renew lock {}, it is held by {}", name, lock.holder),
+                    Ok(LockResponse::Error { message }) => warn!("Failed to renew lock {}: {}", name, message),
+                    _ => {},
+                }
+            });
+        }
+    }
+
+    // A node that lost its last connection to the leader loses its locks,
+    // it takes them back with a greater token when it renews them
+    fn release_locks_of(&mut self, peer: PeerId) {
+        let holder = peer.to_base58();
+        let holds = match self.raft.as_ref() {
+            Some(raft) if raft.role() == Role::Leader => raft.locks().map(|locks| locks.iter().any(|l| l.holder == holder)).unwrap_or(false),
+            _ => false,
+        };
+        if holds {
+            info!("Releasing the locks of {}, it disconnected", peer);
+            let (reply, _) = oneshot::channel();
+            self.raft_request(reply, |raft| raft.propose(RaftCommand::ReleaseAll { holder }));
+        }
+    }
+
+    // Send the probes of the membership, to the addresses members announced
+    // when there is no connection
+    fn swim_flush(&mut self) {
+        for (to, msg) in self.membership.take_outbox() {
+            let peer = match to.parse::<PeerId>() {
+                Ok(peer) => peer,
+                Err(_) => {
+                    warn!("Invalid member {}", to);
+                    continue;
+                },
+            };
+            if !self.swarm.is_connected(&peer) {
+                let addrs = self.membership.member(&to).map(|m| m.addrs.clone()).unwrap_or_default();
+                for addr in addrs.iter().filter_map(|a| a.parse::<Multiaddr>().ok()) {
+                    self.swarm.behaviour_mut().swim.add_address(&peer, addr);
+                }
+            }
+            self.swarm.behaviour_mut().swim.send_request(&peer, msg);
+        }
+    }
+
+    fn swim_event(&mut self, event: RequestResponseEvent<SwimMessage, SwimAck>) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                let _ = self.swarm.behaviour_mut().swim.send_response(channel, SwimAck);
+                self.membership.step(&peer.to_base58(), request);
+                self.swim_flush();
+            },
+            // A lost probe is what the protocol detects, nothing to do here
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                debug!("Swim message to {} failed: {:?}", peer, error);
+            },
+            _ => {},
+        }
+    }
+
+    // Returns true when the node has to stop
+    fn handle_message(&mut self, msg: Message) -> bool {
+        info!("You input message: {:?}, send to everyone", msg.message);
+        match msg.type_ {
+            MessageType::Text => {
+                self.swarm.behaviour_mut()
+                    .floodsub
+                    .publish(self.floodsub_topic.clone(), Envelope::from(&msg).encode());
+                false
+            },
+            MessageType::Stop => {
+                warn!("Stopping p2p node...");
+                true
+            },
+        }
+    }
+
+    fn handle_traced_message(&mut self, msg: Message) -> bool {
+        let span = info_span!("message", trace_id = %msg.trace_id, type_ = %msg.type_, direction = "out");
+        span.in_scope(|| self.handle_message(msg))
+    }
+
+    // Returns true when the node has to stop, replies are dropped when the
+    // requester went away
+    fn handle_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Message(msg) => return self.handle_traced_message(msg),
+            Command::Stop => {
+                warn!("Stopping p2p node...");
+                return true;
+            },
+            Command::ListPeers(reply) => {
+                let _ = reply.send(self.live_peers());
+            },
+            Command::TagPeer(peer_id, set, remove, reply) => {
+                let _ = reply.send(self.tag_peer(&peer_id, set, remove));
+            },
+            Command::Dial(addr, reply) => {
+                info!("Dialing {}", addr);
+                let _ = reply.send(self.swarm.dial(addr).map_err(|e| e.to_string()));
+            },
+            Command::Disconnect(peer_id, reply) => {
+                info!("Disconnecting {}", peer_id);
+                let _ = reply.send(self.swarm.disconnect_peer_id(peer_id).is_ok());
+            },
+            Command::Info(reply) => {
+                let _ = reply.send(self.info());
+            },
+            Command::HealthChecks(reply) => {
+                let _ = reply.send(self.health_results());
+            },
+            Command::Alerts(reply) => {
+                let _ = reply.send(self.alerter.firing());
+            },
+            Command::File(peer, req, reply) => {
+                let request_id = self.swarm.behaviour_mut().files.send_request(&peer, req);
+                self.file_exchanges.replies.insert(request_id, reply);
+            },
+            Command::Artifact(peer, op, reply) => {
+                let request_id = self.swarm.behaviour_mut().artifacts.send_request(&peer, op);
+                self.artifact_exchanges.replies.insert(request_id, reply);
+            },
+            Command::ArtifactProviders(id, reply) => {
+                let _ = reply.send(self.providers(&id));
+            },
+            Command::AnnounceArtifacts(artifacts) => {
+                self.publish_artifacts(artifacts);
+            },
+            Command::KvWrite(key, value, reply) => {
+                let _ = reply.send(self.kv_write(&key, value));
+            },
+            Command::KvGet(key, reply) => {
+                let _ = reply.send(self.kv_store.get(&key).map_err(|e| e.to_string()));
+            },
+            Command::KvList(prefix, since, reply) => {
+                let _ = reply.send(self.kv_store.list(&prefix, since).map_err(|e| e.to_string()));
+            },
+            Command::RaftStatus(reply) => {
+                let _ = reply.send(self.raft.as_ref().map(|raft| raft.status()));
+            },
+            Command::RaftWrite(command, reply) => {
+                self.raft_request(reply, |raft| raft.propose(command));
+            },
+            Command::RaftRead(key, reply) => {
+                self.raft_request(reply, |raft| raft.read(&key));
+            },
+            Command::Lock(req, reply) => {
+                if let LockRequest::Release { name, .. } = &req {
+                    self.kept_locks.remove(name);
+                }
+                self.lock_request(req, reply);
+            },
+            Command::KeepLock(name, ttl) => {
+                self.kept_locks.insert(name, (ttl, Instant::now() + ttl / 3));
+            },
+            Command::Members(reply) => {
+                let _ = reply.send(self.membership.members());
+            },
+            Command::Service(None, req, reply) => {
+                let supervisor = self.supervisor.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(Ok(supervisor.serve(req, true).await));
+                });
+            },
+            Command::Service(Some(peer), req, reply) => {
+                let request_id = self.swarm.behaviour_mut().services.send_request(&peer, req);
+                self.service_exchanges.replies.insert(request_id, reply);
+            },
+            Command::Container(None, req, reply) => {
+                let runtime = self.containers.clone();
+                tokio::spawn(async move {
+                    let _ = reply.send(Ok(container::serve(runtime.as_ref(), req).await));
+                });
+            },
+            Command::Container(Some(peer), req, reply) => {
+                let request_id = self.swarm.behaviour_mut().containers.send_request(&peer, req);
+                self.container_exchanges.replies.insert(request_id, reply);
+            },
+            Command::Exec(None, req, reply) => {
+                let local = self.peer_id.to_base58();
+                tokio::spawn(async move {
+                    let _ = reply.send(Ok(exec::serve(&local, req).await));
+                });
+            },
+            Command::Exec(Some(peer), req, reply) => {
+                let request_id = self.swarm.behaviour_mut().exec.send_request(&peer, req);
+                self.exec_exchanges.replies.insert(request_id, reply);
+            },
+        }
+        false
+    }
+
+    // Generic over the error of the connection handlers, the type is private to libp2p
+    fn handle_event<E: std::fmt::Debug>(&mut self, event: SwarmEvent<OutEvent, E>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                info!("Listening on {:?}", address);
+                self.membership.add_addr(&address.to_string());
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Message(message)
+            )) if message.topics.contains(&self.rotation_topic) => {
+                match serde_json::from_slice::<KeyRotation>(&message.data) {
+                    Ok(rotation) => self.peer_key_rotated(rotation),
+                    Err(e) => warn!("Invalid key rotation from {:?}: {}", message.source, e),
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Subscribed { peer_id, topic }
+            )) if topic == self.rotation_topic => {
+                debug!("{:?} subscribed to key rotations", peer_id);
+                self.announce_key_rotation();
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Message(message)
+            )) if message.topics.contains(&self.artifact_topic) => {
+                match serde_json::from_slice::<Announcement>(&message.data) {
+                    Ok(announcement) => self.artifact_announced(message.source, announcement),
+                    Err(e) => warn!("Invalid artifact announcement from {:?}: {}", message.source, e),
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Subscribed { peer_id, topic }
+            )) if topic == self.artifact_topic => {
+                debug!("{:?} subscribed to artifacts", peer_id);
+                let artifacts = self.artifact_store.available();
+                self.publish_artifacts(artifacts);
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Message(message)
+            )) if message.topics.contains(&self.kv_topic) => {
+                match serde_json::from_slice::<KvGossip>(&message.data) {
+                    Ok(gossip) => self.kv_gossiped(message.source, gossip),
+                    Err(e) => warn!("Invalid kv gossip from {:?}: {}", message.source, e),
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Message(message)
+            )) if message.topics.contains(&self.labels_topic) => {
+                match serde_json::from_slice::<LabelAnnouncement>(&message.data) {
+                    Ok(announcement) => self.labels_announced(message.source, announcement),
+                    Err(e) => warn!("Invalid labels from {:?}: {}", message.source, e),
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Subscribed { peer_id, topic }
+            )) if topic == self.labels_topic => {
+                debug!("{:?} subscribed to labels", peer_id);
+                self.publish_labels();
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Message(message)
+            )) if message.topics.contains(&self.health_topic) => {
+                match serde_json::from_slice::<HealthReport>(&message.data) {
+                    Ok(report) => self.health_reported(message.source, report),
+                    Err(e) => warn!("Invalid health results from {:?}: {}", message.source, e),
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Subscribed { peer_id, topic }
+            )) if topic == self.health_topic => {
+                debug!("{:?} subscribed to health", peer_id);
+                self.publish_health();
+            }
+            SwarmEvent::Behaviour(OutEvent::Floodsub(
+                FloodsubEvent::Message(message)
+            )) => {
+                let envelope = Envelope::decode(&message.data);
+                if !envelope.is_for(&self.peer_id.to_base58()) {
+                    debug!("Message {} is for other peers", envelope.trace_id);
+                    return;
+                }
+                let span = info_span!("message", trace_id = %envelope.trace_id, from = %message.source, direction = "in");
+                span.in_scope(|| {
+                    info!(
+                        "Received: '{:?}' from {:?}",
+                        envelope.message,
+                        message.source
+                    );
+                });
+            }
+            SwarmEvent::Behaviour(OutEvent::Files(event)) => {
+                let store = self.file_store.clone();
+                let operator = match &event {
+                    RequestResponseEvent::Message { peer, .. } => self.operators.allows(&peer.to_base58()),
+                    _ => false,
+                };
+                self.file_exchanges.handle("File", event, move |req| store.serve(req, operator));
+            }
+            SwarmEvent::Behaviour(OutEvent::Artifacts(event)) => {
+                let store = self.artifact_store.clone();
+                self.artifact_exchanges.handle("Artifact", event, move |op| store.serve(op));
+            }
+            SwarmEvent::Behaviour(OutEvent::Kv(event)) => {
+                let store = self.kv_store.clone();
+                self.kv_exchanges.handle("Kv", event, move |req| store.serve(req));
+            }
+            SwarmEvent::Behaviour(OutEvent::Raft(event)) => self.raft_event(event),
+            SwarmEvent::Behaviour(OutEvent::Locks(event)) => self.lock_event(event),
+            SwarmEvent::Behaviour(OutEvent::Swim(event)) => self.swim_event(event),
+            SwarmEvent::Behaviour(OutEvent::Services(event)) => {
+                let supervisor = self.supervisor.clone();
+                let operator = match &event {
+                    RequestResponseEvent::Message { peer, .. } => self.operators.allows(&peer.to_base58()),
+                    _ => false,
+                };
+                // On a blocking thread, stopping a service waits for its process
+                self.service_exchanges.handle("Service", event, move |req| {
+                    tokio::runtime::Handle::current().block_on(supervisor.serve(req, operator))
+                });
+            }
+            SwarmEvent::Behaviour(OutEvent::Containers(event)) => {
+                let runtime = self.containers.clone();
+                let operator = match &event {
+                    RequestResponseEvent::Message { peer, .. } => self.operators.allows(&peer.to_base58()),
+                    _ => false,
+                };
+                self.container_exchanges.handle("Container", event, move |req| {
+                    if req.changes() && !operator {
+                        return ContainerResponse::Error { message: "Not an operator of this node".to_string() };
+                    }
+                    tokio::runtime::Handle::current().block_on(container::serve(runtime.as_ref(), req))
+                });
+            }
+            SwarmEvent::Behaviour(OutEvent::Exec(event)) => {
+                let local = self.peer_id.to_base58();
+                let operator = match &event {
+                    RequestResponseEvent::Message { peer, .. } => self.operators.allows(&peer.to_base58()),
+                    _ => false,
+                };
+                // On a blocking thread, the command may take up to `exec::MAX_TIMEOUT`
+                self.exec_exchanges.handle("Exec", event, move |req| {
+                    if !operator {
+                        return ExecResponse::Error { message: "Not an operator of this node".to_string() };
+                    }
+                    tokio::runtime::Handle::current().block_on(exec::serve(&local, req))
+                });
+            }
+            SwarmEvent::Behaviour(OutEvent::Ping(
+                event
+            )) => {
+                debug!("Received ping from {:?}", event.peer.to_base58());
+            }
+            SwarmEvent::Behaviour(OutEvent::Mdns(
+                MdnsEvent::Discovered(list)
+            )) => {
+                for (peer, addr) in list {
+                    // save peer
+                    self.peer_connected(peer, addr.clone());
+                    info!("Discovered {:?}", peer);
+                }
+            }
+            SwarmEvent::Behaviour(OutEvent::Mdns(MdnsEvent::Expired(
+                list
+            ))) => {
+                for (peer, _) in list {
+                    // save peer
+                    self.peer_disconnected(peer);
+                    // remove peer
+                    if !self.swarm.behaviour_mut().mdns.has_node(&peer) {
+                        self.swarm
+                            .behaviour_mut()
+                            .floodsub
+                            .remove_node_from_partial_view(&peer);
+                    }
+                }
+            },
+            SwarmEvent::ConnectionEstablished{
+                peer_id,
+                endpoint,
+                num_established,
+                concurrent_dial_errors: _,
+            } => {
+                let remote_addr = endpoint.get_remote_address();
+                self.peer_connected(peer_id, remote_addr.clone());
+                info!("Connection established: {:?} {:?}", peer_id, remote_addr);
+                // Catch up on the writes made while we were apart
+                if num_established.get() == 1 {
+                    self.kv_pull(peer_id, None);
+                    self.membership.join(&peer_id.to_base58());
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => self.release_locks_of(peer_id),
+            _ => {}
+        }
+    }
+
     async fn dial(&mut self, peer: &Peer) {
         for to_dial in peer.addrs.iter() {
             match self.swarm.dial(to_dial.clone()) {
@@ -174,47 +1422,170 @@ impl Node {
         }
     }
 
-    pub async fn new(receiver: Box<Receiver<Message>>, hooks: Box<dyn NodeLifecycleHooks + Send + Sync>, db: sled::Db, opts: NodeBehaviourOptions) -> Result<Node, Box<dyn Error>> {
+    pub async fn new(queues: NodeQueues, hooks: Box<dyn NodeLifecycleHooks + Send + Sync>, secrets: SecretStore, opts: NodeBehaviourOptions) -> Result<Node, Box<dyn Error>> {
+        let db = secrets.db().clone();
         // Create or load a random secret key
-        let mut secret = identity::secp256k1::SecretKey::generate();
-        // Check if exists local key in database
-        if let Some(k) = db.get(NodeStateKey::NodeLocalKey.to_string() as String)? {
-            secret = identity::secp256k1::SecretKey::from_bytes(k)?;
-            debug!("Found local key in database");
-        } else {
-            // Store the local key in database
-            db.insert(NodeStateKey::NodeLocalKey.to_string() as String, &secret.to_bytes())?;
-        }
-        let local_key = identity::Keypair::Secp256k1(identity::secp256k1::Keypair::from(secret));
-        // let local_key = identity::Keypair::generate_ed25519();
+        let local_key = keys::load_or_generate(&secrets, opts.key_type)?;
         let local_peer_id = PeerId::from(local_key.public());
         // Set up an encrypted DNS-enabled TCP Transport over the Mplex and Yamux protocols
         let k2 = local_key.clone();
-        let transport = libp2p::development_transport(k2).await?;
+        let transport = libp2p::tokio_development_transport(k2)?;
 
         // Create a Floodsub topic
         let floodsub_topic = floodsub::Topic::new("chat");
+        let rotation_topic = floodsub::Topic::new(KEY_ROTATION_TOPIC);
+        let artifact_topic = floodsub::Topic::new(ARTIFACT_TOPIC);
+        let kv_topic = floodsub::Topic::new(KV_TOPIC);
+        let labels_topic = floodsub::Topic::new(LABELS_TOPIC);
+        let health_topic = floodsub::Topic::new(HEALTH_TOPIC);
+        // Writes are synced to disk before the answer
+        let mut request_config = RequestResponseConfig::default();
+        request_config.set_request_timeout(Duration::from_secs(60));
+        // Raft messages are only acknowledged, a lost one is sent again on the next heartbeat
+        let mut raft_config = RequestResponseConfig::default();
+        raft_config.set_request_timeout(Duration::from_secs(5));
+        // Probes are acknowledged at once, a slow one counts as lost anyway
+        let mut swim_config = RequestResponseConfig::default();
+        swim_config.set_request_timeout(Duration::from_secs(2));
         // Create a Swarm to manage peers and events
         let swarm = {
-            let mdns = task::block_on(Mdns::new(MdnsConfig::default()))?;
+            let mdns = TokioMdns::new(MdnsConfig::default()).await?;
             let mut behaviour = MyBehaviour {
                 floodsub: Floodsub::new(local_peer_id),
                 mdns,
                 ping: Ping::new(PingConfig::new().with_interval(Duration::from_secs(5)).with_keep_alive(true)),
+                files: RequestResponse::new(
+                    FileCodec,
+                    iter::once((FileProtocol, ProtocolSupport::Full)),
+                    request_config.clone(),
+                ),
+                artifacts: RequestResponse::new(
+                    ArtifactCodec,
+                    iter::once((ArtifactProtocol, ProtocolSupport::Full)),
+                    request_config.clone(),
+                ),
+                kv: RequestResponse::new(
+                    KvCodec,
+                    iter::once((KvProtocol, ProtocolSupport::Full)),
+                    request_config.clone(),
+                ),
+                raft: RequestResponse::new(
+                    RaftCodec,
+                    iter::once((RaftProtocol, ProtocolSupport::Full)),
+                    raft_config,
+                ),
+                locks: RequestResponse::new(
+                    LockCodec,
+                    iter::once((LockProtocol, ProtocolSupport::Full)),
+                    request_config.clone(),
+                ),
+                swim: RequestResponse::new(
+                    SwimCodec,
+                    iter::once((SwimProtocol, ProtocolSupport::Full)),
+                    swim_config,
+                ),
+                services: RequestResponse::new(
+                    ServiceCodec,
+                    iter::once((ServiceProtocol, ProtocolSupport::Full)),
+                    request_config.clone(),
+                ),
+                containers: RequestResponse::new(
+                    ContainerCodec,
+                    iter::once((ContainerProtocol, ProtocolSupport::Full)),
+                    request_config.clone(),
+                ),
+                exec: RequestResponse::new(
+                    ExecCodec,
+                    iter::once((ExecProtocol, ProtocolSupport::Full)),
+                    request_config,
+                ),
             };
             behaviour.floodsub.subscribe(floodsub_topic.clone());
-            Swarm::new(transport, behaviour, local_peer_id)
+            behaviour.floodsub.subscribe(rotation_topic.clone());
+            behaviour.floodsub.subscribe(artifact_topic.clone());
+            behaviour.floodsub.subscribe(kv_topic.clone());
+            behaviour.floodsub.subscribe(labels_topic.clone());
+            behaviour.floodsub.subscribe(health_topic.clone());
+            // Connection tasks run on the runtime of the node
+            SwarmBuilder::new(transport, behaviour, local_peer_id)
+                .executor(Box::new(|fut| {
+                    tokio::spawn(fut);
+                }))
+                .build()
+        };
+        let kv_store = KvStore::open(&db, &local_key)?;
+        labels::check_labels(&opts.labels)?;
+        opts.health.check()?;
+        let supervisor = Supervisor::new(&opts.services_dir).with_cgroups();
+        for spec in opts.services {
+            supervisor.declare(spec)?;
+        }
+        let containers: Arc<dyn ContainerRuntime> = Arc::new(CliRuntime::new(&opts.container_runtime, &opts.containers_dir));
+        let reconciler = Reconciler::new(&local_peer_id.to_base58(), supervisor.clone(), containers.clone(), &db)?;
+        let raft_voters = opts.raft_voters.iter()
+            .map(|v| v.parse::<PeerId>().map_err(|_| format!("Invalid raft voter {}", v)))
+            .collect::<Result<Vec<PeerId>, String>>()?;
+        let raft = if raft_voters.contains(&local_peer_id) {
+            Some(Raft::new(RaftConfig::new(&local_peer_id.to_base58(), &opts.raft_voters), &db)?)
+        } else {
+            if !raft_voters.is_empty() {
+                info!("This node ({}) is not a raft voter, it sends its lock requests to the voters", local_peer_id);
+            }
+            None
         };
         Ok(Node {
             swarm,
-            db: db,
+            peers: Tree::Peers.open(&db)?,
+            db,
             port: opts.port,
             key: local_key,
             peer_id: local_peer_id,
             floodsub_topic,
-            message_receiver: receiver,
+            rotation_topic,
+            artifact_topic,
+            messages: queues.messages,
+            control: queues.control,
             bootnode: opts.bootnode,
             hooks,
+            file_store: FileStore::new(&opts.files_dir, opts.max_file_size),
+            file_exchanges: Exchanges::new(),
+            artifact_store: ArtifactStore::new(&opts.artifacts_dir),
+            artifact_exchanges: Exchanges::new(),
+            artifact_providers: HashMap::new(),
+            kv_topic,
+            kv_store,
+            kv_exchanges: Exchanges::new(),
+            kv_pulls: mpsc::channel(16),
+            raft,
+            raft_replies: HashMap::new(),
+            raft_voters,
+            lock_exchanges: Exchanges::new(),
+            lock_redirects: mpsc::channel(16),
+            kept_locks: HashMap::new(),
+            // The start time, greater than the incarnation of any earlier run
+            membership: Membership::new(SwimConfig::new(&local_peer_id.to_base58(), now_secs())),
+            labels_topic,
+            labels: opts.labels,
+            operators: Operators::new(&local_peer_id.to_base58(), &opts.operators)?,
+            supervisor,
+            service_exchanges: Exchanges::new(),
+            containers,
+            container_exchanges: Exchanges::new(),
+            exec_exchanges: Exchanges::new(),
+            reconciler,
+            deploy_reports: mpsc::channel(1),
+            reconciling: false,
+            cron_runner: Runner::new(&local_peer_id.to_base58()),
+            cron_runs: mpsc::channel(64),
+            // Not the minute the node started in, a restart would run it again
+            cron_minute: now_secs() / 60 * 60,
+            health_topic,
+            alerter: Alerter::new(opts.health.alerts.clone()),
+            health: opts.health,
+            health_outcomes: mpsc::channel(64),
+            alert_deliveries: mpsc::channel(64),
+            health_results: BTreeMap::new(),
+            health_reports: HashMap::new(),
         })
     }
 }
@@ -252,89 +1623,144 @@ impl NodeBehaviour for Node {
             },
         };
 
+        self.supervisor.autostart();
+        for check in self.health.checks.clone() {
+            health::schedule(check, self.health_outcomes.0.clone());
+        }
+
+        let mut kv_sync = tokio::time::interval(KV_SYNC_INTERVAL);
+        let mut raft_tick = tokio::time::interval(RAFT_TICK);
+        let mut lock_renew = tokio::time::interval(LOCK_RENEW_INTERVAL);
+        let mut swim_tick = tokio::time::interval(SWIM_TICK);
+        // Once the members had time to show up, placing on this node alone
+        // would start replicas that belong elsewhere
+        let mut deploy_tick = tokio::time::interval_at(tokio::time::Instant::now() + deploy::RECONCILE_INTERVAL, deploy::RECONCILE_INTERVAL);
+        let mut cron_tick = tokio::time::interval(CRON_TICK);
         // Kick it off
         loop {
-            let mut stop_flag = false;
-            select! {
-                msg = self.message_receiver.next() => match msg {
-                    Some(msg) => {
-                        info!("You input message: {:?}, send to everyone", msg.message);
-                        match msg.type_ {
-                            MessageType::Text => {
-                                self.swarm.behaviour_mut()
-                                    .floodsub
-                                    .publish(self.floodsub_topic.clone(), msg.message.clone().as_bytes());
-                            },
-                            MessageType::Stop => {
-                                warn!("Stopping p2p node...");
-                                stop_flag = true;
-                            },
-                            // _ => warn!("Unknown message type: {:?}", msg.type_.to_string())
-                        }
-                    },
+            let stop = tokio::select! {
+                msg = self.messages.recv() => match msg {
+                    Some(msg) => self.handle_traced_message(msg),
                     None => {
-                        error!("Error input: None");
+                        warn!("Every node handle was dropped");
+                        true
                     }
                 },
-                event = self.swarm.select_next_some() => match event {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        info!("Listening on {:?}", address);
+                command = self.control.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    None => {
+                        warn!("Every node handle was dropped");
+                        true
                     }
-                    SwarmEvent::Behaviour(OutEvent::Floodsub(
-                        FloodsubEvent::Message(message)
-                    )) => {
-                        info!(
-                            "Received: '{:?}' from {:?}",
-                            String::from_utf8_lossy(&message.data),
-                            message.source
-                        );
+                },
+                Some((channel, response)) = self.file_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().files.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the file response was sent");
                     }
-                    SwarmEvent::Behaviour(OutEvent::Ping(
-                        event
-                    )) => {
-                        debug!("Received ping from {:?}", event.peer.to_base58());
+                    false
+                },
+                Some((channel, response)) = self.artifact_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().artifacts.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the artifact response was sent");
                     }
-                    SwarmEvent::Behaviour(OutEvent::Mdns(
-                        MdnsEvent::Discovered(list)
-                    )) => {
-                        for (peer, addr) in list {
-                            // save peer
-                            self.peer_connected(peer, addr.clone());
-                            info!("Discovered {:?}", peer);
-                        }
+                    false
+                },
+                Some((channel, response)) = self.kv_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().kv.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the kv response was sent");
                     }
-                    SwarmEvent::Behaviour(OutEvent::Mdns(MdnsEvent::Expired(
-                        list
-                    ))) => {
-                        for (peer, _) in list {
-                            // save peer
-                            self.peer_disconnected(peer);
-                            // remove peer
-                            if !self.swarm.behaviour_mut().mdns.has_node(&peer) {
-                                self.swarm
-                                    .behaviour_mut()
-                                    .floodsub
-                                    .remove_node_from_partial_view(&peer);
-                            }
-                        }
-                    },
-                    SwarmEvent::ConnectionEstablished{
-                        peer_id,
-                        endpoint,
-                        num_established: _,
-                        concurrent_dial_errors: _,
-                    } => {
-                        let remote_addr = endpoint.get_remote_address();
-                        self.peer_connected(peer_id, remote_addr.clone());
-                        info!("Connection established: {:?} {:?}", peer_id, remote_addr);
+                    false
+                },
+                Some((peer, keys)) = self.kv_pulls.1.recv() => {
+                    self.kv_pull(peer, keys);
+                    false
+                },
+                Some((channel, response)) = self.lock_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().locks.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the lock response was sent");
+                    }
+                    false
+                },
+                Some((channel, response)) = self.service_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().services.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the service response was sent");
+                    }
+                    false
+                },
+                Some((channel, response)) = self.container_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().containers.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the container response was sent");
                     }
-                    _ => {}
+                    false
+                },
+                Some((channel, response)) = self.exec_exchanges.responses.1.recv() => {
+                    if self.swarm.behaviour_mut().exec.send_response(channel, response).is_err() {
+                        debug!("Peer went away before the exec response was sent");
+                    }
+                    false
+                },
+                Some((leader, req, reply)) = self.lock_redirects.1.recv() => {
+                    self.lock_send(leader, req, reply, false);
+                    false
+                },
+                _ = swim_tick.tick() => {
+                    self.membership.tick();
+                    self.swim_flush();
+                    false
+                },
+                _ = deploy_tick.tick(), if !self.reconciling => {
+                    self.reconcile();
+                    false
+                },
+                Some(reports) = self.deploy_reports.1.recv() => {
+                    self.deploy_reported(reports);
+                    false
+                },
+                _ = cron_tick.tick() => {
+                    self.run_cron();
+                    false
+                },
+                Some(run) = self.cron_runs.1.recv() => {
+                    self.cron_finished(run);
+                    false
+                },
+                Some((check, outcome)) = self.health_outcomes.1.recv() => {
+                    self.health_checked(check, outcome);
+                    false
+                },
+                Some((alert, delivered)) = self.alert_deliveries.1.recv() => {
+                    self.alerter.delivered(&alert, delivered);
+                    false
+                },
+                _ = lock_renew.tick(), if !self.kept_locks.is_empty() => {
+                    self.renew_locks();
+                    false
+                },
+                _ = raft_tick.tick(), if self.raft.is_some() => {
+                    self.raft_tick();
+                    false
+                },
+                _ = kv_sync.tick() => {
+                    // Peers that missed the announcement, floodsub does not retry
+                    self.publish_labels();
+                    self.publish_health();
+                    let peer = self.swarm.connected_peers().choose(&mut rand::thread_rng()).copied();
+                    if let Some(peer) = peer {
+                        self.kv_pull(peer, None);
+                    }
+                    false
+                },
+                event = self.swarm.select_next_some() => {
+                    let span = info_span!("swarm_event", kind = event_kind(&event));
+                    span.in_scope(|| self.handle_event(event));
+                    false
                 }
-            }
-            if stop_flag {
+            };
+            if stop {
                 break;
             }
         }
+        self.supervisor.stop_all().await;
         info!("Stopped");
         self.hooks.on_stopped();
         Ok(())
@@ -0,0 +1,29 @@
+use std::collections::BTreeSet;
+
+use libp2p::PeerId;
+
+/// Peers allowed to run workloads on this node: the deployments and cron jobs
+/// they write to the cluster kv, and the containers and commands they send.
+/// The node itself is always one, others come from `operators` in
+/// `<datadir>/config.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Operators {
+    ids: BTreeSet<String>,
+}
+
+impl Operators {
+    pub fn new(local: &str, operators: &[String]) -> Result<Operators, String> {
+        let mut ids = BTreeSet::from([local.to_string()]);
+        for id in operators {
+            if id.parse::<PeerId>().is_err() {
+                return Err(format!("Invalid operator {}, expected a peer id", id));
+            }
+            ids.insert(id.clone());
+        }
+        Ok(Operators { ids })
+    }
+
+    pub fn allows(&self, peer: &str) -> bool {
+        self.ids.contains(peer)
+    }
+}
@@ -1,7 +1,9 @@
 use std::{collections::HashSet, fmt::Display};
 
 use libp2p::{Multiaddr};
-use log::warn;
+use tracing::warn;
+
+use crate::{labels::Labels, utils::now_secs};
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,10 +15,41 @@ pub enum PeerStatus {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub id: String,
+    #[serde(default)]
     pub hostname: String,
+    #[serde(default)]
     pub host_mac: String,
+    #[serde(default)]
     pub addrs: HashSet<Multiaddr>,
     pub status: PeerStatus,
+    // Unix timestamp (seconds) of the last time the peer was seen
+    #[serde(default)]
+    pub last_seen: Option<u64>,
+    // Labels the peer announces from its config
+    #[serde(default)]
+    pub labels: Labels,
+    // Labels set on this node with `hanode tag`, they win over the announced ones
+    #[serde(default)]
+    pub tags: Labels,
+}
+
+impl Peer {
+    /// Parse a peer record as stored in the `peers` tree
+    pub fn parse(v: &[u8]) -> Result<Peer, serde_json::Error> {
+        serde_json::from_slice(v)
+    }
+
+    /// Labels matched by the selectors
+    pub fn all_labels(&self) -> Labels {
+        let mut labels = self.labels.clone();
+        labels.extend(self.tags.clone());
+        labels
+    }
+
+    // Record that the peer has just been seen
+    pub fn touch(&mut self) {
+        self.last_seen = Some(now_secs());
+    }
 }
 
 impl Display for Peer {
@@ -31,21 +64,3 @@ impl Display for Peer {
         Ok(())
     }
 }
-
-impl From<String> for Peer {
-    fn from(id: String) -> Self {
-        match serde_json::from_str(&id) {
-            Ok(p) => p,
-            Err(e) => {
-                warn!("Failed to parse peer id: {}", e);
-                Peer {
-                    id: "".to_string(),
-                    hostname: String::new(),
-                    host_mac: String::new(),
-                    addrs: HashSet::new(),
-                    status: PeerStatus::Disconnected,
-                }
-            }
-        }
-    }
-}
@@ -0,0 +1,860 @@
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, error::Error, io};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{files::{read_frames, write_frames}, lock::Lock, schema::Tree};
+
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+// Entries per append, a follower far behind catches up over several rounds.
+// Values are limited to `kv::MAX_VALUE_SIZE` so an append fits a frame.
+const MAX_APPEND_ENTRIES: usize = 32;
+
+const TERM_KEY: &str = "term";
+const VOTED_FOR_KEY: &str = "voted_for";
+const APPLIED_KEY: &str = "applied";
+// Greatest leader time applied, lock expiry never goes back with the leader
+const CLOCK_KEY: &str = "clock";
+
+#[derive(Debug, Clone)]
+pub struct RaftProtocol;
+
+impl ProtocolName for RaftProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/raft/1.0.0"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    // Peer id of this node
+    pub id: String,
+    // Peer ids of every voter, this node included
+    pub voters: Vec<String>,
+    pub heartbeat_ticks: u32,
+    // A follower that heard nothing from a leader for a number of ticks
+    // picked in this range starts an election
+    pub election_ticks: (u32, u32),
+}
+
+impl RaftConfig {
+    pub fn new(id: &str, voters: &[String]) -> RaftConfig {
+        RaftConfig { id: id.to_string(), voters: voters.to_vec(), heartbeat_ticks: 2, election_ticks: (10, 20) }
+    }
+}
+
+/// Operations on the replicated state, a map of strings and the locks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RaftCommand {
+    // Appended by a new leader to commit the entries of earlier terms
+    Noop,
+    Set { key: String, value: String },
+    Delete { key: String },
+    // `now` is the unix time in milliseconds of the proposing leader, so
+    // every voter expires the locks alike
+    Acquire { name: String, holder: String, ttl: u64, now: u64 },
+    Release { name: String, holder: String, token: Option<u64> },
+    // Locks of a node that went away
+    ReleaseAll { holder: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: RaftCommand,
+}
+
+/// Messages between voters, each sent as a request answered with a
+/// `RaftAck`, the replies are messages of their own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RaftMessage {
+    RequestVote { term: u64, candidate: String, last_log_index: u64, last_log_term: u64 },
+    Vote { term: u64, granted: bool },
+    // `round` is echoed in the result, the leader knows a majority still
+    // follows it when it serves a read
+    AppendEntries { term: u64, leader: String, prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, leader_commit: u64, round: u64 },
+    // On failure `match_index` is where the log of the follower ends
+    AppendResult { term: u64, success: bool, match_index: u64, round: u64 },
+}
+
+impl RaftMessage {
+    fn term(&self) -> u64 {
+        match self {
+            RaftMessage::RequestVote { term, .. } => *term,
+            RaftMessage::Vote { term, .. } => *term,
+            RaftMessage::AppendEntries { term, .. } => *term,
+            RaftMessage::AppendResult { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftAck;
+
+#[derive(Debug, Clone)]
+pub struct RaftCodec;
+
+#[async_trait]
+impl RequestResponseCodec for RaftCodec {
+    type Protocol = RaftProtocol;
+    type Request = RaftMessage;
+    type Response = RaftAck;
+
+    async fn read_request<T>(&mut self, _: &RaftProtocol, io: &mut T) -> io::Result<RaftMessage>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (msg, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(msg)
+    }
+
+    async fn read_response<T>(&mut self, _: &RaftProtocol, io: &mut T) -> io::Result<RaftAck>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (ack, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(ack)
+    }
+
+    async fn write_request<T>(&mut self, _: &RaftProtocol, io: &mut T, msg: RaftMessage) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &msg, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &RaftProtocol, io: &mut T, ack: RaftAck) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &ack, &[]).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaftStatus {
+    pub id: String,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub voters: Vec<String>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub last_log_index: u64,
+}
+
+/// A key of the replicated state, None when it is not set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateEntry {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+/// Value read, None for writes. Lock commands fail when refused, an
+/// acquisition gives the `Lock` as JSON, that of the other holder when it is
+/// held.
+pub type RaftResult = Result<Option<String>, String>;
+
+// The log, the term and vote that must survive a restart, and the state the
+// committed entries were applied to
+#[derive(Debug, Clone)]
+struct RaftStorage {
+    log: sled::Tree,
+    meta: sled::Tree,
+    state: sled::Tree,
+    locks: sled::Tree,
+}
+
+fn be_u64(v: &[u8]) -> Result<u64, Box<dyn Error>> {
+    let bytes: [u8; 8] = v.try_into().map_err(|_| "Invalid raft index")?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl RaftStorage {
+    fn open(db: &sled::Db) -> sled::Result<RaftStorage> {
+        Ok(RaftStorage {
+            log: Tree::RaftLog.open(db)?,
+            meta: Tree::RaftMeta.open(db)?,
+            state: Tree::RaftState.open(db)?,
+            locks: Tree::RaftLocks.open(db)?,
+        })
+    }
+
+    fn get_u64(&self, key: &str) -> Result<u64, Box<dyn Error>> {
+        match self.meta.get(key)? {
+            Some(v) => be_u64(&v),
+            None => Ok(0),
+        }
+    }
+
+    fn voted_for(&self) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self.meta.get(VOTED_FOR_KEY)?.map(|v| String::from_utf8_lossy(&v).to_string()))
+    }
+
+    // Synced before any message that depends on it is sent
+    fn save_hard_state(&self, term: u64, voted_for: &Option<String>) -> Result<(), Box<dyn Error>> {
+        self.meta.insert(TERM_KEY, &term.to_be_bytes())?;
+        match voted_for {
+            Some(id) => self.meta.insert(VOTED_FOR_KEY, id.as_bytes())?,
+            None => self.meta.remove(VOTED_FOR_KEY)?,
+        };
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    fn last_index(&self) -> Result<u64, Box<dyn Error>> {
+        match self.log.last()? {
+            Some((k, _)) => be_u64(&k),
+            None => Ok(0),
+        }
+    }
+
+    fn entry(&self, index: u64) -> Result<Option<Entry>, Box<dyn Error>> {
+        match self.log.get(index.to_be_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn term_at(&self, index: u64) -> Result<Option<u64>, Box<dyn Error>> {
+        if index == 0 {
+            return Ok(Some(0));
+        }
+        Ok(self.entry(index)?.map(|e| e.term))
+    }
+
+    fn entries(&self, from: u64, max: usize) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        for item in self.log.range(from.to_be_bytes()..).take(max) {
+            let (_, v) = item?;
+            entries.push(serde_json::from_slice(&v)?);
+        }
+        Ok(entries)
+    }
+
+    fn append(&self, entries: &[Entry]) -> Result<(), Box<dyn Error>> {
+        let mut batch = sled::Batch::default();
+        for entry in entries {
+            batch.insert(&entry.index.to_be_bytes(), serde_json::to_vec(entry)?);
+        }
+        self.log.apply_batch(batch)?;
+        self.log.flush()?;
+        Ok(())
+    }
+
+    // Remove the entries from `index` on, they conflict with the leader
+    fn truncate(&self, index: u64) -> Result<(), Box<dyn Error>> {
+        let mut batch = sled::Batch::default();
+        for item in self.log.range(index.to_be_bytes()..) {
+            let (k, _) = item?;
+            batch.remove(k);
+        }
+        self.log.apply_batch(batch)?;
+        Ok(())
+    }
+
+    // Applying twice is harmless, an entry may be applied again after a crash.
+    // Errors are those of the storage, the result those of the command.
+    fn apply(&self, entry: &Entry) -> Result<RaftResult, Box<dyn Error>> {
+        let result = match &entry.command {
+            RaftCommand::Noop => Ok(None),
+            RaftCommand::Set { key, value } => {
+                self.state.insert(key.as_bytes(), value.as_bytes())?;
+                Ok(None)
+            },
+            RaftCommand::Delete { key } => {
+                self.state.remove(key.as_bytes())?;
+                Ok(None)
+            },
+            RaftCommand::Acquire { name, holder, ttl, now } => self.acquire(entry.index, name, holder, *ttl, *now)?,
+            RaftCommand::Release { name, holder, token } => {
+                match self.lock(name)? {
+                    Some(lock) if lock.holder == *holder && token.map(|t| t == lock.token).unwrap_or(true) => {
+                        self.locks.remove(name.as_bytes())?;
+                        Ok(None)
+                    },
+                    Some(lock) if lock.holder == *holder => Err(format!("Lock {} has token {}", name, lock.token)),
+                    Some(lock) => Err(format!("Lock {} is held by {}", name, lock.holder)),
+                    None => Err(format!("Lock {} is not held", name)),
+                }
+            },
+            RaftCommand::ReleaseAll { holder } => {
+                for lock in self.all_locks()? {
+                    if lock.holder == *holder {
+                        self.locks.remove(lock.name.as_bytes())?;
+                    }
+                }
+                Ok(None)
+            },
+        };
+        self.meta.insert(APPLIED_KEY, &entry.index.to_be_bytes())?;
+        Ok(result)
+    }
+
+    // A new holder gets the index of the entry as its token, a lock held by
+    // another node is left as it is
+    fn acquire(&self, index: u64, name: &str, holder: &str, ttl: u64, now: u64) -> Result<RaftResult, Box<dyn Error>> {
+        let now = now.max(self.get_u64(CLOCK_KEY)?);
+        self.meta.insert(CLOCK_KEY, &now.to_be_bytes())?;
+        let token = match self.lock(name)? {
+            Some(lock) if lock.expires > now && lock.holder != holder => return Ok(Ok(Some(serde_json::to_string(&lock)?))),
+            Some(lock) if lock.expires > now => lock.token,
+            _ => index,
+        };
+        let lock = Lock { name: name.to_string(), holder: holder.to_string(), token, expires: now + ttl };
+        self.locks.insert(name.as_bytes(), serde_json::to_vec(&lock)?)?;
+        Ok(Ok(Some(serde_json::to_string(&lock)?)))
+    }
+
+    fn lock(&self, name: &str) -> Result<Option<Lock>, Box<dyn Error>> {
+        match self.locks.get(name.as_bytes())? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Expired ones included, they stay until acquired or released
+    fn all_locks(&self) -> Result<Vec<Lock>, Box<dyn Error>> {
+        let mut locks = Vec::new();
+        for item in self.locks.iter() {
+            let (_, v) = item?;
+            locks.push(serde_json::from_slice(&v)?);
+        }
+        Ok(locks)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self.state.get(key.as_bytes())?.map(|v| String::from_utf8_lossy(&v).to_string()))
+    }
+}
+
+struct PendingRead {
+    ticket: u64,
+    key: String,
+    // Served once this entry is applied
+    index: u64,
+    // and a majority answered this heartbeat round
+    round: u64,
+}
+
+/// A Raft voter. It does no I/O besides its storage: the owner calls `tick`
+/// at a steady pace, hands it the messages of the other voters with `step`
+/// and sends what `take_outbox` returns, so the same code runs over the p2p
+/// request-response layer and over `MemoryNetwork` in tests. Requests get a
+/// ticket, their results come out of `take_results`.
+pub struct Raft {
+    config: RaftConfig,
+    storage: RaftStorage,
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    commit_index: u64,
+    last_applied: u64,
+    elapsed: u32,
+    timeout: u32,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    // Heartbeat round of the leader, and the last one each follower answered
+    round: u64,
+    acked: HashMap<String, u64>,
+    // Entry appended when this node became leader
+    leader_start: u64,
+    // Log index of the proposals, with their ticket and term
+    proposals: HashMap<u64, (u64, u64)>,
+    reads: Vec<PendingRead>,
+    next_ticket: u64,
+    outbox: Vec<(String, RaftMessage)>,
+    results: Vec<(u64, RaftResult)>,
+}
+
+impl Raft {
+    pub fn new(config: RaftConfig, db: &sled::Db) -> Result<Raft, Box<dyn Error>> {
+        let storage = RaftStorage::open(db)?;
+        let last_applied = storage.get_u64(APPLIED_KEY)?;
+        let mut raft = Raft {
+            term: storage.get_u64(TERM_KEY)?,
+            voted_for: storage.voted_for()?,
+            config,
+            storage,
+            role: Role::Follower,
+            leader: None,
+            commit_index: last_applied,
+            last_applied,
+            elapsed: 0,
+            timeout: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            round: 0,
+            acked: HashMap::new(),
+            leader_start: 0,
+            proposals: HashMap::new(),
+            reads: Vec::new(),
+            next_ticket: 0,
+            outbox: Vec::new(),
+            results: Vec::new(),
+        };
+        raft.reset_timeout();
+        Ok(raft)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        RaftStatus {
+            id: self.config.id.clone(),
+            role: self.role,
+            term: self.term,
+            leader: self.leader.clone(),
+            voters: self.config.voters.clone(),
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            last_log_index: self.storage.last_index().unwrap_or(0),
+        }
+    }
+
+    /// The value this node applied, which may be behind the leader
+    pub fn applied(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        self.storage.get(key)
+    }
+
+    /// The locks this node applied, expired ones included
+    pub fn locks(&self) -> Result<Vec<Lock>, Box<dyn Error>> {
+        self.storage.all_locks()
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(String, RaftMessage)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub fn take_results(&mut self) -> Vec<(u64, RaftResult)> {
+        std::mem::take(&mut self.results)
+    }
+
+    fn others(&self) -> Vec<String> {
+        self.config.voters.iter().filter(|v| **v != self.config.id).cloned().collect()
+    }
+
+    fn quorum(&self) -> usize {
+        self.config.voters.len() / 2 + 1
+    }
+
+    fn reset_timeout(&mut self) {
+        let (min, max) = self.config.election_ticks;
+        self.elapsed = 0;
+        self.timeout = rand::thread_rng().gen_range(min..=max);
+    }
+
+    fn send(&mut self, to: &str, msg: RaftMessage) {
+        self.outbox.push((to.to_string(), msg));
+    }
+
+    fn ticket(&mut self) -> u64 {
+        self.next_ticket += 1;
+        self.next_ticket
+    }
+
+    fn last_log(&self) -> Result<(u64, u64), Box<dyn Error>> {
+        let index = self.storage.last_index()?;
+        Ok((index, self.storage.term_at(index)?.unwrap_or(0)))
+    }
+
+    pub fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= self.config.heartbeat_ticks => self.broadcast(),
+            Role::Follower | Role::Candidate if self.elapsed >= self.timeout => self.campaign(),
+            _ => Ok(()),
+        }
+    }
+
+    fn campaign(&mut self) -> Result<(), Box<dyn Error>> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.config.id.clone());
+        self.leader = None;
+        self.storage.save_hard_state(self.term, &self.voted_for)?;
+        self.reset_timeout();
+        self.votes = [self.config.id.clone()].into_iter().collect();
+        info!("Starting a raft election for term {}", self.term);
+        if self.votes.len() >= self.quorum() {
+            return self.become_leader();
+        }
+        let (last_log_index, last_log_term) = self.last_log()?;
+        for peer in self.others() {
+            let candidate = self.config.id.clone();
+            self.send(&peer, RaftMessage::RequestVote { term: self.term, candidate, last_log_index, last_log_term });
+        }
+        Ok(())
+    }
+
+    fn fail_pending(&mut self) {
+        for (_, (ticket, _)) in self.proposals.drain() {
+            self.results.push((ticket, Err("Leadership was lost, the write may or may not be applied".to_string())));
+        }
+        for read in std::mem::take(&mut self.reads) {
+            self.results.push((read.ticket, Err("Leadership was lost, retry the read".to_string())));
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) -> Result<(), Box<dyn Error>> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.storage.save_hard_state(self.term, &self.voted_for)?;
+        }
+        if self.role == Role::Leader {
+            info!("No longer the raft leader, term {}", self.term);
+            self.fail_pending();
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timeout();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Elected raft leader for term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.config.id.clone());
+        let last = self.storage.last_index()?;
+        self.next_index.clear();
+        self.match_index.clear();
+        self.acked.clear();
+        for peer in self.others() {
+            self.next_index.insert(peer.clone(), last + 1);
+            self.match_index.insert(peer, 0);
+        }
+        // Entries of earlier terms only commit along with one of this term
+        self.leader_start = self.append(RaftCommand::Noop)?;
+        self.advance_commit()?;
+        self.broadcast()
+    }
+
+    fn append(&mut self, command: RaftCommand) -> Result<u64, Box<dyn Error>> {
+        let index = self.storage.last_index()? + 1;
+        self.storage.append(&[Entry { term: self.term, index, command }])?;
+        Ok(index)
+    }
+
+    // Heartbeat, carrying the entries each follower misses
+    fn broadcast(&mut self) -> Result<(), Box<dyn Error>> {
+        self.elapsed = 0;
+        self.round += 1;
+        for peer in self.others() {
+            self.send_append(&peer)?;
+        }
+        self.serve_reads();
+        Ok(())
+    }
+
+    fn send_append(&mut self, peer: &str) -> Result<(), Box<dyn Error>> {
+        let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = next - 1;
+        let prev_log_term = self.storage.term_at(prev_log_index)?.unwrap_or(0);
+        let entries = self.storage.entries(next, MAX_APPEND_ENTRIES)?;
+        let msg = RaftMessage::AppendEntries {
+            term: self.term,
+            leader: self.config.id.clone(),
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+            round: self.round,
+        };
+        self.send(peer, msg);
+        Ok(())
+    }
+
+    // Commit the last entry of this term stored by a majority
+    fn advance_commit(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut index = self.storage.last_index()?;
+        while index > self.commit_index && self.storage.term_at(index)? == Some(self.term) {
+            let replicas = 1 + self.match_index.values().filter(|m| **m >= index).count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+            index -= 1;
+        }
+        self.apply()
+    }
+
+    fn apply(&mut self) -> Result<(), Box<dyn Error>> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self.storage.entry(index)?.ok_or_else(|| format!("The raft log has no entry {}", index))?;
+            let result = self.storage.apply(&entry)?;
+            self.last_applied = index;
+            if let Some((ticket, term)) = self.proposals.remove(&index) {
+                let result = match term == entry.term {
+                    true => result,
+                    false => Err("Leadership changed before the write was committed".to_string()),
+                };
+                self.results.push((ticket, result));
+            }
+        }
+        self.serve_reads();
+        Ok(())
+    }
+
+    fn serve_reads(&mut self) {
+        let mut waiting = Vec::new();
+        for read in std::mem::take(&mut self.reads) {
+            let confirmed = 1 + self.acked.values().filter(|r| **r >= read.round).count() >= self.quorum();
+            if confirmed && self.last_applied >= read.index {
+                let value = self.storage.get(&read.key).map_err(|e| e.to_string());
+                self.results.push((read.ticket, value));
+            } else {
+                waiting.push(read);
+            }
+        }
+        self.reads = waiting;
+    }
+
+    fn check_leader(&self) -> Result<(), String> {
+        match (self.role, &self.leader) {
+            (Role::Leader, _) => Ok(()),
+            (_, Some(leader)) => Err(format!("Not the raft leader, send the request to {}", leader)),
+            (_, None) => Err("No raft leader elected yet, retry later".to_string()),
+        }
+    }
+
+    /// Replicate a write, the result comes out once a majority stored it
+    pub fn propose(&mut self, command: RaftCommand) -> Result<u64, String> {
+        self.check_leader()?;
+        let ticket = self.ticket();
+        let index = self.append(command).map_err(|e| e.to_string())?;
+        self.proposals.insert(index, (ticket, self.term));
+        self.advance_commit().map_err(|e| e.to_string())?;
+        self.broadcast().map_err(|e| e.to_string())?;
+        Ok(ticket)
+    }
+
+    /// Linearizable read: served once this node applied everything committed
+    /// when the read arrived and a majority confirmed it is still the leader
+    pub fn read(&mut self, key: &str) -> Result<u64, String> {
+        self.check_leader()?;
+        let ticket = self.ticket();
+        self.reads.push(PendingRead {
+            ticket,
+            key: key.to_string(),
+            index: self.commit_index.max(self.leader_start),
+            round: self.round + 1,
+        });
+        self.broadcast().map_err(|e| e.to_string())?;
+        Ok(ticket)
+    }
+
+    /// Handle a message of another voter
+    pub fn step(&mut self, from: &str, msg: RaftMessage) -> Result<(), Box<dyn Error>> {
+        if !self.config.voters.iter().any(|v| v == from) {
+            warn!("Ignoring raft message from {}, it is not a voter", from);
+            return Ok(());
+        }
+        if msg.term() > self.term {
+            let leader = match &msg {
+                RaftMessage::AppendEntries { leader, .. } => Some(leader.clone()),
+                _ => None,
+            };
+            self.become_follower(msg.term(), leader)?;
+        }
+        match msg {
+            RaftMessage::RequestVote { term, candidate, last_log_index, last_log_term } => {
+                let (index, log_term) = self.last_log()?;
+                let granted = term == self.term
+                    && (last_log_term, last_log_index) >= (log_term, index)
+                    && self.voted_for.as_ref().map(|v| *v == candidate).unwrap_or(true);
+                if granted {
+                    self.voted_for = Some(candidate);
+                    self.storage.save_hard_state(self.term, &self.voted_for)?;
+                    self.reset_timeout();
+                }
+                self.send(from, RaftMessage::Vote { term: self.term, granted });
+            },
+            RaftMessage::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from.to_string());
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+            },
+            RaftMessage::AppendEntries { term, leader, prev_log_index, prev_log_term, entries, leader_commit, round } => {
+                if term < self.term {
+                    self.send(from, RaftMessage::AppendResult { term: self.term, success: false, match_index: 0, round });
+                    return Ok(());
+                }
+                // Candidates of the same term lost the election
+                self.become_follower(term, Some(leader))?;
+                if self.storage.term_at(prev_log_index)? != Some(prev_log_term) {
+                    let hint = self.storage.last_index()?.min(prev_log_index.saturating_sub(1));
+                    self.send(from, RaftMessage::AppendResult { term: self.term, success: false, match_index: hint, round });
+                    return Ok(());
+                }
+                let match_index = prev_log_index + entries.len() as u64;
+                let mut new = Vec::new();
+                for entry in entries {
+                    match self.storage.term_at(entry.index)? {
+                        Some(t) if t == entry.term => {},
+                        Some(_) => {
+                            self.storage.truncate(entry.index)?;
+                            new.push(entry);
+                        },
+                        None => new.push(entry),
+                    }
+                }
+                if !new.is_empty() {
+                    self.storage.append(&new)?;
+                }
+                if leader_commit > self.commit_index {
+                    self.commit_index = leader_commit.min(match_index).max(self.commit_index);
+                    self.apply()?;
+                }
+                self.send(from, RaftMessage::AppendResult { term: self.term, success: true, match_index, round });
+            },
+            RaftMessage::AppendResult { term, success, match_index, round } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Ok(());
+                }
+                let acked = self.acked.entry(from.to_string()).or_insert(0);
+                *acked = (*acked).max(round);
+                if success {
+                    let matched = self.match_index.entry(from.to_string()).or_insert(0);
+                    *matched = (*matched).max(match_index);
+                    let next = *matched + 1;
+                    self.next_index.insert(from.to_string(), next);
+                    self.advance_commit()?;
+                    if next <= self.storage.last_index()? {
+                        self.send_append(from)?;
+                    }
+                } else {
+                    let next = self.next_index.get(from).copied().unwrap_or(1);
+                    self.next_index.insert(from.to_string(), (match_index + 1).min(next.saturating_sub(1)).max(1));
+                    self.send_append(from)?;
+                }
+                self.serve_reads();
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Voters in one process whose messages are delivered in memory, to check
+/// elections and replication without a network
+pub struct MemoryNetwork {
+    pub nodes: BTreeMap<String, Raft>,
+    // Messages in flight, as (from, to, message)
+    queue: VecDeque<(String, String, RaftMessage)>,
+    // Nodes cut off from the others
+    isolated: HashSet<String>,
+    results: HashMap<(String, u64), RaftResult>,
+}
+
+impl MemoryNetwork {
+    /// One voter per id, each with a temporary database
+    pub fn new(ids: &[&str]) -> Result<MemoryNetwork, Box<dyn Error>> {
+        let voters: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        let mut nodes = BTreeMap::new();
+        for id in ids {
+            let db = sled::Config::new().temporary(true).open()?;
+            nodes.insert(id.to_string(), Raft::new(RaftConfig::new(id, &voters), &db)?);
+        }
+        Ok(MemoryNetwork { nodes, queue: VecDeque::new(), isolated: HashSet::new(), results: HashMap::new() })
+    }
+
+    /// Drop the messages from and to a node until `heal`
+    pub fn isolate(&mut self, id: &str) {
+        self.isolated.insert(id.to_string());
+    }
+
+    pub fn heal(&mut self, id: &str) {
+        self.isolated.remove(id);
+    }
+
+    pub fn node(&mut self, id: &str) -> &mut Raft {
+        self.nodes.get_mut(id).expect("no such raft node")
+    }
+
+    /// Advance every node by one tick, then deliver the messages
+    pub fn tick(&mut self) -> Result<(), Box<dyn Error>> {
+        for raft in self.nodes.values_mut() {
+            raft.tick()?;
+        }
+        self.deliver()
+    }
+
+    /// Deliver messages until none are left
+    pub fn deliver(&mut self) -> Result<(), Box<dyn Error>> {
+        loop {
+            for (id, raft) in self.nodes.iter_mut() {
+                for (to, msg) in raft.take_outbox() {
+                    self.queue.push_back((id.clone(), to, msg));
+                }
+                for (ticket, result) in raft.take_results() {
+                    self.results.insert((id.clone(), ticket), result);
+                }
+            }
+            let (from, to, msg) = match self.queue.pop_front() {
+                Some(m) => m,
+                None => return Ok(()),
+            };
+            if self.isolated.contains(&from) || self.isolated.contains(&to) {
+                continue;
+            }
+            if let Some(raft) = self.nodes.get_mut(&to) {
+                raft.step(&from, msg)?;
+            }
+        }
+    }
+
+    /// Leader of the highest term among the nodes that are not isolated
+    pub fn leader(&self) -> Option<String> {
+        self.nodes.values()
+            .filter(|raft| raft.role() == Role::Leader && !self.isolated.contains(raft.id()))
+            .max_by_key(|raft| raft.status().term)
+            .map(|raft| raft.id().to_string())
+    }
+
+    /// Tick until a node that is not isolated leads, at most `ticks` times
+    pub fn elect(&mut self, ticks: usize) -> Result<Option<String>, Box<dyn Error>> {
+        for _ in 0..ticks {
+            if let Some(leader) = self.leader() {
+                return Ok(Some(leader));
+            }
+            self.tick()?;
+        }
+        Ok(self.leader())
+    }
+
+    /// Result of a request made to `id`, once it is out
+    pub fn result(&mut self, id: &str, ticket: u64) -> Option<RaftResult> {
+        self.results.remove(&(id.to_string(), ticket))
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeSet, HashMap};
+use serde::{Deserialize, Serialize};
+
+use crate::{deploy::{DeployState, DeployStatus, Deployment, REPORT_INTERVAL}, utils::now_secs};
+
+/// Seconds a wave has to become ready unless given
+pub const DEFAULT_TIMEOUT: u64 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloutState {
+    Progressing,
+    // On a failure or a timeout, or by hand, until resumed or undone
+    Paused,
+    Complete,
+}
+
+/// Progress of moving the nodes of a deployment to its version, kept in the
+/// spec so every node knows which version to run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rollout {
+    pub state: RolloutState,
+    // Nodes per wave, a count or a percentage of the nodes as in 10%
+    pub batch: String,
+    // Nodes of the deployment that may be not ready at once
+    pub max_unavailable: usize,
+    // Seconds the nodes moved have to become ready before the rollout pauses
+    pub timeout: u64,
+    // Node moving the rollout on, the one it was started or resumed on
+    pub driver: String,
+    // Nodes moved to the version of the spec, the others run the previous one
+    pub nodes: BTreeSet<String>,
+    // Nodes of the current wave, and when nodes were last moved
+    pub wave: Vec<String>,
+    pub moved: u64,
+    pub waves: usize,
+    pub message: Option<String>,
+}
+
+/// How `hanode rollout` goes through the nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RolloutOptions {
+    pub batch: String,
+    pub max_unavailable: usize,
+    pub timeout: u64,
+}
+
+impl RolloutOptions {
+    pub fn check(&self) -> Result<(), String> {
+        batch_size(&self.batch, 1)?;
+        if self.max_unavailable == 0 {
+            return Err("At least one node must be allowed to be unavailable".to_string());
+        }
+        if self.timeout == 0 {
+            return Err("The timeout of a wave must be at least a second".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Body of a request starting a rollout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutRequest {
+    pub deployment: Deployment,
+    pub options: RolloutOptions,
+}
+
+/// Nodes of a wave out of `nodes`, at least one
+pub fn batch_size(batch: &str, nodes: usize) -> Result<usize, String> {
+    let size = match batch.strip_suffix('%') {
+        Some(percent) => match percent.parse::<f64>() {
+            Ok(p) if p > 0.0 && p <= 100.0 => (nodes as f64 * p / 100.0).ceil() as usize,
+            _ => return Err(format!("Invalid batch {}, use a percentage between 0 and 100", batch)),
+        },
+        None => match batch.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("Invalid batch {}, use a number of nodes or a percentage as in 10%", batch)),
+        },
+    };
+    Ok(size.max(1))
+}
+
+/// `current` moving to the version of `next` in waves, driven by `driver`
+pub fn start(current: &Deployment, next: Deployment, options: &RolloutOptions, driver: &str) -> Result<Deployment, String> {
+    next.check()?;
+    options.check()?;
+    if next.name() != current.name() {
+        return Err(format!("Deployment {} is not {}", next.name(), current.name()));
+    }
+    if next.version == current.version {
+        return Err(format!("Deployment {} is already at version {}", current.name(), current.version));
+    }
+    // Nodes not moved yet by an unfinished rollout run the previous version
+    let previous = match &current.rollout {
+        Some(rollout) if rollout.state != RolloutState::Complete => current.previous.clone(),
+        _ => Some(current.revision()),
+    };
+    let rollout = Rollout {
+        state: RolloutState::Progressing,
+        batch: options.batch.clone(),
+        max_unavailable: options.max_unavailable,
+        timeout: options.timeout,
+        driver: driver.to_string(),
+        nodes: BTreeSet::new(),
+        wave: Vec::new(),
+        moved: now_secs(),
+        waves: 0,
+        message: None,
+    };
+    Ok(Deployment { previous, rollout: Some(rollout), ..next })
+}
+
+/// Back to the previous version on every node at once
+pub fn undo(current: &Deployment) -> Result<Deployment, String> {
+    let previous = current.previous.clone().ok_or_else(|| format!("Deployment {} has no previous version", current.name()))?;
+    Ok(Deployment {
+        version: previous.version,
+        workload: previous.workload,
+        previous: Some(current.revision()),
+        rollout: None,
+        ..current.clone()
+    })
+}
+
+pub fn pause(current: &Deployment) -> Result<Deployment, String> {
+    let mut deployment = current.clone();
+    match &mut deployment.rollout {
+        Some(rollout) if rollout.state == RolloutState::Progressing => {
+            rollout.state = RolloutState::Paused;
+            rollout.message = Some("paused by hand".to_string());
+        },
+        _ => return Err(format!("No rollout of {} in progress", current.name())),
+    }
+    Ok(deployment)
+}
+
+/// The paused rollout going on, driven by `driver` from now on
+pub fn resume(current: &Deployment, driver: &str) -> Result<Deployment, String> {
+    let mut deployment = current.clone();
+    match &mut deployment.rollout {
+        Some(rollout) if rollout.state == RolloutState::Paused => {
+            rollout.state = RolloutState::Progressing;
+            rollout.driver = driver.to_string();
+            rollout.moved = now_secs();
+            rollout.message = None;
+        },
+        _ => return Err(format!("No paused rollout of {}", current.name())),
+    }
+    Ok(deployment)
+}
+
+/// The rollout moved on from the statuses the nodes reported: the next nodes
+/// of the wave are moved within the unavailability budget, the next wave
+/// starts once the nodes moved are ready on the new version, and the rollout
+/// pauses when one of them fails or takes longer than the timeout. None when
+/// nothing changed.
+pub fn step(deployment: &Deployment, statuses: &[DeployStatus], now: u64) -> Option<Rollout> {
+    let mut rollout = deployment.rollout.clone().filter(|r| r.state == RolloutState::Progressing)?;
+    // Nodes running the deployment, older reports are from nodes gone
+    let targets: HashMap<&str, &DeployStatus> = statuses.iter()
+        .filter(|s| s.updated + 3 * REPORT_INTERVAL >= now)
+        .map(|s| (s.node.as_str(), s))
+        .collect();
+    let ready = |node: &str| matches!(targets.get(node),
+        Some(s) if s.state == DeployState::Ready && s.version.as_deref() == Some(deployment.version.as_str()));
+
+    let moved: Vec<&String> = rollout.nodes.iter().filter(|n| targets.contains_key(n.as_str())).collect();
+    if let Some(failed) = moved.iter().map(|n| targets[n.as_str()]).find(|s| s.state == DeployState::Failed) {
+        rollout.state = RolloutState::Paused;
+        rollout.message = Some(format!("{} failed: {}", failed.node, failed.message.as_deref().unwrap_or("unknown error")));
+        return Some(rollout);
+    }
+    let pending: Vec<String> = moved.into_iter().filter(|n| !ready(n)).cloned().collect();
+    if !pending.is_empty() && now >= rollout.moved + rollout.timeout {
+        rollout.state = RolloutState::Paused;
+        rollout.message = Some(format!("{} not ready on version {} after {}s", pending.join(", "), deployment.version, rollout.timeout));
+        return Some(rollout);
+    }
+
+    let before = rollout.clone();
+    // Nodes of the wave gone before they moved would hold it forever
+    rollout.wave.retain(|n| targets.contains_key(n.as_str()));
+    if pending.is_empty() && rollout.wave.iter().all(|n| rollout.nodes.contains(n)) {
+        if !rollout.wave.is_empty() {
+            rollout.wave.clear();
+            rollout.waves += 1;
+        }
+        let mut remaining: Vec<&str> = targets.keys().copied().filter(|n| !rollout.nodes.contains(*n)).collect();
+        remaining.sort_unstable();
+        if remaining.is_empty() {
+            rollout.state = RolloutState::Complete;
+            rollout.message = Some(format!("{} nodes on version {}", rollout.nodes.len(), deployment.version));
+            return Some(rollout);
+        }
+        let size = batch_size(&rollout.batch, targets.len()).unwrap_or(1);
+        rollout.wave = remaining.into_iter().take(size).map(|n| n.to_string()).collect();
+    }
+    // Nodes not ready for other reasons count against the budget too
+    let unavailable = pending.len() + targets.values()
+        .filter(|s| !rollout.nodes.contains(&s.node) && s.state != DeployState::Ready)
+        .count();
+    let budget = rollout.max_unavailable.saturating_sub(unavailable);
+    let next: Vec<String> = rollout.wave.iter().filter(|n| !rollout.nodes.contains(*n)).take(budget).cloned().collect();
+    if !next.is_empty() {
+        rollout.nodes.extend(next);
+        rollout.moved = now;
+    }
+    match rollout != before {
+        true => Some(rollout),
+        false => None,
+    }
+}
@@ -0,0 +1,221 @@
+use std::error::Error;
+
+use sled::{transaction::ConflictableTransactionError, Transactional};
+use tracing::{info, warn};
+
+use crate::{node::NodeStateKey, peer::Peer};
+
+/// Version of the database layout written by this build
+pub const SCHEMA_VERSION: u32 = 6;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Named sled trees of the node database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tree {
+    // Schema version and other bookkeeping
+    Meta,
+    // Node identity: key type, key rotation and the key itself when not encrypted
+    Node,
+    // Known peers keyed by peer id
+    Peers,
+    // Records the migrations could not parse, kept for manual inspection
+    Quarantine,
+    // Encrypted secrets and the wrapped data key
+    Secrets,
+    SecretsMeta,
+    // Cluster key-value records replicated to every node
+    Kv,
+    // Raft log, term and vote, and the state the log was applied to
+    RaftLog,
+    RaftMeta,
+    RaftState,
+    // Locks granted by the raft group
+    RaftLocks,
+    // Named label selectors
+    Groups,
+    // Deployments this node applied, by name
+    Deployed,
+}
+
+pub const TREES: [Tree; 13] = [
+    Tree::Meta, Tree::Node, Tree::Peers, Tree::Quarantine, Tree::Secrets, Tree::SecretsMeta,
+    Tree::Kv, Tree::RaftLog, Tree::RaftMeta, Tree::RaftState, Tree::RaftLocks,
+    Tree::Groups, Tree::Deployed,
+];
+
+impl Tree {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tree::Meta => "meta",
+            Tree::Node => "node",
+            Tree::Peers => "peers",
+            Tree::Quarantine => "quarantine",
+            Tree::Secrets => "secrets",
+            Tree::SecretsMeta => "secrets_meta",
+            Tree::Kv => "kv",
+            Tree::RaftLog => "raft_log",
+            Tree::RaftMeta => "raft_meta",
+            Tree::RaftState => "raft_state",
+            Tree::RaftLocks => "raft_locks",
+            Tree::Groups => "groups",
+            Tree::Deployed => "deployed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Tree> {
+        TREES.iter().find(|t| t.name() == name).copied()
+    }
+
+    pub fn open(&self, db: &sled::Db) -> sled::Result<sled::Tree> {
+        db.open_tree(self.name())
+    }
+}
+
+/// A step of the upgrade. The version is stored after the step ran, so a
+/// step interrupted by a crash runs again and must not fail nor duplicate
+/// anything when part of it already happened.
+struct Migration {
+    // Version the database has after the migration ran
+    version: u32,
+    description: &'static str,
+    run: fn(&sled::Db) -> Result<(), Box<dyn Error>>,
+}
+
+const MIGRATIONS: [Migration; 6] = [
+    Migration {
+        version: 1,
+        description: "move node keys and peers from the default tree into named trees",
+        run: migrate_named_trees,
+    },
+    Migration {
+        version: 2,
+        description: "add the kv tree",
+        run: |db| create_trees(db, &[Tree::Kv]),
+    },
+    Migration {
+        version: 3,
+        description: "add the raft log, state and locks trees",
+        run: |db| create_trees(db, &[Tree::RaftLog, Tree::RaftMeta, Tree::RaftState, Tree::RaftLocks]),
+    },
+    Migration {
+        version: 4,
+        description: "add the groups tree",
+        run: |db| create_trees(db, &[Tree::Groups]),
+    },
+    Migration {
+        version: 5,
+        description: "add the deployed tree",
+        run: |db| create_trees(db, &[Tree::Deployed]),
+    },
+    Migration {
+        version: 6,
+        description: "kv records carry the signature of their writer",
+        // The key may still be locked here, `KvStore::open` signs the records
+        // of the node and drops the unsigned ones of its peers
+        run: |_| Ok(()),
+    },
+];
+
+#[derive(Debug)]
+pub enum SchemaError {
+    // The database was written by a newer build
+    TooNew { found: u32, supported: u32 },
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SchemaError::TooNew { found, supported } => write!(
+                f,
+                "Database schema version {} is newer than the supported version {}, upgrade hanode",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl Error for SchemaError {}
+
+/// Version stored in the database, `None` for a database that predates
+/// versioning (or a brand new one).
+pub fn stored_version(db: &sled::Db) -> Result<Option<u32>, Box<dyn Error>> {
+    match Tree::Meta.open(db)?.get(SCHEMA_VERSION_KEY)? {
+        Some(v) => {
+            let bytes: [u8; 4] = v.as_ref().try_into().map_err(|_| "Invalid schema version")?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn set_version(db: &sled::Db, version: u32) -> Result<(), Box<dyn Error>> {
+    Tree::Meta.open(db)?.insert(SCHEMA_VERSION_KEY, &version.to_be_bytes())?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Bring the database up to `SCHEMA_VERSION`, refusing databases written by
+/// a newer build. Returns the version the database had before.
+pub fn migrate(db: &sled::Db) -> Result<u32, Box<dyn Error>> {
+    // Checked first as reading the version opens the meta tree
+    let new = db.is_empty() && db.tree_names().len() <= 1;
+    let current = match stored_version(db)? {
+        Some(v) => v,
+        // No keys nor trees means a new database, nothing to migrate
+        None if new => {
+            set_version(db, SCHEMA_VERSION)?;
+            return Ok(SCHEMA_VERSION);
+        },
+        None => 0,
+    };
+    if current > SCHEMA_VERSION {
+        return Err(SchemaError::TooNew { found: current, supported: SCHEMA_VERSION }.into());
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!("Migrating database to version {}: {}", migration.version, migration.description);
+        (migration.run)(db)?;
+        set_version(db, migration.version)?;
+    }
+    Ok(current)
+}
+
+fn create_trees(db: &sled::Db, trees: &[Tree]) -> Result<(), Box<dyn Error>> {
+    for tree in trees {
+        tree.open(db)?;
+    }
+    Ok(())
+}
+
+// Moves every record in one transaction, a crash leaves them all in place
+fn migrate_named_trees(db: &sled::Db) -> Result<(), Box<dyn Error>> {
+    let node = Tree::Node.open(db)?;
+    let peers = Tree::Peers.open(db)?;
+    let quarantine = Tree::Quarantine.open(db)?;
+    let prefix = format!("{}$", NodeStateKey::NodePeersKey);
+    let legacy_peers = db.scan_prefix(&prefix).collect::<Result<Vec<_>, _>>()?;
+    let keys = [NodeStateKey::NodeLocalKey, NodeStateKey::NodeLocalKeyType, NodeStateKey::NodeKeyRotation]
+        .map(|key| key.to_string());
+    (&**db, &node, &peers, &quarantine).transaction(|(default, node, peers, quarantine)| {
+        for key in &keys {
+            if let Some(v) = default.remove(key.as_str())? {
+                node.insert(key.as_str(), v)?;
+            }
+        }
+        for (key, value) in &legacy_peers {
+            match Peer::parse(value) {
+                Ok(peer) => {
+                    // Re-serialize so records pick up fields added since they were written
+                    peers.insert(peer.id.as_bytes(), peer.to_string().as_bytes())?;
+                },
+                Err(e) => {
+                    warn!("Moving unreadable peer record {} to quarantine: {}", String::from_utf8_lossy(key), e);
+                    quarantine.insert(key, value)?;
+                },
+            }
+            default.remove(key)?;
+        }
+        Ok::<(), ConflictableTransactionError<()>>(())
+    }).map_err(|e| format!("Failed to move records into named trees: {:?}", e))?;
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::error::Error;
+
+use chacha20poly1305::{aead::{Aead, NewAead, Payload}, ChaCha20Poly1305, Key, Nonce};
+use tracing::info;
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+
+use crate::{node::NodeStateKey, schema::Tree};
+
+const META_KEY: &str = "meta";
+
+/// Keys of the `node` tree holding secrets, moved into the encrypted tree
+/// when encryption is enabled.
+const SENSITIVE_KEYS: [NodeStateKey; 1] = [NodeStateKey::NodeLocalKey];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SecretsMeta {
+    version: u32,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+    // Data key encrypted with the key derived from the passphrase
+    nonce: String,
+    wrapped_key: String,
+}
+
+#[derive(Debug)]
+pub enum SecretsError {
+    // The database is encrypted and no passphrase was given
+    Locked,
+    WrongPassphrase,
+}
+
+impl std::fmt::Display for SecretsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SecretsError::Locked => write!(f, "The database secrets are encrypted, a passphrase or keyfile is required"),
+            SecretsError::WrongPassphrase => write!(f, "Wrong passphrase or keyfile for the database secrets"),
+        }
+    }
+}
+
+impl Error for SecretsError {}
+
+/// Sensitive values stored in sled. When unlocked with a passphrase the values
+/// live encrypted in the `secrets` tree, otherwise they stay in plaintext in the
+/// `node` tree.
+#[derive(Clone)]
+pub struct SecretStore {
+    db: sled::Db,
+    tree: sled::Tree,
+    plain: sled::Tree,
+    cipher: Option<ChaCha20Poly1305>,
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], meta: &SecretsMeta) -> Result<[u8; 32], Box<dyn Error>> {
+    let params = scrypt::Params::new(meta.log_n, meta.r, meta.p)
+        .map_err(|e| format!("Invalid scrypt parameters: {}", e))?;
+    let mut out = [0u8; 32];
+    scrypt::scrypt(passphrase, salt, &params, &mut out)
+        .map_err(|e| format!("Failed to derive key: {}", e))?;
+    Ok(out)
+}
+
+fn wrap_data_key(data_key: &[u8], passphrase: &[u8]) -> Result<SecretsMeta, Box<dyn Error>> {
+    let mut salt = [0u8; 32];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut meta = SecretsMeta {
+        version: 1,
+        log_n: 15,
+        r: 8,
+        p: 1,
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        wrapped_key: String::new(),
+    };
+    let kek = derive_key(passphrase, &salt, &meta)?;
+    let wrapped = ChaCha20Poly1305::new(Key::from_slice(&kek))
+        .encrypt(Nonce::from_slice(&nonce), data_key)
+        .map_err(|_| "Failed to wrap the data key")?;
+    meta.wrapped_key = hex::encode(wrapped);
+    Ok(meta)
+}
+
+fn unwrap_data_key(meta: &SecretsMeta, passphrase: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let kek = derive_key(passphrase, &hex::decode(&meta.salt)?, meta)?;
+    ChaCha20Poly1305::new(Key::from_slice(&kek))
+        .decrypt(Nonce::from_slice(&hex::decode(&meta.nonce)?), hex::decode(&meta.wrapped_key)?.as_slice())
+        .map_err(|_| SecretsError::WrongPassphrase.into())
+}
+
+fn load_meta(db: &sled::Db) -> Result<Option<SecretsMeta>, Box<dyn Error>> {
+    match Tree::SecretsMeta.open(db)?.get(META_KEY)? {
+        Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+        None => Ok(None),
+    }
+}
+
+fn store_meta(db: &sled::Db, meta: &SecretsMeta) -> Result<(), Box<dyn Error>> {
+    Tree::SecretsMeta.open(db)?.insert(META_KEY, serde_json::to_vec(meta)?)?;
+    db.flush()?;
+    Ok(())
+}
+
+pub fn is_encrypted(db: &sled::Db) -> Result<bool, Box<dyn Error>> {
+    Ok(load_meta(db)?.is_some())
+}
+
+impl SecretStore {
+    /// Open the store. Passing a passphrase to a plaintext database enables
+    /// encryption and moves the existing secrets into the encrypted tree.
+    pub fn open(db: &sled::Db, passphrase: Option<&[u8]>) -> Result<SecretStore, Box<dyn Error>> {
+        let tree = Tree::Secrets.open(db)?;
+        let plain = Tree::Node.open(db)?;
+        let meta = load_meta(db)?;
+        let cipher = match (meta, passphrase) {
+            (Some(_), None) => return Err(SecretsError::Locked.into()),
+            (Some(meta), Some(passphrase)) => {
+                let data_key = unwrap_data_key(&meta, passphrase)?;
+                Some(ChaCha20Poly1305::new(Key::from_slice(&data_key)))
+            },
+            (None, Some(passphrase)) => {
+                let mut data_key = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut data_key);
+                store_meta(db, &wrap_data_key(&data_key, passphrase)?)?;
+                Some(ChaCha20Poly1305::new(Key::from_slice(&data_key)))
+            },
+            (None, None) => None,
+        };
+        let store = SecretStore { db: db.clone(), tree, plain, cipher };
+        if store.is_encrypted() {
+            store.migrate_plaintext()?;
+        }
+        Ok(store)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+
+    // Move secrets left in plaintext into the encrypted tree. The old values
+    // stay in the sled files, the caller rewrites the database to drop them.
+    fn migrate_plaintext(&self) -> Result<(), Box<dyn Error>> {
+        for key in SENSITIVE_KEYS {
+            let key = key.to_string();
+            if let Some(v) = self.plain.get(&key)? {
+                self.insert(&key, &v)?;
+                self.plain.remove(&key)?;
+                info!("Moved {} into the encrypted secrets tree", key);
+            }
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(self.plain.get(key)?.map(|v| v.to_vec())),
+        };
+        let v = match self.tree.get(key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if v.len() < 12 {
+            return Err(format!("Corrupted secret: {}", key).into());
+        }
+        let plain = cipher.decrypt(Nonce::from_slice(&v[..12]), Payload { msg: &v[12..], aad: key.as_bytes() })
+            .map_err(|_| format!("Failed to decrypt secret: {}", key))?;
+        Ok(Some(plain))
+    }
+
+    pub fn insert(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => {
+                self.plain.insert(key, value)?;
+                return Ok(());
+            },
+        };
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let sealed = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: value, aad: key.as_bytes() })
+            .map_err(|_| format!("Failed to encrypt secret: {}", key))?;
+        let mut v = nonce.to_vec();
+        v.extend(sealed);
+        self.tree.insert(key, v)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        match self.cipher {
+            Some(_) => self.tree.remove(key)?,
+            None => self.plain.remove(key)?,
+        };
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// Change the passphrase protecting the secrets. The data key is only
+/// re-wrapped so the secrets themselves are untouched. A plaintext database
+/// is encrypted with `new` instead.
+pub fn rekey(db: &sled::Db, old: Option<&[u8]>, new: &[u8]) -> Result<(), Box<dyn Error>> {
+    match load_meta(db)? {
+        Some(meta) => {
+            let old = old.ok_or(SecretsError::Locked)?;
+            let data_key = unwrap_data_key(&meta, old)?;
+            store_meta(db, &wrap_data_key(&data_key, new)?)?;
+        },
+        None => {
+            SecretStore::open(db, Some(new))?;
+        },
+    }
+    Ok(())
+}
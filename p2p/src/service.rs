@@ -0,0 +1,698 @@
+use std::{collections::{BTreeMap, HashMap, VecDeque}, ffi::{CStr, CString}, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Write}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, process::Stdio, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, AsyncRead as TokioAsyncRead}, process::{Child, Command}, sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::{files::{read_frames, write_frames}, utils::now_secs};
+
+const MAX_FRAME: usize = 4 * 1024 * 1024;
+const MAX_NAME_SIZE: usize = 63;
+// Between the restarts of a service that keeps exiting, doubled each time
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// A service running this long is restarted at once when it exits
+const STABLE_RUN: Duration = Duration::from_secs(10);
+// Between SIGTERM and SIGKILL
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Rotated once this size, with `MAX_LOG_FILES` older files kept
+pub const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_LOG_FILES: usize = 3;
+/// Most lines returned by a `Logs` request
+pub const MAX_LOG_LINES: usize = 10000;
+pub(crate) const LOG_FILE: &str = "output.log";
+// Mount point of cgroups v2
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// Under the cgroup delegated to the node: it moves itself to the first, as a
+// cgroup with processes cannot hand controllers down, and the services get
+// theirs under the second
+const CGROUP_NODE: &str = "node";
+const CGROUP_SERVICES: &str = "services";
+// Tries to remove the cgroup of a service while what it left dies
+const CGROUP_REMOVE_TRIES: u32 = 20;
+
+#[derive(Debug, Clone)]
+pub struct ServiceProtocol;
+
+impl ProtocolName for ServiceProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/svc/1.0.0"
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    #[default]
+    OnFailure,
+    Always,
+}
+
+/// Applied with cgroups v2 when the node runs in a cgroup delegated to it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    // Bytes
+    pub memory: Option<u64>,
+    // Cores, 0.5 is half of one
+    pub cpu: Option<f64>,
+    pub pids: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpu.is_none() && self.pids.is_none()
+    }
+}
+
+fn default_autostart() -> bool {
+    true
+}
+
+/// A process the node runs, from the `services` of `config.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceSpec {
+    pub name: String,
+    // Program and its arguments, not run through a shell
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    // Working directory, the one of the node by default
+    #[serde(default)]
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub limits: Limits,
+    // Started with the node
+    #[serde(default = "default_autostart")]
+    pub autostart: bool,
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_SIZE
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+impl ServiceSpec {
+    pub fn check(&self) -> Result<(), String> {
+        if !valid_name(&self.name) {
+            return Err(format!("Invalid service name {}, use at most {} letters, digits and ._-", self.name, MAX_NAME_SIZE));
+        }
+        if self.command.is_empty() || self.command[0].is_empty() {
+            return Err(format!("Service {} has no command", self.name));
+        }
+        if matches!(self.limits.cpu, Some(cpu) if cpu <= 0.0) {
+            return Err(format!("The cpu limit of service {} must be positive", self.name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Stopped,
+    Running,
+    // Waiting out the backoff before the next start
+    Restarting,
+    // Exited with 0 and not restarted
+    Exited,
+    // Exited otherwise, or could not be started, and not restarted
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: ServiceState,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    // None when killed by a signal
+    pub exit_code: Option<i32>,
+    // Unix time in seconds of the last change of state
+    pub since: u64,
+    // The limits are enforced by a cgroup
+    pub cgroup: bool,
+    pub error: Option<String>,
+}
+
+impl ServiceStatus {
+    fn new(name: &str) -> ServiceStatus {
+        ServiceStatus {
+            name: name.to_string(),
+            state: ServiceState::Stopped,
+            pid: None,
+            restarts: 0,
+            exit_code: None,
+            since: now_secs(),
+            cgroup: false,
+            error: None,
+        }
+    }
+}
+
+/// Operations on the services of a node, served to its peers too
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ServiceRequest {
+    List,
+    Start { name: String },
+    Stop { name: String },
+    Restart { name: String },
+    // Last lines of the output
+    Logs { name: String, lines: usize },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ServiceResponse {
+    Services { services: Vec<ServiceStatus> },
+    Service { service: ServiceStatus },
+    Logs { lines: Vec<String> },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ServiceCodec;
+
+#[async_trait]
+impl RequestResponseCodec for ServiceCodec {
+    type Protocol = ServiceProtocol;
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+
+    async fn read_request<T>(&mut self, _: &ServiceProtocol, io: &mut T) -> io::Result<ServiceRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (req, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(req)
+    }
+
+    async fn read_response<T>(&mut self, _: &ServiceProtocol, io: &mut T) -> io::Result<ServiceResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (res, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(res)
+    }
+
+    async fn write_request<T>(&mut self, _: &ServiceProtocol, io: &mut T, req: ServiceRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &req, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &ServiceProtocol, io: &mut T, res: ServiceResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &res, &[]).await
+    }
+}
+
+/// Output of a service, rotated to `output.log.1` and so on
+struct ServiceLog {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+}
+
+impl ServiceLog {
+    fn open(dir: &Path) -> io::Result<ServiceLog> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(ServiceLog { path, file: Some(file), size })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), n))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        for n in (1..MAX_LOG_FILES).rev() {
+            if self.rotated(n).exists() {
+                fs::rename(self.rotated(n), self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size >= MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        if let Some(file) = self.file.as_mut() {
+            writeln!(file, "{}", line)?;
+            self.size += line.len() as u64 + 1;
+        }
+        Ok(())
+    }
+}
+
+// Last lines of the output, reaching into the rotated file when needed
+pub(crate) fn tail(dir: &Path, lines: usize) -> io::Result<Vec<String>> {
+    let mut tail = VecDeque::with_capacity(lines);
+    let current = dir.join(LOG_FILE);
+    for path in [PathBuf::from(format!("{}.1", current.display())), current] {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(file).lines() {
+            if tail.len() == lines {
+                tail.pop_front();
+            }
+            tail.push_back(line?);
+        }
+    }
+    Ok(tail.into_iter().collect())
+}
+
+// Cgroup the node runs in, from the cgroups v2 line of /proc/self/cgroup
+fn own_cgroup() -> io::Result<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    let path = cgroups.lines()
+        .find_map(|l| l.strip_prefix("0::"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "the node is not in a cgroups v2 hierarchy"))?;
+    let own = Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'));
+    // Moved there by an earlier call
+    match own.file_name() {
+        Some(name) if name == CGROUP_NODE => Ok(own.parent().map(Path::to_path_buf).unwrap_or(own)),
+        _ => Ok(own),
+    }
+}
+
+// Parent of the cgroups of the services, under the cgroup of the node which
+// has to be delegated to it, as systemd does with Delegate=yes. Controllers
+// are never enabled above it.
+fn delegate(own: &Path) -> io::Result<PathBuf> {
+    if own == Path::new(CGROUP_ROOT) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the node runs in the root cgroup, which is not delegated"));
+    }
+    let node = own.join(CGROUP_NODE);
+    fs::create_dir_all(&node)?;
+    fs::write(node.join("cgroup.procs"), std::process::id().to_string())?;
+    let enable = |dir: &Path| fs::write(dir.join("cgroup.subtree_control"), "+cpu +memory +pids");
+    let services = own.join(CGROUP_SERVICES);
+    enable(own).and_then(|_| fs::create_dir_all(&services)).and_then(|_| enable(&services))?;
+    Ok(services)
+}
+
+// cgroup v2 hierarchy of the services, None when there is none delegated
+fn cgroup_parent() -> Option<PathBuf> {
+    if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        return None;
+    }
+    match own_cgroup().and_then(|own| delegate(&own)) {
+        Ok(parent) => Some(parent),
+        Err(e) => {
+            warn!("Service limits are not enforced, the node needs a delegated cgroup: {}", e);
+            None
+        },
+    }
+}
+
+fn create_cgroup(parent: &Path, name: &str, limits: &Limits) -> io::Result<PathBuf> {
+    let dir = parent.join(name);
+    match fs::create_dir(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {},
+    }
+    fs::write(dir.join("memory.max"), limits.memory.map(|m| m.to_string()).unwrap_or_else(|| "max".to_string()))?;
+    let cpu = match limits.cpu {
+        Some(cores) => format!("{} 100000", (cores * 100000.0) as u64),
+        None => "max 100000".to_string(),
+    };
+    fs::write(dir.join("cpu.max"), cpu)?;
+    fs::write(dir.join("pids.max"), limits.pids.map(|p| p.to_string()).unwrap_or_else(|| "max".to_string()))?;
+    Ok(dir)
+}
+
+// Runs in the child between fork and exec, where allocating is not safe:
+// the process joins its cgroup before the program starts
+fn join_cgroup(procs: &CStr) -> io::Result<()> {
+    let fd = unsafe { libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // 0 is the writing process
+    let written = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    match written {
+        1 => Ok(()),
+        _ => Err(error),
+    }
+}
+
+// Once the process exited, killing what it left in its cgroup
+async fn remove_cgroup(dir: &Path) {
+    let _ = fs::write(dir.join("cgroup.kill"), "1");
+    for _ in 0..CGROUP_REMOVE_TRIES {
+        match fs::remove_dir(dir) {
+            Ok(_) => return,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+    warn!("Failed to remove cgroup {}, processes are left in it", dir.display());
+}
+
+struct Managed {
+    spec: ServiceSpec,
+    status: ServiceStatus,
+    // Tells the task to stop the process
+    stop: Option<watch::Sender<bool>>,
+    task: Option<JoinHandle<()>>,
+    // Counts the starts, so a task that ended does not clear a later one
+    run: u64,
+}
+
+/// Runs the services of the node, restarting them as their policy says
+#[derive(Clone)]
+pub struct Supervisor {
+    dir: PathBuf,
+    services: Arc<Mutex<HashMap<String, Managed>>>,
+    cgroups: Arc<Option<PathBuf>>,
+}
+
+impl std::fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Supervisor").field("dir", &self.dir).finish()
+    }
+}
+
+impl Supervisor {
+    /// Logs are written under `<dir>/<name>/`
+    pub fn new(dir: &str) -> Supervisor {
+        Supervisor {
+            dir: PathBuf::from(dir),
+            services: Arc::new(Mutex::new(HashMap::new())),
+            cgroups: Arc::new(None),
+        }
+    }
+
+    /// Enforce the limits of the services when cgroups v2 is writable
+    pub fn with_cgroups(mut self) -> Supervisor {
+        self.cgroups = Arc::new(cgroup_parent());
+        self
+    }
+
+    /// Add a service, or replace the spec of a stopped one
+    pub fn declare(&self, spec: ServiceSpec) -> Result<(), String> {
+        spec.check()?;
+        let mut services = self.services.lock().unwrap();
+        if let Some(managed) = services.get_mut(&spec.name) {
+            if managed.task.is_some() {
+                return Err(format!("Service {} is running, stop it first", spec.name));
+            }
+            managed.spec = spec;
+            return Ok(());
+        }
+        let status = ServiceStatus::new(&spec.name);
+        services.insert(spec.name.clone(), Managed { spec, status, stop: None, task: None, run: 0 });
+        Ok(())
+    }
+
+    /// Start the services declared with `autostart`
+    pub fn autostart(&self) {
+        let names: Vec<String> = self.services.lock().unwrap().values()
+            .filter(|m| m.spec.autostart)
+            .map(|m| m.spec.name.clone())
+            .collect();
+        for name in names {
+            if let Err(e) = self.start(&name) {
+                warn!("Failed to start service {}: {}", name, e);
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<ServiceStatus> {
+        let mut list: Vec<ServiceStatus> = self.services.lock().unwrap().values().map(|m| m.status.clone()).collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    pub fn status(&self, name: &str) -> Result<ServiceStatus, String> {
+        self.services.lock().unwrap().get(name).map(|m| m.status.clone()).ok_or_else(|| format!("No service {}", name))
+    }
+
+    /// Start a service, nothing to do when it runs already
+    pub fn start(&self, name: &str) -> Result<ServiceStatus, String> {
+        let mut services = self.services.lock().unwrap();
+        let managed = services.get_mut(name).ok_or_else(|| format!("No service {}", name))?;
+        if managed.task.is_none() {
+            let (stop, stopped) = watch::channel(false);
+            managed.status.restarts = 0;
+            managed.status.error = None;
+            managed.stop = Some(stop);
+            managed.run += 1;
+            managed.task = Some(tokio::spawn(self.clone().supervise(managed.spec.clone(), managed.run, stopped)));
+        }
+        Ok(managed.status.clone())
+    }
+
+    /// Stop a service, waiting for its process to exit
+    pub async fn stop(&self, name: &str) -> Result<ServiceStatus, String> {
+        let (stop, task) = {
+            let mut services = self.services.lock().unwrap();
+            let managed = services.get_mut(name).ok_or_else(|| format!("No service {}", name))?;
+            (managed.stop.take(), managed.task.take())
+        };
+        if let (Some(stop), Some(task)) = (stop, task) {
+            let _ = stop.send(true);
+            let _ = task.await;
+        }
+        self.status(name)
+    }
+
+    pub async fn restart(&self, name: &str) -> Result<ServiceStatus, String> {
+        self.stop(name).await?;
+        self.start(name)
+    }
+
+    /// Stop a service and forget its spec
+    pub async fn remove(&self, name: &str) -> Result<(), String> {
+        self.stop(name).await?;
+        self.services.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    /// Stop every service, when the node stops
+    pub async fn stop_all(&self) {
+        let names: Vec<String> = self.services.lock().unwrap().keys().cloned().collect();
+        for name in names {
+            let _ = self.stop(&name).await;
+        }
+    }
+
+    pub fn logs(&self, name: &str, lines: usize) -> Result<Vec<String>, String> {
+        self.status(name)?;
+        tail(&self.dir.join(name), lines.min(MAX_LOG_LINES)).map_err(|e| format!("Failed to read the output of {}: {}", name, e))
+    }
+
+    /// Answers a request, only the list for a peer that is not an operator
+    pub async fn serve(&self, req: ServiceRequest, operator: bool) -> ServiceResponse {
+        if !operator && !matches!(req, ServiceRequest::List) {
+            return ServiceResponse::Error { message: "Not an operator of this node".to_string() };
+        }
+        let result = match req {
+            ServiceRequest::List => return ServiceResponse::Services { services: self.list() },
+            ServiceRequest::Start { name } => self.start(&name),
+            ServiceRequest::Stop { name } => self.stop(&name).await,
+            ServiceRequest::Restart { name } => self.restart(&name).await,
+            ServiceRequest::Logs { name, lines } => return match self.logs(&name, lines) {
+                Ok(lines) => ServiceResponse::Logs { lines },
+                Err(message) => ServiceResponse::Error { message },
+            },
+        };
+        match result {
+            Ok(service) => ServiceResponse::Service { service },
+            Err(message) => ServiceResponse::Error { message },
+        }
+    }
+
+    fn update(&self, name: &str, update: impl FnOnce(&mut ServiceStatus)) {
+        if let Some(managed) = self.services.lock().unwrap().get_mut(name) {
+            update(&mut managed.status);
+            managed.status.since = now_secs();
+        }
+    }
+
+    // The end of a run the policy does not restart, `start` runs it again
+    fn finish(&self, name: &str, run: u64, update: impl FnOnce(&mut ServiceStatus)) {
+        if let Some(managed) = self.services.lock().unwrap().get_mut(name) {
+            update(&mut managed.status);
+            managed.status.since = now_secs();
+            if managed.run == run {
+                managed.stop = None;
+                managed.task = None;
+            }
+        }
+    }
+
+    // The process with the cgroup it runs in, if any
+    fn spawn(&self, spec: &ServiceSpec, log: &Arc<Mutex<ServiceLog>>) -> io::Result<(Child, Option<PathBuf>)> {
+        let mut command = Command::new(&spec.command[0]);
+        command.args(&spec.command[1..])
+            .envs(&spec.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(dir) = &spec.dir {
+            command.current_dir(dir);
+        }
+        let mut cgroup = None;
+        if let (Some(parent), false) = (self.cgroups.as_ref(), spec.limits.is_empty()) {
+            match create_cgroup(parent, &spec.name, &spec.limits) {
+                Ok(dir) => {
+                    let procs = CString::new(dir.join("cgroup.procs").as_os_str().as_bytes())?;
+                    // Children it forks are limited as well
+                    unsafe {
+                        command.pre_exec(move || join_cgroup(&procs));
+                    }
+                    cgroup = Some(dir);
+                },
+                Err(e) => warn!("Limits of service {} are not enforced: {}", spec.name, e),
+            }
+        }
+        let mut child = command.spawn()?;
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(capture(stdout, log.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(capture(stderr, log.clone()));
+        }
+        Ok((child, cgroup))
+    }
+
+    async fn supervise(self, spec: ServiceSpec, run: u64, mut stopped: watch::Receiver<bool>) {
+        let name = spec.name.clone();
+        let log = match ServiceLog::open(&self.dir.join(&name)) {
+            Ok(log) => Arc::new(Mutex::new(log)),
+            Err(e) => {
+                self.finish(&name, run, |s| {
+                    s.state = ServiceState::Failed;
+                    s.error = Some(format!("Failed to open the log: {}", e));
+                });
+                return;
+            },
+        };
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let exit_code = match self.spawn(&spec, &log) {
+                Ok((mut child, cgroup)) => {
+                    info!("Started service {} with pid {:?}", name, child.id());
+                    self.update(&name, |s| {
+                        s.state = ServiceState::Running;
+                        s.pid = child.id();
+                        s.exit_code = None;
+                        s.cgroup = cgroup.is_some();
+                    });
+                    let exit_code = tokio::select! {
+                        status = child.wait() => match status {
+                            Ok(status) => status.code(),
+                            Err(e) => {
+                                warn!("Failed to wait for service {}: {}", name, e);
+                                None
+                            },
+                        },
+                        _ = stopped.changed() => {
+                            terminate(&name, &mut child).await;
+                            if let Some(dir) = &cgroup {
+                                remove_cgroup(dir).await;
+                            }
+                            self.update(&name, |s| {
+                                s.state = ServiceState::Stopped;
+                                s.pid = None;
+                            });
+                            return;
+                        },
+                    };
+                    if let Some(dir) = &cgroup {
+                        remove_cgroup(dir).await;
+                    }
+                    exit_code
+                },
+                Err(e) => {
+                    self.update(&name, |s| s.error = Some(format!("Failed to start {}: {}", spec.command[0], e)));
+                    None
+                },
+            };
+            let restart = match spec.restart {
+                RestartPolicy::Always => true,
+                RestartPolicy::OnFailure => exit_code != Some(0),
+                RestartPolicy::Never => false,
+            };
+            info!("Service {} exited with {:?}", name, exit_code);
+            if !restart {
+                self.finish(&name, run, |s| {
+                    s.state = if exit_code == Some(0) { ServiceState::Exited } else { ServiceState::Failed };
+                    s.pid = None;
+                    s.exit_code = exit_code;
+                });
+                return;
+            }
+            if started.elapsed() >= STABLE_RUN {
+                backoff = MIN_BACKOFF;
+            }
+            self.update(&name, |s| {
+                s.state = ServiceState::Restarting;
+                s.pid = None;
+                s.exit_code = exit_code;
+                s.restarts += 1;
+            });
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = stopped.changed() => {
+                    self.update(&name, |s| s.state = ServiceState::Stopped);
+                    return;
+                },
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+async fn capture(output: impl TokioAsyncRead + Unpin, log: Arc<Mutex<ServiceLog>>) {
+    let mut lines = tokio::io::BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Err(e) = log.lock().unwrap().write_line(&line) {
+            warn!("Failed to write service output: {}", e);
+            return;
+        }
+    }
+}
+
+// SIGTERM, then SIGKILL once `STOP_TIMEOUT` went by
+async fn terminate(name: &str, child: &mut Child) {
+    if let Some(pid) = child.id() {
+        // std only sends SIGKILL
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+        if tokio::time::timeout(STOP_TIMEOUT, child.wait()).await.is_ok() {
+            info!("Stopped service {}", name);
+            return;
+        }
+        warn!("Service {} did not stop within {}s, killing it", name, STOP_TIMEOUT.as_secs());
+    }
+    let _ = child.kill().await;
+}
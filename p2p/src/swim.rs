@@ -0,0 +1,559 @@
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, io};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use rand::seq::{IteratorRandom, SliceRandom};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{files::{read_frames, write_frames}, utils::now_secs};
+
+const MAX_FRAME: usize = 256 * 1024;
+// Updates carried by each message
+const MAX_PIGGYBACK: usize = 8;
+// A dead member is pinged every so many periods, to notice it came back
+const DEAD_PROBE_PERIODS: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub struct SwimProtocol;
+
+impl ProtocolName for SwimProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/hanode/swim/1.0.0"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SwimConfig {
+    // Peer id of this node
+    pub id: String,
+    // One member is probed each period
+    pub period_ticks: u32,
+    // Without an ack by then, other members are asked to probe
+    pub ack_ticks: u32,
+    pub indirect_probes: usize,
+    // A suspect that did not refute its suspicion by then is dead
+    pub suspect_ticks: u32,
+    // Greater after a restart, so the node is not taken for dead
+    pub incarnation: u64,
+}
+
+impl SwimConfig {
+    pub fn new(id: &str, incarnation: u64) -> SwimConfig {
+        SwimConfig { id: id.to_string(), period_ticks: 5, ack_ticks: 2, indirect_probes: 3, suspect_ticks: 25, incarnation }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// What a member said or was said of it, the greater incarnation wins
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemberUpdate {
+    pub id: String,
+    pub state: MemberState,
+    pub incarnation: u64,
+    // Listen addresses, only in what a member says of itself
+    #[serde(default)]
+    pub addrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: String,
+    pub state: MemberState,
+    pub incarnation: u64,
+    pub addrs: Vec<String>,
+    // Unix time of the last change of state
+    pub since: u64,
+}
+
+/// Messages between members, each sent as a request answered with a
+/// `SwimAck`. Every message carries the latest membership updates.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SwimMessage {
+    Ping { seq: u64, updates: Vec<MemberUpdate> },
+    // Probe `target` on behalf of the sender, which did not get its ack
+    PingReq { seq: u64, target: String, updates: Vec<MemberUpdate> },
+    // `target` answered the ping `seq`, directly or through another member
+    Ack { seq: u64, target: String, updates: Vec<MemberUpdate> },
+}
+
+impl SwimMessage {
+    fn take_updates(&mut self) -> Vec<MemberUpdate> {
+        match self {
+            SwimMessage::Ping { updates, .. } => std::mem::take(updates),
+            SwimMessage::PingReq { updates, .. } => std::mem::take(updates),
+            SwimMessage::Ack { updates, .. } => std::mem::take(updates),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwimAck;
+
+#[derive(Debug, Clone)]
+pub struct SwimCodec;
+
+#[async_trait]
+impl RequestResponseCodec for SwimCodec {
+    type Protocol = SwimProtocol;
+    type Request = SwimMessage;
+    type Response = SwimAck;
+
+    async fn read_request<T>(&mut self, _: &SwimProtocol, io: &mut T) -> io::Result<SwimMessage>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (msg, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(msg)
+    }
+
+    async fn read_response<T>(&mut self, _: &SwimProtocol, io: &mut T) -> io::Result<SwimAck>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let (ack, _) = read_frames(io, MAX_FRAME).await?;
+        Ok(ack)
+    }
+
+    async fn write_request<T>(&mut self, _: &SwimProtocol, io: &mut T, msg: SwimMessage) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &msg, &[]).await
+    }
+
+    async fn write_response<T>(&mut self, _: &SwimProtocol, io: &mut T, ack: SwimAck) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_frames(io, &ack, &[]).await
+    }
+}
+
+struct Probe {
+    target: String,
+    seq: u64,
+    sent: u64,
+    acked: bool,
+    indirect: bool,
+}
+
+// Ping sent for the `PingReq` of another member
+struct Relay {
+    requester: String,
+    seq: u64,
+    sent: u64,
+}
+
+/// SWIM membership of one node: each period it pings a member, asks others
+/// to ping it when no ack comes, then suspects it. A suspect that does not
+/// refute in time is dead. Changes spread on the pings and acks. Like `Raft`
+/// it does no I/O: the owner calls `tick`, hands it messages with `step` and
+/// sends what `take_outbox` returns.
+pub struct Membership {
+    config: SwimConfig,
+    incarnation: u64,
+    addrs: Vec<String>,
+    since: u64,
+    members: BTreeMap<String, Member>,
+    // Updates to piggyback, with the number of times each was sent
+    updates: HashMap<String, (MemberUpdate, u32)>,
+    ticks: u64,
+    seq: u64,
+    probe: Option<Probe>,
+    // Members left to probe this round, in random order
+    order: Vec<String>,
+    relays: HashMap<u64, Relay>,
+    // Tick each suspect was suspected at
+    suspects: HashMap<String, u64>,
+    outbox: Vec<(String, SwimMessage)>,
+}
+
+impl Membership {
+    pub fn new(config: SwimConfig) -> Membership {
+        Membership {
+            incarnation: config.incarnation,
+            config,
+            addrs: Vec::new(),
+            since: now_secs(),
+            members: BTreeMap::new(),
+            updates: HashMap::new(),
+            ticks: 0,
+            seq: 0,
+            probe: None,
+            order: Vec::new(),
+            relays: HashMap::new(),
+            suspects: HashMap::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    pub fn member(&self, id: &str) -> Option<&Member> {
+        self.members.get(id)
+    }
+
+    /// Every member this node heard of, itself included
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.values().cloned().collect();
+        members.push(Member {
+            id: self.config.id.clone(),
+            state: MemberState::Alive,
+            incarnation: self.incarnation,
+            addrs: self.addrs.clone(),
+            since: self.since,
+        });
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        members
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<(String, SwimMessage)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// A listen address of this node, announced to the members
+    pub fn add_addr(&mut self, addr: &str) {
+        if !self.addrs.iter().any(|a| a == addr) {
+            self.addrs.push(addr.to_string());
+            self.announce();
+        }
+    }
+
+    /// A peer this node connected to. It is probed from now on, and learns of
+    /// the other members on the next messages.
+    pub fn join(&mut self, id: &str) {
+        if id == self.config.id {
+            return;
+        }
+        match self.members.get(id) {
+            Some(member) if member.state != MemberState::Alive => self.reach(id),
+            Some(_) => {},
+            None => {
+                self.members.insert(id.to_string(), Member {
+                    id: id.to_string(),
+                    state: MemberState::Alive,
+                    incarnation: 0,
+                    addrs: Vec::new(),
+                    since: now_secs(),
+                });
+            },
+        }
+        self.announce();
+    }
+
+    // Ping a member that is not probed, telling it its state so it refutes
+    fn reach(&mut self, id: &str) {
+        if let Some(member) = self.members.get(id).cloned() {
+            self.enqueue(&member);
+            self.seq += 1;
+            let seq = self.seq;
+            self.send(id, |updates| SwimMessage::Ping { seq, updates });
+        }
+    }
+
+    fn announce(&mut self) {
+        let update = MemberUpdate {
+            id: self.config.id.clone(),
+            state: MemberState::Alive,
+            incarnation: self.incarnation,
+            addrs: self.addrs.clone(),
+        };
+        self.updates.insert(update.id.clone(), (update, 0));
+    }
+
+    fn enqueue(&mut self, member: &Member) {
+        let update = MemberUpdate {
+            id: member.id.clone(),
+            state: member.state,
+            incarnation: member.incarnation,
+            addrs: member.addrs.clone(),
+        };
+        self.updates.insert(update.id.clone(), (update, 0));
+    }
+
+    // Each update is sent about 3 log(n) times, the least sent first
+    fn piggyback(&mut self) -> Vec<MemberUpdate> {
+        let limit = 3 * (64 - (self.members.len() as u64 + 1).leading_zeros()).max(1);
+        let mut pending: Vec<(&String, &mut (MemberUpdate, u32))> = self.updates.iter_mut().collect();
+        pending.sort_by_key(|(_, (_, sent))| *sent);
+        let mut updates = Vec::new();
+        for (_, (update, sent)) in pending.into_iter().take(MAX_PIGGYBACK) {
+            *sent += 1;
+            updates.push(update.clone());
+        }
+        self.updates.retain(|_, (_, sent)| *sent < limit);
+        updates
+    }
+
+    fn send(&mut self, to: &str, msg: impl FnOnce(Vec<MemberUpdate>) -> SwimMessage) {
+        let updates = self.piggyback();
+        self.outbox.push((to.to_string(), msg(updates)));
+    }
+
+    fn apply(&mut self, update: MemberUpdate) {
+        // No member could refute it, there is no greater incarnation
+        if update.incarnation == u64::MAX {
+            debug!("Dropping update of {} with the last incarnation", update.id);
+            return;
+        }
+        if update.id == self.config.id {
+            // Refute, the suspicion or death is wrong as long as we run
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = match update.incarnation.checked_add(1) {
+                    Some(incarnation) => incarnation,
+                    None => return,
+                };
+                info!("Refuting being {:?}, incarnation {}", update.state, self.incarnation);
+                self.announce();
+            }
+            return;
+        }
+        let newer = match self.members.get(&update.id) {
+            None => true,
+            Some(current) => match (update.state, current.state) {
+                (MemberState::Alive, _) => update.incarnation > current.incarnation,
+                (MemberState::Suspect, MemberState::Alive) => update.incarnation >= current.incarnation,
+                (MemberState::Suspect, _) => update.incarnation > current.incarnation,
+                (MemberState::Dead, MemberState::Dead) => update.incarnation > current.incarnation,
+                (MemberState::Dead, _) => update.incarnation >= current.incarnation,
+            },
+        };
+        if !newer {
+            return;
+        }
+        let mut member = self.members.remove(&update.id).unwrap_or(Member {
+            id: update.id.clone(),
+            state: update.state,
+            incarnation: update.incarnation,
+            addrs: Vec::new(),
+            since: now_secs(),
+        });
+        if member.state != update.state {
+            info!("Member {} is {:?}", member.id, update.state);
+            member.since = now_secs();
+        }
+        member.state = update.state;
+        member.incarnation = update.incarnation;
+        if !update.addrs.is_empty() {
+            member.addrs = update.addrs;
+        }
+        match member.state {
+            MemberState::Suspect => {
+                self.suspects.entry(member.id.clone()).or_insert(self.ticks);
+            },
+            _ => {
+                self.suspects.remove(&member.id);
+            },
+        }
+        self.enqueue(&member);
+        self.members.insert(member.id.clone(), member);
+    }
+
+    fn mark(&mut self, id: &str, state: MemberState) {
+        if let Some(member) = self.members.get(id) {
+            let update = MemberUpdate { id: id.to_string(), state, incarnation: member.incarnation, addrs: Vec::new() };
+            self.apply(update);
+        }
+    }
+
+    fn next_target(&mut self) -> Option<String> {
+        loop {
+            match self.order.pop() {
+                Some(id) => match self.members.get(&id) {
+                    Some(member) if member.state != MemberState::Dead => return Some(id),
+                    _ => continue,
+                },
+                None => {
+                    let mut order: Vec<String> = self.members.values()
+                        .filter(|m| m.state != MemberState::Dead)
+                        .map(|m| m.id.clone())
+                        .collect();
+                    if order.is_empty() {
+                        return None;
+                    }
+                    order.shuffle(&mut rand::thread_rng());
+                    self.order = order;
+                },
+            }
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        let ticks = self.ticks;
+        let ask = match self.probe.as_mut() {
+            Some(probe) if !probe.acked && !probe.indirect && ticks - probe.sent >= self.config.ack_ticks as u64 => {
+                probe.indirect = true;
+                Some((probe.target.clone(), probe.seq))
+            },
+            _ => None,
+        };
+        if let Some((target, seq)) = ask {
+            let helpers: Vec<String> = self.members.values()
+                .filter(|m| m.state == MemberState::Alive && m.id != target)
+                .map(|m| m.id.clone())
+                .choose_multiple(&mut rand::thread_rng(), self.config.indirect_probes);
+            for helper in helpers {
+                let target = target.clone();
+                self.send(&helper, |updates| SwimMessage::PingReq { seq, target, updates });
+            }
+        }
+        if ticks.is_multiple_of(self.config.period_ticks as u64) {
+            if let Some(probe) = self.probe.take() {
+                if !probe.acked {
+                    self.mark(&probe.target, MemberState::Suspect);
+                }
+            }
+            if let Some(target) = self.next_target() {
+                self.seq += 1;
+                let seq = self.seq;
+                self.send(&target, |updates| SwimMessage::Ping { seq, updates });
+                self.probe = Some(Probe { target, seq, sent: ticks, acked: false, indirect: false });
+            }
+            if ticks.is_multiple_of(self.config.period_ticks as u64 * DEAD_PROBE_PERIODS) {
+                let dead = self.members.values()
+                    .filter(|m| m.state == MemberState::Dead)
+                    .map(|m| m.id.clone())
+                    .choose(&mut rand::thread_rng());
+                if let Some(id) = dead {
+                    self.reach(&id);
+                }
+            }
+        }
+        let expired: Vec<String> = self.suspects.iter()
+            .filter(|(_, at)| ticks - **at >= self.config.suspect_ticks as u64)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.mark(&id, MemberState::Dead);
+        }
+        let period = self.config.period_ticks as u64;
+        self.relays.retain(|_, relay| ticks - relay.sent < period);
+    }
+
+    /// Handle a message of another member
+    pub fn step(&mut self, from: &str, mut msg: SwimMessage) {
+        for update in msg.take_updates() {
+            self.apply(update);
+        }
+        match self.members.get(from) {
+            // Tell it, so it refutes
+            Some(member) if member.state != MemberState::Alive => {
+                let member = member.clone();
+                self.enqueue(&member);
+            },
+            Some(_) => {},
+            None => self.join(from),
+        }
+        match msg {
+            SwimMessage::Ping { seq, .. } => {
+                let target = self.config.id.clone();
+                self.send(from, |updates| SwimMessage::Ack { seq, target, updates });
+            },
+            SwimMessage::PingReq { seq, target, .. } => {
+                self.seq += 1;
+                let own = self.seq;
+                self.relays.insert(own, Relay { requester: from.to_string(), seq, sent: self.ticks });
+                self.send(&target, |updates| SwimMessage::Ping { seq: own, updates });
+            },
+            SwimMessage::Ack { seq, target, .. } => {
+                if let Some(relay) = self.relays.remove(&seq) {
+                    self.send(&relay.requester, |updates| SwimMessage::Ack { seq: relay.seq, target, updates });
+                } else if let Some(probe) = self.probe.as_mut() {
+                    if probe.seq == seq && probe.target == target {
+                        probe.acked = true;
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Members in one process whose messages are delivered in memory, to check
+/// failure detection without a network
+pub struct MemoryNetwork {
+    pub nodes: BTreeMap<String, Membership>,
+    queue: VecDeque<(String, String, SwimMessage)>,
+    isolated: HashSet<String>,
+    // Links that drop messages, both ways
+    cut: HashSet<(String, String)>,
+}
+
+impl MemoryNetwork {
+    pub fn new(ids: &[&str]) -> MemoryNetwork {
+        let nodes = ids.iter().map(|id| (id.to_string(), Membership::new(SwimConfig::new(id, 1)))).collect();
+        MemoryNetwork { nodes, queue: VecDeque::new(), isolated: HashSet::new(), cut: HashSet::new() }
+    }
+
+    /// As if `a` and `b` opened a connection
+    pub fn connect(&mut self, a: &str, b: &str) {
+        self.node(a).join(b);
+        self.node(b).join(a);
+    }
+
+    pub fn node(&mut self, id: &str) -> &mut Membership {
+        self.nodes.get_mut(id).expect("no such member")
+    }
+
+    /// State of `id` as `by` sees it
+    pub fn state(&self, by: &str, id: &str) -> Option<MemberState> {
+        self.nodes.get(by).and_then(|m| m.member(id)).map(|m| m.state)
+    }
+
+    /// Drop the messages from and to a member until `heal`
+    pub fn isolate(&mut self, id: &str) {
+        self.isolated.insert(id.to_string());
+    }
+
+    pub fn heal(&mut self, id: &str) {
+        self.isolated.remove(id);
+    }
+
+    /// Drop the messages between two members only
+    pub fn cut(&mut self, a: &str, b: &str) {
+        self.cut.insert((a.to_string(), b.to_string()));
+        self.cut.insert((b.to_string(), a.to_string()));
+    }
+
+    pub fn tick(&mut self) {
+        for member in self.nodes.values_mut() {
+            member.tick();
+        }
+        self.deliver();
+    }
+
+    pub fn deliver(&mut self) {
+        loop {
+            for (id, member) in self.nodes.iter_mut() {
+                for (to, msg) in member.take_outbox() {
+                    self.queue.push_back((id.clone(), to, msg));
+                }
+            }
+            let (from, to, msg) = match self.queue.pop_front() {
+                Some(m) => m,
+                None => return,
+            };
+            if self.isolated.contains(&from) || self.isolated.contains(&to) || self.cut.contains(&(from.clone(), to.clone())) {
+                continue;
+            }
+            if let Some(member) = self.nodes.get_mut(&to) {
+                member.step(&from, msg);
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::{collections::HashMap, error::Error, fs::{self, File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, Instant}};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{files::{self, FileOp, FileRequest, FileResponse, FileResult, CHUNK_SIZE}, handle::NodeHandle, utils::now_millis};
+
+// Attempts before a transfer fails, the peer may be away for a while
+const MAX_ATTEMPTS: u32 = 10;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    // Local file to the peer
+    Push,
+    // File of the peer to a local path
+    Pull,
+}
+
+/// A copy between a local path of the node and a path in the files
+/// directory of a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferSpec {
+    pub peer: String,
+    pub direction: Direction,
+    // Absolute, the node does not share the working directory of the client
+    pub local: String,
+    pub remote: String,
+    // Bytes per second
+    #[serde(default)]
+    pub rate_limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferState {
+    Running,
+    // Waiting to resume after an error
+    Retrying,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferStatus {
+    pub id: u64,
+    #[serde(flatten)]
+    pub spec: TransferSpec,
+    pub state: TransferState,
+    pub size: Option<u64>,
+    pub transferred: u64,
+    // Bytes already there when the last attempt started
+    pub resumed_from: u64,
+    pub attempts: u32,
+    pub error: Option<String>,
+    // Unix milliseconds
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+/// Transfers started since the node runs
+#[derive(Debug, Default)]
+pub struct Transfers {
+    next_id: AtomicU64,
+    transfers: Mutex<HashMap<u64, TransferStatus>>,
+}
+
+impl Transfers {
+    pub fn list(&self) -> Vec<TransferStatus> {
+        let mut list: Vec<TransferStatus> = self.transfers.lock().unwrap().values().cloned().collect();
+        list.sort_by_key(|t| t.id);
+        list
+    }
+
+    pub fn get(&self, id: u64) -> Option<TransferStatus> {
+        self.transfers.lock().unwrap().get(&id).cloned()
+    }
+
+    fn insert(&self, spec: TransferSpec) -> TransferStatus {
+        let now = now_millis();
+        let status = TransferStatus {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            spec,
+            state: TransferState::Running,
+            size: None,
+            transferred: 0,
+            resumed_from: 0,
+            attempts: 0,
+            error: None,
+            started_at: now,
+            updated_at: now,
+        };
+        self.transfers.lock().unwrap().insert(status.id, status.clone());
+        status
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut TransferStatus)) {
+        if let Some(status) = self.transfers.lock().unwrap().get_mut(&id) {
+            f(status);
+            status.updated_at = now_millis();
+        }
+    }
+}
+
+enum AttemptError {
+    // The peer went away or timed out, resume later
+    Retry(String),
+    Fatal(String),
+}
+
+fn fatal<E: ToString>(e: E) -> AttemptError {
+    AttemptError::Fatal(e.to_string())
+}
+
+fn unexpected(result: FileResult) -> AttemptError {
+    AttemptError::Fatal(format!("Unexpected answer from the peer: {:?}", result))
+}
+
+// Spreads the chunks so the average rate stays under the limit
+struct Throttle {
+    rate: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: Option<u64>) -> Throttle {
+        Throttle { rate, start: Instant::now(), bytes: 0 }
+    }
+
+    // Smaller chunks under low limits, so the transfer does not stall between bursts
+    fn chunk_size(&self) -> u64 {
+        match self.rate {
+            Some(rate) => (rate / 4).clamp(4096, CHUNK_SIZE),
+            None => CHUNK_SIZE,
+        }
+    }
+
+    async fn wait(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if let Some(rate) = self.rate {
+            let due = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                tokio::time::sleep(due - elapsed).await;
+            }
+        }
+    }
+}
+
+async fn hash_file(path: PathBuf) -> Result<String, AttemptError> {
+    tokio::task::spawn_blocking(move || files::file_sha256(&path))
+        .await
+        .map_err(fatal)?
+        .map_err(fatal)
+}
+
+struct Transfer {
+    id: u64,
+    peer: PeerId,
+    spec: TransferSpec,
+    handle: NodeHandle,
+}
+
+impl Transfer {
+    async fn request(&self, op: FileOp, data: Vec<u8>) -> Result<FileResponse, AttemptError> {
+        match self.handle.file_request(self.peer, FileRequest { op, data }).await {
+            Ok(FileResponse { result: FileResult::Error { message }, .. }) => Err(AttemptError::Fatal(message)),
+            Ok(res) => Ok(res),
+            Err(e) => Err(AttemptError::Retry(e.to_string())),
+        }
+    }
+
+    fn progress(&self, transferred: u64) {
+        self.handle.transfers().update(self.id, |t| t.transferred = transferred);
+    }
+
+    fn started(&self, size: u64, offset: u64) {
+        self.handle.transfers().update(self.id, |t| {
+            t.size = Some(size);
+            t.transferred = offset;
+            t.resumed_from = offset;
+            t.state = TransferState::Running;
+        });
+    }
+
+    async fn push(&self) -> Result<(), AttemptError> {
+        let local = Path::new(&self.spec.local);
+        let size = fs::metadata(local).map_err(|e| fatal(format!("{}: {}", local.display(), e)))?.len();
+        let file_sha256 = hash_file(local.to_path_buf()).await?;
+        let partial = match self.request(FileOp::Stat { path: self.spec.remote.clone() }, Vec::new()).await?.result {
+            FileResult::Stat { partial, .. } => partial,
+            result => return Err(unexpected(result)),
+        };
+        // A complete partial failed its hash check, start over
+        let mut offset = if partial < size { partial } else { 0 };
+        self.started(size, offset);
+        let mut file = File::open(local).map_err(fatal)?;
+        let mut throttle = Throttle::new(self.spec.rate_limit);
+        loop {
+            let len = throttle.chunk_size().min(size - offset);
+            let mut data = Vec::with_capacity(len as usize);
+            file.seek(SeekFrom::Start(offset)).map_err(fatal)?;
+            (&mut file).take(len).read_to_end(&mut data).map_err(fatal)?;
+            let op = FileOp::Write {
+                path: self.spec.remote.clone(),
+                offset,
+                size,
+                sha256: files::sha256_hex(&data),
+                file_sha256: file_sha256.clone(),
+            };
+            offset = match self.request(op, data).await?.result {
+                FileResult::Written { partial } => partial,
+                result => return Err(unexpected(result)),
+            };
+            self.progress(offset);
+            if offset >= size {
+                return Ok(());
+            }
+            throttle.wait(len).await;
+        }
+    }
+
+    async fn pull(&self) -> Result<(), AttemptError> {
+        let (size, file_sha256) = match self.request(FileOp::Stat { path: self.spec.remote.clone() }, Vec::new()).await?.result {
+            FileResult::Stat { size: Some(size), sha256: Some(sha256), .. } => (size, sha256),
+            FileResult::Stat { .. } => return Err(AttemptError::Fatal(format!("No file {} on the peer", self.spec.remote))),
+            result => return Err(unexpected(result)),
+        };
+        let local = Path::new(&self.spec.local);
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent).map_err(fatal)?;
+        }
+        let partial = files::partial_path(local);
+        let mut file = OpenOptions::new().create(true).append(true).open(&partial).map_err(fatal)?;
+        let mut offset = file.metadata().map_err(fatal)?.len();
+        if offset > size {
+            file.set_len(0).map_err(fatal)?;
+            offset = 0;
+        }
+        self.started(size, offset);
+        let mut throttle = Throttle::new(self.spec.rate_limit);
+        while offset < size {
+            let op = FileOp::Read { path: self.spec.remote.clone(), offset, len: throttle.chunk_size() };
+            let res = self.request(op, Vec::new()).await?;
+            match res.result {
+                FileResult::Chunk { size: current, .. } if current != size => {
+                    return Err(AttemptError::Fatal(format!("{} changed on the peer during the copy", self.spec.remote)));
+                },
+                FileResult::Chunk { sha256, .. } if sha256 != files::sha256_hex(&res.data) => {
+                    return Err(AttemptError::Retry("Chunk does not match its hash".to_string()));
+                },
+                FileResult::Chunk { .. } if res.data.is_empty() => {
+                    return Err(AttemptError::Fatal(format!("{} is shorter than announced", self.spec.remote)));
+                },
+                FileResult::Chunk { .. } => {},
+                result => return Err(unexpected(result)),
+            }
+            file.write_all(&res.data).map_err(fatal)?;
+            offset += res.data.len() as u64;
+            self.progress(offset);
+            throttle.wait(res.data.len() as u64).await;
+        }
+        file.sync_all().map_err(fatal)?;
+        if hash_file(partial.clone()).await? != file_sha256 {
+            let _ = fs::remove_file(&partial);
+            return Err(AttemptError::Fatal("Copy does not match the hash of the file, the partial copy was removed".to_string()));
+        }
+        fs::rename(&partial, local).map_err(fatal)
+    }
+
+    async fn run(self) {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            self.handle.transfers().update(self.id, |t| t.attempts += 1);
+            let r = match self.spec.direction {
+                Direction::Push => self.push().await,
+                Direction::Pull => self.pull().await,
+            };
+            let attempts = self.handle.transfers().get(self.id).map(|t| t.attempts).unwrap_or(MAX_ATTEMPTS);
+            match r {
+                Ok(_) => {
+                    info!("Transfer {} done: {:?} {} {}", self.id, self.spec.direction, self.spec.local, self.spec.remote);
+                    self.handle.transfers().update(self.id, |t| {
+                        t.state = TransferState::Done;
+                        t.error = None;
+                    });
+                    return;
+                },
+                Err(AttemptError::Retry(e)) if attempts < MAX_ATTEMPTS => {
+                    warn!("Transfer {} interrupted, resuming in {:?}: {}", self.id, backoff, e);
+                    self.handle.transfers().update(self.id, |t| {
+                        t.state = TransferState::Retrying;
+                        t.error = Some(e);
+                    });
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                },
+                Err(AttemptError::Retry(e)) | Err(AttemptError::Fatal(e)) => {
+                    warn!("Transfer {} failed: {}", self.id, e);
+                    self.handle.transfers().update(self.id, |t| {
+                        t.state = TransferState::Failed;
+                        t.error = Some(e);
+                    });
+                    return;
+                },
+            }
+        }
+    }
+}
+
+/// Check the spec and run the transfer in the background, its progress is
+/// read from `NodeHandle::transfers`
+pub fn start(handle: &NodeHandle, spec: TransferSpec) -> Result<TransferStatus, Box<dyn Error>> {
+    let peer = spec.peer.parse::<PeerId>().map_err(|e| format!("Invalid peer id {}: {}", spec.peer, e))?;
+    if !Path::new(&spec.local).is_absolute() {
+        return Err(format!("Local path {} is not absolute", spec.local).into());
+    }
+    if spec.direction == Direction::Push && !Path::new(&spec.local).is_file() {
+        return Err(format!("No file {}", spec.local).into());
+    }
+    if spec.rate_limit == Some(0) {
+        return Err("Rate limit must be more than 0".into());
+    }
+    let status = handle.transfers().insert(spec.clone());
+    let transfer = Transfer { id: status.id, peer, spec, handle: handle.clone() };
+    tokio::spawn(transfer.run());
+    Ok(status)
+}
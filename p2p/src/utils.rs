@@ -0,0 +1,11 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Unix time in milliseconds, 0 if the clock is set before 1970
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Unix time in seconds
+pub fn now_secs() -> u64 {
+    now_millis() / 1000
+}
@@ -1,43 +1,1048 @@
-use std::{error::{Error}, sync::{Mutex, RwLock, Arc}};
-use actix_web::{get, web::{self, Data}, App, HttpServer, Responder, dev::Service as _,};
-use log::{debug, info};
-use p2p::{message::Message, state::NodeState};
-use p2p::node::Sender;
-use futures::SinkExt;
+use std::{collections::HashMap, fs::File, io::{BufReader, Read, Seek, SeekFrom}, sync::{Arc, Mutex}, time::Duration};
+use actix_web::{dev::Server, get, post, web::{self, Data}, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, dev::Service as _, http::header::{HeaderName, HeaderValue}};
+use tracing::{debug, info, info_span, Instrument};
+use p2p::{container::{ContainerRequest, ContainerResponse, ContainerSpec}, deploy::{self, DeployStatus, Deployment, DeploymentStatus}, exec::{ExecRequest, ExecResult}, rollout::{self, RolloutRequest}, cron::{self, Job, JobStatus, Run}, artifact::Artifacts, backup, handle::{NodeHandle, QueueFull}, kv, labels::{Groups, Labels, Selector}, message::{self, Message}, peer::Peer, raft::{RaftCommand, StateEntry}, service::{ServiceRequest, ServiceResponse}, transfer::{self, TransferSpec}};
+use libp2p::{Multiaddr, PeerId};
 use futures_util::future::FutureExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 struct AppState {
     counter: Mutex<i32>,
-    proxy_sender: Arc<RwLock<Sender<Message>>>,
-    state: Arc<RwLock<NodeState>>
+    node: NodeHandle,
+    db: sled::Db,
+    log_file: Option<String>,
+    artifacts: Arc<Artifacts>,
+    db_tasks: Arc<Mutex<DbTasks>>,
+}
+
+/// Work on the database files the node is stopped for, `hanode start` runs it
+/// and starts the node again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum DbTask {
+    Restore { file: String },
+    Compact,
+}
+
+/// How a task went, `result` is what the same command prints offline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbTaskResult {
+    pub id: u64,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+/// The tasks of one process, kept while the node is stopped and started again
+#[derive(Debug, Default)]
+pub struct DbTasks {
+    pub last_id: u64,
+    // Waiting for the node to stop
+    pub pending: Option<(u64, DbTask)>,
+    // The last one run
+    pub done: Option<DbTaskResult>,
+}
+
+// Trace id of the request, carried by the messages it sends to peers
+#[derive(Debug, Clone)]
+struct TraceId(String);
+
+// Most bytes of log returned by one /logs request
+const MAX_LOG_READ: u64 = 1024 * 1024;
+
+// `?selector=env=prod,role!=db&group=db`, every peer without them
+#[derive(Debug, Deserialize)]
+struct TargetQuery {
+    selector: Option<String>,
+    group: Option<String>,
+}
+
+impl TargetQuery {
+    fn is_empty(&self) -> bool {
+        self.selector.is_none() && self.group.is_none()
+    }
+}
+
+// Requests that run commands or read and write paths of the node are only
+// served on the unix socket, whose permissions keep other users out, never on
+// the tcp listener
+fn socket_only(req: &HttpRequest) -> Result<(), HttpResponse> {
+    if req.peer_addr().is_some() {
+        return Err(HttpResponse::Forbidden().body(format!("{} is only served on the unix socket", req.path())));
+    }
+    Ok(())
+}
+
+// Selector of the query, with the group resolved
+fn target_selector(state: &AppState, query: &TargetQuery) -> Result<Selector, HttpResponse> {
+    Groups::open(&state.db)
+        .map_err(|e| e.into())
+        .and_then(|groups| groups.resolve(query.selector.as_deref(), query.group.as_deref()))
+        .map_err(|err| HttpResponse::BadRequest().body(err.to_string()))
+}
+
+// Known peers matching the query, the error is the response to return
+async fn target_peers(state: &AppState, query: &TargetQuery) -> Result<Vec<Peer>, HttpResponse> {
+    let selector = target_selector(state, query)?;
+    let peers = state.node.peers().await.map_err(|err| HttpResponse::ServiceUnavailable().body(err.to_string()))?;
+    Ok(peers.into_iter().filter(|p| selector.matches(&p.all_labels())).collect())
 }
 
 #[get("/boardcast/{message}")]
-async fn boardcast(state: Data<AppState>, message: web::Path<String>) -> impl Responder {
-    let mut counter = state.counter.lock().unwrap(); // <- get counter's MutexGuard
-    *counter += 1; // <- access counter inside MutexGuard
-    let _ = (*state.proxy_sender.write().unwrap()).send(Message::from(message.to_string())).await;
-    format!("Hello {message} {counter}!")
+async fn boardcast(req: HttpRequest, state: Data<AppState>, message: web::Path<String>, query: web::Query<TargetQuery>) -> HttpResponse {
+    let targets: Vec<String> = if query.is_empty() {
+        Vec::new()
+    } else {
+        match target_peers(&state, &query).await {
+            Ok(peers) if peers.is_empty() => return HttpResponse::NotFound().body("No peer matches the selector"),
+            Ok(peers) => peers.into_iter().map(|p| p.id).collect(),
+            Err(res) => return res,
+        }
+    };
+    // Released before publishing, which may wait for room in the queue
+    let count = {
+        let mut counter = state.counter.lock().unwrap(); // <- get counter's MutexGuard
+        *counter += 1; // <- access counter inside MutexGuard
+        *counter
+    };
+    let mut msg = Message::from(message.to_string());
+    msg.targets = targets.clone();
+    if let Some(trace_id) = req.extensions().get::<TraceId>() {
+        msg.trace_id = trace_id.0.clone();
+    }
+    let trace_id = msg.trace_id.clone();
+    let (sent, dropped) = match state.node.publish(msg).await {
+        Ok(queued) => (queued, !queued),
+        Err(err) if err.is::<QueueFull>() => return HttpResponse::TooManyRequests().body(err.to_string()),
+        Err(_) => (false, false),
+    };
+    HttpResponse::Ok().json(json!({
+        "message": message.to_string(),
+        "sent": sent,
+        "dropped": dropped,
+        "count": count,
+        "trace_id": trace_id,
+        "targets": targets,
+    }))
 }
 
 #[get("/stop")]
 async fn stop_p2p_node(state: Data<AppState>) -> impl Responder {
-    match (*state.proxy_sender.write().unwrap()).send(Message::stop_message()).await {
+    let stopped = match state.node.stop().await {
         Ok(_) => {
             println!("Stopped p2p node");
+            true
         },
         Err(err) => {
             println!("Failed to stop p2p node: {:?}", err);
+            false
         }
-    }
-    format!("stop")
+    };
+    web::Json(json!({
+        "stopped": stopped,
+    }))
 }
 
 #[get("/peers")]
-async fn peers(state: Data<AppState>) -> Result<impl Responder, Box<dyn Error>> {
-    let peers = state.state.read().unwrap().peers.clone();
-    debug!("{:?}", peers);
-    Ok(web::Json(peers))
+async fn peers(state: Data<AppState>, query: web::Query<TargetQuery>) -> HttpResponse {
+    match target_peers(&state, &query).await {
+        Ok(peers) => {
+            debug!("{:?}", peers);
+            // Keyed by peer id as returned by older versions
+            let peers: HashMap<String, _> = peers.into_iter().map(|p| (p.id.clone(), p)).collect();
+            HttpResponse::Ok().json(peers)
+        },
+        Err(res) => res,
+    }
+}
+
+#[derive(Deserialize)]
+struct TagRequest {
+    #[serde(default)]
+    set: Labels,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+#[post("/peers/{peer_id}/tags")]
+async fn tag_peer(state: Data<AppState>, peer_id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: TagRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid tags: {}", err)),
+    };
+    let id = match peer_id.parse::<PeerId>() {
+        Ok(id) => id,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid peer id {}: {}", peer_id, err)),
+    };
+    match state.node.tag_peer(id, req.set, req.remove).await {
+        Ok(peer) => HttpResponse::Ok().json(peer),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[get("/groups")]
+async fn groups(state: Data<AppState>) -> HttpResponse {
+    match Groups::open(&state.db).map_err(|e| e.into()).and_then(|groups| groups.list()) {
+        Ok(groups) => HttpResponse::Ok().json(groups),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct GroupRequest {
+    // Null removes the group
+    selector: Option<String>,
+}
+
+#[post("/groups/{name}")]
+async fn group_write(state: Data<AppState>, name: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: GroupRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid group: {}", err)),
+    };
+    let groups = match Groups::open(&state.db) {
+        Ok(groups) => groups,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    match req.selector {
+        Some(selector) => match selector.parse::<Selector>() {
+            Ok(selector) => match groups.set(&name, &selector) {
+                Ok(group) => HttpResponse::Ok().json(group),
+                Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+            },
+            Err(err) => HttpResponse::BadRequest().body(err),
+        },
+        None => match groups.remove(&name) {
+            Ok(true) => HttpResponse::Ok().json(json!({ "name": name.to_string(), "removed": true })),
+            Ok(false) => HttpResponse::NotFound().body(format!("No group {}", name)),
+            Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerQuery {
+    // The services or containers of this peer instead of the node's
+    peer: Option<String>,
+    lines: Option<usize>,
+    #[serde(default)]
+    force: bool,
+}
+
+impl PeerQuery {
+    fn peer(&self) -> Result<Option<PeerId>, HttpResponse> {
+        match self.peer.as_deref().map(|p| p.parse::<PeerId>()) {
+            Some(Ok(peer)) => Ok(Some(peer)),
+            Some(Err(err)) => Err(HttpResponse::BadRequest().body(format!("Invalid peer id: {}", err))),
+            None => Ok(None),
+        }
+    }
+}
+
+async fn service_request(http: &HttpRequest, state: &AppState, query: &PeerQuery, req: ServiceRequest) -> HttpResponse {
+    if let Err(res) = socket_only(http) {
+        return res;
+    }
+    let peer = match query.peer() {
+        Ok(peer) => peer,
+        Err(res) => return res,
+    };
+    match state.node.service(peer, req).await {
+        Ok(ServiceResponse::Error { message }) if message.starts_with("No service") => HttpResponse::NotFound().body(message),
+        Ok(ServiceResponse::Error { message }) => HttpResponse::BadRequest().body(message),
+        Ok(ServiceResponse::Services { services }) => HttpResponse::Ok().json(services),
+        Ok(ServiceResponse::Service { service }) => HttpResponse::Ok().json(service),
+        Ok(ServiceResponse::Logs { lines }) => HttpResponse::Ok().json(lines),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[get("/services")]
+async fn services(http: HttpRequest, state: Data<AppState>, query: web::Query<PeerQuery>) -> HttpResponse {
+    service_request(&http, &state, &query, ServiceRequest::List).await
+}
+
+#[get("/services/{name}/logs")]
+async fn service_logs(http: HttpRequest, state: Data<AppState>, name: web::Path<String>, query: web::Query<PeerQuery>) -> HttpResponse {
+    let lines = query.lines.unwrap_or(100);
+    service_request(&http, &state, &query, ServiceRequest::Logs { name: name.to_string(), lines }).await
+}
+
+#[post("/services/{name}/{action}")]
+async fn service_action(http: HttpRequest, state: Data<AppState>, path: web::Path<(String, String)>, query: web::Query<PeerQuery>) -> HttpResponse {
+    let (name, action) = path.into_inner();
+    let req = match action.as_str() {
+        "start" => ServiceRequest::Start { name },
+        "stop" => ServiceRequest::Stop { name },
+        "restart" => ServiceRequest::Restart { name },
+        _ => return HttpResponse::NotFound().body(format!("Unknown service action {}", action)),
+    };
+    service_request(&http, &state, &query, req).await
+}
+
+async fn container_request(http: &HttpRequest, state: &AppState, query: &PeerQuery, req: ContainerRequest) -> HttpResponse {
+    if let Err(res) = socket_only(http) {
+        return res;
+    }
+    let peer = match query.peer() {
+        Ok(peer) => peer,
+        Err(res) => return res,
+    };
+    match state.node.container(peer, req).await {
+        Ok(ContainerResponse::Error { message }) if message.starts_with("No container") => HttpResponse::NotFound().body(message),
+        Ok(ContainerResponse::Error { message }) => HttpResponse::BadRequest().body(message),
+        Ok(ContainerResponse::Containers { containers }) => HttpResponse::Ok().json(containers),
+        Ok(ContainerResponse::Container { container }) => HttpResponse::Ok().json(container),
+        Ok(ContainerResponse::Removed { name }) => HttpResponse::Ok().json(json!({ "name": name, "removed": true })),
+        Ok(ContainerResponse::Logs { lines }) => HttpResponse::Ok().json(lines),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[get("/containers")]
+async fn containers(http: HttpRequest, state: Data<AppState>, query: web::Query<PeerQuery>) -> HttpResponse {
+    container_request(&http, &state, &query, ContainerRequest::List).await
+}
+
+#[post("/containers")]
+async fn container_run(http: HttpRequest, state: Data<AppState>, query: web::Query<PeerQuery>, body: web::Bytes) -> HttpResponse {
+    let spec: ContainerSpec = match serde_json::from_slice(&body) {
+        Ok(spec) => spec,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid container: {}", err)),
+    };
+    container_request(&http, &state, &query, ContainerRequest::Run { spec }).await
+}
+
+#[get("/containers/{name}/logs")]
+async fn container_logs(http: HttpRequest, state: Data<AppState>, name: web::Path<String>, query: web::Query<PeerQuery>) -> HttpResponse {
+    let lines = query.lines.unwrap_or(100);
+    container_request(&http, &state, &query, ContainerRequest::Logs { name: name.to_string(), lines }).await
+}
+
+#[post("/containers/{name}/{action}")]
+async fn container_action(http: HttpRequest, state: Data<AppState>, path: web::Path<(String, String)>, query: web::Query<PeerQuery>) -> HttpResponse {
+    let (name, action) = path.into_inner();
+    let req = match action.as_str() {
+        "stop" => ContainerRequest::Stop { name },
+        "rm" => ContainerRequest::Remove { name, force: query.force },
+        _ => return HttpResponse::NotFound().body(format!("Unknown container action {}", action)),
+    };
+    container_request(&http, &state, &query, req).await
+}
+
+// The node alone without a selector, otherwise it and the peers matching
+#[post("/exec")]
+async fn exec(http: HttpRequest, state: Data<AppState>, query: web::Query<TargetQuery>, body: web::Bytes) -> HttpResponse {
+    if let Err(res) = socket_only(&http) {
+        return res;
+    }
+    let req: ExecRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid command: {}", err)),
+    };
+    if let Err(message) = req.check() {
+        return HttpResponse::BadRequest().body(message);
+    }
+    let info = match state.node.info().await {
+        Ok(info) => info,
+        Err(err) => return HttpResponse::ServiceUnavailable().body(err.to_string()),
+    };
+    let mut targets = vec![(info.peer_id.clone(), None)];
+    if !query.is_empty() {
+        let selector = match target_selector(&state, &query) {
+            Ok(selector) => selector,
+            Err(res) => return res,
+        };
+        if !selector.matches(&info.labels) {
+            targets.clear();
+        }
+        match target_peers(&state, &query).await {
+            Ok(peers) => targets.extend(peers.into_iter().filter_map(|p| p.id.parse::<PeerId>().ok().map(|id| (p.id, Some(id))))),
+            Err(res) => return res,
+        }
+        if targets.is_empty() {
+            return HttpResponse::NotFound().body("No peer matches the selector");
+        }
+    }
+    let runs = targets.into_iter().map(|(node, peer)| {
+        let req = req.clone();
+        let handle = state.node.clone();
+        async move { ExecResult::new(&node, handle.exec(peer, req).await.map_err(|e| e.to_string())) }
+    });
+    HttpResponse::Ok().json(futures::future::join_all(runs).await)
+}
+
+#[get("/metrics")]
+async fn metrics(state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(state.node.metrics())
+}
+
+#[get("/info")]
+async fn node_info(state: Data<AppState>) -> HttpResponse {
+    match state.node.info().await {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DialRequest {
+    addr: String,
+}
+
+#[get("/members")]
+async fn members(state: Data<AppState>) -> HttpResponse {
+    match state.node.members().await {
+        Ok(members) => HttpResponse::Ok().json(members),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[post("/peers/dial")]
+async fn dial(state: Data<AppState>, body: web::Bytes) -> HttpResponse {
+    // Parsed here, clients do not all send a json content type
+    let req: DialRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid dial request: {}", err)),
+    };
+    let addr = match req.addr.parse::<Multiaddr>() {
+        Ok(addr) => addr,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid address {}: {}", req.addr, err)),
+    };
+    match state.node.dial(addr).await {
+        Ok(_) => HttpResponse::Ok().json(json!({ "addr": req.addr, "dialed": true })),
+        Err(err) => HttpResponse::BadRequest().body(format!("Failed to dial {}: {}", req.addr, err)),
+    }
+}
+
+#[post("/peers/{peer_id}/disconnect")]
+async fn disconnect(state: Data<AppState>, peer_id: web::Path<String>) -> HttpResponse {
+    let id = match peer_id.parse::<PeerId>() {
+        Ok(id) => id,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid peer id {}: {}", peer_id, err)),
+    };
+    match state.node.disconnect(id).await {
+        Ok(true) => HttpResponse::Ok().json(json!({ "peer_id": peer_id.to_string(), "disconnected": true })),
+        Ok(false) => HttpResponse::NotFound().body(format!("Peer {} is not connected", peer_id)),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[post("/transfers")]
+async fn start_transfer(http: HttpRequest, state: Data<AppState>, body: web::Bytes) -> HttpResponse {
+    if let Err(res) = socket_only(&http) {
+        return res;
+    }
+    let spec: TransferSpec = match serde_json::from_slice(&body) {
+        Ok(spec) => spec,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid transfer: {}", err)),
+    };
+    match transfer::start(&state.node, spec) {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[get("/transfers")]
+async fn transfers(state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.node.transfers().list())
+}
+
+#[get("/transfers/{id}")]
+async fn transfer_status(state: Data<AppState>, id: web::Path<u64>) -> HttpResponse {
+    match state.node.transfers().get(*id) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::NotFound().body(format!("No transfer {}", id)),
+    }
+}
+
+#[derive(Deserialize)]
+struct AddArtifactRequest {
+    path: String,
+    name: Option<String>,
+}
+
+#[post("/artifacts")]
+async fn add_artifact(http: HttpRequest, state: Data<AppState>, body: web::Bytes) -> HttpResponse {
+    if let Err(res) = socket_only(&http) {
+        return res;
+    }
+    let req: AddArtifactRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid artifact: {}", err)),
+    };
+    let name = req.name.unwrap_or_else(|| {
+        std::path::Path::new(&req.path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default()
+    });
+    match state.artifacts.add(&state.node, &req.path, &name).await {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[get("/artifacts")]
+async fn artifacts(state: Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.artifacts.list())
+}
+
+#[get("/artifacts/{id}")]
+async fn artifact(state: Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    match state.artifacts.info(&id) {
+        Some(info) => HttpResponse::Ok().json(info),
+        None => HttpResponse::NotFound().body(format!("No artifact {}", id)),
+    }
+}
+
+#[derive(Deserialize)]
+struct FetchArtifactRequest {
+    #[serde(default)]
+    pin: bool,
+}
+
+#[post("/artifacts/{id}/fetch")]
+async fn fetch_artifact(state: Data<AppState>, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: FetchArtifactRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid fetch request: {}", err)),
+    };
+    match state.artifacts.fetch(&state.node, &id, req.pin) {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportArtifactRequest {
+    path: String,
+}
+
+#[post("/artifacts/{id}/export")]
+async fn export_artifact(http: HttpRequest, state: Data<AppState>, id: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    if let Err(res) = socket_only(&http) {
+        return res;
+    }
+    let req: ExportArtifactRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid export request: {}", err)),
+    };
+    match state.artifacts.export(&id, &req.path).await {
+        Ok(info) => HttpResponse::Ok().json(info),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[post("/artifacts/gc")]
+async fn gc_artifacts(state: Data<AppState>) -> HttpResponse {
+    match state.artifacts.gc() {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KvQuery {
+    #[serde(default)]
+    prefix: String,
+    // Cursor returned by the previous call, to watch the changes
+    since: Option<u64>,
+}
+
+#[get("/kv")]
+async fn kv_list(state: Data<AppState>, query: web::Query<KvQuery>) -> HttpResponse {
+    if !kv::valid_prefix(&query.prefix) {
+        return HttpResponse::BadRequest().body(format!("Invalid prefix {}", query.prefix));
+    }
+    match state.node.kv_list(&query.prefix, query.since).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[get("/kv/{key:.*}")]
+async fn kv_get(state: Data<AppState>, key: web::Path<String>) -> HttpResponse {
+    match state.node.kv_get(&key).await {
+        Ok(Some(record)) => HttpResponse::Ok().json(record),
+        Ok(None) => HttpResponse::NotFound().body(format!("No key {}", key)),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct KvWriteRequest {
+    // Null deletes the key
+    value: Option<String>,
+}
+
+#[post("/kv/{key:.*}")]
+async fn kv_write(state: Data<AppState>, key: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: KvWriteRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid kv write: {}", err)),
+    };
+    let r = match req.value {
+        Some(value) => state.node.kv_put(&key, &value).await,
+        None => state.node.kv_delete(&key).await,
+    };
+    match r {
+        Ok(record) => HttpResponse::Ok().json(record),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+// Deployments of the cluster kv and the statuses the nodes reported
+async fn deploy_records(state: &AppState) -> Result<Vec<kv::Record>, HttpResponse> {
+    match state.node.kv_list(deploy::DEPLOY_PREFIX, None).await {
+        Ok(list) => Ok(list.records),
+        Err(err) => Err(HttpResponse::ServiceUnavailable().body(err.to_string())),
+    }
+}
+
+#[get("/deployments")]
+async fn deployments(state: Data<AppState>) -> HttpResponse {
+    let records = match deploy_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (deployments, mut statuses) = deploy::parse_records(&records);
+    let list: Vec<DeploymentStatus> = deployments.values()
+        .map(|d| DeploymentStatus::new(d, statuses.remove(d.name()).unwrap_or_default()))
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+#[get("/deployments/{name}")]
+async fn deployment(state: Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let records = match deploy_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (deployments, mut statuses) = deploy::parse_records(&records);
+    match deployments.get(name.as_str()) {
+        Some(d) => HttpResponse::Ok().json(DeploymentStatus::new(d, statuses.remove(d.name()).unwrap_or_default())),
+        None => HttpResponse::NotFound().body(format!("No deployment {}", name)),
+    }
+}
+
+#[post("/deployments")]
+async fn deployment_apply(state: Data<AppState>, body: web::Bytes) -> HttpResponse {
+    let deployment: Deployment = match serde_json::from_slice(&body) {
+        Ok(deployment) => deployment,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid deployment: {}", err)),
+    };
+    if let Err(err) = deployment.check() {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let records = match deploy_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (deployments, mut statuses) = deploy::parse_records(&records);
+    let reported = statuses.remove(deployment.name()).unwrap_or_default();
+    let current = deployments.get(deployment.name());
+    let deployment = deployment.replacing(current);
+    save_deployment(&state, deployment, reported).await
+}
+
+async fn save_deployment(state: &AppState, deployment: Deployment, statuses: Vec<DeployStatus>) -> HttpResponse {
+    let value = match serde_json::to_string(&deployment) {
+        Ok(value) => value,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    match state.node.kv_put(&deploy::spec_key(deployment.name()), &value).await {
+        Ok(_) => HttpResponse::Ok().json(DeploymentStatus::new(&deployment, statuses)),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+// The spec of a deployment replaced by what `update` makes of it, with the
+// node serving the request as the driver of a rollout
+async fn update_deployment<F>(state: &AppState, name: &str, update: F) -> HttpResponse
+where
+    F: FnOnce(&Deployment, &str) -> Result<Deployment, String>,
+{
+    let driver = match state.node.info().await {
+        Ok(info) => info.peer_id,
+        Err(err) => return HttpResponse::ServiceUnavailable().body(err.to_string()),
+    };
+    let records = match deploy_records(state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (deployments, mut statuses) = deploy::parse_records(&records);
+    let current = match deployments.get(name) {
+        Some(current) => current,
+        None => return HttpResponse::NotFound().body(format!("No deployment {}", name)),
+    };
+    match update(current, &driver) {
+        Ok(deployment) => save_deployment(state, deployment, statuses.remove(name).unwrap_or_default()).await,
+        Err(err) => HttpResponse::BadRequest().body(err),
+    }
+}
+
+#[post("/rollouts/{name}")]
+async fn rollout_start(state: Data<AppState>, name: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: RolloutRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid rollout: {}", err)),
+    };
+    update_deployment(&state, &name, |current, driver| rollout::start(current, req.deployment, &req.options, driver)).await
+}
+
+#[post("/rollouts/{name}/{action}")]
+async fn rollout_action(state: Data<AppState>, path: web::Path<(String, String)>) -> HttpResponse {
+    let (name, action) = path.into_inner();
+    match action.as_str() {
+        "pause" => update_deployment(&state, &name, |current, _| rollout::pause(current)).await,
+        "resume" => update_deployment(&state, &name, rollout::resume).await,
+        "undo" => update_deployment(&state, &name, |current, _| rollout::undo(current)).await,
+        _ => HttpResponse::NotFound().body(format!("Unknown rollout action {}", action)),
+    }
+}
+
+#[post("/deployments/{name}/rm")]
+async fn deployment_remove(state: Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let records = match deploy_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (deployments, _) = deploy::parse_records(&records);
+    if !deployments.contains_key(name.as_str()) {
+        return HttpResponse::NotFound().body(format!("No deployment {}", name));
+    }
+    // The statuses too, nodes that went away would leave theirs behind
+    let prefix = format!("{}{}/", deploy::DEPLOY_PREFIX, name);
+    for record in records.iter().filter(|r| r.key.starts_with(&prefix)) {
+        if let Err(err) = state.node.kv_delete(&record.key).await {
+            return HttpResponse::ServiceUnavailable().body(err.to_string());
+        }
+    }
+    HttpResponse::Ok().json(json!({ "name": name.to_string(), "removed": true }))
+}
+
+// Jobs of the cluster kv and the runs the nodes wrote
+async fn cron_records(state: &AppState) -> Result<Vec<kv::Record>, HttpResponse> {
+    match state.node.kv_list(cron::CRON_PREFIX, None).await {
+        Ok(list) => Ok(list.records),
+        Err(err) => Err(HttpResponse::ServiceUnavailable().body(err.to_string())),
+    }
+}
+
+#[get("/cron")]
+async fn cron_jobs(state: Data<AppState>) -> HttpResponse {
+    let records = match cron_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (jobs, runs) = cron::parse_records(&records);
+    let list: Vec<JobStatus> = jobs.into_values()
+        .map(|job| {
+            let runs = runs.get(&job.name).map(|r| &r[..]).unwrap_or_default();
+            JobStatus::new(job, runs)
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+#[post("/cron")]
+async fn cron_add(state: Data<AppState>, body: web::Bytes) -> HttpResponse {
+    let job: Job = match serde_json::from_slice(&body) {
+        Ok(job) => job,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid job: {}", err)),
+    };
+    if let Err(err) = job.check() {
+        return HttpResponse::BadRequest().body(err);
+    }
+    let value = match serde_json::to_string(&job) {
+        Ok(value) => value,
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+    match state.node.kv_put(&cron::job_key(&job.name), &value).await {
+        Ok(_) => HttpResponse::Ok().json(JobStatus::new(job, &[])),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[get("/cron/{name}/history")]
+async fn cron_history(state: Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let records = match cron_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (jobs, mut runs) = cron::parse_records(&records);
+    if !jobs.contains_key(name.as_str()) {
+        return HttpResponse::NotFound().body(format!("No job {}", name));
+    }
+    let mut history: Vec<Run> = runs.remove(name.as_str()).unwrap_or_default().into_iter().map(|(_, run)| run).collect();
+    history.sort_by(|a, b| b.started.cmp(&a.started));
+    HttpResponse::Ok().json(history)
+}
+
+#[post("/cron/{name}/rm")]
+async fn cron_remove(state: Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let records = match cron_records(&state).await {
+        Ok(records) => records,
+        Err(res) => return res,
+    };
+    let (jobs, _) = cron::parse_records(&records);
+    if !jobs.contains_key(name.as_str()) {
+        return HttpResponse::NotFound().body(format!("No job {}", name));
+    }
+    let prefix = format!("{}{}/", cron::CRON_PREFIX, name);
+    for record in records.iter().filter(|r| r.key.starts_with(&prefix)) {
+        if let Err(err) = state.node.kv_delete(&record.key).await {
+            return HttpResponse::ServiceUnavailable().body(err.to_string());
+        }
+    }
+    HttpResponse::Ok().json(json!({ "name": name.to_string(), "removed": true }))
+}
+
+#[get("/health/checks")]
+async fn health_checks(state: Data<AppState>) -> HttpResponse {
+    match state.node.health_checks().await {
+        Ok(results) => HttpResponse::Ok().json(results),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[get("/health/alerts")]
+async fn health_alerts(state: Data<AppState>) -> HttpResponse {
+    match state.node.alerts().await {
+        Ok(alerts) => HttpResponse::Ok().json(alerts),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[get("/cluster/status")]
+async fn cluster_status(state: Data<AppState>) -> HttpResponse {
+    match state.node.raft_status().await {
+        Ok(Some(status)) => HttpResponse::Ok().json(status),
+        Ok(None) => HttpResponse::NotFound().body("Raft is not enabled on this node, start it with --raft-voters"),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+// Raft errors are mostly "not the leader", the request can be sent elsewhere
+#[get("/cluster/state/{key:.*}")]
+async fn cluster_get(state: Data<AppState>, key: web::Path<String>) -> HttpResponse {
+    match state.node.raft_read(&key).await {
+        Ok(Some(value)) => HttpResponse::Ok().json(StateEntry { key: key.to_string(), value: Some(value) }),
+        Ok(None) => HttpResponse::NotFound().body(format!("No key {}", key)),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[post("/cluster/state/{key:.*}")]
+async fn cluster_write(state: Data<AppState>, key: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: KvWriteRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid write: {}", err)),
+    };
+    if !kv::valid_key(&key) {
+        return HttpResponse::BadRequest().body(format!("Invalid key {}", key));
+    }
+    if req.value.as_ref().map(|v| v.len() > kv::MAX_VALUE_SIZE).unwrap_or(false) {
+        return HttpResponse::BadRequest().body(format!("The value of {} is larger than {} bytes", key, kv::MAX_VALUE_SIZE));
+    }
+    let command = match req.value.clone() {
+        Some(value) => RaftCommand::Set { key: key.to_string(), value },
+        None => RaftCommand::Delete { key: key.to_string() },
+    };
+    match state.node.raft_write(command).await {
+        Ok(_) => HttpResponse::Ok().json(StateEntry { key: key.to_string(), value: req.value }),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[get("/locks")]
+async fn lock_list(state: Data<AppState>) -> HttpResponse {
+    match state.node.lock_list().await {
+        Ok(locks) => HttpResponse::Ok().json(locks),
+        Err(err) => HttpResponse::ServiceUnavailable().body(err.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LockAcquireRequest {
+    // Seconds
+    ttl: u64,
+    // Renewed by the node until released
+    #[serde(default)]
+    keep: bool,
+}
+
+// Refusals are conflicts, the caller may retry once the lock is free or a
+// leader is elected
+#[post("/locks/{name:.*}/acquire")]
+async fn lock_acquire(state: Data<AppState>, name: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: LockAcquireRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid lock request: {}", err)),
+    };
+    if !kv::valid_key(&name) {
+        return HttpResponse::BadRequest().body(format!("Invalid lock name {}", name));
+    }
+    match state.node.lock_acquire(&name, Duration::from_secs(req.ttl), req.keep).await {
+        Ok(lock) => HttpResponse::Ok().json(lock),
+        Err(err) => HttpResponse::Conflict().body(err.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LockReleaseRequest {
+    token: Option<u64>,
+}
+
+#[post("/locks/{name:.*}/release")]
+async fn lock_release(state: Data<AppState>, name: web::Path<String>, body: web::Bytes) -> HttpResponse {
+    let req: LockReleaseRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid lock request: {}", err)),
+    };
+    match state.node.lock_release(&name, req.token).await {
+        Ok(_) => HttpResponse::Ok().json(json!({ "name": name.to_string(), "released": true })),
+        Err(err) => HttpResponse::Conflict().body(err.to_string()),
+    }
+}
+
+// The backup holds the node key, see `socket_only`
+#[get("/db/backup")]
+async fn db_backup(req: HttpRequest, state: Data<AppState>) -> HttpResponse {
+    if let Err(res) = socket_only(&req) {
+        return res;
+    }
+    let mut out = Vec::new();
+    match backup::export(&state.db, &mut out) {
+        Ok(summary) => {
+            info!("Exported {} records from {} trees", summary.records, summary.trees);
+            HttpResponse::Ok().content_type("application/x-ndjson").body(out)
+        },
+        Err(err) => HttpResponse::InternalServerError().body(format!("Backup failed: {}", err)),
+    }
+}
+
+// Stops the node for the task, the id tells the caller which result is its own
+async fn schedule_db_task(state: &AppState, task: DbTask) -> HttpResponse {
+    let id = {
+        let mut tasks = state.db_tasks.lock().unwrap();
+        if tasks.pending.is_some() {
+            return HttpResponse::Conflict().body("The node is already stopping for a database task");
+        }
+        tasks.last_id += 1;
+        tasks.pending = Some((tasks.last_id, task));
+        tasks.last_id
+    };
+    if let Err(err) = state.node.stop().await {
+        state.db_tasks.lock().unwrap().pending = None;
+        return HttpResponse::ServiceUnavailable().body(err.to_string());
+    }
+    info!("Stopping the node for database task {}", id);
+    HttpResponse::Ok().json(json!({ "id": id }))
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    // Absolute path of the backup on the node
+    file: String,
+}
+
+#[post("/db/restore")]
+async fn db_restore(http: HttpRequest, state: Data<AppState>, body: web::Bytes) -> HttpResponse {
+    if let Err(res) = socket_only(&http) {
+        return res;
+    }
+    let req: RestoreRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Invalid restore request: {}", err)),
+    };
+    if !std::path::Path::new(&req.file).is_absolute() {
+        return HttpResponse::BadRequest().body(format!("{} is not an absolute path", req.file));
+    }
+    // Checked before the node stops for it
+    let checked = match File::open(&req.file) {
+        Ok(file) => backup::inspect(BufReader::new(file)).map_err(|e| e.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    if let Err(err) = checked {
+        return HttpResponse::BadRequest().body(format!("Invalid backup {}: {}", req.file, err));
+    }
+    schedule_db_task(&state, DbTask::Restore { file: req.file }).await
+}
+
+#[post("/db/compact")]
+async fn db_compact(http: HttpRequest, state: Data<AppState>) -> HttpResponse {
+    if let Err(res) = socket_only(&http) {
+        return res;
+    }
+    schedule_db_task(&state, DbTask::Compact).await
+}
+
+// Null until the task ran and the node started again
+#[get("/db/tasks/{id}")]
+async fn db_task(state: Data<AppState>, id: web::Path<u64>) -> HttpResponse {
+    let done = state.db_tasks.lock().unwrap().done.clone().filter(|done| done.id == *id);
+    HttpResponse::Ok().json(done)
+}
+
+#[get("/db/dump/{tree}")]
+async fn db_dump(state: Data<AppState>, tree: web::Path<String>) -> HttpResponse {
+    match backup::dump(&state.db, &tree) {
+        Ok(records) => HttpResponse::Ok().json(records),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LogsQuery {
+    // Byte offset returned by the previous call, to follow the file
+    offset: Option<u64>,
+    // Lines from the end of the file on the first call
+    lines: Option<usize>,
+}
+
+fn read_logs(path: &str, query: &LogsQuery) -> std::io::Result<(Vec<String>, u64)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let start = match query.offset {
+        // The file was rotated since the last call
+        Some(offset) if offset > len => 0,
+        Some(offset) => offset,
+        None => len.saturating_sub(MAX_LOG_READ),
+    };
+    file.seek(SeekFrom::Start(start))?;
+    let mut buf = Vec::new();
+    file.take(MAX_LOG_READ).read_to_end(&mut buf)?;
+    // Keep a partly written last line for the next call
+    let end = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => 0,
+    };
+    let text = String::from_utf8_lossy(&buf[..end]);
+    let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+    if query.offset.is_none() {
+        // Reading started in the middle of a line
+        if start > 0 && !lines.is_empty() {
+            lines.remove(0);
+        }
+        let keep = query.lines.unwrap_or(100);
+        lines = lines.split_off(lines.len().saturating_sub(keep));
+    }
+    Ok((lines, start + end as u64))
+}
+
+#[get("/logs")]
+async fn logs(state: Data<AppState>, query: web::Query<LogsQuery>) -> HttpResponse {
+    let path = match &state.log_file {
+        Some(path) => path,
+        None => return HttpResponse::NotFound().body("The node does not write a log file"),
+    };
+    match read_logs(path, &query) {
+        Ok((lines, offset)) => HttpResponse::Ok().json(json!({
+            "file": path,
+            "lines": lines,
+            "offset": offset,
+        })),
+        Err(err) => HttpResponse::InternalServerError().body(format!("Failed to read {}: {}", path, err)),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -45,13 +1050,19 @@ pub struct ServerOptions {
     pub server: bool, // true if the open server
     pub host: Option<String>,
     pub port: u16,
-    pub sock_file: String
+    pub sock_file: String,
+    pub log_file: Option<String>, // served by /logs
+    pub artifacts_dir: String,
+    // Database tasks the node is stopped for
+    pub db_tasks: Arc<Mutex<DbTasks>>,
 }
 
 /**
  * UDS client example: curl -v --unix-socket hanode.sock http://localhost/peers
  */
-pub async fn start_server(proxy_sender: Arc<RwLock<Sender<Message>>>, state: Arc<RwLock<NodeState>>, opts: ServerOptions) -> Result<(), std::io::Error> {
+/// Bind the control API, the returned server runs once spawned and its
+/// handle stops it
+pub fn start_server(node: NodeHandle, db: sled::Db, opts: ServerOptions) -> Result<Server, std::io::Error> {
     let host = match opts.host {
         Some(host) => host,
         None => "127.0.0.1".to_string(),
@@ -59,28 +1070,99 @@ pub async fn start_server(proxy_sender: Arc<RwLock<Sender<Message>>>, state: Arc
     let port = opts.port.clone();
     let state = Data::new(AppState {
         counter: Mutex::new(0),
-        proxy_sender,
-        state
+        node,
+        db,
+        log_file: opts.log_file.clone(),
+        artifacts: Arc::new(Artifacts::new(&opts.artifacts_dir)),
+        db_tasks: opts.db_tasks.clone(),
     });
     // IPC devops
     let server = HttpServer::new(move || {
         App::new().
             wrap_fn(|req, srv| {
-                // This is a middleware that for output request_url
-                srv.call(req).map(|res| {
-                    res
-                })
+                // Every request starts a trace, returned in the x-trace-id header
+                let trace_id = message::new_trace_id();
+                let span = info_span!(
+                    "http_request",
+                    method = %req.method(),
+                    path = %req.path(),
+                    trace_id = %trace_id,
+                    status = tracing::field::Empty,
+                );
+                req.extensions_mut().insert(TraceId(trace_id.clone()));
+                let request_span = span.clone();
+                srv.call(req).map(move |res| {
+                    res.map(|mut res| {
+                        request_span.record("status", res.status().as_u16());
+                        if let Ok(value) = HeaderValue::from_str(&trace_id) {
+                            res.headers_mut().insert(HeaderName::from_static("x-trace-id"), value);
+                        }
+                        res
+                    })
+                }).instrument(span)
             })
             .app_data(state.clone())
             .service(boardcast)
             .service(stop_p2p_node)
             .service(peers)
+            .service(tag_peer)
+            .service(groups)
+            .service(group_write)
+            .service(members)
+            .service(services)
+            .service(service_logs)
+            .service(service_action)
+            .service(containers)
+            .service(container_run)
+            .service(exec)
+            .service(container_logs)
+            .service(container_action)
+            .service(node_info)
+            .service(metrics)
+            .service(dial)
+            .service(disconnect)
+            .service(start_transfer)
+            .service(transfers)
+            .service(transfer_status)
+            .service(add_artifact)
+            .service(gc_artifacts)
+            .service(artifacts)
+            .service(artifact)
+            .service(fetch_artifact)
+            .service(export_artifact)
+            .service(kv_list)
+            .service(kv_get)
+            .service(kv_write)
+            .service(deployments)
+            .service(deployment)
+            .service(deployment_apply)
+            .service(deployment_remove)
+            .service(rollout_start)
+            .service(rollout_action)
+            .service(cron_jobs)
+            .service(cron_add)
+            .service(cron_history)
+            .service(cron_remove)
+            .service(health_checks)
+            .service(health_alerts)
+            .service(cluster_status)
+            .service(cluster_get)
+            .service(cluster_write)
+            .service(lock_list)
+            .service(lock_acquire)
+            .service(lock_release)
+            .service(db_backup)
+            .service(db_restore)
+            .service(db_compact)
+            .service(db_task)
+            .service(db_dump)
+            .service(logs)
     })
     .bind_uds(opts.sock_file)?;
     if opts.server {
         info!("Server listening on {}:{}", host, port);
-        server.bind((host, port))?.run().await
+        Ok(server.bind((host, port))?.run())
     } else {
-        server.run().await
+        Ok(server.run())
     }
 }
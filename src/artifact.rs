@@ -0,0 +1,106 @@
+use std::{error::Error, time::Duration};
+use p2p::artifact::{ArtifactInfo, GcResult};
+use serde_json::json;
+
+use crate::error::ClientError;
+use crate::output::{self, Tabular};
+use crate::startup::{self, ServerOptions};
+use crate::utils;
+
+impl Tabular for ArtifactInfo {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["ID", "NAME", "SIZE", "CHUNKS", "PINNED", "STATE", "ERROR"]
+        } else {
+            vec!["ID", "NAME", "SIZE", "CHUNKS", "PINNED", "STATE"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let state = match (self.complete, self.fetching, &self.error) {
+            (true, _, _) => "complete",
+            (false, true, _) => "fetching",
+            (false, false, Some(_)) => "failed",
+            (false, false, None) => "partial",
+        };
+        // Like git, a prefix of the id is enough to tell artifacts apart
+        let id = if wide { self.id.clone() } else { self.id.chars().take(12).collect() };
+        let mut row = vec![
+            id,
+            if self.name.is_empty() { "-".to_string() } else { self.name.clone() },
+            output::format_bytes(self.size),
+            format!("{}/{}", self.have, self.chunks),
+            self.pinned.to_string(),
+            state.to_string(),
+        ];
+        if wide {
+            row.push(self.error.clone().unwrap_or_else(|| "-".to_string()));
+        }
+        row
+    }
+}
+
+impl Tabular for GcResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["REMOVED", "FREED"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.removed.len().to_string(), output::format_bytes(self.freed)]
+    }
+}
+
+/// Add a local file to the store of the running node, which announces it
+pub async fn add(opts: &ServerOptions, file: &str, name: Option<&str>) -> Result<ArtifactInfo, Box<dyn Error>> {
+    let payload = serde_json::to_vec(&json!({ "path": utils::absolute_path(file)?, "name": name }))?;
+    let body = startup::call(opts, "/artifacts", Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+pub struct GetOptions {
+    pub id: String,
+    // Where to write the artifact once fetched
+    pub out: Option<String>,
+    pub pin: bool,
+    pub detach: bool,
+}
+
+/// Fetch an artifact from the peers into the store of the running node,
+/// printing the progress to stderr, then write it to `out`
+pub async fn get(opts: &ServerOptions, get: &GetOptions) -> Result<ArtifactInfo, Box<dyn Error>> {
+    let payload = serde_json::to_vec(&json!({ "pin": get.pin }))?;
+    let body = startup::call(opts, &format!("/artifacts/{}/fetch", get.id), Some(payload)).await?;
+    let mut info: ArtifactInfo = startup::parse_body(&body)?;
+    if get.detach {
+        return Ok(info);
+    }
+    while !info.complete {
+        if let (false, Some(error)) = (info.fetching, &info.error) {
+            eprintln!();
+            return Err(ClientError::ServerError(format!("fetching {} failed: {}", get.id, error)).into());
+        }
+        eprint!("\r\x1b[K{}/{} chunks", info.have, info.chunks);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let body = startup::call(opts, &format!("/artifacts/{}", get.id), None).await?;
+        info = startup::parse_body(&body)?;
+    }
+    if info.chunks > 0 {
+        eprintln!("\r\x1b[K{}/{} chunks", info.have, info.chunks);
+    }
+    if let Some(out) = &get.out {
+        let payload = serde_json::to_vec(&json!({ "path": utils::absolute_path(out)? }))?;
+        let body = startup::call(opts, &format!("/artifacts/{}/export", get.id), Some(payload)).await?;
+        info = startup::parse_body(&body)?;
+    }
+    Ok(info)
+}
+
+pub async fn list(opts: &ServerOptions) -> Result<Vec<ArtifactInfo>, Box<dyn Error>> {
+    let body = startup::call(opts, "/artifacts", None).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn gc(opts: &ServerOptions) -> Result<GcResult, Box<dyn Error>> {
+    let body = startup::call(opts, "/artifacts/gc", Some(Vec::new())).await?;
+    startup::parse_body(&body)
+}
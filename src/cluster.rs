@@ -0,0 +1,76 @@
+use std::error::Error;
+use p2p::raft::{RaftStatus, Role, StateEntry};
+use serde_json::json;
+
+use crate::error::ClientError;
+use crate::output::Tabular;
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for RaftStatus {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["ID", "ROLE", "TERM", "LEADER", "COMMIT", "APPLIED", "LAST LOG", "VOTERS"]
+        } else {
+            vec!["ID", "ROLE", "TERM", "LEADER", "COMMIT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let role = match self.role {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        };
+        let mut row = vec![
+            self.id.clone(),
+            role.to_string(),
+            self.term.to_string(),
+            self.leader.clone().unwrap_or_else(|| "-".to_string()),
+            self.commit_index.to_string(),
+        ];
+        if wide {
+            row.push(self.last_applied.to_string());
+            row.push(self.last_log_index.to_string());
+            row.push(self.voters.join(","));
+        }
+        row
+    }
+}
+
+impl Tabular for StateEntry {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["KEY", "VALUE"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.key.clone(), self.value.clone().unwrap_or_else(|| "-".to_string())]
+    }
+}
+
+// Keys go in the url as they are
+fn check_key(key: &str) -> Result<(), Box<dyn Error>> {
+    match p2p::kv::valid_key(key) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid key {}, use at most {} letters, digits and ._-/:", key, p2p::kv::MAX_KEY_SIZE)).into()),
+    }
+}
+
+pub async fn status(opts: &ServerOptions) -> Result<RaftStatus, Box<dyn Error>> {
+    let body = startup::call(opts, "/cluster/status", None).await?;
+    startup::parse_body(&body)
+}
+
+/// Linearizable read, the node must be the leader
+pub async fn get(opts: &ServerOptions, key: &str) -> Result<StateEntry, Box<dyn Error>> {
+    check_key(key)?;
+    let body = startup::call(opts, &format!("/cluster/state/{}", key), None).await?;
+    startup::parse_body(&body)
+}
+
+/// Set a key, or delete it with None, once a majority of the voters stored it
+pub async fn write(opts: &ServerOptions, key: &str, value: Option<&str>) -> Result<StateEntry, Box<dyn Error>> {
+    check_key(key)?;
+    let payload = serde_json::to_vec(&json!({ "value": value }))?;
+    let body = startup::call(opts, &format!("/cluster/state/{}", key), Some(payload)).await?;
+    startup::parse_body(&body)
+}
@@ -0,0 +1,82 @@
+use std::{error::Error, time::UNIX_EPOCH};
+use p2p::container::{ContainerInfo, ContainerSpec, ContainerState};
+use p2p::service;
+
+use crate::error::ClientError;
+use crate::output::{format_ago, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for ContainerInfo {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NAME", "STATE", "PID", "CREATED", "ROOTFS"]
+        } else {
+            vec!["NAME", "STATE", "PID", "CREATED"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let state = match self.state {
+            ContainerState::Creating => "creating",
+            ContainerState::Created => "created",
+            ContainerState::Running => "running",
+            ContainerState::Paused => "paused",
+            ContainerState::Stopped => "stopped",
+        };
+        let created = humantime::parse_rfc3339_weak(&self.created).ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+        let mut row = vec![
+            self.name.clone(),
+            state.to_string(),
+            self.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string()),
+            format_ago(created),
+        ];
+        if wide {
+            row.push(self.rootfs.clone());
+        }
+        row
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Box<dyn Error>> {
+    match service::valid_name(name) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid container name {}", name)).into()),
+    }
+}
+
+fn peer_query(peer: Option<&str>) -> String {
+    peer.map(|p| format!("peer={}", p)).unwrap_or_default()
+}
+
+/// Run a container on the running node, or on a peer
+pub async fn run(opts: &ServerOptions, peer: Option<&str>, spec: &ContainerSpec) -> Result<ContainerInfo, Box<dyn Error>> {
+    spec.check().map_err(ClientError::BadRequest)?;
+    let payload = serde_json::to_vec(spec)?;
+    let body = startup::call(opts, &format!("/containers?{}", peer_query(peer)), Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn list(opts: &ServerOptions, peer: Option<&str>) -> Result<Vec<ContainerInfo>, Box<dyn Error>> {
+    let body = startup::call(opts, &format!("/containers?{}", peer_query(peer)), None).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn stop(opts: &ServerOptions, peer: Option<&str>, name: &str) -> Result<ContainerInfo, Box<dyn Error>> {
+    check_name(name)?;
+    let body = startup::call(opts, &format!("/containers/{}/stop?{}", name, peer_query(peer)), Some(Vec::new())).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn remove(opts: &ServerOptions, peer: Option<&str>, name: &str, force: bool) -> Result<(), Box<dyn Error>> {
+    check_name(name)?;
+    startup::call(opts, &format!("/containers/{}/rm?force={}&{}", name, force, peer_query(peer)), Some(Vec::new())).await?;
+    Ok(())
+}
+
+pub async fn logs(opts: &ServerOptions, peer: Option<&str>, name: &str, lines: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    check_name(name)?;
+    let body = startup::call(opts, &format!("/containers/{}/logs?lines={}&{}", name, lines, peer_query(peer)), None).await?;
+    startup::parse_body(&body)
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use p2p::cron::{Job, JobStatus, Run, Target};
+use p2p::handle::NodeInfo;
+
+use crate::error::ClientError;
+use crate::output::{format_ago, format_in, format_secs, Tabular};
+use crate::startup::{self, ServerOptions};
+
+fn format_target(target: &Target) -> String {
+    match target {
+        Target::Node { node } => node.clone(),
+        Target::All => "all".to_string(),
+        Target::One => "one".to_string(),
+    }
+}
+
+// Exit code of a run, or why it has none
+fn format_result(run: &Run) -> String {
+    match (run.exit_code, &run.error) {
+        (_, Some(error)) => error.clone(),
+        (Some(code), None) => format!("exit {}", code),
+        (None, None) => "-".to_string(),
+    }
+}
+
+impl Tabular for JobStatus {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NAME", "SCHEDULE", "TARGET", "NEXT", "LAST", "RESULT", "SELECTOR", "COMMAND"]
+        } else {
+            vec!["NAME", "SCHEDULE", "TARGET", "NEXT", "LAST", "RESULT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![
+            self.job.name.clone(),
+            self.job.schedule.clone(),
+            format_target(&self.job.target),
+            format_in(self.next),
+            format_ago(self.last.as_ref().map(|r| r.started)),
+            self.last.as_ref().map(format_result).unwrap_or_else(|| "-".to_string()),
+        ];
+        if wide {
+            row.push(if self.job.selector.is_empty() { "-".to_string() } else { self.job.selector.clone() });
+            row.push(self.job.command.join(" "));
+        }
+        row
+    }
+}
+
+impl Tabular for Run {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NODE", "STARTED", "DURATION", "RESULT", "OUTPUT"]
+        } else {
+            vec!["NODE", "STARTED", "DURATION", "RESULT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![
+            self.node.clone(),
+            format_ago(Some(self.started)),
+            format_secs(self.finished.saturating_sub(self.started)),
+            format_result(self),
+        ];
+        if wide {
+            row.push(self.output.lines().last().unwrap_or("-").to_string());
+        }
+        row
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Box<dyn Error>> {
+    match p2p::service::valid_name(name) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid job name {}", name)).into()),
+    }
+}
+
+/// Add a job, replacing the one of the same name. A job targeted at the
+/// node with no peer id runs on the running node.
+pub async fn add(opts: &ServerOptions, mut job: Job) -> Result<JobStatus, Box<dyn Error>> {
+    if let Target::Node { node } = &mut job.target {
+        if node.is_empty() {
+            let info: NodeInfo = startup::parse_body(&startup::call(opts, "/info", None).await?)?;
+            *node = info.peer_id;
+        }
+    }
+    job.check().map_err(ClientError::BadRequest)?;
+    let body = startup::call(opts, "/cron", Some(serde_json::to_vec(&job)?)).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn list(opts: &ServerOptions) -> Result<Vec<JobStatus>, Box<dyn Error>> {
+    let body = startup::call(opts, "/cron", None).await?;
+    startup::parse_body(&body)
+}
+
+/// Runs of a job on every node, the last one first
+pub async fn history(opts: &ServerOptions, name: &str) -> Result<Vec<Run>, Box<dyn Error>> {
+    check_name(name)?;
+    let body = startup::call(opts, &format!("/cron/{}/history", name), None).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn remove(opts: &ServerOptions, name: &str) -> Result<(), Box<dyn Error>> {
+    check_name(name)?;
+    startup::call(opts, &format!("/cron/{}/rm", name), Some(Vec::new())).await?;
+    Ok(())
+}
@@ -0,0 +1,340 @@
+use std::{error::Error, fs::{self, File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use fs2::FileExt;
+use tracing::info;
+use serde::{Serialize, Deserialize};
+use sysinfo::{System, SystemExt, Pid};
+
+use crate::output::Tabular;
+
+/// Version of the data directory layout written by this build
+pub const LAYOUT_VERSION: u32 = 1;
+
+const LAYOUT_FILE: &str = "layout.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Layout {
+    version: u32,
+}
+
+/// Files of a node, laid out as:
+///
+/// ```text
+/// <root>/layout.json   layout version
+/// <root>/config.json   optional settings, see `logging::LogConfig`
+/// <root>/artifacts/    content addressed artifacts, see `hanode artifact`
+/// <root>/containers/   bundles and output of the containers, see `hanode container`
+/// <root>/db/           sled database
+/// <root>/files/        files peers copy to and from with `hanode cp`
+/// <root>/keys/         exported keystores
+/// <root>/logs/         daemon logs
+/// <root>/run/          pid, lock and socket of the running node
+/// <root>/services/     output of the services, see `hanode svc`
+/// ```
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitResult {
+    pub root: String,
+    pub layout_version: u32,
+    // Files moved from the layout of older versions
+    pub migrated: Vec<String>,
+}
+
+impl Tabular for InitResult {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["ROOT", "LAYOUT", "MIGRATED"]
+        } else {
+            vec!["ROOT", "LAYOUT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![self.root.clone(), self.layout_version.to_string()];
+        if wide {
+            row.push(if self.migrated.is_empty() { "-".to_string() } else { self.migrated.join(",") });
+        }
+        row
+    }
+}
+
+/// Exclusive lock on a data directory, held by the running node and released
+/// when dropped or when the process exits
+pub struct DataDirLock {
+    file: File,
+}
+
+impl DataDirLock {
+    /// Record the pid of the holder, called again once daemonized
+    pub fn write_pid(&mut self) -> Result<(), Box<dyn Error>> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        write!(self.file, "{}", std::process::id())?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+fn path_string(path: PathBuf) -> String {
+    path.to_string_lossy().to_string()
+}
+
+// Whether a sled database lives in the directory
+fn is_sled_dir(path: &Path) -> bool {
+    path.join("conf").is_file()
+}
+
+impl DataDir {
+    pub fn new(root: &str) -> DataDir {
+        DataDir { root: PathBuf::from(root) }
+    }
+
+    pub fn db_path(&self) -> String {
+        path_string(self.root.join("db"))
+    }
+
+    pub fn keys_dir(&self) -> String {
+        path_string(self.root.join("keys"))
+    }
+
+    pub fn artifacts_dir(&self) -> String {
+        path_string(self.root.join("artifacts"))
+    }
+
+    pub fn files_dir(&self) -> String {
+        path_string(self.root.join("files"))
+    }
+
+    pub fn logs_dir(&self) -> String {
+        path_string(self.root.join("logs"))
+    }
+
+    pub fn services_dir(&self) -> String {
+        path_string(self.root.join("services"))
+    }
+
+    pub fn containers_dir(&self) -> String {
+        path_string(self.root.join("containers"))
+    }
+
+    pub fn run_dir(&self) -> String {
+        path_string(self.root.join("run"))
+    }
+
+    pub fn sock_path(&self) -> String {
+        path_string(self.root.join("run").join("hanode.sock"))
+    }
+
+    pub fn pid_path(&self) -> String {
+        path_string(self.root.join("run").join("hanode.pid"))
+    }
+
+    pub fn lock_path(&self) -> String {
+        path_string(self.root.join("run").join("hanode.lock"))
+    }
+
+    pub fn config_path(&self) -> String {
+        path_string(self.root.join("config.json"))
+    }
+
+    // Written by the logger of the node, rotated
+    pub fn node_log_file(&self) -> String {
+        path_string(self.root.join("logs").join("hanode.log"))
+    }
+
+    // Spans exported in the OTLP/JSON encoding
+    pub fn traces_file(&self) -> String {
+        path_string(self.root.join("logs").join("traces.jsonl"))
+    }
+
+    // stdout and stderr of the daemon
+    pub fn log_file(&self) -> String {
+        path_string(self.root.join("logs").join("info.log"))
+    }
+
+    pub fn err_file(&self) -> String {
+        path_string(self.root.join("logs").join("error.log"))
+    }
+
+    fn layout_version(&self) -> Result<Option<u32>, Box<dyn Error>> {
+        let path = self.root.join(LAYOUT_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let layout: Layout = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| format!("Invalid layout file {}: {}", path.display(), e))?;
+        Ok(Some(layout.version))
+    }
+
+    /// Create the layout, moving the files of older versions into it. Safe to
+    /// call on an initialized directory.
+    pub fn init(&self) -> Result<InitResult, Box<dyn Error>> {
+        let mut migrated = Vec::new();
+        match self.layout_version()? {
+            Some(version) if version > LAYOUT_VERSION => {
+                return Err(format!(
+                    "Data directory {} has layout version {}, newer than the supported version {}, upgrade hanode",
+                    self.root.display(), version, LAYOUT_VERSION
+                ).into());
+            },
+            Some(_) => {},
+            None => {
+                fs::create_dir_all(&self.root)?;
+                migrated = self.migrate_legacy()?;
+            },
+        }
+        for dir in [self.artifacts_dir(), self.db_path(), self.files_dir(), self.keys_dir(), self.logs_dir(), self.run_dir(), self.services_dir(), self.containers_dir()] {
+            fs::create_dir_all(&dir)?;
+        }
+        // The socket answers with the node key, keep other users away from it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(self.run_dir(), fs::Permissions::from_mode(0o700))?;
+        }
+        if self.layout_version()?.is_none() {
+            let layout = Layout { version: LAYOUT_VERSION };
+            fs::write(self.root.join(LAYOUT_FILE), serde_json::to_string(&layout)?)?;
+        }
+        Ok(InitResult {
+            root: path_string(self.root.clone()),
+            layout_version: LAYOUT_VERSION,
+            migrated,
+        })
+    }
+
+    // Before the layout the database was nested in `<root>/hanode.db/hanode.db`
+    // and the pid, socket and logs sat in the root
+    fn migrate_legacy(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let legacy_pid = self.root.join("hanode.pid");
+        if let Some(pid) = fs::read_to_string(&legacy_pid).ok().and_then(|p| p.trim().parse::<i32>().ok()) {
+            if System::new_all().process(Pid::from(pid)).is_some() {
+                return Err(format!(
+                    "A node is still running on {} with pid {}, stop it before upgrading the data directory",
+                    self.root.display(), pid
+                ).into());
+            }
+        }
+        let mut migrated = Vec::new();
+        let legacy_db = self.root.join("hanode.db");
+        let db = self.root.join("db");
+        if is_sled_dir(&legacy_db.join("hanode.db")) {
+            fs::rename(legacy_db.join("hanode.db"), &db)?;
+            // Only the nested database lived there
+            let _ = fs::remove_dir(&legacy_db);
+            migrated.push("hanode.db/hanode.db".to_string());
+        } else if is_sled_dir(&legacy_db) {
+            fs::rename(&legacy_db, &db)?;
+            migrated.push("hanode.db".to_string());
+        }
+        fs::create_dir_all(self.logs_dir())?;
+        fs::create_dir_all(self.run_dir())?;
+        for (name, to) in [("info.log", self.log_file()), ("error.log", self.err_file())] {
+            let from = self.root.join(name);
+            if from.is_file() {
+                fs::rename(&from, &to)?;
+                migrated.push(name.to_string());
+            }
+        }
+        for name in migrated.iter() {
+            info!("Moved {} into the {} data directory layout", name, self.root.display());
+        }
+        // The node is not running, its pid and socket are stale
+        for name in ["hanode.pid", "hanode.sock"] {
+            let from = self.root.join(name);
+            if from.exists() {
+                fs::remove_file(&from)?;
+                info!("Removed stale {} from {}", name, self.root.display());
+                migrated.push(name.to_string());
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Take the exclusive lock of the directory so no second node shares it
+    pub fn lock(&self) -> Result<DataDirLock, Box<dyn Error>> {
+        let path = self.lock_path();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = match holder.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {})", pid),
+            };
+            return Err(format!(
+                "Data directory {} is in use by another node{}",
+                self.root.display(), holder
+            ).into());
+        }
+        let mut lock = DataDirLock { file };
+        lock.write_pid()?;
+        Ok(lock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_dir(tmp: &tempfile::TempDir) -> DataDir {
+        DataDir::new(&path_string(tmp.path().join("hanode")))
+    }
+
+    #[test]
+    fn test_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = data_dir(&tmp);
+        dir.init().unwrap();
+        let lock = dir.lock().unwrap();
+        let err = dir.lock().err().unwrap().to_string();
+        assert!(err.ends_with(&format!("in use by another node (pid {})", std::process::id())), "{}", err);
+        drop(lock);
+        dir.lock().unwrap();
+    }
+
+    #[test]
+    fn test_init_migrates_legacy_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = data_dir(&tmp);
+        // Files as written before the layout, by a node no longer running
+        fs::create_dir_all(dir.root.join("hanode.db").join("hanode.db")).unwrap();
+        fs::write(dir.root.join("hanode.db").join("hanode.db").join("conf"), "").unwrap();
+        fs::write(dir.root.join("info.log"), "started").unwrap();
+        fs::write(dir.root.join("hanode.pid"), "999999999").unwrap();
+        fs::write(dir.root.join("hanode.sock"), "").unwrap();
+
+        let result = dir.init().unwrap();
+        assert_eq!(result.migrated, vec!["hanode.db/hanode.db", "info.log", "hanode.pid", "hanode.sock"]);
+        assert!(is_sled_dir(Path::new(&dir.db_path())));
+        assert!(!dir.root.join("hanode.db").exists());
+        assert_eq!(fs::read_to_string(dir.log_file()).unwrap(), "started");
+        assert!(!dir.root.join("hanode.pid").exists() && !dir.root.join("hanode.sock").exists());
+        assert_eq!(dir.layout_version().unwrap(), Some(LAYOUT_VERSION));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(dir.run_dir()).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        // Initialized already, nothing left to move
+        assert!(dir.init().unwrap().migrated.is_empty());
+    }
+
+    #[test]
+    fn test_init_refuses_newer_layout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = data_dir(&tmp);
+        fs::create_dir_all(&dir.root).unwrap();
+        fs::write(dir.root.join(LAYOUT_FILE), format!("{{\"version\":{}}}", LAYOUT_VERSION + 1)).unwrap();
+        assert!(dir.init().is_err());
+    }
+}
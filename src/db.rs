@@ -0,0 +1,432 @@
+use std::{env, error::Error, fs, io::{BufReader, Write}, path::PathBuf, time::Duration};
+use tracing::{info, warn};
+use p2p::backup::{self, BackupSummary, DumpRecord};
+use p2p::secrets::{self, SecretStore};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
+use serde_json::json;
+use server::core::{DbTask, DbTaskResult};
+
+use crate::error::ClientError;
+use crate::output::Tabular;
+use crate::startup::{self, ServerOptions};
+
+pub const PASSPHRASE_ENV: &str = "HANODE_DB_PASSPHRASE";
+pub const NEW_PASSPHRASE_ENV: &str = "HANODE_DB_NEW_PASSPHRASE";
+// How often and how long to wait for a node stopped for a database task
+const TASK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const TASK_WAIT_POLLS: u32 = 240;
+
+/// Where to read the passphrase protecting the database secrets from
+#[derive(Debug, Clone, Default)]
+pub struct UnlockOptions {
+    pub passphrase_file: Option<String>,
+    pub keyfile: Option<String>,
+    // Environment variable holding the passphrase
+    pub env: Option<String>,
+    // Ask on the terminal when nothing else is given
+    pub prompt: bool,
+}
+
+impl UnlockOptions {
+    fn read(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if let Some(file) = &self.passphrase_file {
+            let passphrase = fs::read_to_string(file)?;
+            let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+            if passphrase.is_empty() {
+                return Err(format!("Passphrase file {} is empty", file).into());
+            }
+            return Ok(Some(passphrase.as_bytes().to_vec()));
+        }
+        if let Some(file) = &self.keyfile {
+            let key = fs::read(file)?;
+            if key.is_empty() {
+                return Err(format!("Keyfile {} is empty", file).into());
+            }
+            return Ok(Some(key));
+        }
+        if let Some(name) = &self.env {
+            if let Ok(passphrase) = env::var(name) {
+                if passphrase.is_empty() {
+                    return Err(format!("{} is set but empty", name).into());
+                }
+                return Ok(Some(passphrase.into_bytes()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn prompt(&self, message: &str, confirm: bool) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        if !self.prompt {
+            return Ok(None);
+        }
+        let passphrase = rpassword::prompt_password(message)
+            .map_err(|e| format!("A passphrase is required but the terminal is not available: {}", e))?;
+        if confirm && passphrase != rpassword::prompt_password("Repeat passphrase: ")? {
+            return Err("Passphrases do not match".into());
+        }
+        if passphrase.is_empty() {
+            return Err("The passphrase must not be empty".into());
+        }
+        Ok(Some(passphrase.into_bytes()))
+    }
+}
+
+/// Open the secrets of the database, asking for the passphrase when the
+/// secrets are encrypted and none was configured.
+pub fn open_secrets(db: &sled::Db, unlock: &UnlockOptions) -> Result<SecretStore, Box<dyn Error>> {
+    let mut passphrase = unlock.read()?;
+    if passphrase.is_none() && secrets::is_encrypted(db)? {
+        passphrase = unlock.prompt("Database passphrase: ", false)?;
+    }
+    SecretStore::open(db, passphrase.as_deref())
+}
+
+/// Change the passphrase of the database secrets, encrypting them if they
+/// are still stored in plaintext.
+pub fn rekey(db_path: &str, old: &UnlockOptions, new: &UnlockOptions) -> Result<(), Box<dyn Error>> {
+    let db = startup::open_db(db_path)?;
+    let encrypted = secrets::is_encrypted(&db)?;
+    let old_passphrase = match (encrypted, old.read()?) {
+        (true, None) => old.prompt("Current database passphrase: ", false)?,
+        (_, passphrase) => passphrase,
+    };
+    let new_passphrase = match new.read()? {
+        Some(passphrase) => passphrase,
+        None => match new.prompt("New database passphrase: ", true)? {
+            Some(passphrase) => passphrase,
+            None => return Err("A new passphrase or keyfile is required".into()),
+        },
+    };
+    secrets::rekey(&db, old_passphrase.as_deref(), &new_passphrase)?;
+    db.flush()?;
+    drop(db);
+    if encrypted {
+        info!("Database secrets re-encrypted with the new passphrase");
+    } else {
+        // The plaintext key stays in the sled log until rewritten
+        rewrite(db_path)?;
+        info!("Database secrets are now encrypted");
+    }
+    Ok(())
+}
+
+pub struct DbOptions {
+    pub db_path: String,
+    // Control API of the node, used while the node is running
+    pub server_opts: ServerOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupResult {
+    pub file: String,
+    // Whether the running node served the request
+    pub online: bool,
+    pub schema_version: u32,
+    pub trees: usize,
+    pub records: usize,
+}
+
+impl BackupResult {
+    fn new(file: &str, online: bool, summary: BackupSummary) -> BackupResult {
+        BackupResult {
+            file: file.to_string(),
+            online,
+            schema_version: summary.schema_version,
+            trees: summary.trees,
+            records: summary.records,
+        }
+    }
+}
+
+impl Tabular for BackupResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["FILE", "MODE", "SCHEMA", "TREES", "RECORDS"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![
+            self.file.clone(),
+            if self.online { "online" } else { "offline" }.to_string(),
+            self.schema_version.to_string(),
+            self.trees.to_string(),
+            self.records.to_string(),
+        ]
+    }
+}
+
+impl Tabular for DumpRecord {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["KEY", "VALUE"]
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let value = match &self.value {
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        };
+        // Long values are cut in the table, the wide output shows them whole
+        if !wide && value.chars().count() > 80 {
+            let cut: String = value.chars().take(77).collect();
+            return vec![self.key.clone(), format!("{}...", cut)];
+        }
+        vec![self.key.clone(), value]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactResult {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl Tabular for CompactResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["SIZE BEFORE", "SIZE AFTER"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.size_before.to_string(), self.size_after.to_string()]
+    }
+}
+
+/// Call the control API of the node, `None` when the node is not running so
+/// the caller works on the database directly
+async fn call_online(opts: &DbOptions, url_path: &str, payload: Option<Vec<u8>>) -> Result<Option<String>, Box<dyn Error>> {
+    match startup::call(&opts.server_opts, url_path, payload).await {
+        Ok(body) => Ok(Some(body)),
+        Err(e) => match e.downcast_ref::<ClientError>() {
+            Some(ClientError::NodeNotRunning(_)) | Some(ClientError::ConnectionRefused(_)) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Write a portable backup of the database to `file`, readable by the owner
+/// only as it holds the node key
+pub async fn backup(opts: &DbOptions, file: &str) -> Result<BackupResult, Box<dyn Error>> {
+    if let Some(body) = call_online(opts, "/db/backup", None).await? {
+        // Validate what the node sent before keeping it
+        let summary = backup::inspect(body.as_bytes())?;
+        create_private(file)?.write_all(body.as_bytes())?;
+        return Ok(BackupResult::new(file, true, summary));
+    }
+    let db = startup::open_db(&opts.db_path)?;
+    let mut out = create_private(file)?;
+    let summary = backup::export(&db, &mut out)?;
+    Ok(BackupResult::new(file, false, summary))
+}
+
+fn create_private(file: &str) -> Result<fs::File, Box<dyn Error>> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let out = options.open(file)?;
+    // The mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        out.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(out)
+}
+
+/// Replace the content of the database with a backup. Encrypted secrets are
+/// restored with their wrapped key, so they unlock with the passphrase that
+/// was in use when the backup was taken. A running node is stopped for it, as
+/// it would keep running with the state it loaded, and started again.
+pub async fn restore(opts: &DbOptions, file: &str) -> Result<BackupResult, Box<dyn Error>> {
+    // The node may run from another directory
+    let path = fs::canonicalize(file)?.to_string_lossy().to_string();
+    let payload = serde_json::to_vec(&json!({ "file": path }))?;
+    if let Some(body) = call_online(opts, "/db/restore", Some(payload)).await? {
+        return wait_task(opts, &body).await;
+    }
+    restore_db(&opts.db_path, file)
+}
+
+fn restore_db(db_path: &str, file: &str) -> Result<BackupResult, Box<dyn Error>> {
+    let summary = backup::inspect(BufReader::new(fs::File::open(file)?))?;
+    let db = startup::open_db(db_path)?;
+    backup::import(&db, BufReader::new(fs::File::open(file)?))?;
+    Ok(BackupResult::new(file, false, summary))
+}
+
+/// Decode the records of one tree, secrets are redacted
+pub async fn dump(opts: &DbOptions, tree: &str) -> Result<Vec<DumpRecord>, Box<dyn Error>> {
+    if let Some(body) = call_online(opts, &format!("/db/dump/{}", tree), None).await? {
+        return serde_json::from_str(&body).map_err(|e| ClientError::InvalidResponse(e.to_string()).into());
+    }
+    let db = startup::open_db(&opts.db_path)?;
+    backup::dump(&db, tree)
+}
+
+/// Rewrite the database into fresh files to reclaim the space of deleted and
+/// overwritten records. sled holds the files open so a running node is
+/// stopped for it and started again.
+pub async fn compact(opts: &DbOptions) -> Result<CompactResult, Box<dyn Error>> {
+    if let Some(body) = call_online(opts, "/db/compact", Some(Vec::new())).await? {
+        return wait_task(opts, &body).await;
+    }
+    compact_db(&opts.db_path)
+}
+
+fn compact_db(db_path: &str) -> Result<CompactResult, Box<dyn Error>> {
+    let (size_before, size_after) = rewrite(db_path)?;
+    info!("Compacted {} from {} to {} bytes", db_path, size_before, size_after);
+    Ok(CompactResult { size_before, size_after })
+}
+
+/// Run a task the node was stopped for, see `server::core::DbTask`
+pub fn run_task(db_path: &str, id: u64, task: DbTask) -> DbTaskResult {
+    let result: Result<serde_json::Value, Box<dyn Error>> = match task {
+        DbTask::Restore { file } => restore_db(db_path, &file).and_then(|r| Ok(serde_json::to_value(BackupResult { online: true, ..r })?)),
+        DbTask::Compact => compact_db(db_path).and_then(|r| Ok(serde_json::to_value(r)?)),
+    };
+    match result {
+        Ok(result) => DbTaskResult { id, result: Some(result), error: None },
+        Err(err) => DbTaskResult { id, result: None, error: Some(err.to_string()) },
+    }
+}
+
+#[derive(Deserialize)]
+struct ScheduledTask {
+    id: u64,
+}
+
+// Wait for the node to run the task it stopped for and to answer again
+async fn wait_task<T: DeserializeOwned>(opts: &DbOptions, body: &str) -> Result<T, Box<dyn Error>> {
+    let scheduled: ScheduledTask = startup::parse_body(body)?;
+    let url_path = format!("/db/tasks/{}", scheduled.id);
+    for _ in 0..TASK_WAIT_POLLS {
+        tokio::time::sleep(TASK_POLL_INTERVAL).await;
+        // Nothing answers until the node is started again
+        let done = match call_online(opts, &url_path, None).await {
+            Ok(Some(body)) => startup::parse_body::<Option<DbTaskResult>>(&body)?,
+            _ => None,
+        };
+        match done {
+            Some(DbTaskResult { error: Some(err), .. }) => return Err(ClientError::ServerError(err).into()),
+            Some(DbTaskResult { result: Some(result), .. }) => {
+                return serde_json::from_value(result).map_err(|e| ClientError::InvalidResponse(e.to_string()).into());
+            },
+            _ => {},
+        }
+    }
+    Err(ClientError::ServerError("the node did not start again after the database task".to_string()).into())
+}
+
+/// Put back the database a rewrite interrupted between its two renames left
+/// missing, from the old files or else the rewritten ones
+pub fn recover(db_path: &str) -> Result<(), Box<dyn Error>> {
+    if PathBuf::from(db_path).exists() {
+        return Ok(());
+    }
+    for suffix in ["old", "compact"] {
+        let path = PathBuf::from(format!("{}.{}", db_path, suffix));
+        if path.exists() {
+            warn!("Database {} is missing, recovering it from {}", db_path, path.display());
+            fs::rename(&path, db_path)?;
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Copy the records of the database into fresh files and delete the old ones,
+/// which keep overwritten and removed values until sled reuses their space.
+/// Returns the sizes before and after.
+pub fn rewrite(db_path: &str) -> Result<(u64, u64), Box<dyn Error>> {
+    let tmp_path = PathBuf::from(format!("{}.compact", db_path));
+    let old_path = PathBuf::from(format!("{}.old", db_path));
+    let db = startup::open_db(db_path)?;
+    let size_before = db.size_on_disk()?;
+    let mut content = Vec::new();
+    backup::export(&db, &mut content)?;
+    drop(db);
+
+    // Left behind by a rewrite that failed after `recover` ran
+    for path in [&tmp_path, &old_path] {
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+    }
+    let rewritten = sled::open(&tmp_path)?;
+    backup::import(&rewritten, content.as_slice())?;
+    let size_after = rewritten.size_on_disk()?;
+    drop(rewritten);
+
+    // Swap the directories, the old one is only removed once the new one is in place
+    fs::rename(db_path, &old_path)?;
+    fs::rename(&tmp_path, db_path)?;
+    fs::remove_dir_all(&old_path)?;
+    Ok((size_before, size_after))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_path(tmp: &tempfile::TempDir) -> String {
+        tmp.path().join("hanode.db").to_string_lossy().to_string()
+    }
+
+    fn write_key(db_path: &str) {
+        let db = startup::open_db(db_path).unwrap();
+        db.insert("key", "value").unwrap();
+        db.flush().unwrap();
+    }
+
+    fn read_key(db_path: &str) -> Option<sled::IVec> {
+        startup::open_db(db_path).unwrap().get("key").unwrap()
+    }
+
+    #[test]
+    fn test_recover_interrupted_rewrite() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = db_path(&tmp);
+        write_key(&path);
+        // Stopped between the two renames
+        fs::rename(&path, format!("{}.old", path)).unwrap();
+        fs::create_dir(format!("{}.compact", path)).unwrap();
+        assert_eq!(read_key(&path).unwrap(), "value");
+        assert!(!PathBuf::from(format!("{}.old", path)).exists());
+    }
+
+    #[test]
+    fn test_run_task() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = db_path(&tmp);
+        write_key(&path);
+        let file = tmp.path().join("backup.jsonl");
+        backup::export(&startup::open_db(&path).unwrap(), &mut fs::File::create(&file).unwrap()).unwrap();
+        let db = startup::open_db(&path).unwrap();
+        db.remove("key").unwrap();
+        db.flush().unwrap();
+        drop(db);
+        let done = run_task(&path, 1, DbTask::Restore { file: file.to_string_lossy().to_string() });
+        assert_eq!(done.error, None);
+        assert_eq!(done.result.unwrap()["online"], true);
+        assert_eq!(read_key(&path).unwrap(), "value");
+        let done = run_task(&path, 2, DbTask::Compact);
+        assert!(done.result.unwrap()["size_after"].is_u64());
+        let done = run_task(&path, 3, DbTask::Restore { file: "/missing".to_string() });
+        assert!(done.result.is_none() && done.error.is_some());
+    }
+
+    #[test]
+    fn test_rewrite_clears_leftovers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = db_path(&tmp);
+        write_key(&path);
+        fs::create_dir(format!("{}.old", path)).unwrap();
+        fs::create_dir(format!("{}.compact", path)).unwrap();
+        rewrite(&path).unwrap();
+        assert_eq!(read_key(&path).unwrap(), "value");
+        assert!(!PathBuf::from(format!("{}.old", path)).exists());
+        assert!(!PathBuf::from(format!("{}.compact", path)).exists());
+    }
+}
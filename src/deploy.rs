@@ -0,0 +1,131 @@
+use std::{error::Error, fs};
+use p2p::deploy::{DeployState, DeployStatus, Deployment, DeploymentStatus};
+use p2p::rollout::RolloutState;
+use p2p::service;
+
+use crate::error::ClientError;
+use crate::output::{self, format_ago, OutputFormat, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for DeploymentStatus {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NAME", "KIND", "VERSION", "READY", "ROLLOUT", "PREVIOUS", "SELECTOR", "REPLICAS"]
+        } else {
+            vec!["NAME", "KIND", "VERSION", "READY", "ROLLOUT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![
+            self.name.clone(),
+            self.kind.clone(),
+            self.version.clone(),
+            format!("{}/{}", self.ready, self.nodes),
+            rollout_progress(self),
+        ];
+        if wide {
+            row.push(self.previous.clone().unwrap_or_else(|| "-".to_string()));
+            row.push(if self.selector.is_empty() { "-".to_string() } else { self.selector.clone() });
+            row.push(self.replicas.map(|r| r.to_string()).unwrap_or_else(|| "all".to_string()));
+        }
+        row
+    }
+}
+
+// State of the rollout with the nodes moved to the new version
+fn rollout_progress(status: &DeploymentStatus) -> String {
+    match &status.rollout {
+        Some(rollout) => {
+            let state = match rollout.state {
+                RolloutState::Progressing => "progressing",
+                RolloutState::Paused => "paused",
+                RolloutState::Complete => "complete",
+            };
+            format!("{} {}/{}", state, rollout.nodes.len(), status.nodes)
+        },
+        None => "-".to_string(),
+    }
+}
+
+impl Tabular for DeployStatus {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NODE", "VERSION", "STATE", "UPDATED", "DRIFT", "DRIFTED", "MESSAGE"]
+        } else {
+            vec!["NODE", "VERSION", "STATE", "UPDATED", "DRIFT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let state = match self.state {
+            DeployState::Progressing => "progressing",
+            DeployState::Ready => "ready",
+            DeployState::Failed => "failed",
+        };
+        let mut row = vec![
+            self.node.clone(),
+            self.version.clone().unwrap_or_else(|| "-".to_string()),
+            state.to_string(),
+            format_ago(Some(self.updated)),
+            self.drift.clone().unwrap_or_else(|| "-".to_string()),
+        ];
+        if wide {
+            row.push(format_ago(self.drifted));
+            row.push(self.message.clone().unwrap_or_else(|| "-".to_string()));
+        }
+        row
+    }
+}
+
+pub(crate) fn check_name(name: &str) -> Result<(), Box<dyn Error>> {
+    match service::valid_name(name) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid deployment name {}", name)).into()),
+    }
+}
+
+/// A deployment from a JSON or YAML file
+pub(crate) fn read(file: &str) -> Result<Deployment, Box<dyn Error>> {
+    let deployment: Deployment = serde_yaml::from_str(&fs::read_to_string(file)?)
+        .map_err(|e| ClientError::BadRequest(format!("invalid deployment {}: {}", file, e)))?;
+    deployment.check().map_err(ClientError::BadRequest)?;
+    Ok(deployment)
+}
+
+/// Submit the deployment of a JSON or YAML file, replacing the one of the same name
+pub async fn apply(opts: &ServerOptions, file: &str) -> Result<DeploymentStatus, Box<dyn Error>> {
+    let deployment = read(file)?;
+    let body = startup::call(opts, "/deployments", Some(serde_json::to_vec(&deployment)?)).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn list(opts: &ServerOptions) -> Result<Vec<DeploymentStatus>, Box<dyn Error>> {
+    let body = startup::call(opts, "/deployments", None).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn status(opts: &ServerOptions, name: &str) -> Result<DeploymentStatus, Box<dyn Error>> {
+    check_name(name)?;
+    let body = startup::call(opts, &format!("/deployments/{}", name), None).await?;
+    startup::parse_body(&body)
+}
+
+/// The deployment with the status of each node under it in a table
+pub fn print_status(status: &DeploymentStatus, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    output::print_one(status, format)?;
+    if let OutputFormat::Table | OutputFormat::Wide = format {
+        if let Some(message) = status.rollout.as_ref().and_then(|r| r.message.as_ref()) {
+            println!("\nRollout: {}", message);
+        }
+        println!();
+        output::print_list(&status.statuses, format)?;
+    }
+    Ok(())
+}
+
+pub async fn remove(opts: &ServerOptions, name: &str) -> Result<(), Box<dyn Error>> {
+    check_name(name)?;
+    startup::call(opts, &format!("/deployments/{}/rm", name), Some(Vec::new())).await?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::{error::Error, fmt, io};
+
+/// Errors returned by the CLI when talking to a running node.
+///
+/// Each variant maps to a stable exit code so scripts can tell them apart:
+///
+/// | code | meaning            |
+/// |------|--------------------|
+/// | 1    | other errors       |
+/// | 3    | node not running   |
+/// | 4    | connection refused |
+/// | 5    | auth failed        |
+/// | 6    | bad request        |
+/// | 7    | server error       |
+/// | 8    | invalid response   |
+#[derive(Debug)]
+pub enum ClientError {
+    NodeNotRunning(String),
+    ConnectionRefused(String),
+    AuthFailed(String),
+    BadRequest(String),
+    ServerError(String),
+    InvalidResponse(String),
+}
+
+pub const EXIT_FAILURE: i32 = 1;
+
+impl ClientError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::NodeNotRunning(_) => 3,
+            ClientError::ConnectionRefused(_) => 4,
+            ClientError::AuthFailed(_) => 5,
+            ClientError::BadRequest(_) => 6,
+            ClientError::ServerError(_) => 7,
+            ClientError::InvalidResponse(_) => 8,
+        }
+    }
+
+    /// Map a non-2xx status code returned by the node
+    pub fn from_status(status: u16, body: &str) -> Option<ClientError> {
+        let body = body.trim().to_string();
+        match status {
+            200..=299 => None,
+            401 | 403 => Some(ClientError::AuthFailed(body)),
+            400..=499 => Some(ClientError::BadRequest(format!("{} {}", status, body))),
+            _ => Some(ClientError::ServerError(format!("{} {}", status, body))),
+        }
+    }
+
+    /// Map an error raised while connecting to the unix domain socket
+    pub fn from_uds_error(path: &str, err: &io::Error) -> ClientError {
+        match err.kind() {
+            io::ErrorKind::NotFound => ClientError::NodeNotRunning(format!("socket {} does not exist", path)),
+            io::ErrorKind::ConnectionRefused => ClientError::ConnectionRefused(format!("socket {} refused the connection, is the node still running?", path)),
+            _ => ClientError::ServerError(format!("socket {}: {}", path, err)),
+        }
+    }
+
+    /// Map an error raised by the http client
+    pub fn from_http_error(url: &str, err: &reqwest::Error) -> ClientError {
+        if err.is_connect() {
+            ClientError::ConnectionRefused(format!("{} refused the connection, is the server enabled?", url))
+        } else if err.is_decode() {
+            ClientError::InvalidResponse(format!("{}: {}", url, err))
+        } else {
+            ClientError::ServerError(format!("{}: {}", url, err))
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::NodeNotRunning(msg) => write!(f, "node is not running: {}", msg),
+            ClientError::ConnectionRefused(msg) => write!(f, "connection refused: {}", msg),
+            ClientError::AuthFailed(msg) => write!(f, "authentication failed: {}", msg),
+            ClientError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            ClientError::ServerError(msg) => write!(f, "server error: {}", msg),
+            ClientError::InvalidResponse(msg) => write!(f, "invalid response: {}", msg),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+/// Exit code for any error returned by a subcommand
+pub fn exit_code(err: &(dyn Error + 'static)) -> i32 {
+    match err.downcast_ref::<ClientError>() {
+        Some(e) => e.exit_code(),
+        None => EXIT_FAILURE,
+    }
+}
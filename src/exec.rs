@@ -0,0 +1,42 @@
+use std::error::Error;
+use p2p::exec::{ExecRequest, ExecResult};
+
+use crate::error::ClientError;
+use crate::output::Tabular;
+use crate::startup::{self, ServerOptions, Target};
+
+impl Tabular for ExecResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["NODE", "RESULT", "OUTPUT"]
+    }
+
+    // The last line of the output, all of it in json and yaml
+    fn row(&self, _wide: bool) -> Vec<String> {
+        let result = match (self.exit_code, &self.error) {
+            (_, Some(error)) => error.clone(),
+            (Some(code), None) => format!("exit {}", code),
+            (None, None) => "-".to_string(),
+        };
+        vec![self.node.clone(), result, self.output.lines().last().unwrap_or("-").to_string()]
+    }
+}
+
+/// Run a command on the running node, or on it and the peers of the target
+pub async fn exec(opts: &ServerOptions, target: &Target, req: &ExecRequest) -> Result<Vec<ExecResult>, Box<dyn Error>> {
+    req.check().map_err(ClientError::BadRequest)?;
+    let payload = serde_json::to_vec(req)?;
+    let body = startup::call(opts, &format!("/exec{}", target.query()?), Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+/// An error naming the nodes the command did not succeed on
+pub fn check(results: &[ExecResult]) -> Result<(), Box<dyn Error>> {
+    let failed: Vec<String> = results.iter()
+        .filter(|r| !r.succeeded())
+        .map(|r| r.node.clone())
+        .collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(ClientError::ServerError(format!("the command failed on {} of {} nodes: {}", failed.len(), results.len(), failed.join(","))).into())
+}
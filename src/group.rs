@@ -0,0 +1,53 @@
+use std::error::Error;
+use p2p::labels::{self, Group, Labels, Selector};
+use serde_json::json;
+
+use crate::error::ClientError;
+use crate::output::{PeerView, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for Group {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["NAME", "SELECTOR"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.name.clone(), if self.selector.is_empty() { "-".to_string() } else { self.selector.clone() }]
+    }
+}
+
+fn check_group(name: &str) -> Result<(), Box<dyn Error>> {
+    match labels::valid_label_value(name) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid group name {}, use letters, digits and ._-", name)).into()),
+    }
+}
+
+/// Set tags of a peer from `key=value` pairs and remove the `keys`
+pub async fn tag(opts: &ServerOptions, peer_id: &str, set: &[String], remove: &[String]) -> Result<PeerView, Box<dyn Error>> {
+    let set: Labels = labels::parse_labels(&set.join(",")).map_err(ClientError::BadRequest)?;
+    let payload = serde_json::to_vec(&json!({ "set": set, "remove": remove }))?;
+    let body = startup::call(opts, &format!("/peers/{}/tags", peer_id), Some(payload)).await?;
+    let peer: p2p::peer::Peer = startup::parse_body(&body)?;
+    Ok(PeerView::from(peer))
+}
+
+pub async fn set(opts: &ServerOptions, name: &str, selector: &str) -> Result<Group, Box<dyn Error>> {
+    check_group(name)?;
+    selector.parse::<Selector>().map_err(ClientError::BadRequest)?;
+    let payload = serde_json::to_vec(&json!({ "selector": selector }))?;
+    let body = startup::call(opts, &format!("/groups/{}", name), Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn remove(opts: &ServerOptions, name: &str) -> Result<(), Box<dyn Error>> {
+    check_group(name)?;
+    let payload = serde_json::to_vec(&json!({ "selector": null }))?;
+    startup::call(opts, &format!("/groups/{}", name), Some(payload)).await?;
+    Ok(())
+}
+
+pub async fn list(opts: &ServerOptions) -> Result<Vec<Group>, Box<dyn Error>> {
+    let body = startup::call(opts, "/groups", None).await?;
+    startup::parse_body(&body)
+}
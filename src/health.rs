@@ -0,0 +1,65 @@
+use std::error::Error;
+use p2p::health::{Alert, CheckResult, CheckStatus};
+
+use crate::output::{format_ago, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for CheckResult {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NODE", "CHECK", "STATUS", "SINCE", "MESSAGE", "CHECKED"]
+        } else {
+            vec!["NODE", "CHECK", "STATUS", "SINCE", "MESSAGE"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let status = match self.status {
+            CheckStatus::Passing => "passing",
+            CheckStatus::Failing => "failing",
+        };
+        let mut row = vec![
+            self.node.clone(),
+            self.check.clone(),
+            status.to_string(),
+            format_ago(Some(self.since)),
+            self.message.clone().unwrap_or_else(|| "-".to_string()),
+        ];
+        if wide {
+            row.push(format_ago(Some(self.checked)));
+        }
+        row
+    }
+}
+
+impl Tabular for Alert {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["RULE", "NODE", "CHECK", "FIRED", "MESSAGE"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![
+            self.rule.clone(),
+            self.node.clone(),
+            self.check.clone(),
+            format_ago(Some(self.fired)),
+            self.message.clone().unwrap_or_else(|| "-".to_string()),
+        ]
+    }
+}
+
+/// Last results of the checks of the node and of the peers that reported lately
+pub async fn checks(opts: &ServerOptions, failing: bool) -> Result<Vec<CheckResult>, Box<dyn Error>> {
+    let body = startup::call(opts, "/health/checks", None).await?;
+    let mut results: Vec<CheckResult> = startup::parse_body(&body)?;
+    if failing {
+        results.retain(|r| r.status == CheckStatus::Failing);
+    }
+    Ok(results)
+}
+
+/// Alerts of the node firing
+pub async fn alerts(opts: &ServerOptions) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let body = startup::call(opts, "/health/alerts", None).await?;
+    startup::parse_body(&body)
+}
@@ -0,0 +1,142 @@
+use std::{env, error::Error, fs, path::{Path, PathBuf}};
+use libp2p::PeerId;
+use tracing::info;
+use p2p::keys::{self, KeyType};
+use p2p::keystore::Keystore;
+use p2p::secrets::SecretStore;
+use serde::{Serialize, Deserialize};
+
+use crate::output::Tabular;
+use crate::{db, startup};
+
+pub const PASSWORD_ENV: &str = "HANODE_KEY_PASSWORD";
+
+pub struct KeyOptions {
+    pub db_path: String,
+    // Default location of exported keystores
+    pub keys_dir: String,
+    pub password_file: Option<String>,
+    pub unlock: db::UnlockOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyInfo {
+    pub peer_id: String,
+    pub key_type: KeyType,
+    pub public_key: String,
+    pub rotated_from: Option<String>,
+}
+
+impl Tabular for KeyInfo {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["PEER ID", "KEY TYPE", "ROTATED FROM", "PUBLIC KEY"]
+        } else {
+            vec!["PEER ID", "KEY TYPE", "ROTATED FROM"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![
+            self.peer_id.clone(),
+            self.key_type.to_string(),
+            self.rotated_from.clone().unwrap_or_else(|| "-".to_string()),
+        ];
+        if wide {
+            row.push(self.public_key.clone());
+        }
+        row
+    }
+}
+
+/// Read the keystore password from a file, the environment or the terminal
+fn read_password(opts: &KeyOptions, confirm: bool) -> Result<String, Box<dyn Error>> {
+    if let Some(file) = &opts.password_file {
+        let password = fs::read_to_string(file)?.trim_end_matches(['\r', '\n']).to_string();
+        if password.is_empty() {
+            return Err(format!("Password file {} is empty", file).into());
+        }
+        return Ok(password);
+    }
+    if let Ok(password) = env::var(PASSWORD_ENV) {
+        if password.is_empty() {
+            return Err(format!("{} is set but empty", PASSWORD_ENV).into());
+        }
+        return Ok(password);
+    }
+    let password = rpassword::prompt_password("Keystore password: ")?;
+    if confirm && password != rpassword::prompt_password("Repeat password: ")? {
+        return Err("Passwords do not match".into());
+    }
+    if password.is_empty() {
+        return Err("The keystore password must not be empty".into());
+    }
+    Ok(password)
+}
+
+fn load_key(secrets: &SecretStore) -> Result<libp2p::identity::Keypair, Box<dyn Error>> {
+    match keys::load_local_key(secrets)? {
+        Some(key) => Ok(key),
+        None => Err("The node has no key yet, start it once or import one".into()),
+    }
+}
+
+fn key_info(secrets: &SecretStore) -> Result<KeyInfo, Box<dyn Error>> {
+    let key = load_key(secrets)?;
+    let peer_id = PeerId::from(key.public()).to_base58();
+    let rotated_from = keys::pending_rotation(secrets.db())
+        .filter(|r| r.new_peer_id == peer_id)
+        .map(|r| r.old_peer_id);
+    Ok(KeyInfo {
+        peer_id,
+        key_type: keys::key_type(&key),
+        public_key: hex::encode(key.public().to_protobuf_encoding()),
+        rotated_from,
+    })
+}
+
+pub fn show(opts: &KeyOptions) -> Result<KeyInfo, Box<dyn Error>> {
+    let db = startup::open_db(&opts.db_path)?;
+    key_info(&db::open_secrets(&db, &opts.unlock)?)
+}
+
+/// Export the key to `file`, `<keys dir>/<peer id>.json` by default
+pub fn export(opts: &KeyOptions, file: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let db = startup::open_db(&opts.db_path)?;
+    let key = load_key(&db::open_secrets(&db, &opts.unlock)?)?;
+    let peer_id = PeerId::from(key.public());
+    let file = match file {
+        Some(file) => PathBuf::from(file),
+        None => Path::new(&opts.keys_dir).join(format!("{}.json", peer_id)),
+    };
+    let password = read_password(opts, true)?;
+    Keystore::encrypt(&key, &password)?.save(&file)?;
+    info!("Exported key {} to {}", peer_id, file.display());
+    Ok(())
+}
+
+pub fn import(opts: &KeyOptions, file: &str, force: bool) -> Result<KeyInfo, Box<dyn Error>> {
+    let keystore = Keystore::load(Path::new(file))?;
+    let password = read_password(opts, false)?;
+    let key = keystore.decrypt(&password)?;
+    let db = startup::open_db(&opts.db_path)?;
+    let secrets = db::open_secrets(&db, &opts.unlock)?;
+    if let Some(current) = keys::load_local_key(&secrets)? {
+        if current.public() != key.public() && !force {
+            return Err(format!(
+                "The node already has key {}, pass --force to replace it",
+                PeerId::from(current.public())
+            ).into());
+        }
+    }
+    keys::store_local_key(&secrets, &key)?;
+    key_info(&secrets)
+}
+
+pub fn rotate(opts: &KeyOptions, key_type: KeyType) -> Result<KeyInfo, Box<dyn Error>> {
+    let db = startup::open_db(&opts.db_path)?;
+    let secrets = db::open_secrets(&db, &opts.unlock)?;
+    let rotation = keys::rotate(&secrets, key_type)?;
+    info!("Rotated key {} -> {}, peers are notified on the next start", rotation.old_peer_id, rotation.new_peer_id);
+    key_info(&secrets)
+}
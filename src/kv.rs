@@ -0,0 +1,86 @@
+use std::{error::Error, time::Duration};
+use p2p::kv::{self, KvList, Record};
+use serde_json::json;
+
+use crate::error::ClientError;
+use crate::output::{self, OutputFormat, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for Record {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["KEY", "VALUE", "WRITTEN", "BY"]
+        } else {
+            vec!["KEY", "VALUE"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![self.key.clone(), self.value.clone().unwrap_or_else(|| "-".to_string())];
+        if wide {
+            row.push(output::format_ago(Some(self.version.time / 1000)));
+            row.push(self.version.node.clone());
+        }
+        row
+    }
+}
+
+// Keys go in the url as they are
+fn check(key: &str, valid: bool) -> Result<(), Box<dyn Error>> {
+    match valid {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid key {}, use at most {} letters, digits and ._-/:", key, kv::MAX_KEY_SIZE)).into()),
+    }
+}
+
+pub async fn put(opts: &ServerOptions, key: &str, value: &str) -> Result<Record, Box<dyn Error>> {
+    check(key, kv::valid_key(key))?;
+    let payload = serde_json::to_vec(&json!({ "value": value }))?;
+    let body = startup::call(opts, &format!("/kv/{}", key), Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn get(opts: &ServerOptions, key: &str) -> Result<Record, Box<dyn Error>> {
+    check(key, kv::valid_key(key))?;
+    let body = startup::call(opts, &format!("/kv/{}", key), None).await?;
+    startup::parse_body(&body)
+}
+
+/// Returns the tombstone that replaced the record
+pub async fn delete(opts: &ServerOptions, key: &str) -> Result<Record, Box<dyn Error>> {
+    check(key, kv::valid_key(key))?;
+    let payload = serde_json::to_vec(&json!({ "value": null }))?;
+    let body = startup::call(opts, &format!("/kv/{}", key), Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn list(opts: &ServerOptions, prefix: &str) -> Result<Vec<Record>, Box<dyn Error>> {
+    check(prefix, kv::valid_prefix(prefix))?;
+    let body = startup::call(opts, &format!("/kv?prefix={}", prefix), None).await?;
+    let list: KvList = startup::parse_body(&body)?;
+    Ok(list.records)
+}
+
+/// Print the changes under a prefix as the node applies them, local writes
+/// and those of the peers alike, one line per change
+pub async fn watch(opts: &ServerOptions, prefix: &str, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    check(prefix, kv::valid_prefix(prefix))?;
+    let body = startup::call(opts, &format!("/kv?prefix={}", prefix), None).await?;
+    let mut seq = startup::parse_body::<KvList>(&body)?.seq;
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let body = startup::call(opts, &format!("/kv?prefix={}&since={}", prefix, seq), None).await?;
+        let list: KvList = startup::parse_body(&body)?;
+        for record in list.records.iter() {
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string(record)?),
+                OutputFormat::Yaml => print!("---\n{}", serde_yaml::to_string(record)?),
+                OutputFormat::Table | OutputFormat::Wide => match &record.value {
+                    Some(value) => println!("PUT {} {}", record.key, value),
+                    None => println!("DEL {}", record.key),
+                },
+            }
+        }
+        seq = list.seq;
+    }
+}
@@ -0,0 +1,63 @@
+use std::{error::Error, time::Duration};
+use p2p::{lock::Lock, utils::now_millis};
+use serde_json::json;
+
+use crate::error::ClientError;
+use crate::output::Tabular;
+use crate::startup::{self, ServerOptions};
+
+// Between two attempts of `acquire --wait`
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Tabular for Lock {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["NAME", "HOLDER", "TOKEN", "EXPIRES"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        let now = now_millis();
+        vec![
+            self.name.clone(),
+            self.holder.clone(),
+            self.token.to_string(),
+            format!("in {}s", self.expires.saturating_sub(now) / 1000),
+        ]
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Box<dyn Error>> {
+    match p2p::kv::valid_key(name) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid lock name {}, use at most {} letters, digits and ._-/:", name, p2p::kv::MAX_KEY_SIZE)).into()),
+    }
+}
+
+/// Acquire a lock for the running node. With `keep` the node renews it until
+/// released, with `wait` the lock is retried until it is free.
+pub async fn acquire(opts: &ServerOptions, name: &str, ttl: Duration, keep: bool, wait: bool) -> Result<Lock, Box<dyn Error>> {
+    check_name(name)?;
+    p2p::lock::check_ttl(ttl).map_err(ClientError::BadRequest)?;
+    let payload = serde_json::to_vec(&json!({ "ttl": ttl.as_secs(), "keep": keep }))?;
+    loop {
+        match startup::call(opts, &format!("/locks/{}/acquire", name), Some(payload.clone())).await {
+            Ok(body) => return startup::parse_body(&body),
+            // Held by another node or no leader yet
+            Err(e) if wait && matches!(e.downcast_ref::<ClientError>(), Some(ClientError::BadRequest(_))) => {
+                tokio::time::sleep(WAIT_INTERVAL).await;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub async fn release(opts: &ServerOptions, name: &str, token: Option<u64>) -> Result<(), Box<dyn Error>> {
+    check_name(name)?;
+    let payload = serde_json::to_vec(&json!({ "token": token }))?;
+    startup::call(opts, &format!("/locks/{}/release", name), Some(payload)).await?;
+    Ok(())
+}
+
+pub async fn list(opts: &ServerOptions) -> Result<Vec<Lock>, Box<dyn Error>> {
+    let body = startup::call(opts, "/locks", None).await?;
+    startup::parse_body(&body)
+}
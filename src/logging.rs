@@ -0,0 +1,484 @@
+use std::{env, error::Error, fmt, fs::{self, File, OpenOptions}, io::{self, Write}, path::Path, str::FromStr, sync::Mutex, time::{Duration, SystemTime}};
+use env_logger::filter::{self, Filter};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use tracing::{field::{Field, Visit}, span, Event, Subscriber};
+use tracing_log::{AsLog, LogTracer, NormalizeEvent};
+use tracing_subscriber::{layer::{Context, SubscriberExt}, registry::LookupSpan, Layer, Registry};
+use p2p::{container::ContainerConfig, files::FileConfig, health::HealthConfig, labels::Labels, service::ServiceSpec};
+
+use crate::trace::{Exporter, SpanData};
+
+pub const LOG_ENV: &str = "HANODE_LOG";
+pub const LOG_FORMAT_ENV: &str = "HANODE_LOG_FORMAT";
+pub const TRACE_EXPORT_ENV: &str = "HANODE_TRACE_EXPORT";
+pub const LOG_FORMATS: [&str; 2] = ["text", "json"];
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+const DEFAULT_FILTERS: &str = "info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// `log` section of `<datadir>/config.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    // Filters like `info,p2p=debug`, the syntax of RUST_LOG
+    pub level: Option<String>,
+    pub format: Option<LogFormat>,
+    // Rotate the log file once it grows past this size
+    pub max_size_mb: u64,
+    // Rotate the log file once a day as well
+    pub rotate_daily: bool,
+    // Rotated files kept next to the current one
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: None, format: None, max_size_mb: 10, rotate_daily: true, max_files: 7 }
+    }
+}
+
+/// `trace` section of `<datadir>/config.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraceConfig {
+    // `file` or the URL of an OTLP/HTTP collector, spans are not exported without it
+    pub export: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub trace: TraceConfig,
+    // Labels the node announces, as in {"env": "prod", "role": "db"}
+    #[serde(default)]
+    pub labels: Labels,
+    // Processes the node runs, see `p2p::service::ServiceSpec`
+    #[serde(default)]
+    pub services: Vec<ServiceSpec>,
+    // Runtime of `hanode container`, as in {"runtime": "youki"}
+    #[serde(default)]
+    pub containers: ContainerConfig,
+    // Largest file peers may copy to the node, as in {"max_size": 1073741824}
+    #[serde(default)]
+    pub files: FileConfig,
+    // Checks the node runs and alert rules, see `p2p::health::HealthConfig`
+    #[serde(default)]
+    pub health: HealthConfig,
+    // Peer ids allowed to run workloads on the node, see `p2p::operators::Operators`
+    #[serde(default)]
+    pub operators: Vec<String>,
+}
+
+/// Read a config file, defaults when the file is missing
+pub fn read_config(path: &str) -> Result<Config, Box<dyn Error>> {
+    if !Path::new(path).exists() {
+        return Ok(Config::default());
+    }
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| format!("Invalid config file {}: {}", path, e).into())
+}
+
+pub struct LogOptions {
+    pub filters: String,
+    pub format: LogFormat,
+    // Write to stdout, on top of the file if any
+    pub stdout: bool,
+    pub file: Option<String>,
+    pub config: LogConfig,
+    // `file` or a collector URL, see `trace::Exporter`
+    pub trace_export: Option<String>,
+    // Written when `trace_export` is `file`
+    pub traces_file: Option<String>,
+}
+
+impl LogOptions {
+    /// Level, format and trace export from the command line, else the
+    /// environment, else the config file
+    pub fn resolve(level: Option<&str>, format: Option<LogFormat>, trace_export: Option<&str>, config: Config) -> Result<LogOptions, Box<dyn Error>> {
+        let trace_export = match trace_export {
+            Some(export) => Some(export.to_string()),
+            None => env::var(TRACE_EXPORT_ENV).ok().or(config.trace.export),
+        };
+        let config = config.log;
+        let filters = match level {
+            Some(level) => level.to_string(),
+            None => match env::var(LOG_ENV) {
+                Ok(filters) => filters,
+                Err(_) => config.level.clone().unwrap_or_else(|| DEFAULT_FILTERS.to_string()),
+            },
+        };
+        let format = match format {
+            Some(format) => format,
+            None => match env::var(LOG_FORMAT_ENV) {
+                Ok(format) => format.parse()?,
+                Err(_) => config.format.unwrap_or_default(),
+            },
+        };
+        Ok(LogOptions { filters, format, stdout: true, file: None, config, trace_export, traces_file: None })
+    }
+}
+
+/// Log file rotated by size and age, keeping `max_files` older files as
+/// `<path>.1` (newest) to `<path>.<max_files>`
+pub struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+    max_size: u64,
+    max_age: Option<Duration>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: &str, config: &LogConfig) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            path: path.to_string(),
+            size: metadata.len(),
+            // The age of an existing file counts so a restart does not reset it
+            opened_at: metadata.created().or_else(|_| metadata.modified()).unwrap_or_else(|_| SystemTime::now()),
+            file,
+            max_size: config.max_size_mb.max(1) * 1024 * 1024,
+            max_age: if config.rotate_daily { Some(Duration::from_secs(24 * 60 * 60)) } else { None },
+            max_files: config.max_files,
+        })
+    }
+
+    fn should_rotate(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if self.size + len as u64 > self.max_size {
+            return true;
+        }
+        match self.max_age {
+            Some(max_age) => self.opened_at.elapsed().map(|age| age >= max_age).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.max_files));
+            for i in (1..self.max_files).rev() {
+                let from = format!("{}.{}", self.path, i);
+                if Path::new(&from).exists() {
+                    fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_at = SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Sends the records to stdout and the log file
+struct LogWriter {
+    stdout: bool,
+    file: Option<RotatingFile>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stdout {
+            io::stdout().write_all(buf)?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stdout {
+            io::stdout().flush()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Collects the fields of spans and events
+#[derive(Default)]
+struct FieldVisitor {
+    message: Option<String>,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+impl FieldVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "message" => self.message = Some(value),
+            // Metadata added by the bridge of the `log` crate
+            name if name.starts_with("log.") => {},
+            name => self.fields.push((name.to_string(), value)),
+        }
+    }
+}
+
+/// Formats the events to stdout and the log file, tagged with the trace id of
+/// their span, and hands the closed spans to the exporter
+struct HanodeLayer {
+    filter: Filter,
+    format: LogFormat,
+    writer: Mutex<LogWriter>,
+    exporter: Option<Exporter>,
+}
+
+fn log_metadata<'a>(metadata: &'a tracing::Metadata<'a>) -> log::Metadata<'a> {
+    log::Metadata::builder()
+        .level(metadata.level().as_log())
+        .target(metadata.target())
+        .build()
+}
+
+fn span_id() -> String {
+    p2p::message::new_trace_id()[..16].to_string()
+}
+
+impl HanodeLayer {
+    fn format(&self, level: &tracing::Level, target: &str, visitor: FieldVisitor, trace_id: Option<&str>) -> String {
+        let ts = humantime::format_rfc3339_seconds(SystemTime::now());
+        let message = visitor.message.unwrap_or_default();
+        match self.format {
+            LogFormat::Text => {
+                let mut line = format!("[{} {:<5} {}] {}", ts, level, target, message);
+                for (key, value) in visitor.fields.iter() {
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+                if let Some(trace_id) = trace_id {
+                    line.push_str(&format!(" trace_id={}", trace_id));
+                }
+                line.push('\n');
+                line
+            },
+            LogFormat::Json => {
+                let mut record = Map::new();
+                record.insert("ts".to_string(), Value::String(ts.to_string()));
+                record.insert("level".to_string(), Value::String(level.to_string()));
+                record.insert("target".to_string(), Value::String(target.to_string()));
+                record.insert("message".to_string(), Value::String(message));
+                for (key, value) in visitor.fields {
+                    record.insert(key, Value::String(value));
+                }
+                if let Some(trace_id) = trace_id {
+                    record.insert("trace_id".to_string(), Value::String(trace_id.to_string()));
+                }
+                format!("{}\n", Value::Object(record))
+            },
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for HanodeLayer {
+    fn enabled(&self, metadata: &tracing::Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        self.filter.enabled(&log_metadata(metadata))
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        let parent = span.parent().and_then(|p| p.extensions().get::<SpanData>().map(|d| (d.trace_id.clone(), d.span_id.clone())));
+        let explicit = visitor.fields.iter().position(|(k, _)| k == "trace_id").map(|i| visitor.fields.remove(i).1);
+        let (trace_id, parent_span_id) = match (explicit, parent) {
+            // A message carries its trace id from the node that sent it
+            (Some(trace_id), _) => (trace_id, None),
+            (None, Some((trace_id, span_id))) => (trace_id, Some(span_id)),
+            (None, None) => (p2p::message::new_trace_id(), None),
+        };
+        span.extensions_mut().insert(SpanData {
+            trace_id,
+            span_id: span_id(),
+            parent_span_id,
+            name: attrs.metadata().name().to_string(),
+            target: attrs.metadata().target().to_string(),
+            start: SystemTime::now(),
+            attributes: visitor.fields,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = FieldVisitor::default();
+            values.record(&mut visitor);
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.attributes.extend(visitor.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        if !self.filter.matches(&log::Record::builder().metadata(log_metadata(metadata)).build()) {
+            return;
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let trace_id = ctx.event_span(event)
+            .and_then(|span| span.extensions().get::<SpanData>().map(|d| d.trace_id.clone()));
+        let line = self.format(metadata.level(), metadata.target(), visitor, trace_id.as_deref());
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writer.write_all(line.as_bytes());
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let (Some(exporter), Some(span)) = (&self.exporter, ctx.span(&id)) {
+            if let Some(data) = span.extensions().get::<SpanData>() {
+                exporter.export(data, SystemTime::now());
+            }
+        }
+    }
+}
+
+pub fn init(opts: &LogOptions) -> Result<(), Box<dyn Error>> {
+    let file = match &opts.file {
+        Some(path) => Some(RotatingFile::open(path, &opts.config)?),
+        None => None,
+    };
+    let exporter = match (&opts.trace_export, &opts.traces_file) {
+        (Some(export), Some(traces_file)) => Some(Exporter::new(export, traces_file, &opts.config)?),
+        _ => None,
+    };
+    let filter = filter::Builder::new().parse(&opts.filters).build();
+    let max_level = filter.filter();
+    let layer = HanodeLayer {
+        filter,
+        format: opts.format,
+        writer: Mutex::new(LogWriter { stdout: opts.stdout, file }),
+        exporter,
+    };
+    // Records of the `log` crate, used by libp2p and actix, become events
+    LogTracer::builder().with_max_level(max_level).init()?;
+    tracing::subscriber::set_global_default(Registry::default().with(layer))?;
+    Ok(())
+}
+
+/// Level of a log line in either format, `None` for lines that are not
+/// records such as panic messages
+pub fn line_level(line: &str) -> Option<log::Level> {
+    if line.starts_with('{') {
+        let value: serde_json::Value = serde_json::from_str(line).ok()?;
+        return value.get("level")?.as_str()?.parse().ok();
+    }
+    // [<timestamp> <LEVEL> <target>] <message>
+    line.strip_prefix('[')?.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_files: usize, rotate_daily: bool) -> LogConfig {
+        LogConfig { max_size_mb: 1, rotate_daily, max_files, ..LogConfig::default() }
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("hanode.log").to_string_lossy().to_string();
+        let mut file = RotatingFile::open(&path, &config(2, false)).unwrap();
+        // Each write past the first would take the file over 1MB
+        for fill in [b'a', b'b', b'c', b'd'] {
+            file.write_all(&vec![fill; 600 * 1024]).unwrap();
+        }
+        file.flush().unwrap();
+        let first = |p: &str| fs::read(p).unwrap()[0];
+        assert_eq!(first(&path), b'd');
+        assert_eq!(first(&format!("{}.1", path)), b'c');
+        assert_eq!(first(&format!("{}.2", path)), b'b');
+        // Only `max_files` old files are kept
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+    }
+
+    #[test]
+    fn test_rotate_daily() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("hanode.log").to_string_lossy().to_string();
+        let mut file = RotatingFile::open(&path, &config(0, true)).unwrap();
+        file.write_all(b"yesterday\n").unwrap();
+        file.write_all(b"still today\n").unwrap();
+        file.opened_at = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+        file.write_all(b"today\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "today\n");
+        // Without files to keep the old content is dropped
+        assert!(!Path::new(&format!("{}.1", path)).exists());
+
+        // A reopened file keeps its size
+        drop(file);
+        let file = RotatingFile::open(&path, &config(0, true)).unwrap();
+        assert_eq!(file.size, 6);
+    }
+}
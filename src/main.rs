@@ -1,22 +1,59 @@
 
-use std::{error::Error, path::Path, fs};
+use std::{collections::BTreeMap, error::Error, fs, process, time::Duration};
 use clap::{arg, Command, ArgMatches};
 use dirs::home_dir;
-use env_logger::{Builder, Target};
-use log::{error, debug};
+use tracing::{error, debug};
+use p2p::keys::{KeyType, KEY_TYPES};
+use p2p::handle::{QueueOptions, QUEUE_POLICIES};
+use p2p::rollout::RolloutOptions;
+mod artifact;
+mod cluster;
+mod container;
+mod cron;
+mod datadir;
+mod db;
+mod deploy;
+mod error;
+mod exec;
+mod group;
+mod health;
+mod key;
+mod kv;
+mod lock;
+mod members;
+mod logging;
+mod output;
+mod rollout;
+mod service;
 mod startup;
+mod trace;
 mod utils;
 
 fn cli() -> Command {
     let port_arg = arg!(-p - -port <PORT> "Specify a port to listen or connect to").value_parser(clap::value_parser!(u16).range(3000..)).required(false);
     let host_arg = arg!(-H - -host <HOST> "Specify a host to listen or connect to").required(false);
-    let uds_path_arg = arg!(--sock <SOCK_FILE> "Specify a socket file to connect to, default is $HOME/.hanode/hanode.sock").required(false);
+    let uds_path_arg = arg!(--sock <SOCK_FILE> "Specify a socket file to connect to, default is $HOME/.hanode/run/hanode.sock").required(false);
     let data_dir_arg = arg!(--datadir <DATA_DIR> "Data directory, default is $USER_HOME/.hanode").required(false);
+    let output_arg = arg!(-o - -output <FORMAT> "Output format").value_parser(output::OUTPUT_FORMATS).default_value("table").required(false);
+    let db_passphrase_file_arg = arg!(--"db-passphrase-file" <FILE> "Read the database passphrase from a file instead of $HANODE_DB_PASSPHRASE or the terminal").required(false);
+    let db_keyfile_arg = arg!(--"db-keyfile" <FILE> "Unlock the database secrets with a keyfile").required(false).conflicts_with("db-passphrase-file");
+    let password_file_arg = arg!(--"password-file" <FILE> "Read the keystore password from a file instead of $HANODE_KEY_PASSWORD or the terminal").required(false);
+    let selector_arg = arg!(-l - -selector <SELECTOR> "Only the peers whose labels match, as in env=prod,role!=db,gpu,!canary").required(false);
+    let group_arg = arg!(-g - -group <GROUP> "Only the peers of a group saved with `hanode group set`").required(false);
+    let peer_arg = arg!(--peer <PEER_ID> "Run on this peer instead of the running node").required(false);
     let p2p_port_arg = arg!(--"p2p-port" <P2P_PORT> "Specify a port for p2p connections").value_parser(clap::value_parser!(u16).range(3000..)).default_value("32000").required(false);
     Command::new("hanode")
         .about("A server for manage node")
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(arg!(--"log-level" <FILTER> "Log level, per module as in `info,p2p=debug`, default is $HANODE_LOG or the config file").required(false).global(true))
+        .arg(arg!(--"log-format" <FORMAT> "Log format, default is $HANODE_LOG_FORMAT or the config file").value_parser(logging::LOG_FORMATS).required(false).global(true))
+        .subcommand(
+            Command::new("init")
+               .about("Create the data directory, moving the files of older versions into its layout")
+               .arg(&data_dir_arg)
+               .arg(&output_arg)
+        )
         .subcommand(
             Command::new("start")
                .about("Start a node")
@@ -28,6 +65,14 @@ fn cli() -> Command {
                .arg(&host_arg)
                .arg(&uds_path_arg)
                .arg(&p2p_port_arg)
+               .arg(arg!(--"key-type" <KEY_TYPE> "Type of the node key generated on first start").value_parser(KEY_TYPES).default_value("secp256k1").required(false))
+               .arg(&db_passphrase_file_arg)
+               .arg(&db_keyfile_arg)
+               .arg(arg!(--"queue-capacity" <SIZE> "Messages waiting to be published before the queue is full").value_parser(clap::value_parser!(u32).range(1..)).default_value("1024").required(false))
+               .arg(arg!(--"queue-full" <POLICY> "What publishing does when the queue is full: wait for room, reject (429 to HTTP callers) or drop the message").value_parser(QUEUE_POLICIES).default_value("reject").required(false))
+               .arg(arg!(--"raft-voters" <PEER_IDS> "Comma separated peer ids of the raft voters, this node included, to keep strongly consistent state").value_delimiter(',').required(false))
+               .arg(arg!(--labels <LABELS> "Labels announced to the peers, as in env=prod,role=db, added to the labels of the config file").value_parser(p2p::labels::parse_labels).required(false))
+               .arg(arg!(--"trace-export" <EXPORT> "Export spans to <DATA_DIR>/logs/traces.jsonl with `file`, or to an OTLP/HTTP collector URL such as http://127.0.0.1:4318").required(false))
         )
         .subcommand(
             Command::new("stop")
@@ -36,28 +81,780 @@ fn cli() -> Command {
                .arg(&port_arg)
                .arg(&host_arg)
                .arg(&uds_path_arg)
+               .arg(&output_arg)
         )
         .subcommand(
             Command::new("peers")
                .about("List all peers")
+               .arg(&selector_arg)
+               .arg(&group_arg)
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("members")
+               .about("List the members of the cluster as alive, suspect or dead, from the failure detector of the running node")
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("info")
+               .about("Show the peer id, listen addresses and connections of the running node")
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("metrics")
+               .about("Print the metrics of the running node in the Prometheus text format")
                .arg(&data_dir_arg)
                .arg(&port_arg)
                .arg(&host_arg)
                .arg(&uds_path_arg)
         )
+        .subcommand(
+            Command::new("dial")
+               .about("Connect the running node to an address")
+               .arg(arg!(<ADDR> "Multiaddr to dial, as in /ip4/192.168.1.2/tcp/32000"))
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("disconnect")
+               .about("Close the connections of the running node to a peer")
+               .arg(arg!(<PEER_ID> "Peer to disconnect"))
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("cp")
+               .about("Copy a file to or from the files directory of a peer, through the running node")
+               .arg(arg!(<SOURCE> "Local path, or <PEER_ID>:<PATH> to copy from a peer"))
+               .arg(arg!(<DEST> "Local path, or <PEER_ID>:<PATH> to copy to a peer, :<PATH> to copy to the selected peers"))
+               .arg(arg!(--limit <RATE> "Most bytes per second, as in 512K or 10M").value_parser(utils::parse_rate).required(false))
+               .arg(arg!(--detach "Return once the copy started, follow it with `hanode transfers`"))
+               .arg(&selector_arg)
+               .arg(&group_arg)
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("exec")
+               .about("Run a command on the running node, or on it and its peers matching --selector or --group, which must list it as an operator")
+               .trailing_var_arg(true)
+               .arg(arg!(-e - -env <ENV> "Environment variable as KEY=VALUE, repeat for more").action(clap::ArgAction::Append).required(false))
+               .arg(arg!(--timeout <DURATION> "Time the command may take before it is killed, at most 50s").value_parser(utils::parse_duration).default_value("30s").required(false))
+               .arg(arg!(<COMMAND> ... "Program and its arguments").allow_hyphen_values(true))
+               .arg(&selector_arg)
+               .arg(&group_arg)
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("tag")
+               .about("Set tags on a peer, stored by the running node and matched by selectors like the labels the peer announces")
+               .arg(arg!(<PEER_ID> "Peer to tag"))
+               .arg(arg!(<TAGS> ... "Tags as in rack=a3"))
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("untag")
+               .about("Remove tags from a peer")
+               .arg(arg!(<PEER_ID> "Peer to untag"))
+               .arg(arg!(<KEYS> ... "Keys of the tags to remove"))
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("group")
+               .about("Named selectors of the running node, used with -g")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("set")
+                      .about("Create or replace a group")
+                      .arg(arg!(<NAME> "Group name, as in databases"))
+                      .arg(arg!(<SELECTOR> "Selector of its peers, as in role=db,env=prod"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the groups")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("rm")
+                      .about("Remove a group")
+                      .arg(arg!(<NAME> "Group name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+        )
+        .subcommand(
+            Command::new("svc")
+               .about("Services run by the node, declared in the services of <DATA_DIR>/config.json")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the services and their state")
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("start")
+                      .about("Start a service")
+                      .arg(arg!(<NAME> "Service name"))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("stop")
+                      .about("Stop a service, killing it if it is still running 10s after SIGTERM")
+                      .arg(arg!(<NAME> "Service name"))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("restart")
+                      .about("Stop then start a service")
+                      .arg(arg!(<NAME> "Service name"))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("logs")
+                      .about("Print the last lines of the output of a service")
+                      .arg(arg!(<NAME> "Service name"))
+                      .arg(arg!(-n - -lines <LINES> "Number of lines to print").value_parser(clap::value_parser!(usize)).default_value("100").required(false))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+        )
+        .subcommand(
+            Command::new("container")
+               .about("Containers run by the node with runc or youki, see the containers of <DATA_DIR>/config.json")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("run")
+                      .about("Run a container from a root filesystem on the node")
+                      .trailing_var_arg(true)
+                      .arg(arg!(<NAME> "Container name"))
+                      .arg(arg!(--rootfs <DIR> "Root filesystem under <DATA_DIR>/containers/rootfs on the node, absolute with --peer"))
+                      .arg(arg!(-e - -env <ENV> "Environment variable as KEY=VALUE, repeat for more").action(clap::ArgAction::Append).required(false))
+                      .arg(arg!(-w - -workdir <DIR> "Working directory inside the container, default is /").required(false))
+                      .arg(arg!(--memory <SIZE> "Most memory, as in 512M or 2G").value_parser(utils::parse_size).required(false))
+                      .arg(arg!(--cpus <CORES> "Most cores, as in 0.5 or 2").value_parser(clap::value_parser!(f64)).required(false))
+                      .arg(arg!(--pids <PIDS> "Most processes").value_parser(clap::value_parser!(u64)).required(false))
+                      .arg(arg!(-u - -user <UID> "User and group ids as UID[:GID], default is nobody").required(false))
+                      .arg(arg!(--"host-network" "Share the network of the node, default is a loopback only"))
+                      .arg(arg!(<COMMAND> ... "Program and its arguments").allow_hyphen_values(true))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("ps")
+                      .about("List the containers and their state")
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("stop")
+                      .about("Stop a container, killing it if it is still running 10s after SIGTERM")
+                      .arg(arg!(<NAME> "Container name"))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("rm")
+                      .about("Remove a stopped container with its bundle and output")
+                      .arg(arg!(<NAME> "Container name"))
+                      .arg(arg!(-f - -force "Remove a running container too, killing it"))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+               .subcommand(
+                   Command::new("logs")
+                      .about("Print the last lines of the output of a container")
+                      .arg(arg!(<NAME> "Container name"))
+                      .arg(arg!(-n - -lines <LINES> "Number of lines to print").value_parser(clap::value_parser!(usize)).default_value("100").required(false))
+                      .arg(&peer_arg)
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+        )
+        .subcommand(
+            Command::new("deploy")
+               .about("Deployments of services and containers kept in the cluster kv, converged to by each node")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("apply")
+                      .about("Submit a deployment from a JSON or YAML file, replacing the one of the same name")
+                      .arg(arg!(<FILE> "Deployment file"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the deployments with the number of nodes ready")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("status")
+                      .about("Status and drift reported by each node of a deployment, or every deployment")
+                      .arg(arg!([NAME] "Deployment name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("rm")
+                      .about("Remove a deployment, the nodes stop its workload")
+                      .arg(arg!(<NAME> "Deployment name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+        )
+        .subcommand(
+            Command::new("rollout")
+               .about("Move a deployment to a new version in waves of nodes, each wave ready before the next")
+               .args_conflicts_with_subcommands(true)
+               .subcommand_negates_reqs(true)
+               .arg(arg!(<DEPLOYMENT> "Deployment name"))
+               .arg(arg!(-f - -file <FILE> "Deployment with the new version, JSON or YAML").required(true))
+               .arg(arg!(--batch <BATCH> "Nodes per wave, a count or a percentage as in 10%").default_value("10%").required(false))
+               .arg(arg!(--"max-unavailable" <NODES> "Nodes of the deployment that may be not ready at once").value_parser(clap::value_parser!(usize)).default_value("1").required(false))
+               .arg(arg!(--timeout <DURATION> "Time the nodes moved have to become ready before the rollout pauses").value_parser(utils::parse_duration).default_value("5m").required(false))
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+               .subcommand(
+                   Command::new("status")
+                      .about("Progress of the rollout of a deployment with the status of each node")
+                      .arg(arg!(<DEPLOYMENT> "Deployment name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("pause")
+                      .about("Stop moving nodes to the new version, each node keeps the version it runs")
+                      .arg(arg!(<DEPLOYMENT> "Deployment name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("resume")
+                      .about("Go on with a paused rollout, driven by the running node from now on")
+                      .arg(arg!(<DEPLOYMENT> "Deployment name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("undo")
+                      .about("Back to the previous version on every node at once")
+                      .arg(arg!(<DEPLOYMENT> "Deployment name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
+        .subcommand(
+            Command::new("cron")
+               .about("Commands run on a schedule by the nodes, kept in the cluster kv")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("add")
+                      .about("Add a job, replacing the one of the same name")
+                      .trailing_var_arg(true)
+                      .arg(arg!(<NAME> "Job name"))
+                      .arg(arg!(<SCHEDULE> "Minute, hour, day of the month, month and day of the week in UTC, as in \"*/5 * * * *\", or @hourly, @daily..."))
+                      .arg(arg!(--target <TARGET> "Nodes running it: local for the running node, a peer id, all or one of the nodes matching --selector").default_value("local").required(false))
+                      .arg(arg!(-l - -selector <SELECTOR> "Label selector of the nodes with all or one").required(false))
+                      .arg(arg!(-e - -env <ENV> "Environment variable as KEY=VALUE, repeat for more").action(clap::ArgAction::Append).required(false))
+                      .arg(arg!(--timeout <DURATION> "Time a run may take before it is killed").value_parser(utils::parse_duration).default_value("1h").required(false))
+                      .arg(arg!(<COMMAND> ... "Program and its arguments").allow_hyphen_values(true))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the jobs with their next and last run")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("rm")
+                      .about("Remove a job and its history")
+                      .arg(arg!(<NAME> "Job name"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+               .subcommand(
+                   Command::new("history")
+                      .about("Runs of a job on every node with their exit code, the last one first")
+                      .arg(arg!(<NAME> "Job name"))
+                      .arg(arg!(-n - -runs <RUNS> "Number of runs to print").value_parser(clap::value_parser!(usize)).default_value("20").required(false))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
+        .subcommand(
+            Command::new("health")
+               .about("Checks the nodes run on their own and the alerts they fire")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("checks")
+                      .about("Last results of the checks of the running node and of its peers")
+                      .arg(arg!(--failing "Only the checks failing"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("alerts")
+                      .about("Alerts of the running node firing")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
+        .subcommand(
+            Command::new("transfers")
+               .about("List the file copies of the running node")
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("artifact")
+               .about("Share files through the peers, each node that fetched an artifact serves it to the others")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("add")
+                      .about("Add a file to the store of the running node and announce it")
+                      .arg(arg!(<FILE> "File to add"))
+                      .arg(arg!(--name <NAME> "Name shown by `ls`, default is the file name").required(false))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("get")
+                      .about("Fetch an artifact from the peers, and write it to a file")
+                      .arg(arg!(<ID> "Id printed by `add`"))
+                      .arg(arg!([OUT] "File to write, the artifact is only kept in the store without it"))
+                      .arg(arg!(--pin "Keep the artifact when running `gc`"))
+                      .arg(arg!(--detach "Return once the fetch started, follow it with `hanode artifact ls`").conflicts_with("OUT"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the artifacts in the store of the running node")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("gc")
+                      .about("Remove the artifacts that are neither pinned nor being fetched")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
+        .subcommand(
+            Command::new("kv")
+               .about("Read and write the key-value namespace shared by every node of the cluster")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("put")
+                      .about("Set a key, the write reaches the other nodes through the running node")
+                      .arg(arg!(<KEY> "Key, as in config/app/replicas"))
+                      .arg(arg!(<VALUE> "Value to set"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("get")
+                      .about("Print the value of a key")
+                      .arg(arg!(<KEY> "Key to read"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("del")
+                      .about("Delete a key on every node")
+                      .arg(arg!(<KEY> "Key to delete"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the keys under a prefix")
+                      .arg(arg!([PREFIX] "Only list the keys starting with it"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("watch")
+                      .about("Print the changes to the keys under a prefix until interrupted")
+                      .arg(arg!([PREFIX] "Only watch the keys starting with it"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
+        .subcommand(
+            Command::new("cluster")
+               .about("Strongly consistent state kept by the raft voters")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("status")
+                      .about("Show the raft role, term and leader of the running node")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("get")
+                      .about("Read a key, the running node must be the leader")
+                      .arg(arg!(<KEY> "Key to read"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("put")
+                      .about("Set a key once a majority of the voters stored it, the running node must be the leader")
+                      .arg(arg!(<KEY> "Key, as in policy/exec"))
+                      .arg(arg!(<VALUE> "Value to set"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("del")
+                      .about("Delete a key, the running node must be the leader")
+                      .arg(arg!(<KEY> "Key to delete"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
+        .subcommand(
+            Command::new("lock")
+               .about("Locks and leader election across the cluster, granted by the raft voters")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("acquire")
+                      .about("Acquire a lock for the running node and print its fencing token, renewing it when the node holds it")
+                      .arg(arg!(<NAME> "Lock name, as in jobs/nightly-backup"))
+                      .arg(arg!(--ttl <DURATION> "Time the lock is held unless renewed, as in 30s or 10m").value_parser(utils::parse_duration).default_value("30s").required(false))
+                      .arg(arg!(--keep "The node renews the lock until it is released, which makes it the leader of that name"))
+                      .arg(arg!(--wait "Retry until the lock is free instead of failing"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("release")
+                      .about("Release a lock held by the running node")
+                      .arg(arg!(<NAME> "Lock name"))
+                      .arg(arg!(--token <TOKEN> "Only release the lock if it still has this token").value_parser(clap::value_parser!(u64)).required(false))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+               )
+               .subcommand(
+                   Command::new("ls")
+                      .about("List the locks held in the cluster")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+        )
         .subcommand(
             Command::new("boardcast")
-               .about("Stop a node")
+               .about("Boardcast a message to all peers, or to the peers matching -l or -g")
+               .arg(&selector_arg)
+               .arg(&group_arg)
                .arg(&data_dir_arg)
                .arg(&port_arg)
                .arg(&host_arg)
                .arg(arg!(<MESSAGE> "Specify a message to boardcast"))
                .arg(&uds_path_arg)
+               .arg(&output_arg)
+        )
+        .subcommand(
+            Command::new("logs")
+               .about("Print the log of the running node")
+               .arg(arg!(-f - -follow "Keep printing new lines"))
+               .arg(arg!(--level <LEVEL> "Only print records of this level or more severe").value_parser(logging::LOG_LEVELS).required(false))
+               .arg(arg!(-n - -lines <LINES> "Number of lines from the end of the log to print").value_parser(clap::value_parser!(usize)).default_value("100").required(false))
+               .arg(&data_dir_arg)
+               .arg(&port_arg)
+               .arg(&host_arg)
+               .arg(&uds_path_arg)
+        )
+        .subcommand(
+            Command::new("db")
+               .about("Manage the node database")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("backup")
+                      .about("Write a portable backup of the database, through the node when it is running")
+                      .arg(arg!(<FILE> "Backup file to write"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("restore")
+                      .about("Replace the database with a backup, stopping the node for it when it is running")
+                      .arg(arg!(<FILE> "Backup file to read"))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("dump")
+                      .about("Print the records of a database tree, secrets are redacted")
+                      .arg(arg!(--tree <TREE> "Tree to print, e.g. peers, node or meta").default_value("peers").required(false))
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("compact")
+                      .about("Rewrite the database files to reclaim space, stopping the node for it when it is running")
+                      .arg(&data_dir_arg)
+                      .arg(&port_arg)
+                      .arg(&host_arg)
+                      .arg(&uds_path_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("rekey")
+                      .about("Change the passphrase of the database secrets, encrypting them if needed, the node must be stopped")
+                      .arg(&data_dir_arg)
+                      .arg(&db_passphrase_file_arg)
+                      .arg(&db_keyfile_arg)
+                      .arg(arg!(--"new-passphrase-file" <FILE> "Read the new passphrase from a file instead of $HANODE_DB_NEW_PASSPHRASE or the terminal").required(false))
+                      .arg(arg!(--"new-keyfile" <FILE> "Protect the database secrets with a keyfile").required(false).conflicts_with("new-passphrase-file"))
+               )
+        )
+        .subcommand(
+            Command::new("key")
+               .about("Manage the node identity key, the node must be stopped")
+               .subcommand_required(true)
+               .arg_required_else_help(true)
+               .subcommand(
+                   Command::new("show")
+                      .about("Show the node key")
+                      .arg(&data_dir_arg)
+                      .arg(&db_passphrase_file_arg)
+                      .arg(&db_keyfile_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("export")
+                      .about("Export the node key to a password encrypted keystore file")
+                      .arg(arg!([FILE] "Keystore file to write, default is <DATA_DIR>/keys/<PEER_ID>.json"))
+                      .arg(&data_dir_arg)
+                      .arg(&db_passphrase_file_arg)
+                      .arg(&db_keyfile_arg)
+                      .arg(&password_file_arg)
+               )
+               .subcommand(
+                   Command::new("import")
+                      .about("Import the node key from a keystore file")
+                      .arg(arg!(<FILE> "Keystore file to read"))
+                      .arg(arg!(--force "Replace an existing node key"))
+                      .arg(&data_dir_arg)
+                      .arg(&db_passphrase_file_arg)
+                      .arg(&db_keyfile_arg)
+                      .arg(&password_file_arg)
+                      .arg(&output_arg)
+               )
+               .subcommand(
+                   Command::new("rotate")
+                      .about("Replace the node key, peers are notified with a rotation signed by the old key")
+                      .arg(arg!(--type <KEY_TYPE> "Type of the new key").value_parser(KEY_TYPES).default_value("ed25519").required(false))
+                      .arg(&data_dir_arg)
+                      .arg(&db_passphrase_file_arg)
+                      .arg(&db_keyfile_arg)
+                      .arg(&output_arg)
+               )
         )
-
 }
 
-fn get_datadir(sub_matches: &ArgMatches) -> String {
+fn get_datadir(sub_matches: &ArgMatches) -> datadir::DataDir {
     let data_dir = match sub_matches.get_one::<String>("datadir") {
         Some(dir) => dir.clone(),
         None => match home_dir() {
@@ -65,19 +862,53 @@ fn get_datadir(sub_matches: &ArgMatches) -> String {
             None => ".hanode".to_string()
         },
     };
-    // Check if the directory exists
-    let data_dir_path = Path::new(&data_dir);
-    if !data_dir_path.exists() {
-        // Create a new directory
-        fs::create_dir_all(&data_dir).unwrap();
+    datadir::DataDir::new(&data_dir)
+}
+
+fn get_key_type(sub_matches: &ArgMatches, id: &str) -> Result<KeyType, Box<dyn Error>> {
+    match sub_matches.get_one::<String>(id) {
+        Some(t) => Ok(t.parse()?),
+        None => Ok(KeyType::Secp256k1),
+    }
+}
+
+
+fn get_unlock_options(sub_matches: &ArgMatches, passphrase_file: &str, keyfile: &str, env: &str, prompt: bool) -> db::UnlockOptions {
+    db::UnlockOptions {
+        passphrase_file: sub_matches.get_one::<String>(passphrase_file).cloned(),
+        keyfile: sub_matches.get_one::<String>(keyfile).cloned(),
+        env: Some(env.to_string()),
+        prompt,
+    }
+}
+
+fn get_key_options(sub_matches: &ArgMatches) -> key::KeyOptions {
+    key::KeyOptions {
+        db_path: get_datadir(sub_matches).db_path(),
+        keys_dir: get_datadir(sub_matches).keys_dir(),
+        password_file: sub_matches.try_get_one::<String>("password-file").ok().flatten().cloned(),
+        unlock: get_unlock_options(sub_matches, "db-passphrase-file", "db-keyfile", db::PASSPHRASE_ENV, true),
+    }
+}
+
+fn get_db_options(sub_matches: &ArgMatches) -> db::DbOptions {
+    db::DbOptions {
+        db_path: get_datadir(sub_matches).db_path(),
+        server_opts: get_server_opts(sub_matches),
+    }
+}
+
+fn get_output_format(sub_matches: &ArgMatches) -> output::OutputFormat {
+    match sub_matches.get_one::<String>("output") {
+        Some(format) => format.parse().unwrap_or(output::OutputFormat::Table),
+        None => output::OutputFormat::Table,
     }
-    data_dir
 }
 
 fn get_server_opts(sub_matches: &ArgMatches) -> startup::ServerOptions {
     let port = sub_matches.get_one::<u16>("port");
     let p: u16 = match port {
-        Some(port) => port.clone(),
+        Some(port) => *port,
         None => 8080,
     };
     let host = sub_matches.get_one::<String>("host");
@@ -94,61 +925,480 @@ fn get_server_opts(sub_matches: &ArgMatches) -> startup::ServerOptions {
     };
     let uds_path = match sub_matches.get_one::<String>("sock") {
         Some(path) => path.clone(),
-        None => Path::new(&get_datadir(&sub_matches)).join("hanode.sock").to_str().unwrap().to_string(),
+        None => get_datadir(sub_matches).sock_path(),
     };
     startup::ServerOptions{
-        server: server,
+        server,
         port: p,
         host: h,
-        uds_path: uds_path,
+        uds_path,
     }
 }
 
 fn get_daemon_options(sub_matches: &ArgMatches) -> startup::DaemonOptions {
     let daemon = sub_matches.get_flag("daemon");
     let data_dir = get_datadir(sub_matches);
-    // Check if the directory exists
-    let data_dir_path = Path::new(&data_dir);
-    let pid_path = data_dir_path.join("hanode.pid");
-    let err_path = data_dir_path.join("error.log");
-    let info_path = data_dir_path.join("info.log");
     startup::DaemonOptions {
         daemon,
-        pid: String::from(pid_path.to_str().unwrap()),
-        err_file: String::from(err_path.to_str().unwrap()),
-        log_file: String::from(info_path.to_str().unwrap()),
+        pid: data_dir.pid_path(),
+        err_file: data_dir.err_file(),
+        log_file: data_dir.log_file(),
     }
 }
 
-#[async_std::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    Builder::new()
-        .target(Target::Stdout)
-        .filter_level(log::LevelFilter::Info)
-        .init();
-    debug!("Starting environment logger");
-    let matches = cli().get_matches();
+// Labels of the config file, with the ones of --labels taking precedence
+fn get_labels(sub_matches: &ArgMatches, mut labels: p2p::labels::Labels) -> Result<p2p::labels::Labels, Box<dyn Error>> {
+    if let Some(flag) = sub_matches.get_one::<p2p::labels::Labels>("labels") {
+        labels.extend(flag.clone());
+    }
+    p2p::labels::check_labels(&labels)?;
+    Ok(labels)
+}
+
+fn get_peer(sub_matches: &ArgMatches) -> Option<&str> {
+    sub_matches.get_one::<String>("peer").map(|p| p.as_str())
+}
+
+/// Spec of `container run`, a relative rootfs is resolved here when the
+/// container runs on this host
+fn get_container_spec(sub_matches: &ArgMatches) -> Result<p2p::container::ContainerSpec, Box<dyn Error>> {
+    let mut rootfs = sub_matches.get_one::<String>("rootfs").unwrap().clone();
+    if get_peer(sub_matches).is_none() {
+        rootfs = utils::absolute_path(&rootfs)?;
+    }
+    // Nobody unless given, the group the one of the user
+    let (uid, gid) = match sub_matches.get_one::<String>("user") {
+        Some(user) => {
+            let (uid, gid) = user.split_once(':').unwrap_or((user, user));
+            let parse = |id: &str| id.parse::<u32>().map_err(|_| format!("Invalid user {}, expected UID[:GID]", user));
+            (parse(uid)?, parse(gid)?)
+        },
+        None => (65534, 65534),
+    };
+    Ok(p2p::container::ContainerSpec {
+        name: sub_matches.get_one::<String>("NAME").unwrap().clone(),
+        rootfs,
+        command: sub_matches.get_many::<String>("COMMAND").unwrap().cloned().collect(),
+        env: get_env(sub_matches)?,
+        dir: sub_matches.get_one::<String>("workdir").cloned(),
+        limits: p2p::service::Limits {
+            memory: sub_matches.get_one::<u64>("memory").copied(),
+            cpu: sub_matches.get_one::<f64>("cpus").copied(),
+            pids: sub_matches.get_one::<u64>("pids").copied(),
+        },
+        uid,
+        gid,
+        host_network: sub_matches.get_flag("host-network"),
+    })
+}
+
+fn get_env(sub_matches: &ArgMatches) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut env = BTreeMap::new();
+    for pair in sub_matches.get_many::<String>("env").unwrap_or_default() {
+        match pair.split_once('=') {
+            Some((key, value)) => env.insert(key.to_string(), value.to_string()),
+            None => return Err(error::ClientError::BadRequest(format!("invalid environment variable {}, expected KEY=VALUE", pair)).into()),
+        };
+    }
+    Ok(env)
+}
+
+fn get_job(sub_matches: &ArgMatches) -> Result<p2p::cron::Job, Box<dyn Error>> {
+    let target = match sub_matches.get_one::<String>("target").unwrap().as_str() {
+        // Filled in with the peer id of the running node
+        "local" => p2p::cron::Target::Node { node: String::new() },
+        "all" => p2p::cron::Target::All,
+        "one" => p2p::cron::Target::One,
+        peer => p2p::cron::Target::Node { node: peer.to_string() },
+    };
+    Ok(p2p::cron::Job {
+        name: sub_matches.get_one::<String>("NAME").unwrap().clone(),
+        schedule: sub_matches.get_one::<String>("SCHEDULE").unwrap().clone(),
+        command: sub_matches.get_many::<String>("COMMAND").unwrap().cloned().collect(),
+        env: get_env(sub_matches)?,
+        target,
+        selector: sub_matches.get_one::<String>("selector").cloned().unwrap_or_default(),
+        timeout: sub_matches.get_one::<Duration>("timeout").unwrap().as_secs(),
+    })
+}
+
+fn get_target(sub_matches: &ArgMatches) -> startup::Target {
+    startup::Target {
+        selector: sub_matches.get_one::<String>("selector").cloned(),
+        group: sub_matches.get_one::<String>("group").cloned(),
+    }
+}
+
+/// Start a node. A daemon forks before the runtime is built since only the
+/// forking thread survives, then the node runs on the runtime.
+fn start(sub_matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    get_datadir(sub_matches).init()?;
+    let bootnode = sub_matches.get_one::<String>("bootnode");
+    let p2p_port = sub_matches.get_one::<u16>("p2p-port").copied();
+    let config = logging::read_config(&get_datadir(sub_matches).config_path())?;
+    let options = startup::StartOptions{
+        server_opts: get_server_opts(sub_matches),
+        daemon_opts: get_daemon_options(sub_matches),
+        bootnode: bootnode.map(|bootnode| bootnode.to_string()),
+        p2p_port,
+        key_type: get_key_type(sub_matches, "key-type")?,
+        unlock: get_unlock_options(sub_matches, "db-passphrase-file", "db-keyfile", db::PASSPHRASE_ENV, !sub_matches.get_flag("daemon")),
+        datadir: get_datadir(sub_matches),
+        log_file: Some(get_datadir(sub_matches).node_log_file()),
+        queue: QueueOptions {
+            capacity: *sub_matches.get_one::<u32>("queue-capacity").unwrap() as usize,
+            policy: sub_matches.get_one::<String>("queue-full").unwrap().parse()?,
+        },
+        raft_voters: sub_matches.get_many::<String>("raft-voters").map(|v| v.cloned().collect()).unwrap_or_default(),
+        labels: get_labels(sub_matches, config.labels)?,
+        services: config.services,
+        container_runtime: config.containers.runtime,
+        max_file_size: config.files.max_size,
+        health: config.health,
+        operators: config.operators,
+    };
+    let lock = startup::prepare(&options)?;
+    let rt = runtime()?;
+    let result = rt.block_on(startup::start(&options, lock));
+    // Reading stdin blocks a thread that would hold up the shutdown
+    rt.shutdown_background();
+    result
+}
+
+// The one runtime of the process, every task of the node and the clients runs on it
+fn runtime() -> Result<tokio::runtime::Runtime, Box<dyn Error>> {
+    Ok(tokio::runtime::Builder::new_multi_thread().enable_all().build()?)
+}
+
+async fn run(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    // Commands working on the data directory bring it to the current layout first
+    if let Some((name, sub_matches)) = matches.subcommand() {
+        if ["db", "key"].contains(&name) {
+            let sub_matches = sub_matches.subcommand().map(|(_, m)| m).unwrap_or(sub_matches);
+            get_datadir(sub_matches).init()?;
+        }
+    }
     match matches.subcommand() {
-        Some(("start", sub_matches)) => {
-            let bootnode = sub_matches.get_one::<String>("bootnode");
-            let p2p_port = match sub_matches.get_one::<u16>("p2p-port") {
-                Some(port) => Some(port.clone()),
-                None => None,
-            };
-            let datadir = get_datadir(sub_matches);
-            startup::start(&startup::StartOptions{
-                server_opts: get_server_opts(&sub_matches),
-                daemon_opts: get_daemon_options(&sub_matches),
-                bootnode: bootnode.map(|bootnode| bootnode.to_string()),
-                p2p_port: p2p_port,
-                db_dir: Some(Path::new(datadir.as_str()).join("hanode.db").to_str().unwrap().to_string()),
-            }).await?;
+        Some(("init", sub_matches)) => {
+            let result = get_datadir(sub_matches).init()?;
+            output::print_one(&result, get_output_format(sub_matches))?;
         },
         Some(("stop", sub_matches)) => {
-            startup::stop(get_server_opts(&sub_matches)).await?;
+            let result = startup::stop(get_server_opts(sub_matches)).await?;
+            output::print_one(&result, get_output_format(sub_matches))?;
         },
         Some(("peers", sub_matches)) => {
-            startup::list_peers(get_server_opts(&sub_matches)).await?;
+            let peers = startup::list_peers(&get_server_opts(sub_matches), &get_target(sub_matches)).await?;
+            output::print_list(&peers, get_output_format(sub_matches))?;
+        },
+        Some(("members", sub_matches)) => {
+            let members = members::list(&get_server_opts(sub_matches)).await?;
+            output::print_list(&members, get_output_format(sub_matches))?;
+        },
+        Some(("info", sub_matches)) => {
+            let info = startup::info(get_server_opts(sub_matches)).await?;
+            output::print_one(&info, get_output_format(sub_matches))?;
+        },
+        Some(("metrics", sub_matches)) => {
+            print!("{}", startup::metrics(get_server_opts(sub_matches)).await?);
+        },
+        Some(("dial", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("ADDR").unwrap();
+            let result = startup::dial(get_server_opts(sub_matches), addr).await?;
+            output::print_one(&result, get_output_format(sub_matches))?;
+        },
+        Some(("disconnect", sub_matches)) => {
+            let peer_id = sub_matches.get_one::<String>("PEER_ID").unwrap();
+            let result = startup::disconnect(get_server_opts(sub_matches), peer_id).await?;
+            output::print_one(&result, get_output_format(sub_matches))?;
+        },
+        Some(("cp", sub_matches)) => {
+            let opts = startup::CopyOptions {
+                server_opts: get_server_opts(sub_matches),
+                source: sub_matches.get_one::<String>("SOURCE").unwrap().clone(),
+                dest: sub_matches.get_one::<String>("DEST").unwrap().clone(),
+                rate_limit: sub_matches.get_one::<u64>("limit").copied(),
+                detach: sub_matches.get_flag("detach"),
+                target: get_target(sub_matches),
+            };
+            if opts.target.is_empty() {
+                let result = startup::copy(opts).await?;
+                output::print_one(&result, get_output_format(sub_matches))?;
+            } else {
+                let results = startup::copy_to_peers(opts).await?;
+                output::print_list(&results, get_output_format(sub_matches))?;
+                startup::check_transfers(&results)?;
+            }
+        },
+        Some(("exec", sub_matches)) => {
+            let req = p2p::exec::ExecRequest {
+                command: sub_matches.get_many::<String>("COMMAND").unwrap().cloned().collect(),
+                env: get_env(sub_matches)?,
+                timeout: sub_matches.get_one::<Duration>("timeout").unwrap().as_secs(),
+            };
+            let results = exec::exec(&get_server_opts(sub_matches), &get_target(sub_matches), &req).await?;
+            output::print_list(&results, get_output_format(sub_matches))?;
+            exec::check(&results)?;
+        },
+        Some(("tag", sub_matches)) => {
+            let peer_id = sub_matches.get_one::<String>("PEER_ID").unwrap();
+            let tags: Vec<String> = sub_matches.get_many::<String>("TAGS").unwrap().cloned().collect();
+            let peer = group::tag(&get_server_opts(sub_matches), peer_id, &tags, &[]).await?;
+            output::print_one(&peer, get_output_format(sub_matches))?;
+        },
+        Some(("untag", sub_matches)) => {
+            let peer_id = sub_matches.get_one::<String>("PEER_ID").unwrap();
+            let keys: Vec<String> = sub_matches.get_many::<String>("KEYS").unwrap().cloned().collect();
+            let peer = group::tag(&get_server_opts(sub_matches), peer_id, &[], &keys).await?;
+            output::print_one(&peer, get_output_format(sub_matches))?;
+        },
+        Some(("svc", sub_matches)) => match sub_matches.subcommand() {
+            Some(("ls", sub_matches)) => {
+                let services = service::list(&get_server_opts(sub_matches), get_peer(sub_matches)).await?;
+                output::print_list(&services, get_output_format(sub_matches))?;
+            },
+            Some((action, sub_matches)) if ["start", "stop", "restart"].contains(&action) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let status = service::action(&get_server_opts(sub_matches), get_peer(sub_matches), name, action).await?;
+                output::print_one(&status, get_output_format(sub_matches))?;
+            },
+            Some(("logs", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let lines = *sub_matches.get_one::<usize>("lines").unwrap();
+                for line in service::logs(&get_server_opts(sub_matches), get_peer(sub_matches), name, lines).await? {
+                    println!("{}", line);
+                }
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("container", sub_matches)) => match sub_matches.subcommand() {
+            Some(("run", sub_matches)) => {
+                let spec = get_container_spec(sub_matches)?;
+                let info = container::run(&get_server_opts(sub_matches), get_peer(sub_matches), &spec).await?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            Some(("ps", sub_matches)) => {
+                let containers = container::list(&get_server_opts(sub_matches), get_peer(sub_matches)).await?;
+                output::print_list(&containers, get_output_format(sub_matches))?;
+            },
+            Some(("stop", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let info = container::stop(&get_server_opts(sub_matches), get_peer(sub_matches), name).await?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            Some(("rm", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                container::remove(&get_server_opts(sub_matches), get_peer(sub_matches), name, sub_matches.get_flag("force")).await?;
+                println!("Removed {}", name);
+            },
+            Some(("logs", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let lines = *sub_matches.get_one::<usize>("lines").unwrap();
+                for line in container::logs(&get_server_opts(sub_matches), get_peer(sub_matches), name, lines).await? {
+                    println!("{}", line);
+                }
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("deploy", sub_matches)) => match sub_matches.subcommand() {
+            Some(("apply", sub_matches)) => {
+                let file = sub_matches.get_one::<String>("FILE").unwrap();
+                let status = deploy::apply(&get_server_opts(sub_matches), file).await?;
+                output::print_one(&status, get_output_format(sub_matches))?;
+            },
+            Some(("ls", sub_matches)) => {
+                let list = deploy::list(&get_server_opts(sub_matches)).await?;
+                output::print_list(&list, get_output_format(sub_matches))?;
+            },
+            Some(("status", sub_matches)) => match sub_matches.get_one::<String>("NAME") {
+                Some(name) => {
+                    let status = deploy::status(&get_server_opts(sub_matches), name).await?;
+                    deploy::print_status(&status, get_output_format(sub_matches))?;
+                },
+                None => {
+                    let list = deploy::list(&get_server_opts(sub_matches)).await?;
+                    output::print_list(&list, get_output_format(sub_matches))?;
+                },
+            },
+            Some(("rm", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                deploy::remove(&get_server_opts(sub_matches), name).await?;
+                println!("Removed {}", name);
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("cron", sub_matches)) => match sub_matches.subcommand() {
+            Some(("add", sub_matches)) => {
+                let status = cron::add(&get_server_opts(sub_matches), get_job(sub_matches)?).await?;
+                output::print_one(&status, get_output_format(sub_matches))?;
+            },
+            Some(("ls", sub_matches)) => {
+                let list = cron::list(&get_server_opts(sub_matches)).await?;
+                output::print_list(&list, get_output_format(sub_matches))?;
+            },
+            Some(("rm", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                cron::remove(&get_server_opts(sub_matches), name).await?;
+                println!("Removed {}", name);
+            },
+            Some(("history", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let mut runs = cron::history(&get_server_opts(sub_matches), name).await?;
+                runs.truncate(*sub_matches.get_one::<usize>("runs").unwrap());
+                output::print_list(&runs, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("health", sub_matches)) => match sub_matches.subcommand() {
+            Some(("checks", sub_matches)) => {
+                let results = health::checks(&get_server_opts(sub_matches), sub_matches.get_flag("failing")).await?;
+                output::print_list(&results, get_output_format(sub_matches))?;
+            },
+            Some(("alerts", sub_matches)) => {
+                let alerts = health::alerts(&get_server_opts(sub_matches)).await?;
+                output::print_list(&alerts, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("rollout", sub_matches)) => match sub_matches.subcommand() {
+            Some(("status", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("DEPLOYMENT").unwrap();
+                let status = deploy::status(&get_server_opts(sub_matches), name).await?;
+                deploy::print_status(&status, get_output_format(sub_matches))?;
+            },
+            Some((action, sub_matches)) => {
+                let name = sub_matches.get_one::<String>("DEPLOYMENT").unwrap();
+                let status = rollout::action(&get_server_opts(sub_matches), name, action).await?;
+                deploy::print_status(&status, get_output_format(sub_matches))?;
+            },
+            None => {
+                let name = sub_matches.get_one::<String>("DEPLOYMENT").unwrap();
+                let file = sub_matches.get_one::<String>("file").unwrap();
+                let options = RolloutOptions {
+                    batch: sub_matches.get_one::<String>("batch").unwrap().clone(),
+                    max_unavailable: *sub_matches.get_one::<usize>("max-unavailable").unwrap(),
+                    timeout: sub_matches.get_one::<Duration>("timeout").unwrap().as_secs(),
+                };
+                let status = rollout::start(&get_server_opts(sub_matches), name, file, options).await?;
+                deploy::print_status(&status, get_output_format(sub_matches))?;
+            },
+        },
+        Some(("group", sub_matches)) => match sub_matches.subcommand() {
+            Some(("set", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let selector = sub_matches.get_one::<String>("SELECTOR").unwrap();
+                let group = group::set(&get_server_opts(sub_matches), name, selector).await?;
+                output::print_one(&group, get_output_format(sub_matches))?;
+            },
+            Some(("ls", sub_matches)) => {
+                let groups = group::list(&get_server_opts(sub_matches)).await?;
+                output::print_list(&groups, get_output_format(sub_matches))?;
+            },
+            Some(("rm", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                group::remove(&get_server_opts(sub_matches), name).await?;
+                println!("Removed {}", name);
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("transfers", sub_matches)) => {
+            let transfers = startup::list_transfers(get_server_opts(sub_matches)).await?;
+            output::print_list(&transfers, get_output_format(sub_matches))?;
+        },
+        Some(("artifact", sub_matches)) => match sub_matches.subcommand() {
+            Some(("add", sub_matches)) => {
+                let file = sub_matches.get_one::<String>("FILE").unwrap();
+                let name = sub_matches.get_one::<String>("name").map(|n| n.as_str());
+                let info = artifact::add(&get_server_opts(sub_matches), file, name).await?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            Some(("get", sub_matches)) => {
+                let info = artifact::get(&get_server_opts(sub_matches), &artifact::GetOptions {
+                    id: sub_matches.get_one::<String>("ID").unwrap().clone(),
+                    out: sub_matches.get_one::<String>("OUT").cloned(),
+                    pin: sub_matches.get_flag("pin"),
+                    detach: sub_matches.get_flag("detach"),
+                }).await?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            Some(("ls", sub_matches)) => {
+                let artifacts = artifact::list(&get_server_opts(sub_matches)).await?;
+                output::print_list(&artifacts, get_output_format(sub_matches))?;
+            },
+            Some(("gc", sub_matches)) => {
+                let result = artifact::gc(&get_server_opts(sub_matches)).await?;
+                output::print_one(&result, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("kv", sub_matches)) => match sub_matches.subcommand() {
+            Some(("put", sub_matches)) => {
+                let key = sub_matches.get_one::<String>("KEY").unwrap();
+                let value = sub_matches.get_one::<String>("VALUE").unwrap();
+                let record = kv::put(&get_server_opts(sub_matches), key, value).await?;
+                output::print_one(&record, get_output_format(sub_matches))?;
+            },
+            Some(("get", sub_matches)) => {
+                let key = sub_matches.get_one::<String>("KEY").unwrap();
+                let record = kv::get(&get_server_opts(sub_matches), key).await?;
+                output::print_one(&record, get_output_format(sub_matches))?;
+            },
+            Some(("del", sub_matches)) => {
+                let key = sub_matches.get_one::<String>("KEY").unwrap();
+                let record = kv::delete(&get_server_opts(sub_matches), key).await?;
+                output::print_one(&record, get_output_format(sub_matches))?;
+            },
+            Some(("ls", sub_matches)) => {
+                let prefix = sub_matches.get_one::<String>("PREFIX").map(|p| p.as_str()).unwrap_or("");
+                let records = kv::list(&get_server_opts(sub_matches), prefix).await?;
+                output::print_list(&records, get_output_format(sub_matches))?;
+            },
+            Some(("watch", sub_matches)) => {
+                let prefix = sub_matches.get_one::<String>("PREFIX").map(|p| p.as_str()).unwrap_or("");
+                kv::watch(&get_server_opts(sub_matches), prefix, get_output_format(sub_matches)).await?;
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("cluster", sub_matches)) => match sub_matches.subcommand() {
+            Some(("status", sub_matches)) => {
+                let status = cluster::status(&get_server_opts(sub_matches)).await?;
+                output::print_one(&status, get_output_format(sub_matches))?;
+            },
+            Some(("get", sub_matches)) => {
+                let key = sub_matches.get_one::<String>("KEY").unwrap();
+                let entry = cluster::get(&get_server_opts(sub_matches), key).await?;
+                output::print_one(&entry, get_output_format(sub_matches))?;
+            },
+            Some(("put", sub_matches)) => {
+                let key = sub_matches.get_one::<String>("KEY").unwrap();
+                let value = sub_matches.get_one::<String>("VALUE").map(|v| v.as_str());
+                let entry = cluster::write(&get_server_opts(sub_matches), key, value).await?;
+                output::print_one(&entry, get_output_format(sub_matches))?;
+            },
+            Some(("del", sub_matches)) => {
+                let key = sub_matches.get_one::<String>("KEY").unwrap();
+                let entry = cluster::write(&get_server_opts(sub_matches), key, None).await?;
+                output::print_one(&entry, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("lock", sub_matches)) => match sub_matches.subcommand() {
+            Some(("acquire", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                let ttl = *sub_matches.get_one::<Duration>("ttl").unwrap();
+                let lock = lock::acquire(&get_server_opts(sub_matches), name, ttl, sub_matches.get_flag("keep"), sub_matches.get_flag("wait")).await?;
+                output::print_one(&lock, get_output_format(sub_matches))?;
+            },
+            Some(("release", sub_matches)) => {
+                let name = sub_matches.get_one::<String>("NAME").unwrap();
+                lock::release(&get_server_opts(sub_matches), name, sub_matches.get_one::<u64>("token").copied()).await?;
+                println!("Released {}", name);
+            },
+            Some(("ls", sub_matches)) => {
+                let locks = lock::list(&get_server_opts(sub_matches)).await?;
+                output::print_list(&locks, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
         },
         Some(("boardcast", sub_matches)) => {
             let message = sub_matches.get_one::<String>("MESSAGE");
@@ -156,12 +1406,112 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 Some(host) => host.clone(),
                 None => "".to_string(),
             };
-            startup::boardcast(startup::BoardcastOptions{
-                server_opts: get_server_opts(&sub_matches),
+            let result = startup::boardcast(startup::BoardcastOptions{
+                server_opts: get_server_opts(sub_matches),
                 msg: m,
+                target: get_target(sub_matches),
+            }).await?;
+            output::print_one(&result, get_output_format(sub_matches))?;
+        },
+        Some(("logs", sub_matches)) => {
+            startup::logs(startup::LogsOptions {
+                server_opts: get_server_opts(sub_matches),
+                follow: sub_matches.get_flag("follow"),
+                level: sub_matches.get_one::<String>("level").and_then(|l| l.parse().ok()),
+                lines: *sub_matches.get_one::<usize>("lines").unwrap(),
             }).await?;
         },
+        Some(("db", sub_matches)) => match sub_matches.subcommand() {
+            Some(("rekey", sub_matches)) => {
+                db::rekey(
+                    &get_datadir(sub_matches).db_path(),
+                    &get_unlock_options(sub_matches, "db-passphrase-file", "db-keyfile", db::PASSPHRASE_ENV, true),
+                    &get_unlock_options(sub_matches, "new-passphrase-file", "new-keyfile", db::NEW_PASSPHRASE_ENV, true),
+                )?;
+            },
+            Some(("backup", sub_matches)) => {
+                let file = sub_matches.get_one::<String>("FILE").unwrap();
+                let result = db::backup(&get_db_options(sub_matches), file).await?;
+                output::print_one(&result, get_output_format(sub_matches))?;
+            },
+            Some(("restore", sub_matches)) => {
+                let file = sub_matches.get_one::<String>("FILE").unwrap();
+                let result = db::restore(&get_db_options(sub_matches), file).await?;
+                output::print_one(&result, get_output_format(sub_matches))?;
+            },
+            Some(("dump", sub_matches)) => {
+                let tree = sub_matches.get_one::<String>("tree").unwrap();
+                let records = db::dump(&get_db_options(sub_matches), tree).await?;
+                output::print_list(&records, get_output_format(sub_matches))?;
+            },
+            Some(("compact", sub_matches)) => {
+                let result = db::compact(&get_db_options(sub_matches)).await?;
+                output::print_one(&result, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
+        },
+        Some(("key", sub_matches)) => match sub_matches.subcommand() {
+            Some(("show", sub_matches)) => {
+                let info = key::show(&get_key_options(sub_matches))?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            Some(("export", sub_matches)) => {
+                let file = sub_matches.get_one::<String>("FILE").map(|f| f.as_str());
+                key::export(&get_key_options(sub_matches), file)?;
+            },
+            Some(("import", sub_matches)) => {
+                let file = sub_matches.get_one::<String>("FILE").unwrap();
+                let info = key::import(&get_key_options(sub_matches), file, sub_matches.get_flag("force"))?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            Some(("rotate", sub_matches)) => {
+                let info = key::rotate(&get_key_options(sub_matches), get_key_type(sub_matches, "type")?)?;
+                output::print_one(&info, get_output_format(sub_matches))?;
+            },
+            _ => error!("not implemented"),
+        },
         _ => error!("not implemented"),
     }
     Ok(())
 }
+
+/// The node logs to stdout, unless it runs as a daemon, and to the rotated
+/// `logs/hanode.log` of its data directory, and exports its spans when asked
+/// to. Other commands only log to stdout.
+fn init_logging(matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let mut sub_matches = matches;
+    while let Some((_, m)) = sub_matches.subcommand() {
+        sub_matches = m;
+    }
+    let datadir = get_datadir(sub_matches);
+    let mut opts = logging::LogOptions::resolve(
+        sub_matches.get_one::<String>("log-level").map(|l| l.as_str()),
+        sub_matches.get_one::<String>("log-format").and_then(|f| f.parse().ok()),
+        sub_matches.try_get_one::<String>("trace-export").ok().flatten().map(|e| e.as_str()),
+        logging::read_config(&datadir.config_path())?,
+    )?;
+    if let Some(("start", start_matches)) = matches.subcommand() {
+        fs::create_dir_all(datadir.logs_dir())?;
+        opts.file = Some(datadir.node_log_file());
+        opts.traces_file = Some(datadir.traces_file());
+        opts.stdout = !start_matches.get_flag("daemon");
+    }
+    logging::init(&opts)
+}
+
+fn main() {
+    let matches = cli().get_matches();
+    if let Err(err) = init_logging(&matches) {
+        eprintln!("Error: {}", err);
+        process::exit(1);
+    }
+    debug!("Starting environment logger");
+    let result = match matches.subcommand() {
+        Some(("start", sub_matches)) => start(sub_matches),
+        _ => runtime().and_then(|rt| rt.block_on(run(&matches))),
+    };
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        process::exit(error::exit_code(err.as_ref()));
+    }
+}
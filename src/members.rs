@@ -0,0 +1,34 @@
+use std::error::Error;
+use p2p::swim::{Member, MemberState};
+
+use crate::output::{self, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for Member {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["ID", "STATE", "SINCE", "INCARNATION", "ADDRS"]
+        } else {
+            vec!["ID", "STATE", "SINCE"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let state = match self.state {
+            MemberState::Alive => "alive",
+            MemberState::Suspect => "suspect",
+            MemberState::Dead => "dead",
+        };
+        let mut row = vec![self.id.clone(), state.to_string(), output::format_ago(Some(self.since))];
+        if wide {
+            row.push(self.incarnation.to_string());
+            row.push(self.addrs.join(","));
+        }
+        row
+    }
+}
+
+pub async fn list(opts: &ServerOptions) -> Result<Vec<Member>, Box<dyn Error>> {
+    let body = startup::call(opts, "/members", None).await?;
+    startup::parse_body(&body)
+}
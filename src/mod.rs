@@ -1,2 +0,0 @@
-pub mod startup;
-pub mod utils;
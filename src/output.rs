@@ -0,0 +1,429 @@
+use std::{error::Error, fmt, str::FromStr};
+use p2p::{handle::NodeInfo, labels::Labels, peer::{Peer, PeerStatus}, transfer::{Direction, TransferState, TransferStatus}, utils::now_secs};
+use serde::{Serialize, Deserialize};
+
+pub const OUTPUT_FORMATS: [&str; 4] = ["table", "json", "yaml", "wide"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Yaml,
+    Wide,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "wide" => Ok(OutputFormat::Wide),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OutputFormat::Table => write!(f, "table"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Yaml => write!(f, "yaml"),
+            OutputFormat::Wide => write!(f, "wide"),
+        }
+    }
+}
+
+/// Rows that can be rendered as a table, `wide` adds the extra columns
+pub trait Tabular {
+    fn headers(wide: bool) -> Vec<&'static str>;
+    fn row(&self, wide: bool) -> Vec<String>;
+}
+
+/// Print a list of items in the given format
+pub fn print_list<T: Serialize + Tabular>(items: &[T], format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    print!("{}", render_list(items, format)?);
+    Ok(())
+}
+
+/// Render a list of items in the given format, ending with a newline
+pub fn render_list<T: Serialize + Tabular>(items: &[T], format: OutputFormat) -> Result<String, Box<dyn Error>> {
+    Ok(match format {
+        OutputFormat::Json => format!("{}\n", serde_json::to_string_pretty(items)?),
+        OutputFormat::Yaml => serde_yaml::to_string(items)?,
+        OutputFormat::Table | OutputFormat::Wide => {
+            let wide = format == OutputFormat::Wide;
+            let rows: Vec<Vec<String>> = items.iter().map(|item| item.row(wide)).collect();
+            render_table(&T::headers(wide), &rows)
+        }
+    })
+}
+
+/// Print a single item in the given format
+pub fn print_one<T: Serialize + Tabular>(item: &T, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(item)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(item)?),
+        OutputFormat::Table | OutputFormat::Wide => print_list(std::slice::from_ref(item), format)?,
+    }
+    Ok(())
+}
+
+/// Align the columns with two spaces between them, like `kubectl get`
+pub fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            if i < widths.len() {
+                widths[i] = widths[i].max(cell.chars().count());
+            }
+        }
+    }
+    let mut out = String::new();
+    let mut push_line = |cells: Vec<&str>| {
+        let last = cells.len().saturating_sub(1);
+        let mut line = String::new();
+        for (i, cell) in cells.iter().enumerate() {
+            line.push_str(cell);
+            if i < last {
+                let pad = widths[i] - cell.chars().count() + 2;
+                line.push_str(&" ".repeat(pad));
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    };
+    push_line(headers.to_vec());
+    for row in rows {
+        push_line(row.iter().map(|c| c.as_str()).collect());
+    }
+    out
+}
+
+/// Format a unix timestamp relative to now, e.g. `42s ago`
+pub fn format_ago(ts: Option<u64>) -> String {
+    let ts = match ts {
+        Some(ts) => ts,
+        None => return "-".to_string(),
+    };
+    let now = now_secs();
+    format!("{} ago", format_secs(now.saturating_sub(ts)))
+}
+
+/// Format a unix timestamp still to come, e.g. `in 5m`
+pub fn format_in(ts: Option<u64>) -> String {
+    let ts = match ts {
+        Some(ts) => ts,
+        None => return "-".to_string(),
+    };
+    let now = now_secs();
+    format!("in {}", format_secs(ts.saturating_sub(now)))
+}
+
+pub fn format_secs(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Stable representation of a peer for scripting
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerView {
+    pub id: String,
+    pub hostname: String,
+    pub host_mac: String,
+    pub status: String,
+    pub addrs: Vec<String>,
+    pub last_seen: Option<u64>,
+    // Announced labels with the tags set on this node
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+impl From<Peer> for PeerView {
+    fn from(p: Peer) -> Self {
+        let mut addrs: Vec<String> = p.addrs.iter().map(|a| a.to_string()).collect();
+        addrs.sort();
+        let labels = p.all_labels();
+        PeerView {
+            id: p.id,
+            hostname: p.hostname,
+            host_mac: p.host_mac,
+            status: match p.status {
+                PeerStatus::Connected => "connected".to_string(),
+                PeerStatus::Disconnected => "disconnected".to_string(),
+            },
+            addrs,
+            last_seen: p.last_seen,
+            labels,
+        }
+    }
+}
+
+/// `env=prod,role=db`
+pub fn format_labels(labels: &Labels) -> String {
+    labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(",")
+}
+
+fn or_dash(s: &str) -> String {
+    if s.is_empty() { "-".to_string() } else { s.to_string() }
+}
+
+impl Tabular for PeerView {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["PEER ID", "HOSTNAME", "STATUS", "ADDRESSES", "LAST SEEN", "MAC", "LABELS"]
+        } else {
+            vec!["PEER ID", "HOSTNAME", "STATUS", "ADDRESSES", "LAST SEEN"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let addrs = if wide || self.addrs.len() <= 1 {
+            or_dash(&self.addrs.join(","))
+        } else {
+            format!("{} (+{})", self.addrs[0], self.addrs.len() - 1)
+        };
+        let mut row = vec![
+            self.id.clone(),
+            or_dash(&self.hostname),
+            self.status.clone(),
+            addrs,
+            format_ago(self.last_seen),
+        ];
+        if wide {
+            row.push(or_dash(&self.host_mac));
+            row.push(or_dash(&format_labels(&self.labels)));
+        }
+        row
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoardcastResult {
+    pub message: String,
+    pub sent: bool,
+    #[serde(default)]
+    pub dropped: bool,
+    pub count: i32,
+    #[serde(default)]
+    pub trace_id: String,
+    // Peers selected with `-l` or `-g`, every peer when empty
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+impl Tabular for BoardcastResult {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["MESSAGE", "SENT", "COUNT", "TRACE", "TARGETS"]
+        } else {
+            vec!["MESSAGE", "SENT"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![self.message.clone(), self.sent.to_string()];
+        if wide {
+            row.push(self.count.to_string());
+            row.push(if self.trace_id.is_empty() { "-".to_string() } else { self.trace_id.clone() });
+            row.push(if self.targets.is_empty() { "all".to_string() } else { self.targets.len().to_string() });
+        }
+        row
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopResult {
+    pub stopped: bool,
+}
+
+impl Tabular for StopResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["STOPPED"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.stopped.to_string()]
+    }
+}
+
+impl Tabular for NodeInfo {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["PEER ID", "LISTEN ADDRESSES", "CONNECTED", "LABELS", "CONNECTED PEERS"]
+        } else {
+            vec!["PEER ID", "LISTEN ADDRESSES", "CONNECTED", "LABELS"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let mut row = vec![
+            self.peer_id.clone(),
+            or_dash(&self.listen_addrs.join(",")),
+            self.connected_peers.len().to_string(),
+            or_dash(&format_labels(&self.labels)),
+        ];
+        if wide {
+            row.push(or_dash(&self.connected_peers.join(",")));
+        }
+        row
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DialResult {
+    pub addr: String,
+    pub dialed: bool,
+}
+
+impl Tabular for DialResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["ADDRESS", "DIALED"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.addr.clone(), self.dialed.to_string()]
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisconnectResult {
+    pub peer_id: String,
+    pub disconnected: bool,
+}
+
+impl Tabular for DisconnectResult {
+    fn headers(_wide: bool) -> Vec<&'static str> {
+        vec!["PEER ID", "DISCONNECTED"]
+    }
+
+    fn row(&self, _wide: bool) -> Vec<String> {
+        vec![self.peer_id.clone(), self.disconnected.to_string()]
+    }
+}
+
+/// Bytes with a binary unit, e.g. `1.5MiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+/// `transferred/size (percent)` of a transfer
+pub fn format_progress(status: &TransferStatus) -> String {
+    match status.size {
+        Some(0) => "0B/0B (100%)".to_string(),
+        Some(size) => format!(
+            "{}/{} ({}%)",
+            format_bytes(status.transferred),
+            format_bytes(size),
+            status.transferred * 100 / size,
+        ),
+        None => format_bytes(status.transferred),
+    }
+}
+
+impl Tabular for TransferStatus {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["ID", "SOURCE", "DESTINATION", "STATE", "PROGRESS", "ATTEMPTS", "RESUMED FROM", "ERROR"]
+        } else {
+            vec!["ID", "SOURCE", "DESTINATION", "STATE", "PROGRESS"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let remote = format!("{}:{}", self.spec.peer, self.spec.remote);
+        let (source, destination) = match self.spec.direction {
+            Direction::Push => (self.spec.local.clone(), remote),
+            Direction::Pull => (remote, self.spec.local.clone()),
+        };
+        let state = match self.state {
+            TransferState::Running => "running",
+            TransferState::Retrying => "retrying",
+            TransferState::Done => "done",
+            TransferState::Failed => "failed",
+        };
+        let mut row = vec![self.id.to_string(), source, destination, state.to_string(), format_progress(self)];
+        if wide {
+            row.push(self.attempts.to_string());
+            row.push(format_bytes(self.resumed_from));
+            row.push(or_dash(self.error.as_deref().unwrap_or("")));
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, addrs: &[&str]) -> PeerView {
+        PeerView {
+            id: id.to_string(),
+            hostname: String::new(),
+            host_mac: "aa:bb".to_string(),
+            status: "connected".to_string(),
+            addrs: addrs.iter().map(|a| a.to_string()).collect(),
+            last_seen: None,
+            labels: Labels::from([("env".to_string(), "prod".to_string())]),
+        }
+    }
+
+    #[test]
+    fn test_render_table() {
+        let rows = vec![
+            vec!["a".to_string(), "long value".to_string(), "x".to_string()],
+            vec!["longer key".to_string(), "b".to_string(), String::new()],
+        ];
+        assert_eq!(
+            render_table(&["KEY", "VALUE", "LAST"], &rows),
+            "KEY         VALUE       LAST\na           long value  x\nlonger key  b\n",
+        );
+        assert_eq!(render_table(&["PEER ID", "STATUS"], &[]), "PEER ID  STATUS\n");
+    }
+
+    #[test]
+    fn test_render_list() {
+        let peers = vec![peer("peer-a", &["/ip4/10.0.0.1/tcp/1", "/ip4/10.0.0.2/tcp/1"]), peer("peer-b", &[])];
+        let table = render_list(&peers, OutputFormat::Table).unwrap();
+        assert_eq!(table.lines().collect::<Vec<_>>(), vec![
+            "PEER ID  HOSTNAME  STATUS     ADDRESSES                 LAST SEEN",
+            "peer-a   -         connected  /ip4/10.0.0.1/tcp/1 (+1)  -",
+            "peer-b   -         connected  -                         -",
+        ]);
+        let wide = render_list(&peers, OutputFormat::Wide).unwrap();
+        assert!(wide.lines().next().unwrap().ends_with("MAC    LABELS"));
+        assert!(wide.lines().nth(1).unwrap().contains("/ip4/10.0.0.1/tcp/1,/ip4/10.0.0.2/tcp/1"));
+        assert!(wide.lines().nth(1).unwrap().ends_with("aa:bb  env=prod"));
+
+        let json: Vec<PeerView> = serde_json::from_str(&render_list(&peers, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[0].addrs.len(), 2);
+        let yaml: Vec<PeerView> = serde_yaml::from_str(&render_list(&peers, OutputFormat::Yaml).unwrap()).unwrap();
+        assert_eq!(yaml[1].id, "peer-b");
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(format_secs(59), "59s");
+        assert_eq!(format_secs(3600), "1h");
+        assert_eq!(format_bytes(1536), "1.5KiB");
+        assert_eq!(format_ago(None), "-");
+        assert!("tsv".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,27 @@
+use std::error::Error;
+use p2p::deploy::DeploymentStatus;
+use p2p::rollout::{RolloutOptions, RolloutRequest};
+
+use crate::deploy;
+use crate::error::ClientError;
+use crate::startup::{self, ServerOptions};
+
+/// Move a deployment to the version of a JSON or YAML file in waves of nodes
+pub async fn start(opts: &ServerOptions, name: &str, file: &str, options: RolloutOptions) -> Result<DeploymentStatus, Box<dyn Error>> {
+    deploy::check_name(name)?;
+    options.check().map_err(ClientError::BadRequest)?;
+    let deployment = deploy::read(file)?;
+    if deployment.name() != name {
+        return Err(ClientError::BadRequest(format!("{} is the deployment of {}, not {}", file, deployment.name(), name)).into());
+    }
+    let payload = serde_json::to_vec(&RolloutRequest { deployment, options })?;
+    let body = startup::call(opts, &format!("/rollouts/{}", name), Some(payload)).await?;
+    startup::parse_body(&body)
+}
+
+/// Pause, resume or undo the rollout of a deployment
+pub async fn action(opts: &ServerOptions, name: &str, action: &str) -> Result<DeploymentStatus, Box<dyn Error>> {
+    deploy::check_name(name)?;
+    let body = startup::call(opts, &format!("/rollouts/{}/{}", name, action), Some(Vec::new())).await?;
+    startup::parse_body(&body)
+}
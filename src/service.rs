@@ -0,0 +1,69 @@
+use std::error::Error;
+use p2p::service::{self, ServiceState, ServiceStatus};
+
+use crate::error::ClientError;
+use crate::output::{format_ago, Tabular};
+use crate::startup::{self, ServerOptions};
+
+impl Tabular for ServiceStatus {
+    fn headers(wide: bool) -> Vec<&'static str> {
+        if wide {
+            vec!["NAME", "STATE", "PID", "RESTARTS", "EXIT CODE", "SINCE", "CGROUP", "ERROR"]
+        } else {
+            vec!["NAME", "STATE", "PID", "RESTARTS", "SINCE"]
+        }
+    }
+
+    fn row(&self, wide: bool) -> Vec<String> {
+        let state = match self.state {
+            ServiceState::Stopped => "stopped",
+            ServiceState::Running => "running",
+            ServiceState::Restarting => "restarting",
+            ServiceState::Exited => "exited",
+            ServiceState::Failed => "failed",
+        };
+        let mut row = vec![
+            self.name.clone(),
+            state.to_string(),
+            self.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string()),
+            self.restarts.to_string(),
+            format_ago(Some(self.since)),
+        ];
+        if wide {
+            row.insert(4, self.exit_code.map(|c| c.to_string()).unwrap_or_else(|| "-".to_string()));
+            row.push(self.cgroup.to_string());
+            row.push(self.error.clone().unwrap_or_else(|| "-".to_string()));
+        }
+        row
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Box<dyn Error>> {
+    match service::valid_name(name) {
+        true => Ok(()),
+        false => Err(ClientError::BadRequest(format!("invalid service name {}", name)).into()),
+    }
+}
+
+fn peer_query(peer: Option<&str>) -> String {
+    peer.map(|p| format!("peer={}", p)).unwrap_or_default()
+}
+
+/// Services of the running node, or of a peer
+pub async fn list(opts: &ServerOptions, peer: Option<&str>) -> Result<Vec<ServiceStatus>, Box<dyn Error>> {
+    let body = startup::call(opts, &format!("/services?{}", peer_query(peer)), None).await?;
+    startup::parse_body(&body)
+}
+
+/// `start`, `stop` or `restart` a service
+pub async fn action(opts: &ServerOptions, peer: Option<&str>, name: &str, action: &str) -> Result<ServiceStatus, Box<dyn Error>> {
+    check_name(name)?;
+    let body = startup::call(opts, &format!("/services/{}/{}?{}", name, action, peer_query(peer)), Some(Vec::new())).await?;
+    startup::parse_body(&body)
+}
+
+pub async fn logs(opts: &ServerOptions, peer: Option<&str>, name: &str, lines: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    check_name(name)?;
+    let body = startup::call(opts, &format!("/services/{}/logs?lines={}&{}", name, lines, peer_query(peer)), None).await?;
+    startup::parse_body(&body)
+}
@@ -1,28 +1,30 @@
-use log::{info, debug, warn, error};
+use tracing::{info, debug, error, warn};
 use p2p::lifecycle::{NodeLifecycle};
-use p2p::node::{Sender, NodeBehaviourOptions};
-use p2p::state::NodeState;
-use p2p::{node::NodeBehaviour, message::Message};
+use p2p::node::NodeBehaviourOptions;
+use p2p::node::NodeBehaviour;
+use p2p::handle::{NodeHandle, QueueOptions};
 use p2p::message;
+use p2p::peer::Peer;
+use p2p::keys::KeyType;
+use p2p::labels::{Labels, Selector};
+use p2p::service::ServiceSpec;
+use p2p::health::HealthConfig;
+use p2p::schema;
 
-use signal_hook::consts::SIGINT;
-use std::io::Error;
-use std::fs::{File};
+use std::collections::HashMap;
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use std::fs::OpenOptions;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
-use std::{thread, process};
-use signal_hook::{iterator::Signals};
-use std::io::ErrorKind;
-use async_std::{io};
-use sysinfo::{ProcessExt, System, SystemExt, Pid};
-use futures::{
-    prelude::{stream::StreamExt, *},
-    select,
-};
-use futures::channel::mpsc;
+use std::process;
+use tokio::io::{self, AsyncBufReadExt};
 use daemonize::Daemonize;
-use futures::executor::block_on;
-use crate::utils;
+use crate::{db, logging, utils};
+use crate::datadir::{DataDir, DataDirLock};
+use crate::error::ClientError;
+use crate::output::{self, BoardcastResult, DialResult, DisconnectResult, PeerView, StopResult};
+use p2p::handle::NodeInfo;
+use p2p::transfer::{Direction, TransferSpec, TransferState, TransferStatus};
+use server::core::DbTasks;
 
 pub struct ServerOptions{
     pub server: bool,
@@ -43,29 +45,55 @@ pub struct StartOptions {
     pub server_opts: ServerOptions,
     pub daemon_opts: DaemonOptions,
     pub bootnode: Option<String>,
-    pub db_dir: Option<String>,
+    pub datadir: DataDir,
     pub p2p_port: Option<u16>, // port for p2p connections
+    pub key_type: KeyType, // type of the key generated on first start
+    pub unlock: db::UnlockOptions, // passphrase of the encrypted database secrets
+    pub log_file: Option<String>, // file the logger writes to, served to `hanode logs`
+    pub queue: QueueOptions, // size of the message queue and what to do when it is full
+    pub raft_voters: Vec<String>, // peer ids of the raft voters, empty to disable raft
+    pub labels: Labels, // announced to the peers, from the config file and --labels
+    pub services: Vec<ServiceSpec>, // run by the node, from the config file
+    pub container_runtime: String, // runc or youki, from the config file
+    pub max_file_size: u64, // largest file peers may copy here, from the config file
+    pub health: HealthConfig, // checks and alert rules, from the config file
+    pub operators: Vec<String>, // peers allowed to run workloads here, from the config file
 }
 
-pub async fn start(options: &StartOptions) -> Result<(), Box<dyn std::error::Error>> {
-    if options.daemon_opts.daemon {
-        if utils::exists(&options.daemon_opts.pid) {
-            // Read pid file
-            let pid = utils::read_pid(&options.daemon_opts.pid);
-            if let Some(pid) = pid {
-                debug!("Daemon already running with pid {}", pid);
-                let s = System::new_all();
-                if let Some(process) = s.process(Pid::from(pid)) {
-                    let err = Error::new(
-                        ErrorKind::Other,
-                        format!("There is a {} running, pid file already exists: {}", process.name(), &options.daemon_opts.pid)
-                    );
-                    return Err(Box::new(err));
-                }
-            }
+/// Open the node database and migrate it to the current schema, the node
+/// must not be running as sled locks it
+pub fn open_db(db_path: &str) -> Result<sled::Db, Box<dyn std::error::Error>> {
+    db::recover(db_path)?;
+    let db_path = Path::new(db_path);
+    let db = match sled::open(db_path) {
+        Ok(db) => db,
+        Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => {
+            return Err(format!("Database {} is locked, stop the node first", db_path.display()).into());
+        },
+        Err(e) => return Err(format!("Failed to open database {}: {}", db_path.display(), e).into()),
+    };
+    // Upgrade databases written by older versions before anything reads them
+    schema::migrate(&db)?;
+    Ok(db)
+}
+
+/// Take the data directory and fork into the background when running as a
+/// daemon. Called before the runtime is built, only the forking thread
+/// survives a fork.
+pub fn prepare(options: &StartOptions) -> Result<DataDirLock, Box<dyn std::error::Error>> {
+    // One node per data directory, the lock is released when the process exits
+    let mut lock = options.datadir.lock()?;
+    // Holding the lock means the socket and pid file were left behind by a
+    // node that died
+    for path in [&options.server_opts.uds_path, &options.daemon_opts.pid] {
+        if utils::exists(path) {
+            std::fs::remove_file(path)?;
         }
-        let stdout = File::create(Path::new(&options.daemon_opts.log_file)).unwrap();
-        let stderr = File::create(Path::new(&options.daemon_opts.err_file)).unwrap();
+    }
+    if options.daemon_opts.daemon {
+        // Append so the output of the previous runs is kept
+        let stdout = OpenOptions::new().create(true).append(true).open(Path::new(&options.daemon_opts.log_file))?;
+        let stderr = OpenOptions::new().create(true).append(true).open(Path::new(&options.daemon_opts.err_file))?;
 
         let daemonize = Daemonize::new()
             .pid_file(options.daemon_opts.pid.clone()) // Every method except `new` and `start`
@@ -78,154 +106,480 @@ pub async fn start(options: &StartOptions) -> Result<(), Box<dyn std::error::Err
 
         match daemonize.start() {
             Ok(_) => {
+                lock.write_pid()?;
                 info!("Success, daemonized")
             },
             Err(e) => eprintln!("Error, {}", e),
         }
     }
-    // Create the runtime
-    let rt = tokio::runtime::Runtime::new()?;
+    Ok(lock)
+}
 
-    // Signals handlers
-    let mut signals = Signals::new(&[SIGINT])?;
-    thread::spawn(move || block_on(async {
-        for sig in signals.forever() {
-            // Exit program
-            if sig == SIGINT {
-                process::exit(1);
-            }
-            warn!("Received signal {:?}", sig);
-        }
-    }));
-
-    // Spawn the root task
-    rt.block_on(async {
-        // Create sender and receiver for message processing
-        let (sender, receiver) = mpsc::unbounded::<Message>();
-        // Create node state
-        let state = Arc::new(RwLock::new(NodeState::new()));
-        // Node lifecycle hooks
-        let lifecycle = NodeLifecycle::new(state.clone());
-        // Create db
-        let db_dir = match options.db_dir.clone() {
-            Some(db_dir) => db_dir,
-            None => "data".to_string(),
-        };
-        let db_path = Path::new(db_dir.as_str()).join("hanode.db");
-        let db = match sled::open(&db_path) {
-            Ok(db) => db,
-            Err(e) => {
-                panic!("Failed to open database: {}", e);
-            }
+/// Run the node until it is stopped, holding the lock taken by `prepare`. The
+/// node is started again after a database task it was stopped for.
+pub async fn start(options: &StartOptions, _lock: DataDirLock) -> Result<(), Box<dyn std::error::Error>> {
+    // Handle of the node running, stdin goes to it
+    let current: Arc<Mutex<Option<NodeHandle>>> = Arc::new(Mutex::new(None));
+    // Stop on Ctrl-C like `hanode stop` does, so the services are stopped too
+    let interrupted = Arc::new(AtomicBool::new(false));
+    tokio::spawn(interrupt(current.clone(), interrupted.clone()));
+    // If running in the background there is no input
+    if !options.daemon_opts.daemon {
+        tokio::spawn(input(current.clone()));
+    }
+    let db_tasks = Arc::new(Mutex::new(DbTasks::default()));
+    loop {
+        run_node(options, &current, &db_tasks).await?;
+        let pending = db_tasks.lock().unwrap().pending.take();
+        let (id, task) = match pending {
+            Some(pending) if !interrupted.load(Ordering::SeqCst) => pending,
+            _ => return Ok(()),
         };
-        // Create the node
-        let r = p2p::node::Node::new(Box::new(receiver), lifecycle, db, NodeBehaviourOptions{
-            port: options.p2p_port,
-            bootnode: options.bootnode.clone(),
-        }).await;
-        if r.is_err() {
-            error!("Failed to create node: {}", r.err().unwrap());
-            process::exit(1);
+        let done = db::run_task(&options.datadir.db_path(), id, task);
+        match &done.error {
+            Some(err) => error!("Database task {} failed: {}", id, err),
+            None => info!("Database task {} done, starting the node again", id),
+        }
+        db_tasks.lock().unwrap().done = Some(done);
+        // Left by the server that stopped
+        if utils::exists(&options.server_opts.uds_path) {
+            std::fs::remove_file(&options.server_opts.uds_path)?;
         }
-        let node = Arc::new(RwLock::new(r.ok().unwrap()));
+    }
+}
 
-        // Start node
-        async fn start_node (node: Arc<RwLock<p2p::node::Node>>) -> Result<(), Box<dyn std::error::Error>> {
-            // Start node
-            futures::join!(async {
-                match node.write().unwrap().start().await {
-                    Ok(_ok) => info!("Success"),
-                    Err(err) => error!("Error: {}", err)
-                };
-            });
-            Ok(())
-        }
-        // Input message
-        async fn input(sender: Arc<RwLock<Sender<Message>>>, options: &StartOptions) -> Result<(), Box<dyn std::error::Error>>  {
-            // If running in the background, return immediately
-            if options.daemon_opts.daemon {
-                return Ok(());
-            }
-            // Read full lines from stdin
-            let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
-            loop {
-                select! {
-                    line = stdin.select_next_some() => {
-                        (*sender.write().unwrap()).send(message::Message::from(line.expect("Stdin not to close").to_string()))
-                        .await?
-                    },
-                }
+async fn interrupt(current: Arc<Mutex<Option<NodeHandle>>>, interrupted: Arc<AtomicBool>) {
+    while tokio::signal::ctrl_c().await.is_ok() {
+        interrupted.store(true, Ordering::SeqCst);
+        let handle = current.lock().unwrap().clone();
+        if let Some(handle) = handle {
+            info!("Interrupted, stopping the node");
+            if let Err(err) = handle.stop().await {
+                error!("Failed to stop the node: {}", err);
             }
         }
-        // Start server
-        async fn start_server(proxy_sender: Arc<RwLock<Sender<Message>>>, state: Arc<RwLock<NodeState>>, options: &StartOptions) {
-            debug!("starting server...");
-            match server::core::start_server(proxy_sender, state, server::core::ServerOptions {
-                port: options.server_opts.port,
-                host: Some(options.server_opts.host.to_string()),
-                server: options.server_opts.server,
-                sock_file: options.server_opts.uds_path.clone(),
-            }).await {
-                Ok(_) => debug!("Start server success"),
-                Err(err) => { 
-                    error!("Start server on {}:{} failed: {}", options.server_opts.host, options.server_opts.port, err);
-                    process::exit(1);
+    }
+}
+
+// Read full lines from stdin until it is closed, no faster than the node
+// publishes them
+async fn input(current: Arc<Mutex<Option<NodeHandle>>>) {
+    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    loop {
+        match stdin.next_line().await {
+            Ok(Some(line)) => {
+                let handle = current.lock().unwrap().clone();
+                let sent = match handle {
+                    Some(handle) => handle.publish_wait(message::Message::from(line)).await.is_ok(),
+                    None => false,
+                };
+                if !sent {
+                    warn!("The node is not running, the line was dropped");
                 }
+            },
+            Ok(None) => return,
+            Err(err) => {
+                error!("Failed to read stdin: {}", err);
+                return;
             }
         }
-        let ps = Arc::new(RwLock::new(sender));
-        let _ = futures::join!(start_node(Arc::clone(&node)), input(Arc::clone(&ps), options), start_server(Arc::clone(&ps), Arc::clone(&state), options));
-    });
-    Ok(())
+    }
 }
 
-async fn call_url(opts: &ServerOptions, url_path: &str, output: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(async {
-        if opts.server {
-            // By http
-            let request_url = format!("http://{}:{}/{}", opts.host, opts.port, url_path);
-            let r = reqwest::get(&request_url).await;
-            match r {
-                Ok(r) => {
-                    let data = r.text().await.unwrap();
-                    if output {
-                        println!("{}", data);
-                    }
-                },
-                Err(err) => {
-                    error!("Error: {}", err);
-                }
+// One run of the node and its server, until the node stops
+async fn run_node(options: &StartOptions, current: &Mutex<Option<NodeHandle>>, db_tasks: &Arc<Mutex<DbTasks>>) -> Result<(), Box<dyn std::error::Error>> {
+    // Handle to query and command the node from the server and stdin
+    let (handle, queues) = NodeHandle::channel(options.queue.clone());
+    *current.lock().unwrap() = Some(handle.clone());
+    // Node lifecycle hooks
+    let lifecycle = NodeLifecycle::new();
+    // Create db
+    let mut db = match open_db(&options.datadir.db_path()) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to open the database: {}", e);
+            process::exit(1);
+        }
+    };
+    let encrypted = p2p::secrets::is_encrypted(&db).unwrap_or(true);
+    let mut secrets = match db::open_secrets(&db, &options.unlock) {
+        Ok(secrets) => secrets,
+        Err(e) => {
+            error!("Failed to unlock the database: {}", e);
+            process::exit(1);
+        }
+    };
+    // Encrypting the secrets leaves the plaintext key in the sled log, rewrite it away
+    if !encrypted && secrets.is_encrypted() {
+        drop((secrets, db));
+        if let Err(e) = db::rewrite(&options.datadir.db_path()) {
+            error!("Failed to rewrite the database after encrypting it: {}", e);
+            process::exit(1);
+        }
+        db = match open_db(&options.datadir.db_path()) {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Failed to open the database: {}", e);
+                process::exit(1);
             }
-        } else {
-            // By unix domain sockets
-            let res = uds_client::get(&uds_client::UdsClientOptions{
-               uds_sock_path: opts.uds_path.clone(),
-               url_path: url_path.to_string(),
-            }).await.unwrap();
-            if output {
-                println!("{}", res.body);
+        };
+        secrets = match db::open_secrets(&db, &options.unlock) {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                error!("Failed to unlock the database: {}", e);
+                process::exit(1);
             }
+        };
+        info!("Database secrets are now encrypted");
+    }
+    // Create the node
+    let r = p2p::node::Node::new(queues, lifecycle, secrets, NodeBehaviourOptions{
+        port: options.p2p_port,
+        bootnode: options.bootnode.clone(),
+        key_type: options.key_type,
+        files_dir: options.datadir.files_dir(),
+        max_file_size: options.max_file_size,
+        artifacts_dir: options.datadir.artifacts_dir(),
+        raft_voters: options.raft_voters.clone(),
+        labels: options.labels.clone(),
+        services: options.services.clone(),
+        services_dir: options.datadir.services_dir(),
+        container_runtime: options.container_runtime.clone(),
+        containers_dir: options.datadir.containers_dir(),
+        health: options.health.clone(),
+        operators: options.operators.clone(),
+    }).await;
+    if r.is_err() {
+        error!("Failed to create node: {}", r.err().unwrap());
+        process::exit(1);
+    }
+    let mut node = r.ok().unwrap();
+
+    // Start server
+    debug!("starting server...");
+    let server = match server::core::start_server(handle, db.clone(), server::core::ServerOptions {
+        port: options.server_opts.port,
+        host: Some(options.server_opts.host.to_string()),
+        server: options.server_opts.server,
+        sock_file: options.server_opts.uds_path.clone(),
+        log_file: options.log_file.clone(),
+        artifacts_dir: options.datadir.artifacts_dir(),
+        db_tasks: db_tasks.clone(),
+    }) {
+        Ok(server) => server,
+        Err(err) => {
+            error!("Start server on {}:{} failed: {}", options.server_opts.host, options.server_opts.port, err);
+            process::exit(1);
         }
-    });
+    };
+    let server_handle = server.handle();
+    let server = tokio::spawn(server);
+    // Start node, the swarm task owns it and serves the handles until stopped
+    match node.start().await {
+        Ok(_ok) => info!("Success"),
+        Err(err) => error!("Error: {}", err)
+    };
+    // Let the server answer the requests in flight, the stop request among them
+    server_handle.stop(true).await;
+    // The database closes once the server and the node let go of it
+    let _ = server.await;
+    *current.lock().unwrap() = None;
     Ok(())
 }
 
-pub async fn stop(opts: ServerOptions) -> Result<(), Box<dyn std::error::Error>> {
-    call_url(&opts, "/stop", true).await
+async fn call_url(opts: &ServerOptions, url_path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    call(opts, url_path, None).await
+}
+
+/// Call the control API of the running node, a GET request unless a body is
+/// given in which case it is POSTed
+pub async fn call(opts: &ServerOptions, url_path: &str, payload: Option<Vec<u8>>) -> Result<String, Box<dyn std::error::Error>> {
+    let (status, body) = if opts.server {
+        // By http
+        let request_url = format!("http://{}:{}{}", opts.host, opts.port, url_path);
+        let r = match payload {
+            Some(payload) => reqwest::Client::new().post(&request_url).body(payload).send().await,
+            None => reqwest::get(&request_url).await,
+        }.map_err(|e| ClientError::from_http_error(&request_url, &e))?;
+        let status = r.status().as_u16();
+        let body = r.text().await
+            .map_err(|e| ClientError::from_http_error(&request_url, &e))?;
+        (status, body)
+    } else {
+        // By unix domain sockets
+        if !utils::exists(&opts.uds_path) {
+            return Err(ClientError::NodeNotRunning(format!("socket {} does not exist", opts.uds_path)).into());
+        }
+        let uds_opts = uds_client::UdsClientOptions{
+           uds_sock_path: opts.uds_path.clone(),
+           url_path: url_path.to_string(),
+        };
+        let res = match payload {
+            Some(payload) => uds_client::post(&uds_opts, &payload).await,
+            None => uds_client::get(&uds_opts).await,
+        }.map_err(|e| match e.downcast_ref::<std::io::Error>() {
+            Some(e) => ClientError::from_uds_error(&opts.uds_path, e),
+            None => ClientError::InvalidResponse(e.to_string()),
+        })?;
+        (res.status, res.body)
+    };
+    if let Some(err) = ClientError::from_status(status, &body) {
+        return Err(err.into());
+    }
+    Ok(body)
+}
+
+pub fn parse_body<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, Box<dyn std::error::Error>> {
+    serde_json::from_str(body).map_err(|e| ClientError::InvalidResponse(e.to_string()).into())
+}
+
+pub async fn stop(opts: ServerOptions) -> Result<StopResult, Box<dyn std::error::Error>> {
+    let body = call_url(&opts, "/stop").await?;
+    let result: StopResult = parse_body(&body)?;
+    if !result.stopped {
+        return Err(ClientError::ServerError("the node failed to stop".to_string()).into());
+    }
+    Ok(result)
+}
+
+/// Peers an operation is for, from `-l <SELECTOR>` and `-g <GROUP>`
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub selector: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Target {
+    pub fn is_empty(&self) -> bool {
+        self.selector.is_none() && self.group.is_none()
+    }
+
+    // Query string of the request, selectors and group names need no escaping
+    pub fn query(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut params = Vec::new();
+        if let Some(selector) = &self.selector {
+            selector.parse::<Selector>().map_err(ClientError::BadRequest)?;
+            params.push(format!("selector={}", selector));
+        }
+        if let Some(group) = &self.group {
+            if !p2p::labels::valid_label_value(group) {
+                return Err(ClientError::BadRequest(format!("invalid group name {}", group)).into());
+            }
+            params.push(format!("group={}", group));
+        }
+        Ok(if params.is_empty() { String::new() } else { format!("?{}", params.join("&")) })
+    }
 }
 
 pub struct BoardcastOptions {
     pub server_opts: ServerOptions,
     pub msg: String,
+    pub target: Target,
 }
 
-pub async fn boardcast(opts: BoardcastOptions) -> Result<(), Box<dyn std::error::Error>> {
-    let request_url = format!("/boardcast/{}", opts.msg.as_str());
+pub async fn boardcast(opts: BoardcastOptions) -> Result<BoardcastResult, Box<dyn std::error::Error>> {
+    let request_url = format!("/boardcast/{}{}", opts.msg.as_str(), opts.target.query()?);
     debug!("Send boardcast command to the node: {}", request_url);
-    call_url(&opts.server_opts, request_url.as_str(), false).await
+    let body = call_url(&opts.server_opts, request_url.as_str()).await?;
+    let result: BoardcastResult = parse_body(&body)?;
+    if result.dropped {
+        return Err(ClientError::ServerError("the message queue of the node is full, the message was dropped".to_string()).into());
+    }
+    if !result.sent {
+        return Err(ClientError::ServerError("the node failed to queue the message".to_string()).into());
+    }
+    Ok(result)
+}
+
+pub async fn list_peers(opts: &ServerOptions, target: &Target) -> Result<Vec<PeerView>, Box<dyn std::error::Error>> {
+    let body = call_url(opts, &format!("/peers{}", target.query()?)).await?;
+    let peers: HashMap<String, Peer> = parse_body(&body)?;
+    let mut peers: Vec<PeerView> = peers.into_values().map(PeerView::from).collect();
+    peers.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(peers)
+}
+
+/// Metrics of the running node in the Prometheus text format
+pub async fn metrics(opts: ServerOptions) -> Result<String, Box<dyn std::error::Error>> {
+    call_url(&opts, "/metrics").await
+}
+
+pub async fn info(opts: ServerOptions) -> Result<NodeInfo, Box<dyn std::error::Error>> {
+    let body = call_url(&opts, "/info").await?;
+    parse_body(&body)
+}
+
+pub async fn dial(opts: ServerOptions, addr: &str) -> Result<DialResult, Box<dyn std::error::Error>> {
+    let payload = serde_json::to_vec(&serde_json::json!({ "addr": addr }))?;
+    let body = call(&opts, "/peers/dial", Some(payload)).await?;
+    parse_body(&body)
+}
+
+pub async fn disconnect(opts: ServerOptions, peer_id: &str) -> Result<DisconnectResult, Box<dyn std::error::Error>> {
+    let body = call(&opts, &format!("/peers/{}/disconnect", peer_id), Some(Vec::new())).await?;
+    parse_body(&body)
+}
+
+pub struct CopyOptions {
+    pub server_opts: ServerOptions,
+    pub source: String,
+    pub dest: String,
+    pub rate_limit: Option<u64>,
+    // Return once the transfer started instead of waiting for it
+    pub detach: bool,
+    // Push to every connected peer it selects, DEST is then `:<PATH>`
+    pub target: Target,
 }
 
-pub async fn list_peers(opts: ServerOptions) -> Result<(), Box<dyn std::error::Error>> {
-    call_url(&opts, "/peers", true).await
+// `<peer id>:<path>` on a peer, anything else is a local path
+fn remote_path(s: &str) -> Option<(String, String)> {
+    let (peer, path) = s.split_once(':')?;
+    match peer.parse::<libp2p::PeerId>() {
+        Ok(_) => Some((peer.to_string(), path.to_string())),
+        Err(_) => None,
+    }
+}
+
+fn transfer_spec(opts: &CopyOptions) -> Result<TransferSpec, Box<dyn std::error::Error>> {
+    let (direction, local, (peer, remote)) = match (remote_path(&opts.source), remote_path(&opts.dest)) {
+        (None, Some(remote)) => (Direction::Push, &opts.source, remote),
+        (Some(remote), None) => (Direction::Pull, &opts.dest, remote),
+        _ => return Err(ClientError::BadRequest("exactly one of the paths must be on a peer, as in <PEER_ID>:<PATH>".to_string()).into()),
+    };
+    Ok(TransferSpec {
+        peer,
+        direction,
+        local: utils::absolute_path(local)?,
+        remote,
+        rate_limit: opts.rate_limit,
+    })
+}
+
+/// Copy a file between this machine and a peer through the running node,
+/// printing the progress to stderr until the copy ends
+pub async fn copy(opts: CopyOptions) -> Result<TransferStatus, Box<dyn std::error::Error>> {
+    let payload = serde_json::to_vec(&transfer_spec(&opts)?)?;
+    let body = call(&opts.server_opts, "/transfers", Some(payload)).await?;
+    let mut status: TransferStatus = parse_body(&body)?;
+    if opts.detach {
+        return Ok(status);
+    }
+    loop {
+        match status.state {
+            TransferState::Done => {
+                eprintln!();
+                return Ok(status);
+            },
+            TransferState::Failed => {
+                eprintln!();
+                let error = status.error.unwrap_or_default();
+                return Err(ClientError::ServerError(format!("transfer {} failed: {}", status.id, error)).into());
+            },
+            TransferState::Running | TransferState::Retrying => {
+                eprint!("\r\x1b[K{} {}", output::format_progress(&status), status.error.as_deref().unwrap_or(""));
+            },
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let body = call_url(&opts.server_opts, &format!("/transfers/{}", status.id)).await?;
+        status = parse_body(&body)?;
+    }
+}
+
+/// Push a file to the connected peers selected by the target, one transfer
+/// per peer
+pub async fn copy_to_peers(opts: CopyOptions) -> Result<Vec<TransferStatus>, Box<dyn std::error::Error>> {
+    let remote = match opts.dest.strip_prefix(':') {
+        Some(remote) if remote_path(&opts.source).is_none() => remote.to_string(),
+        _ => return Err(ClientError::BadRequest("copying to selected peers takes a local SOURCE and a DEST as in :<PATH>".to_string()).into()),
+    };
+    let local = utils::absolute_path(&opts.source)?;
+    let peers: Vec<PeerView> = list_peers(&opts.server_opts, &opts.target).await?
+        .into_iter()
+        .filter(|p| p.status == "connected")
+        .collect();
+    if peers.is_empty() {
+        return Err(ClientError::BadRequest("no connected peer matches the selector".to_string()).into());
+    }
+    let mut statuses = Vec::new();
+    for peer in peers {
+        let spec = TransferSpec {
+            peer: peer.id,
+            direction: Direction::Push,
+            local: local.clone(),
+            remote: remote.clone(),
+            rate_limit: opts.rate_limit,
+        };
+        let body = call(&opts.server_opts, "/transfers", Some(serde_json::to_vec(&spec)?)).await?;
+        statuses.push(parse_body::<TransferStatus>(&body)?);
+    }
+    if opts.detach {
+        return Ok(statuses);
+    }
+    loop {
+        let running = statuses.iter().filter(|s| matches!(s.state, TransferState::Running | TransferState::Retrying)).count();
+        if running == 0 {
+            eprintln!();
+            return Ok(statuses);
+        }
+        eprint!("\r\x1b[K{} of {} copies running", running, statuses.len());
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        for status in statuses.iter_mut() {
+            let body = call_url(&opts.server_opts, &format!("/transfers/{}", status.id)).await?;
+            *status = parse_body(&body)?;
+        }
+    }
+}
+
+/// Fails when one of the copies failed, once they were printed
+pub fn check_transfers(statuses: &[TransferStatus]) -> Result<(), Box<dyn std::error::Error>> {
+    let failed: Vec<String> = statuses.iter()
+        .filter(|s| matches!(s.state, TransferState::Failed))
+        .map(|s| s.spec.peer.clone())
+        .collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(ClientError::ServerError(format!("copies to {} of {} peers failed: {}", failed.len(), statuses.len(), failed.join(","))).into())
+}
+
+pub async fn list_transfers(opts: ServerOptions) -> Result<Vec<TransferStatus>, Box<dyn std::error::Error>> {
+    let body = call_url(&opts, "/transfers").await?;
+    parse_body(&body)
+}
+
+pub struct LogsOptions {
+    pub server_opts: ServerOptions,
+    pub follow: bool,
+    pub level: Option<log::Level>,
+    pub lines: usize,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LogsResponse {
+    lines: Vec<String>,
+    offset: u64,
+}
+
+/// Print the log of the running node, polling for new lines when following
+pub async fn logs(opts: LogsOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let mut url_path = format!("/logs?lines={}", opts.lines);
+    loop {
+        let body = call_url(&opts.server_opts, &url_path).await?;
+        let res: LogsResponse = parse_body(&body)?;
+        for line in res.lines {
+            // Lines that are not records, like panics, are always shown
+            let shown = match (opts.level, logging::line_level(&line)) {
+                (Some(level), Some(line_level)) => line_level <= level,
+                _ => true,
+            };
+            if shown {
+                println!("{}", line);
+            }
+        }
+        if !opts.follow {
+            return Ok(());
+        }
+        url_path = format!("/logs?offset={}", res.offset);
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
 }
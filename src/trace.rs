@@ -0,0 +1,191 @@
+use std::{error::Error, io::Write, sync::{mpsc, Mutex}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+use serde_json::{json, Value};
+
+use crate::logging::{LogConfig, RotatingFile};
+
+// Spans sent to a collector in one request
+const BATCH_SIZE: usize = 512;
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Span as tracked by the logging layer until it is closed
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: String,
+    pub target: String,
+    pub start: SystemTime,
+    pub attributes: Vec<(String, String)>,
+}
+
+fn unix_nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+impl SpanData {
+    // A span of the OTLP/JSON encoding, ids are hex as the encoding requires
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let mut attributes: Vec<Value> = self.attributes.iter().map(|(k, v)| attribute(k, v)).collect();
+        attributes.push(attribute("code.namespace", &self.target));
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "name": self.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes,
+        })
+    }
+}
+
+/// Body of an OTLP `ExportTraceServiceRequest`
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", "hanode"),
+                    attribute("service.version", env!("CARGO_PKG_VERSION")),
+                    attribute("process.pid", &std::process::id().to_string()),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "hanode" },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// Where finished spans go
+pub enum Exporter {
+    // One export request per line, the format the OpenTelemetry collector
+    // file exporter writes and its otlpjsonfile receiver reads
+    File(Mutex<RotatingFile>),
+    // OTLP/HTTP with JSON encoding, sent in batches from a thread
+    Collector(Mutex<Collector>),
+}
+
+pub struct Collector {
+    endpoint: Endpoint,
+    sender: Option<mpsc::Sender<Value>>,
+}
+
+impl Collector {
+    // The thread starts with the first span, after a daemon forked as only
+    // the forking thread survives
+    fn send(&mut self, span: Value) {
+        if self.sender.is_none() {
+            let (sender, receiver) = mpsc::channel();
+            let endpoint = self.endpoint.clone();
+            let spawned = thread::Builder::new()
+                .name("trace-exporter".to_string())
+                .spawn(move || send_batches(endpoint, receiver));
+            match spawned {
+                Ok(_) => self.sender = Some(sender),
+                Err(e) => {
+                    eprintln!("Failed to start the trace exporter: {}", e);
+                    return;
+                },
+            }
+        }
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(span);
+        }
+    }
+}
+
+impl Exporter {
+    /// `file` for `traces_file`, else the http or https URL of a collector
+    /// such as `http://127.0.0.1:4318`
+    pub fn new(export: &str, traces_file: &str, config: &LogConfig) -> Result<Exporter, Box<dyn Error>> {
+        if export == "file" {
+            return Ok(Exporter::File(Mutex::new(RotatingFile::open(traces_file, config)?)));
+        }
+        let endpoint = Endpoint::parse(export)?;
+        Ok(Exporter::Collector(Mutex::new(Collector { endpoint, sender: None })))
+    }
+
+    pub fn export(&self, span: &SpanData, end: SystemTime) {
+        let span = span.to_otlp(end);
+        match self {
+            Exporter::File(file) => {
+                if let Ok(mut file) = file.lock() {
+                    let _ = writeln!(file, "{}", export_request(vec![span]));
+                }
+            },
+            Exporter::Collector(collector) => {
+                if let Ok(mut collector) = collector.lock() {
+                    collector.send(span);
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Endpoint {
+    url: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Endpoint, Box<dyn Error>> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("Unsupported trace export {}, use `file` or an http(s):// collector URL", url).into());
+        }
+        let url = url.trim_end_matches('/');
+        let url = if url.ends_with("/v1/traces") { url.to_string() } else { format!("{}/v1/traces", url) };
+        reqwest::Url::parse(&url).map_err(|e| format!("Invalid trace export {}: {}", url, e))?;
+        Ok(Endpoint { url })
+    }
+
+    fn post(&self, client: &reqwest::blocking::Client, body: String) -> Result<(), Box<dyn Error>> {
+        let response = client.post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("collector answered {}", status).into()),
+        }
+    }
+}
+
+fn send_batches(endpoint: Endpoint, receiver: mpsc::Receiver<Value>) {
+    let client = match reqwest::blocking::Client::builder().timeout(Duration::from_secs(5)).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to start the trace exporter: {}", e);
+            return;
+        },
+    };
+    let mut batch = Vec::new();
+    loop {
+        match receiver.recv_timeout(BATCH_INTERVAL) {
+            Ok(span) => {
+                batch.push(span);
+                if batch.len() < BATCH_SIZE {
+                    continue;
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let body = export_request(std::mem::take(&mut batch)).to_string();
+        // Not logged, the record would be a span event feeding the exporter
+        if let Err(e) = endpoint.post(&client, body) {
+            eprintln!("Failed to export traces to {}: {}", endpoint.url, e);
+        }
+    }
+}
@@ -1,12 +1,60 @@
-use std::{path::Path, fs};
+use std::{env, io, path::{Component, Path, PathBuf}, time::Duration};
 
 pub fn exists(s: &String) -> bool {
-    return Path::new(s).exists()
+    Path::new(s).exists()
 }
 
-pub fn read_pid(s: &String) -> Option<i32> {
-    fs::read_to_string(Path::new(s))
-        .expect(format!("Should have been able to read the file: {}", s).as_str())
-        .parse::<i32>()
-        .ok()
+/// Bytes per second from `1048576`, `512K`, `10M` or `1G`
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    parse_bytes(s, "rate")
+}
+
+/// Bytes from `1048576`, `512K`, `10M` or `1G`
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    parse_bytes(s, "size")
+}
+
+fn parse_bytes(s: &str, what: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, ' '),
+    };
+    let multiplier: u64 = match unit {
+        ' ' => 1,
+        'K' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => return Err(format!("Unknown unit {} in {}, use K, M or G", unit, s)),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * multiplier),
+        _ => Err(format!("Invalid {} {}, expected a positive number such as 512K or 10M", what, s)),
+    }
+}
+
+/// Duration from `90`, `90s`, `15m`, `2h` or `1d`, seconds without a unit
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_lowercase()),
+        _ => (s, 's'),
+    };
+    let multiplier: u64 = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return Err(format!("Unknown unit {} in {}, use s, m, h or d", unit, s)),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(Duration::from_secs(n * multiplier)),
+        _ => Err(format!("Invalid duration {}, expected a positive number such as 30s or 5m", s)),
+    }
+}
+
+/// Paths sent to the node, which resolves them from its own working directory
+pub fn absolute_path(path: &str) -> io::Result<String> {
+    let path: PathBuf = env::current_dir()?.join(path).components()
+        .filter(|c| c != &Component::CurDir)
+        .collect();
+    Ok(path.to_string_lossy().to_string())
 }
@@ -0,0 +1,55 @@
+use std::{collections::HashSet, fs};
+use p2p::artifact::{ArtifactOp, ArtifactResult, ArtifactStore, ARTIFACT_CHUNK_SIZE};
+
+#[test]
+fn test_artifact_moves_between_stores() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let file = dir.join("release.bin");
+    let content: Vec<u8> = (0..ARTIFACT_CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+    fs::write(&file, &content).unwrap();
+    let source = ArtifactStore::new(dir.join("a").to_str().unwrap());
+    let manifest = source.add(&file, "release.bin").unwrap();
+    assert!(manifest.verify());
+    assert_eq!(manifest.chunks.len(), 3);
+    // Adding the same content again gives the same id
+    assert_eq!(source.add(&file, "other.bin").unwrap().id, manifest.id);
+
+    let target = ArtifactStore::new(dir.join("b").to_str().unwrap());
+    let mut forged = manifest.clone();
+    forged.size += 1;
+    assert!(target.save_manifest(&forged).is_err());
+    target.save_manifest(&manifest).unwrap();
+    assert_eq!(target.missing(&manifest), vec![0, 1, 2]);
+    assert!(target.write_chunk(&manifest, 0, b"not the chunk").is_err());
+    for index in 0..3 {
+        let res = source.serve(ArtifactOp::Chunk { id: manifest.id.clone(), index });
+        assert!(matches!(res.result, ArtifactResult::Chunk));
+        target.write_chunk(&manifest, index, &res.data).unwrap();
+    }
+    assert!(target.missing(&manifest).is_empty());
+    // Exported only once a fetch marked it complete
+    assert!(target.export(&manifest.id, &dir.join("out.bin")).is_err());
+    source.export(&manifest.id, &dir.join("out.bin")).unwrap();
+    assert_eq!(fs::read(dir.join("out.bin")).unwrap(), content);
+}
+
+#[test]
+fn test_gc_keeps_pinned_artifacts() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let file = dir.join("config.toml");
+    fs::write(&file, b"replicas = 3\n").unwrap();
+    let source = ArtifactStore::new(dir.join("a").to_str().unwrap());
+    let manifest = source.add(&file, "config.toml").unwrap();
+    let target = ArtifactStore::new(dir.join("b").to_str().unwrap());
+    target.save_manifest(&manifest).unwrap();
+
+    assert!(source.gc(&HashSet::new()).unwrap().removed.is_empty());
+    let keep: HashSet<String> = [manifest.id.clone()].into_iter().collect();
+    assert!(target.gc(&keep).unwrap().removed.is_empty());
+    assert_eq!(target.gc(&HashSet::new()).unwrap().removed, vec![manifest.id.clone()]);
+    assert!(target.list().is_empty());
+    assert!(matches!(target.serve(ArtifactOp::Manifest { id: manifest.id }).result, ArtifactResult::Missing));
+    assert!(matches!(target.serve(ArtifactOp::Manifest { id: "../a".to_string() }).result, ArtifactResult::Missing));
+}
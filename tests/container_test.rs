@@ -0,0 +1,115 @@
+use std::{fs, os::unix::fs::PermissionsExt, path::Path, time::Duration};
+use p2p::container::{self, CliRuntime, ContainerRequest, ContainerResponse, ContainerRuntime, ContainerSpec, ContainerState, FakeRuntime};
+
+// Keeps the state of each container in a file, as runc does under --root
+const FAKE_RUNC: &str = r#"#!/bin/sh
+root=$2; cmd=$3; shift 3
+mkdir -p "$root"
+case $cmd in
+run)
+    echo "started $4"
+    printf '{"id":"%s","pid":4242,"status":"running","bundle":"%s","created":"2022-10-10T10:10:10.5Z"}' "$4" "$3" > "$root/$4" ;;
+list)
+    if ls "$root"/* >/dev/null 2>&1; then
+        sep='['; for f in "$root"/*; do printf '%s' "$sep"; cat "$f"; sep=','; done; printf ']'
+    else
+        echo null
+    fi ;;
+kill)
+    sed -i 's/"running"/"stopped"/' "$root/$1" ;;
+delete)
+    if [ "$1" = --force ]; then shift; elif grep -q running "$root/$1"; then echo "cannot delete running container $1" >&2; exit 1; fi
+    rm "$root/$1" ;;
+esac
+"#;
+
+fn spec(name: &str, rootfs: &str) -> ContainerSpec {
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "rootfs": rootfs,
+        "command": ["sh", "-c", "echo $GREETING"],
+        "env": { "GREETING": "hello" },
+        "limits": { "memory": 67108864, "cpu": 0.5 },
+    })).unwrap()
+}
+
+#[tokio::test]
+async fn test_fake_runtime() {
+    let runtime = FakeRuntime::new();
+    let info = runtime.run(&spec("web", "/srv/web")).await.unwrap();
+    assert_eq!(info.state, ContainerState::Running);
+    assert!(runtime.run(&spec("web", "/srv/web")).await.is_err());
+    assert!(runtime.run(&spec("../web", "/srv/web")).await.is_err());
+    assert!(runtime.run(&spec("web2", "srv/web")).await.is_err());
+    runtime.write("web", "hello");
+    match container::serve(&runtime, ContainerRequest::Logs { name: "web".to_string(), lines: 10 }).await {
+        ContainerResponse::Logs { lines } => assert_eq!(lines, vec!["hello"]),
+        res => panic!("{:?}", res),
+    }
+    match container::serve(&runtime, ContainerRequest::Remove { name: "web".to_string(), force: false }).await {
+        ContainerResponse::Error { message } => assert!(message.contains("running"), "{}", message),
+        res => panic!("{:?}", res),
+    }
+    let info = runtime.stop("web", Duration::from_secs(1)).await.unwrap();
+    assert_eq!(info.state, ContainerState::Stopped);
+    assert_eq!(info.pid, None);
+    runtime.remove("web", false).await.unwrap();
+    match container::serve(&runtime, ContainerRequest::Stop { name: "web".to_string() }).await {
+        ContainerResponse::Error { message } => assert_eq!(message, "No container web"),
+        res => panic!("{:?}", res),
+    }
+    // Only operators may send these
+    assert!(ContainerRequest::Remove { name: "web".to_string(), force: true }.changes());
+    assert!(!ContainerRequest::Logs { name: "web".to_string(), lines: 10 }.changes());
+}
+
+#[test]
+fn test_oci_config() {
+    let config = container::oci_config(&spec("web", "/srv/web"));
+    assert_eq!(config["root"]["path"], "/srv/web");
+    assert_eq!(config["process"]["args"][2], "echo $GREETING");
+    assert!(config["process"]["env"].as_array().unwrap().contains(&"GREETING=hello".into()));
+    assert_eq!(config["linux"]["resources"]["memory"]["limit"], 67108864);
+    assert_eq!(config["linux"]["resources"]["cpu"]["quota"], 50000);
+    assert!(config["linux"]["resources"]["pids"].is_null());
+    // Not root, and not on the network of the node unless asked
+    assert_eq!((&config["process"]["user"]["uid"], &config["process"]["user"]["gid"]), (&65534.into(), &65534.into()));
+    assert!(config["linux"]["namespaces"].as_array().unwrap().contains(&serde_json::json!({ "type": "network" })));
+    let host = ContainerSpec { host_network: true, uid: 0, gid: 0, ..spec("web", "/srv/web") };
+    let config = container::oci_config(&host);
+    assert_eq!(config["process"]["user"]["uid"], 0);
+    assert!(!config["linux"]["namespaces"].as_array().unwrap().contains(&serde_json::json!({ "type": "network" })));
+}
+
+#[tokio::test]
+async fn test_cli_runtime() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_string_lossy().to_string();
+    let binary = Path::new(&dir).join("fake-runc");
+    fs::write(&binary, FAKE_RUNC).unwrap();
+    fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+    let runtime = CliRuntime::new(&binary.to_string_lossy(), &format!("{}/containers", dir));
+    assert!(runtime.list().await.unwrap().is_empty());
+    // Only from a rootfs under containers/rootfs, symbolic links resolved
+    assert!(runtime.run(&spec("web", &dir)).await.unwrap_err().contains("must be under"));
+    let rootfs = fs::canonicalize(&dir).unwrap().join("containers/rootfs/web").to_string_lossy().to_string();
+    fs::create_dir_all(&rootfs).unwrap();
+    std::os::unix::fs::symlink(&dir, Path::new(&dir).join("containers/rootfs/host")).unwrap();
+    assert!(runtime.run(&spec("web", &format!("{}/containers/rootfs/host", dir))).await.unwrap_err().contains("must be under"));
+    assert!(runtime.run(&spec("rootfs", &rootfs)).await.is_err());
+    let info = runtime.run(&spec("web", &rootfs)).await.unwrap();
+    assert_eq!((info.state, info.pid), (ContainerState::Running, Some(4242)));
+    // Read back from the bundle written for the runtime
+    assert_eq!(info.rootfs, rootfs);
+    assert!(runtime.run(&spec("web", &rootfs)).await.is_err());
+    assert_eq!(runtime.logs("web", 10).await.unwrap(), vec!["started web"]);
+    let err = runtime.remove("web", false).await.unwrap_err();
+    assert!(err.contains("cannot delete running container web"), "{}", err);
+    let info = runtime.stop("web", Duration::from_secs(1)).await.unwrap();
+    assert_eq!((info.state, info.pid), (ContainerState::Stopped, None));
+    runtime.remove("web", false).await.unwrap();
+    assert!(!Path::new(&dir).join("containers/web").exists());
+    assert!(runtime.list().await.unwrap().is_empty());
+    let missing = CliRuntime::new("hanode-missing-runtime", &format!("{}/containers", dir));
+    assert!(missing.run(&spec("web", &rootfs)).await.unwrap_err().contains("is not installed"));
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use p2p::cron::{self, Job, Run, Runner, Schedule, Target};
+use p2p::kv::{Record, Version};
+use p2p::operators::Operators;
+
+// Monday 2022-10-10 00:00 UTC
+const MONDAY: u64 = 1665360000;
+const HOUR: u64 = 3600;
+const DAY: u64 = 86400;
+
+fn job(command: &str, timeout: u64) -> Job {
+    Job {
+        name: "backup".to_string(),
+        schedule: "*/5 * * * *".to_string(),
+        command: vec!["sh".to_string(), "-c".to_string(), command.to_string()],
+        env: BTreeMap::from([("TARGET".to_string(), "/srv/backup".to_string())]),
+        target: Target::All,
+        selector: String::new(),
+        timeout,
+    }
+}
+
+#[test]
+fn test_schedule() {
+    let every_5: Schedule = "*/5 * * * *".parse().unwrap();
+    assert!(every_5.matches(MONDAY + 10 * 60 + 30));
+    assert!(!every_5.matches(MONDAY + 11 * 60));
+    assert_eq!(every_5.next_after(MONDAY), Some(MONDAY + 5 * 60));
+
+    let weekdays: Schedule = "30 2 * * mon-fri".parse().unwrap();
+    assert!(weekdays.matches(MONDAY + 2 * HOUR + 30 * 60));
+    // Saturday and Sunday skipped
+    assert_eq!(weekdays.next_after(MONDAY + 4 * DAY + 3 * HOUR), Some(MONDAY + 7 * DAY + 2 * HOUR + 30 * 60));
+    // Either the day of the month or of the week when both are given
+    let either: Schedule = "0 0 13 * 5".parse().unwrap();
+    assert_eq!(either.next_after(MONDAY), Some(MONDAY + 3 * DAY));
+    assert_eq!(either.next_after(MONDAY + 3 * DAY), Some(MONDAY + 4 * DAY));
+    let sunday: Schedule = "0 0 * * 7".parse().unwrap();
+    assert_eq!(sunday.next_after(MONDAY), Some(MONDAY + 6 * DAY));
+    // 2023-01-01 and 2024-02-29
+    let yearly: Schedule = "@yearly".parse().unwrap();
+    assert_eq!(yearly.next_after(MONDAY), Some(1672531200));
+    let leap: Schedule = "0 12 29 feb *".parse().unwrap();
+    assert_eq!(leap.next_after(MONDAY), Some(1709208000));
+    let never: Schedule = "0 0 30 2 *".parse().unwrap();
+    assert_eq!(never.next_after(MONDAY), None);
+
+    for invalid in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "* * * foo *"] {
+        assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+    }
+    assert!(Job { schedule: "@often".to_string(), ..job("true", 10) }.check().is_err());
+    assert!(Job { target: Target::Node { node: String::new() }, ..job("true", 10) }.check().is_err());
+}
+
+#[tokio::test]
+async fn test_run() {
+    let run = cron::run(&job("echo saving to $TARGET; echo disk full >&2; exit 3", 10), "a", MONDAY).await;
+    assert_eq!((run.exit_code, run.error.as_deref()), (Some(3), None));
+    assert_eq!(run.output, "saving to /srv/backup\ndisk full\n");
+    assert!(!run.succeeded());
+
+    // Only the end of the output is kept
+    let run = cron::run(&job("head -c 10000 /dev/zero | tr '\\0' x; echo; echo done", 10), "a", MONDAY).await;
+    assert!(run.succeeded());
+    assert_eq!(run.output.len(), cron::MAX_OUTPUT);
+    assert!(run.output.ends_with("x\ndone\n"));
+
+    let run = cron::run(&job("sleep 5", 1), "a", MONDAY).await;
+    assert_eq!((run.exit_code, run.error.as_deref()), (None, Some("Timed out after 1s")));
+    let run = cron::run(&Job { command: vec!["hanode-missing-command".to_string()], ..job("", 1) }, "a", MONDAY).await;
+    assert!(run.error.unwrap().starts_with("Failed to run hanode-missing-command"));
+
+    // A run still going on skips the next one
+    let (done, mut runs) = tokio::sync::mpsc::channel(4);
+    let runner = Runner::new("a");
+    runner.spawn(job("sleep 1", 10), MONDAY, done.clone());
+    runner.spawn(job("true", 10), MONDAY + 60, done.clone());
+    let run = runs.recv().await.unwrap();
+    assert_eq!((run.scheduled, run.node.as_str()), (MONDAY, "a"));
+    runner.spawn(job("true", 10), MONDAY + 120, done);
+    assert_eq!(runs.recv().await.unwrap().scheduled, MONDAY + 120);
+}
+
+#[test]
+fn test_history() {
+    let run = |node: &str, started: u64| Run {
+        job: "backup".to_string(),
+        node: node.to_string(),
+        scheduled: started,
+        started,
+        finished: started + 1,
+        exit_code: Some(0),
+        error: None,
+        output: String::new(),
+    };
+    let record = |key: String, value: String| {
+        // Runs are written by their node, the job by a
+        let node = key.split('/').nth(3).unwrap_or("a").to_string();
+        Record { key, value: Some(value), version: Version { time: 1, node }, seq: 0, public_key: String::new(), signature: String::new() }
+    };
+    let mut records = vec![record(cron::job_key("backup"), serde_json::to_string(&job("true", 10)).unwrap())];
+    for slot in 0..cron::HISTORY_SIZE {
+        // Slot 3 holds the oldest run
+        let started = if slot == 3 { 100 } else { 1000 + slot as u64 };
+        records.push(record(format!("{}{}", cron::runs_prefix("backup", "a"), slot), serde_json::to_string(&run("a", started)).unwrap()));
+    }
+    records.push(record(format!("{}0", cron::runs_prefix("backup", "b")), serde_json::to_string(&run("b", 5000)).unwrap()));
+    // Forged by a for b, and a job named otherwise than its key
+    records.push(Record { version: Version { time: 1, node: "a".to_string() }, ..record(format!("{}1", cron::runs_prefix("backup", "b")), serde_json::to_string(&run("b", 6000)).unwrap()) });
+    records.push(record(cron::job_key("other"), serde_json::to_string(&job("true", 10)).unwrap()));
+    let (jobs, runs) = cron::parse_records(&records);
+    assert_eq!(jobs["backup"], job("true", 10));
+    assert_eq!((jobs.len(), jobs["other"].name.as_str()), (2, "other"));
+    assert_eq!(cron::authorized(records.clone(), &Operators::new("b", &[]).unwrap()).len(), records.len() - 2);
+    let runs = &runs["backup"];
+    assert_eq!(runs.len(), cron::HISTORY_SIZE + 1);
+    assert_eq!(cron::run_key("backup", "a", runs), format!("{}3", cron::runs_prefix("backup", "a")));
+    assert_eq!(cron::run_key("backup", "b", runs), format!("{}1", cron::runs_prefix("backup", "b")));
+    assert_eq!(cron::run_key("backup", "c", runs), format!("{}0", cron::runs_prefix("backup", "c")));
+    let status = cron::JobStatus::new(jobs["backup"].clone(), runs);
+    assert_eq!(status.last.unwrap().node, "b");
+    assert!(status.next.is_some());
+}
@@ -0,0 +1,162 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use p2p::container::{ContainerRuntime, FakeRuntime};
+use p2p::deploy::{self, Candidates, DeployState, DeployStatus, Deployment, DeploymentStatus, Reconciler};
+use p2p::kv::{Record, Version};
+use p2p::labels;
+use p2p::operators::Operators;
+use p2p::service::Supervisor;
+
+fn deployment(version: &str, selector: &str, replicas: Option<usize>, workload: serde_json::Value) -> Deployment {
+    serde_json::from_value(serde_json::json!({
+        "version": version,
+        "selector": selector,
+        "replicas": replicas,
+        "workload": workload,
+    })).unwrap()
+}
+
+fn container(command: &str) -> serde_json::Value {
+    serde_json::json!({ "kind": "container", "name": "web", "rootfs": "/srv/web", "command": [command] })
+}
+
+fn candidates(local: &str) -> Candidates {
+    let mut nodes = BTreeMap::new();
+    for (node, labels) in [("a", "role=web"), ("b", "role=web"), ("c", "role=web"), ("d", "role=db"), ("e", "role=web")] {
+        nodes.insert(node.to_string(), labels::parse_labels(labels).unwrap());
+    }
+    Candidates { local: local.to_string(), nodes }
+}
+
+#[test]
+fn test_placement() {
+    let all = deployment("1", "role=web", None, container("./web"));
+    assert_eq!(candidates("a").placed(&all), vec!["a", "b", "c", "e"]);
+    assert!(!candidates("d").places(&all));
+    let two = deployment("1", "role=web", Some(2), container("./web"));
+    let placed = candidates("a").placed(&two);
+    assert_eq!(placed.len(), 2);
+    // Every node agrees, whatever its own id
+    for node in ["a", "b", "c", "d", "e"] {
+        assert_eq!(candidates(node).placed(&two), placed);
+        assert_eq!(candidates(node).places(&two), placed.contains(&node.to_string()));
+    }
+    // A node going away only moves the replica it ran
+    let unplaced = ["a", "b", "c", "e"].into_iter().find(|n| !placed.contains(&n.to_string())).unwrap();
+    let mut fewer = candidates("a");
+    fewer.nodes.remove(unplaced);
+    assert_eq!(fewer.placed(&two), placed);
+    assert!(deployment("1", "role=", None, container("./web")).check().is_err());
+    assert!(deployment("1.0 beta", "", None, container("./web")).check().is_err());
+    assert!(deployment("1", "", Some(0), container("./web")).check().is_err());
+}
+
+#[tokio::test]
+async fn test_reconcile_containers() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let runtime = Arc::new(FakeRuntime::new());
+    let tmp = tempfile::tempdir().unwrap();
+    let reconciler = Reconciler::new("a", Supervisor::new(&tmp.path().to_string_lossy()), runtime.clone(), &db).unwrap();
+    let v1 = deployment("1", "role=web", None, container("./web"));
+    let reports = reconciler.reconcile(std::slice::from_ref(&v1), &candidates("a")).await;
+    let status = reports[0].1.clone().unwrap();
+    assert_eq!((status.state, status.version.as_deref(), status.drift), (DeployState::Ready, Some("1"), None));
+
+    runtime.exit("web");
+    let status = reconciler.reconcile(std::slice::from_ref(&v1), &candidates("a")).await[0].1.clone().unwrap();
+    assert_eq!(status.state, DeployState::Ready);
+    assert_eq!(status.drift.as_deref(), Some("container web exited, ran it again"));
+
+    let v2 = deployment("2", "role=web", None, container("./web2"));
+    let status = reconciler.reconcile(std::slice::from_ref(&v2), &candidates("a")).await[0].1.clone().unwrap();
+    assert_eq!((status.state, status.version.as_deref()), (DeployState::Ready, Some("2")));
+    assert_eq!(runtime.list().await.unwrap().len(), 1);
+    assert_eq!(reconciler.applied().unwrap(), vec![v2.revision()]);
+
+    // Not placed on d, torn down there
+    let reports = reconciler.reconcile(&[v2], &candidates("d")).await;
+    assert_eq!(reports, vec![("web".to_string(), None)]);
+    assert!(runtime.list().await.unwrap().is_empty());
+    assert!(reconciler.applied().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_reconcile_service() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let supervisor = Supervisor::new(&tmp.path().to_string_lossy());
+    let reconciler = Reconciler::new("a", supervisor.clone(), Arc::new(FakeRuntime::new()), &db).unwrap();
+    let workload = serde_json::json!({ "kind": "service", "name": "sleeper", "command": ["sh", "-c", "exec sleep 30"] });
+    let d = deployment("1", "", None, workload);
+    reconciler.reconcile(std::slice::from_ref(&d), &candidates("a")).await;
+    let mut state = DeployState::Progressing;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state = reconciler.reconcile(std::slice::from_ref(&d), &candidates("a")).await[0].1.as_ref().unwrap().state;
+        if state == DeployState::Ready {
+            break;
+        }
+    }
+    assert_eq!(state, DeployState::Ready);
+    reconciler.reconcile(&[], &candidates("a")).await;
+    assert!(supervisor.status("sleeper").is_err());
+
+    // A repair counts once the service runs again
+    let marker = tmp.path().join("marker");
+    std::fs::write(&marker, "").unwrap();
+    let script = format!("test -f {} && exec sleep 30", marker.display());
+    let workload = serde_json::json!({ "kind": "service", "name": "flaky", "command": ["sh", "-c", script], "restart": "never" });
+    let flaky = deployment("1", "", None, workload);
+    reconciler.reconcile(std::slice::from_ref(&flaky), &candidates("a")).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    supervisor.stop("flaky").await.unwrap();
+    std::fs::remove_file(&marker).unwrap();
+    let status = reconciler.reconcile(std::slice::from_ref(&flaky), &candidates("a")).await[0].1.clone().unwrap();
+    assert_eq!((status.state, status.drift), (DeployState::Failed, None));
+    assert!(status.message.unwrap().starts_with("service flaky was not running, started it but it does not run"));
+    reconciler.reconcile(&[], &candidates("a")).await;
+
+    // Services of the config are left alone
+    supervisor.declare(serde_json::from_value(serde_json::json!({ "name": "sleeper", "command": ["true"] })).unwrap()).unwrap();
+    let status = reconciler.reconcile(&[d], &candidates("a")).await[0].1.clone().unwrap();
+    assert_eq!(status.state, DeployState::Failed);
+    assert_eq!(status.version, None);
+}
+
+#[test]
+fn test_status_records() {
+    let d = deployment("2", "role=web", Some(2), container("./web"));
+    let status = |node: &str, version: &str, state: DeployState| DeployStatus {
+        deployment: "web".to_string(),
+        node: node.to_string(),
+        version: Some(version.to_string()),
+        state,
+        message: None,
+        drift: None,
+        drifted: None,
+        updated: 1000,
+    };
+    let record = |key: String, value: String, writer: &str| Record { key, value: Some(value), version: Version { time: 1, node: writer.to_string() }, seq: 0, public_key: String::new(), signature: String::new() };
+    let records = vec![
+        record(deploy::spec_key("web"), serde_json::to_string(&d).unwrap(), "a"),
+        record(deploy::status_key("web", "a"), serde_json::to_string(&status("a", "2", DeployState::Ready)).unwrap(), "a"),
+        record(deploy::status_key("web", "b"), serde_json::to_string(&status("b", "1", DeployState::Ready)).unwrap(), "b"),
+        // Reported by another node than the one it is about
+        record(deploy::status_key("web", "c"), serde_json::to_string(&status("c", "2", DeployState::Ready)).unwrap(), "b"),
+        record(deploy::status_key("web", "e"), serde_json::to_string(&status("a", "2", DeployState::Ready)).unwrap(), "e"),
+        record("deploy/web/other".to_string(), "{}".to_string(), "a"),
+    ];
+    let (deployments, mut statuses) = deploy::parse_records(&records);
+    let summary = DeploymentStatus::new(&deployments["web"], statuses.remove("web").unwrap());
+    assert_eq!((summary.nodes, summary.ready, summary.not_ready), (2, 1, 1));
+
+    // Only the specs of the operators are applied
+    let spec = record(deploy::spec_key("db"), serde_json::to_string(&d).unwrap(), "b");
+    assert_eq!(deploy::authorized(records.clone(), &Operators::new("c", &[]).unwrap()).len(), records.len() - 1);
+    assert_eq!(deploy::authorized(vec![spec.clone()], &Operators::new("b", &[]).unwrap()), vec![spec]);
+
+    let current = status("a", "2", DeployState::Ready);
+    assert!(!deploy::needs_report(Some(&current), &DeployStatus { updated: 1010, ..current.clone() }));
+    assert!(deploy::needs_report(Some(&current), &DeployStatus { updated: 1000 + deploy::REPORT_INTERVAL, ..current.clone() }));
+    assert!(deploy::needs_report(Some(&current), &DeployStatus { state: DeployState::Failed, ..current.clone() }));
+    assert!(deploy::needs_report(None, &current));
+}
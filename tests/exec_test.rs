@@ -0,0 +1,29 @@
+use std::collections::BTreeMap;
+use p2p::exec::{self, ExecRequest, ExecResponse, ExecResult};
+
+fn request(command: &str, timeout: u64) -> ExecRequest {
+    ExecRequest {
+        command: vec!["sh".to_string(), "-c".to_string(), command.to_string()],
+        env: BTreeMap::from([("GREETING".to_string(), "hello".to_string())]),
+        timeout,
+    }
+}
+
+#[tokio::test]
+async fn test_exec() {
+    let result = ExecResult::new("a", Ok(exec::serve("a", request("echo $GREETING; exit 3", 5)).await));
+    assert_eq!((result.exit_code, result.error, result.output.as_str()), (Some(3), None, "hello\n"));
+
+    let result = ExecResult::new("a", Ok(exec::serve("a", request("sleep 5", 1)).await));
+    assert!(!result.succeeded());
+    assert_eq!(result.error.as_deref(), Some("Timed out after 1s"));
+
+    // Longer than a peer waits for the response
+    match exec::serve("a", request("true", exec::MAX_TIMEOUT + 1)).await {
+        ExecResponse::Error { message } => assert!(message.starts_with("The timeout must be"), "{}", message),
+        res => panic!("{:?}", res),
+    }
+    assert!(ExecRequest { command: Vec::new(), ..request("", 5) }.check().is_err());
+    let result = ExecResult::new("b", Err("Not an operator of this node".to_string()));
+    assert_eq!((result.node.as_str(), result.exit_code, result.error.as_deref()), ("b", None, Some("Not an operator of this node")));
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use p2p::files::{self, FileOp, FileRequest, FileResult, FileStore, DEFAULT_MAX_SIZE};
+
+fn write_as(store: &FileStore, path: &str, offset: u64, data: &[u8], file: &[u8], operator: bool) -> FileResult {
+    let op = FileOp::Write {
+        path: path.to_string(),
+        offset,
+        size: file.len() as u64,
+        sha256: files::sha256_hex(data),
+        file_sha256: files::sha256_hex(file),
+    };
+    store.serve(FileRequest { op, data: data.to_vec() }, operator).result
+}
+
+fn write(store: &FileStore, offset: u64, data: &[u8], file: &[u8]) -> FileResult {
+    write_as(store, "conf/app.toml", offset, data, file, true)
+}
+
+#[test]
+fn test_paths_stay_in_store() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let store = FileStore::new(dir.to_str().unwrap(), DEFAULT_MAX_SIZE);
+    for path in ["../secret", "/etc/passwd", "", "a.part"] {
+        let req = FileRequest { op: FileOp::Stat { path: path.to_string() }, data: Vec::new() };
+        assert!(matches!(store.serve(req, true).result, FileResult::Error { .. }), "{} was accepted", path);
+    }
+}
+
+#[test]
+fn test_write_resumes_and_completes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let store = FileStore::new(dir.to_str().unwrap(), DEFAULT_MAX_SIZE);
+    let file = b"name = \"hanode\"\nport = 32000\n";
+    assert!(matches!(write(&store, 0, &file[..10], file), FileResult::Written { partial: 10 }));
+    // Out of order writes are told where to resume
+    assert!(matches!(write(&store, 20, &file[20..], file), FileResult::Written { partial: 10 }));
+    let stat = store.serve(FileRequest { op: FileOp::Stat { path: "conf/app.toml".to_string() }, data: Vec::new() }, true);
+    assert!(matches!(stat.result, FileResult::Stat { size: None, partial: 10, .. }));
+    assert!(matches!(write(&store, 10, &file[10..], file), FileResult::Written { .. }));
+    assert_eq!(fs::read(dir.join("conf/app.toml")).unwrap(), file);
+    assert!(!dir.join("conf/app.toml.part").exists());
+
+    let read = FileRequest { op: FileOp::Read { path: "conf/app.toml".to_string(), offset: 7, len: 8 }, data: Vec::new() };
+    assert_eq!(store.serve(read, true).data, &file[7..15]);
+}
+
+#[test]
+fn test_write_rejects_corrupt_data() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let store = FileStore::new(dir.to_str().unwrap(), DEFAULT_MAX_SIZE);
+    let file = b"binary";
+    let op = FileOp::Write {
+        path: "conf/app.toml".to_string(),
+        offset: 0,
+        size: 6,
+        sha256: files::sha256_hex(b"other"),
+        file_sha256: files::sha256_hex(file),
+    };
+    assert!(matches!(store.serve(FileRequest { op, data: file.to_vec() }, true).result, FileResult::Error { .. }));
+    // A complete copy not matching the file hash is discarded
+    assert!(matches!(write(&store, 0, file, b"binarY"), FileResult::Error { .. }));
+    assert!(!dir.join("conf/app.toml").exists());
+    assert!(!dir.join("conf/app.toml.part").exists());
+}
+
+#[test]
+fn test_paths_do_not_follow_links_out() {
+    let tmp = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    fs::write(outside.path().join("secret"), "secret").unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.join("out")).unwrap();
+    std::os::unix::fs::symlink(outside.path().join("app.toml.part"), dir.join("app.toml.part")).unwrap();
+    fs::create_dir(dir.join("conf")).unwrap();
+    std::os::unix::fs::symlink(dir.join("conf"), dir.join("in")).unwrap();
+    let store = FileStore::new(dir.to_str().unwrap(), DEFAULT_MAX_SIZE);
+    let read = FileRequest { op: FileOp::Read { path: "out/secret".to_string(), offset: 0, len: 6 }, data: Vec::new() };
+    assert!(matches!(store.serve(read, true).result, FileResult::Error { .. }));
+    assert!(matches!(write_as(&store, "out/new", 0, b"data", b"data", true), FileResult::Error { .. }));
+    // Nor through a dangling link to the partial copy
+    assert!(matches!(write_as(&store, "app.toml", 0, b"data", b"data", true), FileResult::Error { .. }));
+    assert!(!outside.path().join("new").exists());
+    assert!(!outside.path().join("app.toml.part").exists());
+    // Links staying inside are followed
+    assert!(matches!(write_as(&store, "in/app.toml", 0, b"data", b"data", true), FileResult::Written { partial: 4 }));
+    assert_eq!(fs::read(dir.join("conf/app.toml")).unwrap(), b"data");
+}
+
+#[test]
+fn test_write_limits() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let store = FileStore::new(dir.to_str().unwrap(), 8);
+    // Only operators write
+    assert!(matches!(write_as(&store, "a", 0, b"data", b"data", false), FileResult::Error { .. }));
+    assert!(!dir.join("a.part").exists());
+    assert!(matches!(write_as(&store, "a", 0, b"large file", b"large file", true), FileResult::Error { .. }));
+    // Chunks cannot go past the declared size
+    let op = FileOp::Write {
+        path: "a".to_string(),
+        offset: 0,
+        size: 4,
+        sha256: files::sha256_hex(b"too long"),
+        file_sha256: files::sha256_hex(b"data"),
+    };
+    assert!(matches!(store.serve(FileRequest { op, data: b"too long".to_vec() }, true).result, FileResult::Error { .. }));
+    let op = FileOp::Write {
+        path: "a".to_string(),
+        offset: u64::MAX,
+        size: 4,
+        sha256: files::sha256_hex(b"data"),
+        file_sha256: files::sha256_hex(b"data"),
+    };
+    assert!(matches!(store.serve(FileRequest { op, data: b"data".to_vec() }, true).result, FileResult::Error { .. }));
+    assert!(!dir.join("a.part").exists());
+}
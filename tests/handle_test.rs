@@ -0,0 +1,45 @@
+use p2p::handle::{Command, NodeHandle, NodeInfo, QueueFull, QueueOptions, QueuePolicy};
+use p2p::message::Message;
+
+#[tokio::test]
+async fn test_handle_gets_reply() {
+    let (handle, mut queues) = NodeHandle::channel(QueueOptions::default());
+    tokio::spawn(async move {
+        while let Some(command) = queues.control.recv().await {
+            if let Command::Info(reply) = command {
+                let _ = reply.send(NodeInfo {
+                    peer_id: "peer-a".to_string(),
+                    listen_addrs: vec![],
+                    connected_peers: vec![],
+                    labels: Default::default(),
+                });
+            }
+        }
+    });
+    assert_eq!(handle.clone().info().await.unwrap().peer_id, "peer-a");
+}
+
+#[tokio::test]
+async fn test_handle_fails_when_node_stopped() {
+    let (handle, queues) = NodeHandle::channel(QueueOptions::default());
+    drop(queues);
+    assert!(handle.info().await.is_err());
+    assert!(handle.peers().await.is_err());
+}
+
+#[tokio::test]
+async fn test_full_queue_policies() {
+    let (handle, _queues) = NodeHandle::channel(QueueOptions { capacity: 2, policy: QueuePolicy::Reject });
+    for _ in 0..2 {
+        assert!(handle.publish(Message::from("hello".to_string())).await.unwrap());
+    }
+    let err = handle.publish(Message::from("hello".to_string())).await.unwrap_err();
+    assert!(err.is::<QueueFull>());
+    assert_eq!(handle.depth(), 2);
+    assert!(handle.metrics().contains("hanode_messages_rejected_total 1"));
+
+    let (handle, _queues) = NodeHandle::channel(QueueOptions { capacity: 1, policy: QueuePolicy::Drop });
+    assert!(handle.publish(Message::from("hello".to_string())).await.unwrap());
+    assert!(!handle.publish(Message::from("hello".to_string())).await.unwrap());
+    assert!(handle.metrics().contains("hanode_messages_dropped_total 1"));
+}
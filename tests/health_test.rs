@@ -0,0 +1,171 @@
+use std::{io::{Read, Write}, net::TcpListener, sync::{mpsc, Arc}, thread, time::Duration};
+use p2p::health::{self, Alert, AlertState, Alerter, Check, CheckResult, CheckStatus, HealthConfig, Notify, Outcome, Readings};
+
+// A node with its disk and memory as full as given
+struct Fixed(f64, f64);
+
+impl Readings for Fixed {
+    fn disk_usage(&self, _path: &str) -> Result<(String, f64), String> {
+        Ok(("/".to_string(), self.0))
+    }
+
+    fn memory_usage(&self) -> Result<f64, String> {
+        Ok(self.1)
+    }
+}
+
+fn config(value: serde_json::Value) -> HealthConfig {
+    serde_json::from_value(value).unwrap()
+}
+
+#[tokio::test]
+async fn test_thresholds() {
+    let timeout = Duration::from_secs(5);
+    let readings = Arc::new(Fixed(42.0, 91.5));
+    let disk = |max_used: f64| Check::Disk { path: "/var".to_string(), max_used };
+    assert_eq!(disk(50.0).observe_with(timeout, readings.clone()).await, Ok(Some("/ is 42.0% full".to_string())));
+    assert_eq!(disk(42.0).observe_with(timeout, readings.clone()).await, Ok(Some("/ is 42.0% full".to_string())));
+    assert_eq!(disk(40.0).observe_with(timeout, readings.clone()).await, Err("/ is 42.0% full, above 40%".to_string()));
+    assert_eq!(Check::Memory { max_used: 95.0 }.observe_with(timeout, readings.clone()).await, Ok(Some("memory is 91.5% used".to_string())));
+    assert_eq!(Check::Memory { max_used: 90.0 }.observe_with(timeout, readings).await, Err("memory is 91.5% used, above 90%".to_string()));
+    assert!(Check::Disk { path: "data".to_string(), max_used: 90.0 }.check().is_err());
+    assert!(Check::Memory { max_used: 120.0 }.check().is_err());
+    assert!(Check::Http { url: "https://localhost:8443/healthz".to_string() }.check().is_ok());
+    assert!(Check::Http { url: "ftp://localhost/healthz".to_string() }.check().is_err());
+}
+
+#[test]
+fn test_config() {
+    let health = config(serde_json::json!({
+        "checks": [
+            { "name": "disk", "type": "disk", "path": "/", "max_used": 90, "interval": 60 },
+            { "name": "api", "type": "http", "url": "http://localhost:8080/healthz" },
+        ],
+        "alerts": [{ "name": "ops", "checks": ["disk"], "after": 120, "notify": { "type": "webhook", "url": "http://alerts.local/hook" } }],
+    }));
+    health.check().unwrap();
+    assert_eq!(health.checks[0].check, Check::Disk { path: "/".to_string(), max_used: 90.0 });
+    assert_eq!((health.checks[1].interval, health.checks[1].timeout), (health::DEFAULT_INTERVAL, 5));
+    let unknown = config(serde_json::json!({ "alerts": [{ "name": "ops", "checks": ["disk"], "notify": { "type": "log" } }] }));
+    assert!(unknown.check().is_err());
+    let twice = config(serde_json::json!({ "checks": [{ "name": "mem", "type": "memory", "max_used": 90 }, { "name": "mem", "type": "memory", "max_used": 95 }] }));
+    assert!(twice.check().is_err());
+}
+
+#[test]
+fn test_alerts() {
+    let rules = config(serde_json::json!({
+        "alerts": [
+            { "name": "page", "checks": ["disk"], "after": 60, "notify": { "type": "webhook", "url": "http://alerts.local/hook" } },
+            { "name": "log", "notify": { "type": "log" } },
+        ],
+    })).alerts;
+    let mut alerter = Alerter::new(rules);
+    let mut previous: Option<CheckResult> = None;
+    let mut run = |outcome: Outcome, now: u64| {
+        let result = CheckResult::new("a", "disk", outcome, previous.as_ref(), now);
+        previous = Some(result.clone());
+        result
+    };
+    let full = || Err("/ is 95.0% full, above 90%".to_string());
+
+    let result = run(Ok(Some("/ is 50.0% full".to_string())), 1000);
+    assert_eq!((result.status, result.since), (CheckStatus::Passing, 1000));
+    assert!(alerter.evaluate(&result).is_empty());
+
+    // The log rule fires at once, the page one after a minute of failing
+    let result = run(full(), 1030);
+    let sent = alerter.evaluate(&result);
+    assert_eq!(sent.len(), 1);
+    assert_eq!((&sent[0].0, sent[0].1.state), (&Notify::Log, AlertState::Firing));
+    // Not sent again while it is being sent
+    assert!(alerter.evaluate(&result).is_empty());
+    alerter.delivered(&sent[0].1, true);
+    let result = run(full(), 1060);
+    assert_eq!(result.since, 1030);
+    assert!(alerter.evaluate(&result).is_empty());
+    let sent = alerter.evaluate(&run(full(), 1090));
+    assert_eq!(sent.iter().map(|(_, a)| a.rule.as_str()).collect::<Vec<_>>(), vec!["page"]);
+    // Sent again on the next run until delivered
+    alerter.delivered(&sent[0].1, false);
+    assert_eq!(alerter.firing().len(), 1);
+    let sent = alerter.evaluate(&run(full(), 1120));
+    assert_eq!((sent.len(), sent[0].1.fired), (1, 1120));
+    alerter.delivered(&sent[0].1, true);
+    // Firing alerts are not sent again
+    assert!(alerter.evaluate(&run(full(), 1150)).is_empty());
+    assert_eq!(alerter.firing().len(), 2);
+
+    let sent = alerter.evaluate(&run(Ok(None), 1180));
+    assert_eq!(sent.len(), 2);
+    assert!(sent.iter().all(|(_, a)| a.state == AlertState::Resolved && a.resolved == Some(1180)));
+    assert_eq!(sent.iter().find(|(_, a)| a.rule == "page").unwrap().1.fired, 1120);
+    // Firing until the resolution is delivered
+    for (_, alert) in &sent {
+        alerter.delivered(alert, alert.rule == "log");
+    }
+    assert_eq!(alerter.firing().len(), 1);
+    let sent = alerter.evaluate(&run(Ok(None), 1210));
+    assert_eq!(sent.iter().map(|(_, a)| (a.rule.as_str(), a.state)).collect::<Vec<_>>(), vec![("page", AlertState::Resolved)]);
+    alerter.delivered(&sent[0].1, true);
+    assert!(alerter.firing().is_empty());
+    assert!(alerter.evaluate(&run(Ok(None), 1240)).is_empty());
+}
+
+// Receives webhooks, answering with the status given and passing the body on
+fn webhook_stand_in(status: &'static str) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (bodies, received) = mpsc::channel();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            // Read up to the end of the body, the client keeps the connection open
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf) {
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head.lines()
+                        .find_map(|l| l.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("content-length")).map(|(_, v)| v.trim()))
+                        .and_then(|l| l.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        let _ = bodies.send(body.to_string());
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+            let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+        }
+    });
+    (url, received)
+}
+
+#[tokio::test]
+async fn test_webhook() {
+    let alert = Alert {
+        rule: "page".to_string(),
+        node: "a".to_string(),
+        check: "disk".to_string(),
+        state: AlertState::Firing,
+        message: Some("/ is 95.0% full, above 90%".to_string()),
+        fired: 1000,
+        resolved: None,
+    };
+    let (url, received) = webhook_stand_in("200 OK");
+    health::notify(&Notify::Webhook { url }, &alert).await.unwrap();
+    let body: Alert = serde_json::from_str(&received.recv_timeout(Duration::from_secs(2)).unwrap()).unwrap();
+    assert_eq!(body, alert);
+
+    let (url, _received) = webhook_stand_in("500 Internal Server Error");
+    let err = health::notify(&Notify::Webhook { url }, &alert).await.unwrap_err();
+    assert!(err.ends_with("returned 500"), "{}", err);
+    health::notify(&Notify::Log, &alert).await.unwrap();
+    // A redirect passes, it is not followed
+    let (url, _received) = webhook_stand_in("302 Found");
+    Check::Http { url }.probe(Duration::from_secs(2)).await.unwrap();
+}
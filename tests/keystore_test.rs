@@ -0,0 +1,25 @@
+use libp2p::PeerId;
+use p2p::keys::{self, KeyRotation, KeyType};
+use p2p::keystore::Keystore;
+
+#[test]
+fn test_keystore_roundtrip() {
+    for key_type in [KeyType::Ed25519, KeyType::Secp256k1] {
+        let key = keys::generate(key_type);
+        let keystore = Keystore::encrypt(&key, "secret").expect("encrypt failed");
+        assert_eq!(keystore.key_type, key_type);
+        let decrypted = keystore.decrypt("secret").expect("decrypt failed");
+        assert_eq!(decrypted.public(), key.public());
+        assert!(keystore.decrypt("wrong").is_err());
+    }
+}
+
+#[test]
+fn test_key_rotation_signature() {
+    let old_key = keys::generate(KeyType::Secp256k1);
+    let new_key = keys::generate(KeyType::Ed25519);
+    let mut rotation = KeyRotation::new(&old_key, &PeerId::from(new_key.public())).expect("rotation failed");
+    assert!(rotation.verify());
+    rotation.new_peer_id = PeerId::from(keys::generate(KeyType::Ed25519).public()).to_base58();
+    assert!(!rotation.verify());
+}
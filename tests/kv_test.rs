@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use libp2p::{identity::Keypair, PeerId};
+use p2p::kv::{self, KvRequest, KvResponse, KvStore, Record, Version};
+use p2p::schema::Tree;
+use p2p::utils::now_millis;
+
+fn temporary_store(key: &Keypair) -> KvStore {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    KvStore::open(&db, key).unwrap()
+}
+
+fn record(key: &Keypair, time: u64, value: &str) -> Record {
+    let mut record = Record {
+        key: "config/replicas".to_string(),
+        value: Some(value.to_string()),
+        version: Version { time, node: PeerId::from(key.public()).to_base58() },
+        seq: 0,
+        public_key: String::new(),
+        signature: String::new(),
+    };
+    record.sign(key).unwrap();
+    record
+}
+
+fn pull(from: &KvStore, to: &KvStore) {
+    let known = to.versions(None).unwrap();
+    match from.serve(KvRequest::Pull { keys: None, known }) {
+        KvResponse::Records { records, more } => {
+            assert!(!more);
+            for record in records {
+                to.merge(record).unwrap();
+            }
+        },
+        KvResponse::Error { message } => panic!("{}", message),
+    }
+}
+
+#[test]
+fn test_last_writer_wins() {
+    let a = temporary_store(&Keypair::generate_ed25519());
+    let b = temporary_store(&Keypair::generate_ed25519());
+    let first = a.write("config/replicas", Some("3".to_string())).unwrap();
+    let second = b.write("config/replicas", Some("5".to_string())).unwrap();
+    // Both sides end up with the greater version whatever the order
+    assert!(b.merge(first.clone()).is_ok());
+    assert!(a.merge(second.clone()).is_ok());
+    let winner = if second.version > first.version { "5" } else { "3" };
+    assert_eq!(a.get("config/replicas").unwrap().unwrap().value.as_deref(), Some(winner));
+    assert_eq!(b.get("config/replicas").unwrap().unwrap().value.as_deref(), Some(winner));
+
+    let stale = record(&Keypair::generate_ed25519(), 1, "1");
+    assert!(!a.merge(stale).unwrap());
+    assert!(a.write("bad key", Some("x".to_string())).is_err());
+}
+
+#[test]
+fn test_merge_checks_the_writer() {
+    let store = temporary_store(&Keypair::generate_ed25519());
+    let writer = Keypair::generate_secp256k1();
+    let now = now_millis();
+    let mut forged = record(&writer, now, "5");
+    forged.value = Some("6".to_string());
+    assert!(store.merge(forged).is_err());
+    // Signed by the writer but stamped with the id of another node
+    let mut borrowed = record(&writer, now, "5");
+    borrowed.version.node = PeerId::from(Keypair::generate_ed25519().public()).to_base58();
+    assert!(store.merge(borrowed).is_err());
+    let mut unsigned = record(&writer, now, "5");
+    unsigned.signature.clear();
+    assert!(store.merge(unsigned).is_err());
+    // Far ahead of the local clock it would drag every later write along
+    assert!(store.merge(record(&writer, now + kv::MAX_CLOCK_SKEW * 10, "5")).is_err());
+    assert!(store.merge(record(&writer, now, "5")).unwrap());
+    assert!(store.write("config/replicas", Some("7".to_string())).unwrap().version.time < now + kv::MAX_CLOCK_SKEW);
+}
+
+#[test]
+fn test_unsigned_records_are_signed_or_dropped() {
+    let key = Keypair::generate_ed25519();
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    let tree = Tree::Kv.open(&db).unwrap();
+    let mut own = record(&key, 1, "3");
+    let mut other = record(&Keypair::generate_ed25519(), 1, "3");
+    other.key = "config/other".to_string();
+    for unsigned in [&mut own, &mut other] {
+        unsigned.signature.clear();
+        tree.insert(unsigned.key.as_bytes(), serde_json::to_vec(unsigned).unwrap()).unwrap();
+    }
+    let store = KvStore::open(&db, &key).unwrap();
+    store.get("config/replicas").unwrap().unwrap().verify().unwrap();
+    assert!(store.get("config/other").unwrap().is_none());
+}
+
+#[test]
+fn test_delete_replicates_as_tombstone() {
+    let a = temporary_store(&Keypair::generate_ed25519());
+    let b = temporary_store(&Keypair::generate_ed25519());
+    a.write("inventory/web-1", Some("10.0.0.1".to_string())).unwrap();
+    a.write("inventory/web-2", Some("10.0.0.2".to_string())).unwrap();
+    pull(&a, &b);
+    assert_eq!(b.list("inventory/", None).unwrap().records.len(), 2);
+
+    let cursor = b.list("inventory/", None).unwrap().seq;
+    a.write("inventory/web-1", None).unwrap();
+    pull(&a, &b);
+    let live = b.list("inventory/", None).unwrap().records;
+    assert_eq!(live.len(), 1);
+    assert_eq!(live[0].key, "inventory/web-2");
+    // Watchers see the delete
+    let changes = b.list("inventory/", Some(cursor)).unwrap();
+    assert_eq!(changes.records.len(), 1);
+    assert_eq!(changes.records[0].value, None);
+    assert!(b.list("inventory/", Some(changes.seq)).unwrap().records.is_empty());
+    // Nothing left to pull
+    match a.serve(KvRequest::Pull { keys: None, known: b.versions(None).unwrap() }) {
+        KvResponse::Records { records, .. } => assert!(records.is_empty()),
+        KvResponse::Error { message } => panic!("{}", message),
+    }
+    let only: HashMap<String, Version> = HashMap::new();
+    match a.serve(KvRequest::Pull { keys: Some(vec!["inventory/web-2".to_string()]), known: only }) {
+        KvResponse::Records { records, .. } => assert_eq!(records.len(), 1),
+        KvResponse::Error { message } => panic!("{}", message),
+    }
+}
@@ -0,0 +1,39 @@
+use p2p::labels::{self, Groups, Labels, Selector};
+
+fn labels(s: &str) -> Labels {
+    labels::parse_labels(s).unwrap()
+}
+
+#[test]
+fn test_selector_matches() {
+    let db = labels("env=prod,role=db,rack=a3");
+    let web = labels("env=prod,role=web,canary=yes");
+    let selector: Selector = "env=prod,role!=db".parse().unwrap();
+    assert!(!selector.matches(&db));
+    assert!(selector.matches(&web));
+    // A missing label is not equal to any value
+    assert!(selector.matches(&labels("env=prod")));
+    let selector: Selector = "rack,!canary".parse().unwrap();
+    assert!(selector.matches(&db));
+    assert!(!selector.matches(&web));
+    assert!(Selector::default().matches(&Labels::new()));
+    assert_eq!("env=prod, role!=db,!canary".parse::<Selector>().unwrap().to_string(), "env=prod,role!=db,!canary");
+    assert!("env=".parse::<Selector>().is_err());
+    assert!("env=a b".parse::<Selector>().is_err());
+    assert!(labels::parse_labels("env").is_err());
+}
+
+#[test]
+fn test_groups() {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    let groups = Groups::open(&db).unwrap();
+    groups.set("databases", &"role=db".parse().unwrap()).unwrap();
+    assert_eq!(groups.list().unwrap().len(), 1);
+    let selector = groups.resolve(Some("env=prod"), Some("databases")).unwrap();
+    assert!(selector.matches(&labels("env=prod,role=db")));
+    assert!(!selector.matches(&labels("env=dev,role=db")));
+    assert!(groups.resolve(None, Some("web")).is_err());
+    assert!(groups.set("a b", &Selector::default()).is_err());
+    assert!(groups.remove("databases").unwrap());
+    assert!(!groups.remove("databases").unwrap());
+}
@@ -0,0 +1,75 @@
+use p2p::{lock::Lock, raft::{MemoryNetwork, RaftCommand, RaftResult}};
+
+fn acquire(name: &str, holder: &str, ttl: u64, now: u64) -> RaftCommand {
+    RaftCommand::Acquire { name: name.to_string(), holder: holder.to_string(), ttl, now }
+}
+
+fn release(name: &str, holder: &str, token: Option<u64>) -> RaftCommand {
+    RaftCommand::Release { name: name.to_string(), holder: holder.to_string(), token }
+}
+
+// Propose on the leader and wait for the result
+fn run(net: &mut MemoryNetwork, leader: &str, command: RaftCommand) -> RaftResult {
+    let ticket = net.node(leader).propose(command).unwrap();
+    net.deliver().unwrap();
+    net.result(leader, ticket).expect("the command was not committed")
+}
+
+fn granted(result: RaftResult) -> Lock {
+    serde_json::from_str(&result.unwrap().expect("no lock granted")).unwrap()
+}
+
+#[test]
+fn test_lock_is_exclusive_until_it_expires() {
+    let mut net = MemoryNetwork::new(&["a", "b", "c"]).unwrap();
+    let leader = net.elect(200).unwrap().expect("no leader elected");
+
+    let first = granted(run(&mut net, &leader, acquire("backup", "node1", 30_000, 1_000)));
+    assert_eq!(first.holder, "node1");
+    assert_eq!(first.expires, 31_000);
+    // Another node gets the lock of the holder back
+    let held = granted(run(&mut net, &leader, acquire("backup", "node2", 30_000, 2_000)));
+    assert_eq!(held, first);
+
+    // Renewing keeps the token
+    let renewed = granted(run(&mut net, &leader, acquire("backup", "node1", 30_000, 20_000)));
+    assert_eq!(renewed.token, first.token);
+    assert_eq!(renewed.expires, 50_000);
+
+    // Once expired another node gets it, with a greater token
+    let second = granted(run(&mut net, &leader, acquire("backup", "node2", 30_000, 50_000)));
+    assert_eq!(second.holder, "node2");
+    assert!(second.token > first.token);
+}
+
+#[test]
+fn test_release() {
+    let mut net = MemoryNetwork::new(&["a", "b", "c"]).unwrap();
+    let leader = net.elect(200).unwrap().expect("no leader elected");
+
+    let lock = granted(run(&mut net, &leader, acquire("deploy", "node1", 30_000, 1_000)));
+    assert!(run(&mut net, &leader, release("deploy", "node2", None)).is_err());
+    assert!(run(&mut net, &leader, release("deploy", "node1", Some(lock.token + 1))).is_err());
+    assert_eq!(run(&mut net, &leader, release("deploy", "node1", Some(lock.token))), Ok(None));
+    granted(run(&mut net, &leader, acquire("deploy", "node2", 30_000, 2_000)));
+
+    // A node that went away loses all of its locks
+    granted(run(&mut net, &leader, acquire("report", "node2", 30_000, 2_000)));
+    assert_eq!(run(&mut net, &leader, RaftCommand::ReleaseAll { holder: "node2".to_string() }), Ok(None));
+    assert!(net.node(&leader).locks().unwrap().is_empty());
+}
+
+#[test]
+fn test_locks_survive_a_new_leader() {
+    let mut net = MemoryNetwork::new(&["a", "b", "c"]).unwrap();
+    let old = net.elect(200).unwrap().expect("no leader elected");
+    let lock = granted(run(&mut net, &old, acquire("backup", "node1", 60_000, 1_000)));
+
+    net.isolate(&old);
+    let new = net.elect(200).unwrap().expect("no leader elected after the partition");
+    // The clock of the new leader is behind, the lock still has not expired
+    let held = granted(run(&mut net, &new, acquire("backup", "node2", 60_000, 500)));
+    assert_eq!(held, lock);
+    let renewed = granted(run(&mut net, &new, acquire("backup", "node1", 60_000, 30_000)));
+    assert_eq!(renewed.token, lock.token);
+}
@@ -0,0 +1,16 @@
+use p2p::message::{Envelope, Message};
+
+#[test]
+fn test_envelope_keeps_trace_id() {
+    let msg = Message::from("hello".to_string());
+    let envelope = Envelope::decode(&Envelope::from(&msg).encode());
+    assert_eq!(envelope.trace_id, msg.trace_id);
+    assert_eq!(envelope.message, "hello");
+}
+
+#[test]
+fn test_decode_legacy_message() {
+    let envelope = Envelope::decode(b"hello");
+    assert_eq!(envelope.message, "hello");
+    assert_eq!(envelope.trace_id.len(), 32);
+}
@@ -0,0 +1,75 @@
+use p2p::raft::{MemoryNetwork, RaftCommand, Role};
+
+fn set(key: &str, value: &str) -> RaftCommand {
+    RaftCommand::Set { key: key.to_string(), value: value.to_string() }
+}
+
+#[test]
+fn test_elects_one_leader() {
+    let mut net = MemoryNetwork::new(&["a", "b", "c"]).unwrap();
+    let leader = net.elect(200).unwrap().expect("no leader elected");
+    // A few more rounds of heartbeats settle the followers
+    for _ in 0..5 {
+        net.tick().unwrap();
+    }
+    let leaders: Vec<&str> = net.nodes.values().filter(|r| r.role() == Role::Leader).map(|r| r.id()).collect();
+    assert_eq!(leaders, vec![leader.as_str()]);
+    for raft in net.nodes.values() {
+        assert_eq!(raft.leader(), Some(leader.as_str()));
+    }
+}
+
+#[test]
+fn test_replicates_writes_and_reads_linearizably() {
+    let mut net = MemoryNetwork::new(&["a", "b", "c"]).unwrap();
+    let leader = net.elect(200).unwrap().expect("no leader elected");
+    let ticket = net.node(&leader).propose(set("admin", "alice")).unwrap();
+    net.deliver().unwrap();
+    assert_eq!(net.result(&leader, ticket), Some(Ok(None)));
+    let ticket = net.node(&leader).read("admin").unwrap();
+    net.deliver().unwrap();
+    assert_eq!(net.result(&leader, ticket), Some(Ok(Some("alice".to_string()))));
+
+    // Followers learn the commit on the next heartbeat
+    for _ in 0..3 {
+        net.tick().unwrap();
+    }
+    for raft in net.nodes.values() {
+        assert_eq!(raft.applied("admin").unwrap().as_deref(), Some("alice"));
+    }
+    let follower = net.nodes.keys().find(|id| **id != leader).unwrap().clone();
+    let err = net.node(&follower).propose(set("admin", "mallory")).unwrap_err();
+    assert!(err.contains(&leader));
+}
+
+#[test]
+fn test_new_leader_after_partition() {
+    let mut net = MemoryNetwork::new(&["a", "b", "c"]).unwrap();
+    let old = net.elect(200).unwrap().expect("no leader elected");
+    let ticket = net.node(&old).propose(set("policy/exec", "deny")).unwrap();
+    net.deliver().unwrap();
+    assert_eq!(net.result(&old, ticket), Some(Ok(None)));
+
+    net.isolate(&old);
+    // Not committed, the old leader cannot reach a majority
+    let lost = net.node(&old).propose(set("policy/exec", "allow")).unwrap();
+    let new = net.elect(200).unwrap().expect("no leader elected after the partition");
+    assert_ne!(new, old);
+    let ticket = net.node(&new).propose(set("policy/exec", "audit")).unwrap();
+    net.deliver().unwrap();
+    assert_eq!(net.result(&new, ticket), Some(Ok(None)));
+
+    net.heal(&old);
+    for _ in 0..50 {
+        net.tick().unwrap();
+    }
+    assert!(net.result(&old, lost).unwrap().is_err());
+    let leader = net.leader().expect("no leader after healing");
+    for _ in 0..5 {
+        net.tick().unwrap();
+    }
+    let ticket = net.node(&leader).read("policy/exec").unwrap();
+    net.deliver().unwrap();
+    assert_eq!(net.result(&leader, ticket), Some(Ok(Some("audit".to_string()))));
+    assert_eq!(net.node(&old).applied("policy/exec").unwrap().as_deref(), Some("audit"));
+}
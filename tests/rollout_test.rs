@@ -0,0 +1,187 @@
+use std::{io::{Read, Write}, net::TcpListener, sync::Arc, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
+use p2p::container::FakeRuntime;
+use p2p::deploy::{Candidates, DeployState, DeployStatus, Deployment, Reconciler};
+use p2p::health::Check;
+use p2p::rollout::{self, RolloutOptions, RolloutState};
+use p2p::service::Supervisor;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn deployment(version: &str, command: &str) -> Deployment {
+    serde_json::from_value(serde_json::json!({
+        "version": version,
+        "workload": { "kind": "container", "name": "web", "rootfs": "/srv/web", "command": [command] },
+    })).unwrap()
+}
+
+fn options(batch: &str, max_unavailable: usize) -> RolloutOptions {
+    RolloutOptions { batch: batch.to_string(), max_unavailable, timeout: 300 }
+}
+
+fn status(node: &str, version: &str, state: DeployState) -> DeployStatus {
+    DeployStatus {
+        deployment: "web".to_string(),
+        node: node.to_string(),
+        version: Some(version.to_string()),
+        state,
+        message: None,
+        drift: None,
+        drifted: None,
+        updated: now(),
+    }
+}
+
+// What the nodes report once they applied the revision the spec gives them
+fn converged(d: &Deployment, nodes: &[&str]) -> Vec<DeployStatus> {
+    nodes.iter().map(|n| status(n, &d.revision_for(n).version, DeployState::Ready)).collect()
+}
+
+#[test]
+fn test_batch_size() {
+    assert_eq!(rollout::batch_size("10%", 25).unwrap(), 3);
+    assert_eq!(rollout::batch_size("10%", 3).unwrap(), 1);
+    assert_eq!(rollout::batch_size("4", 3).unwrap(), 4);
+    assert!(rollout::batch_size("0", 3).is_err());
+    assert!(rollout::batch_size("150%", 3).is_err());
+    assert!(options("10%", 0).check().is_err());
+}
+
+#[test]
+fn test_rollout_waves() {
+    let nodes = ["a", "b", "c", "d", "e"];
+    let v1 = deployment("1", "./web");
+    assert!(rollout::start(&v1, deployment("1", "./web2"), &options("2", 1), "a").is_err());
+    let mut d = rollout::start(&v1, deployment("2", "./web2"), &options("2", 1), "a").unwrap();
+    assert_eq!(d.previous, Some(v1.revision()));
+    assert_eq!(d.revision_for("b").version, "1");
+
+    // One node at a time, the next one once the node moved is ready
+    let mut waves = Vec::new();
+    for _ in 0..20 {
+        let rollout = match rollout::step(&d, &converged(&d, &nodes), now()) {
+            Some(rollout) => rollout,
+            None => break,
+        };
+        if !rollout.wave.is_empty() && !waves.contains(&rollout.wave) {
+            waves.push(rollout.wave.clone());
+        }
+        let pending = rollout.nodes.iter().filter(|n| d.revision_for(n).version != "2").count();
+        assert!(pending <= 1);
+        d.rollout = Some(rollout);
+    }
+    assert_eq!(waves, vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+    let rollout = d.rollout.clone().unwrap();
+    assert_eq!((rollout.state, rollout.waves), (RolloutState::Complete, 3));
+    assert!(nodes.iter().all(|n| d.revision_for(n).version == "2"));
+
+    // Back to the previous version everywhere, twice goes forward again
+    let undone = rollout::undo(&d).unwrap();
+    assert_eq!((undone.version.as_str(), undone.rollout.clone()), ("1", None));
+    assert_eq!(rollout::undo(&undone).unwrap().revision(), d.revision());
+    assert!(rollout::undo(&v1).is_err());
+}
+
+#[test]
+fn test_rollout_pauses() {
+    let nodes = ["a", "b", "c"];
+    let v1 = deployment("1", "./web");
+    let mut d = rollout::start(&v1, deployment("2", "./web2"), &options("100%", 2), "a").unwrap();
+    d.rollout = rollout::step(&d, &converged(&v1, &nodes), now());
+    assert_eq!(d.rollout.as_ref().unwrap().nodes.len(), 2);
+
+    // A node not ready for other reasons takes from the budget
+    let mut reports = converged(&d, &nodes);
+    reports[2].state = DeployState::Progressing;
+    let mut unready = d.clone();
+    unready.rollout.as_mut().unwrap().nodes.clear();
+    let rollout = rollout::step(&unready, &reports, now()).unwrap();
+    assert_eq!(rollout.nodes.len(), 1);
+
+    // A node moved failing pauses the rollout, the others keep their version
+    let mut reports = converged(&d, &nodes);
+    reports[0] = DeployStatus { state: DeployState::Failed, message: Some("exited with 1".to_string()), ..reports[0].clone() };
+    let paused = rollout::step(&d, &reports, now()).unwrap();
+    assert_eq!(paused.state, RolloutState::Paused);
+    assert_eq!(paused.message.as_deref(), Some("a failed: exited with 1"));
+    d.rollout = Some(paused);
+    assert_eq!(d.revision_for("c").version, "1");
+    assert_eq!(rollout::step(&d, &converged(&d, &nodes), now()), None);
+
+    // Resumed, a node moved and never ready times the rollout out
+    d = rollout::resume(&d, "b").unwrap();
+    assert_eq!(d.rollout.as_ref().unwrap().driver, "b");
+    let reports = vec![status("a", "2", DeployState::Ready), status("b", "1", DeployState::Ready), status("c", "1", DeployState::Ready)];
+    let late = now() + 301;
+    let reports: Vec<DeployStatus> = reports.into_iter().map(|s| DeployStatus { updated: late, ..s }).collect();
+    let paused = rollout::step(&d, &reports, late).unwrap();
+    assert_eq!(paused.state, RolloutState::Paused);
+    assert_eq!(paused.message.as_deref(), Some("b not ready on version 2 after 300s"));
+    assert!(rollout::pause(&d).is_ok());
+    assert!(rollout::resume(&v1, "a").is_err());
+}
+
+#[test]
+fn test_rollout_skips_nodes_gone() {
+    let nodes = ["a", "b", "c"];
+    let v1 = deployment("1", "./web");
+    let mut d = rollout::start(&v1, deployment("2", "./web2"), &options("2", 1), "a").unwrap();
+    d.rollout = rollout::step(&d, &converged(&v1, &nodes), now());
+    assert_eq!(d.rollout.as_ref().unwrap().wave, vec!["a", "b"]);
+
+    // b stops reporting before it moved while c, not ready, takes the budget
+    let reports = vec![status("a", "2", DeployState::Ready), status("c", "1", DeployState::Progressing)];
+    let rollout = rollout::step(&d, &reports, now()).unwrap();
+    assert_eq!((rollout.state, rollout.waves), (RolloutState::Progressing, 1));
+    assert_eq!(rollout.wave, vec!["c"]);
+    assert_eq!(rollout.nodes, ["a".to_string()].into());
+    d.rollout = Some(rollout);
+    let rollout = rollout::step(&d, &converged(&d, &["a", "c"]), now()).unwrap();
+    assert_eq!(rollout.nodes, ["a".to_string(), "c".to_string()].into());
+}
+
+// Answers every connection with the status given, as a service would
+fn http_stand_in(status: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = write!(stream, "HTTP/1.0 {}\r\nContent-Length: 0\r\n\r\n", status);
+        }
+    });
+    address
+}
+
+#[tokio::test]
+async fn test_health_checks() {
+    let timeout = Duration::from_secs(2);
+    let ok = http_stand_in("200 OK");
+    let failing = http_stand_in("503 Service Unavailable");
+    Check::Tcp { address: ok.clone() }.probe(timeout).await.unwrap();
+    Check::Http { url: format!("http://{}/healthz", ok) }.probe(timeout).await.unwrap();
+    let err = Check::Http { url: format!("http://{}/healthz", failing) }.probe(timeout).await.unwrap_err();
+    assert!(err.ends_with("returned 503"), "{}", err);
+    assert!(Check::Http { url: "ftp://localhost/".to_string() }.check().is_err());
+    assert!(Check::Tcp { address: "localhost".to_string() }.check().is_err());
+    let command = |c: &str| Check::Command { command: vec!["sh".to_string(), "-c".to_string(), c.to_string()] };
+    command("true").probe(timeout).await.unwrap();
+    assert_eq!(command("echo down >&2; exit 3").probe(timeout).await.unwrap_err(), "exited with 3: down");
+    assert!(command("sleep 5").probe(Duration::from_millis(100)).await.unwrap_err().starts_with("timed out"));
+
+    // Not ready until the check passes
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let tmp = tempfile::tempdir().unwrap();
+    let reconciler = Reconciler::new("a", Supervisor::new(&tmp.path().to_string_lossy()), Arc::new(FakeRuntime::new()), &db).unwrap();
+    let candidates = Candidates { local: "a".to_string(), nodes: [("a".to_string(), Default::default())].into() };
+    let mut d = deployment("1", "./web");
+    d.health = Some(Check::Http { url: format!("http://{}/", failing) });
+    let status = reconciler.reconcile(std::slice::from_ref(&d), &candidates).await[0].1.clone().unwrap();
+    assert_eq!(status.state, DeployState::Progressing);
+    assert!(status.message.unwrap().starts_with("health check failed"));
+    d.health = Some(Check::Http { url: format!("http://{}/", ok) });
+    let status = reconciler.reconcile(&[d], &candidates).await[0].1.clone().unwrap();
+    assert_eq!(status.state, DeployState::Ready);
+}
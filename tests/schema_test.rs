@@ -0,0 +1,49 @@
+use p2p::schema::{self, Tree, SCHEMA_VERSION};
+use p2p::peer::Peer;
+
+#[test]
+fn test_migrate_legacy_database() {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    // Layout written before schema versioning
+    db.insert("NodeLocalKey", &[1u8; 32]).unwrap();
+    db.insert("NodePeersKey$peer-a", r#"{"id":"peer-a","hostname":"a","host_mac":"","addrs":[],"status":"Connected"}"#).unwrap();
+    db.insert("NodePeersKey$peer-b", "not json").unwrap();
+
+    assert_eq!(schema::migrate(&db).unwrap(), 0);
+    assert_eq!(schema::stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+    assert!(db.is_empty());
+    assert!(Tree::Node.open(&db).unwrap().get("NodeLocalKey").unwrap().is_some());
+    let peer = Peer::parse(&Tree::Peers.open(&db).unwrap().get("peer-a").unwrap().unwrap()).unwrap();
+    assert_eq!(peer.hostname, "a");
+    assert!(Tree::Quarantine.open(&db).unwrap().get("NodePeersKey$peer-b").unwrap().is_some());
+}
+
+#[test]
+fn test_refuse_newer_database() {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    Tree::Meta.open(&db).unwrap().insert("schema_version", &(SCHEMA_VERSION + 1).to_be_bytes()).unwrap();
+    assert!(schema::migrate(&db).is_err());
+}
+
+#[test]
+fn test_new_database_skips_migrations() {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    assert_eq!(schema::migrate(&db).unwrap(), SCHEMA_VERSION);
+    assert_eq!(schema::stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+}
+
+#[test]
+fn test_migrations_run_again() {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    db.insert("NodeLocalKey", &[1u8; 32]).unwrap();
+    db.insert("NodePeersKey$peer-a", r#"{"id":"peer-a","hostname":"a","host_mac":"","addrs":[],"status":"Connected"}"#).unwrap();
+    schema::migrate(&db).unwrap();
+    // As if the node crashed before storing the version
+    schema::set_version(&db, 0).unwrap();
+    assert_eq!(schema::migrate(&db).unwrap(), 0);
+    assert_eq!(schema::stored_version(&db).unwrap(), Some(SCHEMA_VERSION));
+    assert_eq!(Tree::Node.open(&db).unwrap().get("NodeLocalKey").unwrap().unwrap(), &[1u8; 32]);
+    assert_eq!(Tree::Peers.open(&db).unwrap().len(), 1);
+    assert!(Tree::Quarantine.open(&db).unwrap().is_empty());
+    assert!(db.tree_names().iter().any(|name| name == b"deployed"));
+}
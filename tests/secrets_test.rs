@@ -0,0 +1,23 @@
+use p2p::schema::Tree;
+use p2p::secrets::{self, SecretStore};
+
+#[test]
+fn test_secret_store_encryption_and_rekey() {
+    let db = sled::Config::new().temporary(true).open().expect("open db failed");
+    let node = Tree::Node.open(&db).unwrap();
+    node.insert("NodeLocalKey", "plaintext").unwrap();
+
+    let store = SecretStore::open(&db, Some(b"old")).expect("open store failed");
+    assert!(store.is_encrypted());
+    // The plaintext secret was moved into the encrypted tree
+    assert!(node.get("NodeLocalKey").unwrap().is_none());
+    assert_eq!(store.get("NodeLocalKey").unwrap().unwrap(), b"plaintext");
+
+    assert!(SecretStore::open(&db, None).is_err());
+    assert!(SecretStore::open(&db, Some(b"wrong")).is_err());
+
+    secrets::rekey(&db, Some(b"old"), b"new").expect("rekey failed");
+    assert!(SecretStore::open(&db, Some(b"old")).is_err());
+    let store = SecretStore::open(&db, Some(b"new")).expect("open with new passphrase failed");
+    assert_eq!(store.get("NodeLocalKey").unwrap().unwrap(), b"plaintext");
+}
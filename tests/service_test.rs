@@ -0,0 +1,100 @@
+use std::time::Duration;
+use p2p::service::{RestartPolicy, ServiceRequest, ServiceResponse, ServiceSpec, ServiceState, Supervisor};
+
+fn spec(name: &str, script: &str, restart: RestartPolicy) -> ServiceSpec {
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "command": ["sh", "-c", script],
+        "env": { "GREETING": "hello" },
+        "restart": restart,
+    })).unwrap()
+}
+
+async fn wait_for(supervisor: &Supervisor, name: &str, state: ServiceState) {
+    for _ in 0..100 {
+        if supervisor.status(name).unwrap().state == state {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} is {:?}, not {:?}", name, supervisor.status(name).unwrap().state, state);
+}
+
+#[tokio::test]
+async fn test_service_output_and_exit() {
+    let tmp = tempfile::tempdir().unwrap();
+    let supervisor = Supervisor::new(&tmp.path().to_string_lossy());
+    supervisor.declare(spec("echo", "echo $GREETING; echo oops >&2", RestartPolicy::OnFailure)).unwrap();
+    supervisor.start("echo").unwrap();
+    wait_for(&supervisor, "echo", ServiceState::Exited).await;
+    let mut lines = supervisor.logs("echo", 10).unwrap();
+    lines.sort();
+    assert_eq!(lines, vec!["hello", "oops"]);
+    assert_eq!(supervisor.status("echo").unwrap().exit_code, Some(0));
+    assert!(supervisor.start("missing").is_err());
+    assert!(supervisor.declare(spec("../etc", "true", RestartPolicy::Never)).is_err());
+}
+
+#[tokio::test]
+async fn test_service_stop_and_restart() {
+    let tmp = tempfile::tempdir().unwrap();
+    let supervisor = Supervisor::new(&tmp.path().to_string_lossy());
+    supervisor.declare(spec("sleeper", "exec sleep 30", RestartPolicy::Always)).unwrap();
+    supervisor.start("sleeper").unwrap();
+    wait_for(&supervisor, "sleeper", ServiceState::Running).await;
+    let pid = supervisor.status("sleeper").unwrap().pid;
+    // Peers that are not operators may only list the services
+    match supervisor.serve(ServiceRequest::Stop { name: "sleeper".to_string() }, false).await {
+        ServiceResponse::Error { message } => assert_eq!(message, "Not an operator of this node"),
+        res => panic!("{:?}", res),
+    }
+    assert!(matches!(supervisor.serve(ServiceRequest::Logs { name: "sleeper".to_string(), lines: 10 }, false).await, ServiceResponse::Error { .. }));
+    assert!(matches!(supervisor.serve(ServiceRequest::List, false).await, ServiceResponse::Services { .. }));
+    assert_eq!(supervisor.status("sleeper").unwrap().state, ServiceState::Running);
+    match supervisor.serve(ServiceRequest::Restart { name: "sleeper".to_string() }, true).await {
+        ServiceResponse::Service { .. } => {},
+        res => panic!("{:?}", res),
+    }
+    wait_for(&supervisor, "sleeper", ServiceState::Running).await;
+    assert_ne!(supervisor.status("sleeper").unwrap().pid, pid);
+    // Running specs are not replaced
+    assert!(supervisor.declare(spec("sleeper", "true", RestartPolicy::Never)).is_err());
+    let status = supervisor.stop("sleeper").await.unwrap();
+    assert_eq!(status.state, ServiceState::Stopped);
+    assert_eq!(status.pid, None);
+}
+
+#[tokio::test]
+async fn test_service_restart_policy() {
+    let tmp = tempfile::tempdir().unwrap();
+    let supervisor = Supervisor::new(&tmp.path().to_string_lossy());
+    supervisor.declare(spec("crashing", "exit 3", RestartPolicy::OnFailure)).unwrap();
+    supervisor.declare(spec("failing", "exit 3", RestartPolicy::Never)).unwrap();
+    supervisor.start("crashing").unwrap();
+    supervisor.start("failing").unwrap();
+    wait_for(&supervisor, "failing", ServiceState::Failed).await;
+    assert_eq!(supervisor.status("failing").unwrap().exit_code, Some(3));
+    // Counted at each exit, the second start comes 1s after the first
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let status = supervisor.status("crashing").unwrap();
+    assert!(status.restarts >= 2, "{:?}", status);
+    assert_eq!(status.exit_code, Some(3));
+    supervisor.stop_all().await;
+    assert_eq!(supervisor.status("crashing").unwrap().state, ServiceState::Stopped);
+}
+
+#[tokio::test]
+async fn test_service_start_after_exit() {
+    let tmp = tempfile::tempdir().unwrap();
+    let supervisor = Supervisor::new(&tmp.path().to_string_lossy());
+    supervisor.declare(spec("once", "echo run", RestartPolicy::Never)).unwrap();
+    supervisor.start("once").unwrap();
+    wait_for(&supervisor, "once", ServiceState::Exited).await;
+    // Its spec can be replaced, and starting runs it again
+    supervisor.declare(spec("once", "echo again; exec sleep 30", RestartPolicy::Never)).unwrap();
+    supervisor.start("once").unwrap();
+    wait_for(&supervisor, "once", ServiceState::Running).await;
+    assert!(supervisor.status("once").unwrap().pid.is_some());
+    let status = supervisor.stop("once").await.unwrap();
+    assert_eq!(status.state, ServiceState::Stopped);
+}
@@ -0,0 +1,70 @@
+use p2p::swim::{MemberState, MemberUpdate, MemoryNetwork, SwimMessage};
+
+const IDS: [&str; 4] = ["a", "b", "c", "d"];
+
+fn ticks(net: &mut MemoryNetwork, n: usize) {
+    for _ in 0..n {
+        net.tick();
+    }
+}
+
+// Connected in a chain, each node learns the others by gossip
+fn chain() -> MemoryNetwork {
+    let mut net = MemoryNetwork::new(&IDS);
+    net.connect("a", "b");
+    net.connect("b", "c");
+    net.connect("c", "d");
+    ticks(&mut net, 100);
+    net
+}
+
+#[test]
+fn test_members_learn_each_other() {
+    let net = chain();
+    for by in IDS {
+        for id in IDS.iter().filter(|id| **id != by) {
+            assert_eq!(net.state(by, id), Some(MemberState::Alive), "{} sees {}", by, id);
+        }
+    }
+}
+
+#[test]
+fn test_unreachable_member_is_declared_dead_everywhere() {
+    let mut net = chain();
+    net.isolate("d");
+    ticks(&mut net, 200);
+    for by in ["a", "b", "c"] {
+        assert_eq!(net.state(by, "d"), Some(MemberState::Dead), "{} sees d", by);
+    }
+
+    // Once reachable again it refutes its death with a new incarnation
+    net.heal("d");
+    ticks(&mut net, 200);
+    let incarnation = net.node("d").incarnation();
+    assert!(incarnation > 1);
+    for by in ["a", "b", "c"] {
+        assert_eq!(net.state(by, "d"), Some(MemberState::Alive), "{} sees d", by);
+    }
+}
+
+#[test]
+fn test_indirect_probes_keep_a_member_alive() {
+    let mut net = chain();
+    // a cannot reach d itself, the others vouch for it
+    net.cut("a", "d");
+    ticks(&mut net, 200);
+    assert_ne!(net.state("a", "d"), Some(MemberState::Dead));
+    assert_eq!(net.state("b", "d"), Some(MemberState::Alive));
+}
+
+#[test]
+fn test_last_incarnation_is_dropped() {
+    let mut net = chain();
+    let incarnation = net.node("d").incarnation();
+    // Nothing greater would refute it, the node ignores it
+    let dead = MemberUpdate { id: "d".to_string(), state: MemberState::Dead, incarnation: u64::MAX, addrs: Vec::new() };
+    net.node("d").step("c", SwimMessage::Ping { seq: 1, updates: vec![dead.clone()] });
+    assert_eq!(net.node("d").incarnation(), incarnation);
+    net.node("a").step("b", SwimMessage::Ping { seq: 1, updates: vec![dead] });
+    assert_eq!(net.state("a", "d"), Some(MemberState::Alive));
+}
@@ -17,25 +17,56 @@ pub struct Response {
     pub date: String,
 }
 
-/// Only support GET requests now
+/// Send a GET request, see `request`
 pub async fn get(opts: &UdsClientOptions) -> Result<Response, Box<dyn std::error::Error>> {
-    let mut client = Endpoint::connect(&opts.uds_sock_path).await
-		.expect("Failed to connect client.");
-    let message = format!("\
-        GET {} HTTP/1.1\r\n\
+    request(opts, "GET", None).await
+}
+
+/// Send a POST request with a body, see `request`
+pub async fn post(opts: &UdsClientOptions, body: &[u8]) -> Result<Response, Box<dyn std::error::Error>> {
+    request(opts, "POST", Some(body)).await
+}
+
+/// Non-200 responses are returned as is so the caller can inspect the status
+/// code. Connection errors are `std::io::Error`.
+pub async fn request(opts: &UdsClientOptions, method: &str, body: Option<&[u8]>) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut client = Endpoint::connect(&opts.uds_sock_path).await?;
+    let mut message = format!("\
+        {} {} HTTP/1.1\r\n\
         Host: localhost\r\n\
         User-Agent: client/0.0.1\r\n\
-        Accept: */*\r\n\
-        \r\n", opts.url_path);
-    client.write_all(message.as_bytes()).await.expect("Unable to write message to client");
+        Accept: */*\r\n", method, opts.url_path);
+    if let Some(body) = body {
+        message.push_str(&format!("Content-Type: application/octet-stream\r\nContent-Length: {}\r\n", body.len()));
+    }
+    message.push_str("\r\n");
+    client.write_all(message.as_bytes()).await?;
+    if let Some(body) = body {
+        client.write_all(body).await?;
+    }
 
-    let chunk_size = 256;
+    // Read until the headers are complete and the whole body announced by
+    // Content-Length arrived, the server keeps the connection open
+    let chunk_size = 4096;
     let mut buf = BytesMut::with_capacity(chunk_size);
     loop {
-        let n = client.read_buf(&mut buf).await.expect("Unable to read message from client");
-        if n == 0 || buf.len() < buf.capacity() {
+        buf.reserve(chunk_size);
+        let n = client.read_buf(&mut buf).await?;
+        if n == 0 {
             break;
         }
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let mut response = httparse::Response::new(&mut headers);
+        if let Ok(httparse::Status::Complete(offset)) = response.parse(&buf[..]) {
+            let content_length = response.headers.iter()
+                .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                .and_then(|h| std::str::from_utf8(h.value).ok())
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if buf.len() >= offset + content_length {
+                break;
+            }
+        }
     }
     if let Ok(r) = std::str::from_utf8(&buf[..]) {
         let mut headers = [httparse::EMPTY_HEADER; 16];
@@ -44,12 +75,14 @@ pub async fn get(opts: &UdsClientOptions) -> Result<Response, Box<dyn std::error
         if res.is_err() {
             return Err(format!("Failed to parse response: {:?}", res).into());
         }
-        let status = response.code;
-        if status != Some(200) {
-            return Err(format!("Invalid status code: {:?}", status).into());
+        if response.code.is_none() {
+            return Err("Failed to parse response: missing status code".into());
         }
         // parse body
-        let body_offset = res.unwrap().unwrap();
+        let body_offset = match res.unwrap() {
+            httparse::Status::Complete(offset) => offset,
+            httparse::Status::Partial => return Err("Failed to parse response: incomplete headers".into()),
+        };
         let body = std::str::from_utf8(&r.as_bytes()[body_offset..]);
         // parse headers
         let mut content_length = 0;
@@ -59,7 +92,7 @@ pub async fn get(opts: &UdsClientOptions) -> Result<Response, Box<dyn std::error
             let name = response.headers[i].name.to_lowercase();
             let value = std::str::from_utf8(response.headers[i].value).unwrap();
             if name == "content-length" {
-                content_length = value.parse::<i32>().unwrap();
+                content_length = value.parse::<i32>().unwrap_or(0);
             } else if name == "content-type" {
                 content_type = value.to_string();
             } else if name == "date" {